@@ -48,7 +48,8 @@ use std::ptr;
 /// - Once the initializer returns, the thread-local variable is initialized to the returned value,
 ///   and it moves into the *initialized* state.
 /// - When the thread exits, the variable moves into the *dropped* state, and the variable is
-///   dropped.
+///   dropped. This happens exactly once, even if the platform runs thread-local destructors
+///   more than once.
 ///
 /// Thread-local variables can be accessed using the `with` method. If the variable is in the
 /// *uninitialized* or *initialized* states, the variable can be accessed. Otherwise, it cannot,
@@ -226,13 +227,18 @@ impl<T> TLSSlot<T> {
     pub unsafe fn drop(&self) {
         let state = (&*self.slot.get()).state();
         alloc_assert!(
-            state == TLSState::Uninitialized || state == TLSState::Initialized,
+            state != TLSState::Initializing,
             "TLSValue dropped while in state {:?}",
             state
         );
 
-        // TODO: Figure out why it's possible to be dropped in state Uninitialized.
-        if state == TLSState::Uninitialized {
+        // This can be called more than once for the same slot. pthread runs TSD destructors
+        // again if any of them left a value set, and as described below, macOS may reset
+        // thread-local variables while destructors are running, so that a destructor is
+        // registered and run a second time. The value is moved out of the slot the first time, so
+        // later calls find the slot Dropped (or, once it has been reset, Uninitialized) and do
+        // nothing. This is what guarantees that a value is dropped exactly once.
+        if state != TLSState::Initialized {
             return;
         }
 
@@ -382,6 +388,27 @@ mod tests {
         assert_eq!(unsafe { DROPPED }, true);
     }
 
+    #[test]
+    fn drop_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        fn drop() {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+        alloc_thread_local!{ static FOO: CallOnDrop = CallOnDrop(drop); }
+
+        thread::spawn(|| unsafe {
+            FOO.with(|_| {}).unwrap();
+            // as if the thread's destructors were run more than once
+            FOO.drop();
+            FOO.drop();
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+            assert!(FOO.with(|_| {}).is_none());
+        }).join()
+            .unwrap();
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
     #[bench]
     fn bench_tls(b: &mut Bencher) {
         alloc_thread_local!{ static FOO: UnsafeCell<usize> = UnsafeCell::new(0); }
@@ -71,6 +71,7 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, AtomicIsize, Ordering, fence};
 use bag::{WeakBag, SharedWeakBag, RevocableWeakBag, Revocable, PopResult, PopStatus};
 use crossbeam::mem::CachePadded;
+use std::cell::Cell;
 use std::mem;
 
 pub mod queue;
@@ -96,6 +97,55 @@ pub trait BagCleanup {
     fn cleanup(&self, item: Self::Item);
 }
 
+// The states of EPOCH_STATE.
+const EPOCH_UNKNOWN: u8 = 0;
+const EPOCH_USABLE: u8 = 1;
+const EPOCH_DESTROYED: u8 = 2;
+
+/// A thread-local value whose destructor records that crossbeam's thread-local state may have
+/// been destroyed.
+struct EpochCanary;
+
+impl Drop for EpochCanary {
+    fn drop(&mut self) {
+        EPOCH_STATE.with(|state| state.set(EPOCH_DESTROYED));
+    }
+}
+
+thread_local! {
+    static EPOCH_STATE: Cell<u8> = Cell::new(EPOCH_UNKNOWN);
+    static BEFORE_EPOCH: EpochCanary = EpochCanary;
+    static AFTER_EPOCH: EpochCanary = EpochCanary;
+}
+
+/// Initialize crossbeam's thread-local state on the current thread, between the two canaries.
+///
+/// Thread-local destructors run in the order in which they were registered, or in the reverse
+/// order, depending on the platform. Either way, crossbeam's destructor runs between those of the
+/// canaries, so it cannot have run while neither canary has been dropped.
+fn init_epoch() {
+    if EPOCH_STATE.with(|state| state.get()) != EPOCH_UNKNOWN {
+        let _ = crossbeam::mem::epoch::pin();
+        return;
+    }
+    BEFORE_EPOCH.with(|_| {});
+    let _ = crossbeam::mem::epoch::pin();
+    AFTER_EPOCH.with(|_| {});
+    EPOCH_STATE.with(|state| if state.get() == EPOCH_UNKNOWN {
+        state.set(EPOCH_USABLE)
+    });
+}
+
+/// Whether crossbeam's thread-local state, which `BagPipe` operations use, is certainly still
+/// usable on the current thread.
+///
+/// This is meant to be checked by thread-local destructors, which may run before or after
+/// crossbeam's. It is false on threads that have not created or cloned a `BagPipe`, and it
+/// assumes that nothing used crossbeam on the current thread before that.
+pub fn epoch_usable() -> bool {
+    EPOCH_STATE.with(|state| state.get()) == EPOCH_USABLE
+}
+
 #[derive(Copy, Clone)]
 pub struct DummyCleanup<T>(PhantomData<T>);
 
@@ -153,7 +203,7 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> Clone for BagPipe<B, C
         // handle for elfmalloc is initialized, reentrancy guards are no longer checked; this
         // forces later calls to elfmalloc that require EBMR to initialize TLS, and call malloc:
         // resulting in a recursive malloc call that blows out the stack.
-        let _ = crossbeam::mem::epoch::pin();
+        init_epoch();
         #[cfg(feature="prime_schedules")]
         let offset = {
             primes::get(self.pipes.all_refs.fetch_add(1, Ordering::Relaxed) + 1)
@@ -176,6 +226,7 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> Clone for BagPipe<B, C
 
 impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> BagPipe<B, Clean> {
     pub fn new_size_cleanup(size: usize, clean: Clean) -> Self {
+        init_epoch();
         #[cfg(feature="prime_schedules")]
         let offset = primes::get(1);
         #[cfg(not(feature="prime_schedules"))]
@@ -248,6 +299,7 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item> + Default> BagPipe<B, C
     }
 
     pub fn new() -> Self {
+        init_epoch();
         #[cfg(feature="prime_schedules")]
         let offset = primes::get(1);
         #[cfg(not(feature="prime_schedules"))]
@@ -502,3 +554,28 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> BagPipeState<B, Clean>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use queue::YangCrummeyQueue;
+    use std::thread;
+
+    type Pipe = BagPipe<YangCrummeyQueue<usize>, DummyCleanup<usize>>;
+
+    #[test]
+    fn epoch_usable_after_init() {
+        thread::spawn(|| {
+            assert!(!epoch_usable());
+            let bp = Pipe::new();
+            assert!(epoch_usable());
+            thread::spawn(move || {
+                assert!(!epoch_usable());
+                let _clone = bp.clone();
+                assert!(epoch_usable());
+            }).join()
+                .unwrap();
+        }).join()
+            .unwrap();
+    }
+}
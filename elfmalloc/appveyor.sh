@@ -19,6 +19,6 @@ exit 0
 
 cargo build
 RUST_BACKTRACE=1 cargo test
-for feature in prime_schedules huge_segments no_lazy_region nightly; do
+for feature in prime_schedules huge_segments no_lazy_region nightly freeze global-allocator; do
   RUST_BACKTRACE=1 cargo test --features "$feature"
 done
@@ -0,0 +1,21 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Interpret the input as an allocation program over small and medium sizes.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate elfmalloc;
+
+use elfmalloc::program::{decode, Interpreter, SizeProfile};
+
+fuzz_target!(|data: &[u8]| {
+    let ops = decode(data, SizeProfile::Small);
+    if let Err(v) = Interpreter::new().run(&ops) {
+        panic!("{}", v);
+    }
+});
@@ -21,8 +21,10 @@ extern crate libc;
 use self::alloc::allocator::{Alloc, AllocErr, Layout};
 #[cfg(feature = "c-api")]
 use self::malloc_bind::{LayoutFinder, Malloc, MIN_ALIGN};
-use super::general::global;
-use std::mem;
+use super::general::{global, DynamicAllocator};
+#[cfg(feature = "global-allocator")]
+use super::general::bootstrap;
+use std::{cmp, mem, ptr};
 #[cfg(feature = "c-api")]
 use std::intrinsics::unlikely;
 
@@ -31,26 +33,168 @@ use self::libc::{size_t, c_void};
 
 /// A zero-sized type used for implementing `Alloc` and `LayoutFinder` for the global instance of
 /// elfmalloc.
+///
+/// With the `global-allocator` feature enabled, this can be used as the Rust global allocator
+/// with the `#[global_allocator]` attribute.
 pub struct ElfMallocGlobal;
 
+/// Without the `global-allocator` feature, elfmalloc's own allocations never reach
+/// `ElfMallocGlobal`, so operations are never nested.
+#[cfg(not(feature = "global-allocator"))]
+mod bootstrap {
+    use super::Layout;
+
+    #[inline(always)]
+    pub unsafe fn guarded<R, F, N>(f: F, _nested: N) -> R
+    where
+        F: FnOnce() -> R,
+        N: FnOnce() -> R,
+    {
+        f()
+    }
+
+    pub unsafe fn alloc(_l: &Layout) -> *mut u8 {
+        unreachable!()
+    }
+
+    #[inline(always)]
+    pub fn owns(_item: *mut u8) -> bool {
+        false
+    }
+}
+
+unsafe fn alloc_layout(l: &Layout) -> *mut u8 {
+    // All objects are word-aligned, so only larger alignments need the aligned path.
+    if l.align() <= mem::size_of::<usize>() {
+        global::alloc(l.size())
+    } else {
+        global::alloc_aligned(l.size(), l.align())
+    }
+}
+
+/// Like `alloc_layout`, but the object reads as zeros.
+unsafe fn alloc_layout_zeroed(l: &Layout) -> *mut u8 {
+    if l.align() <= mem::size_of::<usize>() {
+        global::alloc_zeroed(l.size())
+    } else {
+        let p = global::alloc_aligned(l.size(), l.align());
+        if !p.is_null() {
+            ptr::write_bytes(p, 0, l.size());
+        }
+        p
+    }
+}
+
 unsafe impl<'a> Alloc for &'a ElfMallocGlobal {
     unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
-        // All objects are only guaranteed to be word-aligned except for powers of two. Powers of
-        // two up to 1MiB are aligned to their size. Past that size, only page-alignment is
-        // guaranteed.
-        if l.size().is_power_of_two() || l.align() <= mem::size_of::<usize>() {
-            Ok(global::alloc(l.size()))
+        let p = bootstrap::guarded(|| alloc_layout(&l), || bootstrap::alloc(&l));
+        if p.is_null() {
+            Err(AllocErr::Exhausted { request: l })
+        } else {
+            Ok(p)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        // The bootstrap arena is zero-initialized and never reused, and once it is exhausted,
+        // bootstrap allocations are freshly mapped, so they always read as zeros.
+        let p = bootstrap::guarded(|| alloc_layout_zeroed(&l), || bootstrap::alloc(&l));
+        if p.is_null() {
+            Err(AllocErr::Exhausted { request: l })
+        } else {
+            Ok(p)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, _l: Layout) {
+        if bootstrap::owns(p) {
+            return;
+        }
+        bootstrap::guarded(|| global::free(p), || global::free_without_handle(p));
+    }
+
+    unsafe fn realloc(&mut self, p: *mut u8, l1: Layout, l2: Layout) -> Result<*mut u8, AllocErr> {
+        if bootstrap::owns(p) {
+            // Objects in the bootstrap arena are never resized in place or freed.
+            let new_p = self.alloc(l2.clone())?;
+            ptr::copy_nonoverlapping(p, new_p, cmp::min(l1.size(), l2.size()));
+            return Ok(new_p);
+        }
+        let new_p = bootstrap::guarded(
+            || global::aligned_realloc(p, l2.size(), l2.align()),
+            || {
+                let new_p = bootstrap::alloc(&l2);
+                if !new_p.is_null() {
+                    ptr::copy_nonoverlapping(p, new_p, cmp::min(l1.size(), l2.size()));
+                    global::free_without_handle(p);
+                }
+                new_p
+            },
+        );
+        // reallocating to size 0 frees p and returns null
+        if new_p.is_null() && l2.size() != 0 {
+            Err(AllocErr::Exhausted { request: l2 })
+        } else {
+            Ok(new_p)
+        }
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        // Looking up the class may initialize the global heap, which allocates.
+        let usable = unsafe {
+            bootstrap::guarded(|| global::usable_size_aligned(l.size(), l.align()), || l.size())
+        };
+        (l.size(), usable)
+    }
+}
+
+/// `DynamicAllocator` handles can be used directly with collections that are parameterized on an
+/// `Alloc`. Each layout is served from the smallest size class that holds it and is aligned
+/// enough, or from the large-allocation path if there is none; see
+/// `DynamicAllocator::alloc_aligned`.
+unsafe impl Alloc for DynamicAllocator {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let p = self.alloc_aligned(l.size(), l.align());
+        if p.is_null() {
+            Err(AllocErr::Exhausted { request: l })
         } else {
-            Ok(global::alloc(l.size().next_power_of_two()))
+            Ok(p)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let p = if l.align() <= mem::size_of::<usize>() {
+            DynamicAllocator::alloc_zeroed(self, l.size())
+        } else {
+            let p = self.alloc_aligned(l.size(), l.align());
+            if !p.is_null() {
+                ptr::write_bytes(p, 0, l.size());
+            }
+            p
+        };
+        if p.is_null() {
+            Err(AllocErr::Exhausted { request: l })
+        } else {
+            Ok(p)
         }
     }
 
     unsafe fn dealloc(&mut self, p: *mut u8, _l: Layout) {
-        global::free(p);
+        self.free(p);
     }
 
     unsafe fn realloc(&mut self, p: *mut u8, _l1: Layout, l2: Layout) -> Result<*mut u8, AllocErr> {
-        Ok(global::aligned_realloc(p, l2.size(), l2.align()))
+        let new_p = self.aligned_realloc(p, l2.size(), l2.align());
+        // reallocating to size 0 frees p and returns null
+        if new_p.is_null() && l2.size() != 0 {
+            Err(AllocErr::Exhausted { request: l2 })
+        } else {
+            Ok(new_p)
+        }
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        (l.size(), self.usable_size_aligned(l.size(), l.align()))
     }
 }
 
@@ -97,3 +241,85 @@ unsafe impl LayoutFinder for ElfMallocGlobal {
         Layout::from_size_align(size, align).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::alloc::raw_vec::RawVec;
+    use std::slice;
+
+    #[test]
+    fn raw_vec_growth() {
+        let mut buf: RawVec<usize, DynamicAllocator> = RawVec::new_in(DynamicAllocator::new());
+        let n = 1 << 20;
+        unsafe {
+            for i in 0..n {
+                if i == buf.cap() {
+                    buf.reserve(i, 1);
+                }
+                ptr::write(buf.ptr().offset(i as isize), i);
+            }
+            for i in 0..n {
+                alloc_assert_eq!(*buf.ptr().offset(i as isize), i);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_round_trip() {
+        let mut dyn = DynamicAllocator::new();
+        let mut align = 1;
+        while align <= (2 << 20) {
+            for &size in &[1, 24, 100, 4096, 40 << 10, 3 << 20] {
+                let l = Layout::from_size_align(size, align).unwrap();
+                let (lower, upper) = Alloc::usable_size(&dyn, &l);
+                alloc_assert_eq!(lower, size);
+                alloc_assert!(upper >= size);
+                unsafe {
+                    let p = Alloc::alloc(&mut dyn, l.clone()).expect("alloc should not fail");
+                    alloc_assert_eq!(p as usize % align, 0, "size={} align={}", size, align);
+                    alloc_assert!(dyn.layout_of(p).0 >= upper, "size={} align={}", size, align);
+                    // the slack reported by usable_size may be used
+                    ptr::write_bytes(p, 1, upper);
+
+                    let l2 = Layout::from_size_align(size * 2, align).unwrap();
+                    let p = Alloc::realloc(&mut dyn, p, l.clone(), l2.clone())
+                        .expect("realloc should not fail");
+                    alloc_assert_eq!(p as usize % align, 0, "size={} align={}", size, align);
+                    alloc_assert_eq!(*p.offset(size as isize - 1), 1);
+                    Alloc::dealloc(&mut dyn, p, l2);
+
+                    let z = Alloc::alloc_zeroed(&mut dyn, l.clone()).expect("alloc should not fail");
+                    alloc_assert_eq!(z as usize % align, 0);
+                    alloc_assert!(slice::from_raw_parts(z, size).iter().all(|&b| b == 0));
+                    Alloc::dealloc(&mut dyn, z, l);
+                }
+            }
+            align *= 2;
+        }
+    }
+
+    #[test]
+    fn global_layout_round_trip() {
+        let mut a = &ElfMallocGlobal;
+        let mut align = 1;
+        while align <= (2 << 20) {
+            for &size in &[1, 24, 48, 100, 4096, 40 << 10, 3 << 20] {
+                let l = Layout::from_size_align(size, align).unwrap();
+                let (lower, upper) = Alloc::usable_size(&a, &l);
+                alloc_assert_eq!(lower, size);
+                alloc_assert!(upper >= size);
+                unsafe {
+                    let p = Alloc::alloc(&mut a, l.clone()).expect("alloc should not fail");
+                    alloc_assert_eq!(p as usize % align, 0, "size={} align={}", size, align);
+                    let usable = global::get_layout(p).0;
+                    alloc_assert!(usable >= upper, "size={} align={}", size, align);
+                    // the slack reported by usable_size may be used
+                    ptr::write_bytes(p, 1, upper);
+                    Alloc::dealloc(&mut a, p, l);
+                }
+            }
+            align *= 2;
+        }
+    }
+}
@@ -14,5 +14,8 @@
 pub enum AllocType {
     SmallSlag,
     BigSlag,
+    /// A slag of a class too large for the pages of `BigSlag`s. Its page holds a single object,
+    /// which starts halfway through the page or earlier.
+    HugeSlag,
     Large,
 }
@@ -0,0 +1,276 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Region allocation on top of a `DynamicAllocator`; see `Arena`.
+use std::cell::{Cell, UnsafeCell};
+use std::cmp;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+use super::alloc_type::AllocType;
+use super::general::DynamicAllocator;
+use super::slag::Slag;
+
+/// A region allocator that frees all of its objects at once when it is dropped.
+///
+/// An `Arena` takes whole small pages from the `DynamicAllocator` it borrows and hands out
+/// objects by bumping a pointer through them. Dropping the arena returns its pages to the
+/// allocator, so releasing every object costs time proportional to the number of pages rather
+/// than the number of objects. Objects larger than a quarter of a page are allocated from the
+/// `DynamicAllocator` directly, and are freed individually when the arena is dropped.
+///
+/// Objects are returned as `ArenaObject`s, which borrow the arena, so none of them can outlive
+/// it. Their memory is uninitialized, and must not be passed to the `DynamicAllocator` (or
+/// anything else) to be freed.
+///
+/// ```rust,ignore
+/// let mut dyn = DynamicAllocator::new();
+/// let arena = dyn.arena();
+/// let mut request = arena.alloc(100);
+/// let aligned = arena.alloc_aligned(64, 64);
+/// unsafe { ptr::write_bytes(request.as_mut_ptr(), 0, request.len()) };
+/// // both objects are freed here
+/// mem::drop(arena);
+/// ```
+///
+/// An object cannot be used once its arena is gone:
+///
+/// ```compile_fail
+/// # extern crate elfmalloc;
+/// # use elfmalloc::general::DynamicAllocator;
+/// # fn main() {
+/// let mut dyn = DynamicAllocator::new();
+/// let obj = {
+///     let arena = dyn.arena();
+///     arena.alloc(8)
+/// };
+/// let _ = obj.len();
+/// # }
+/// ```
+pub struct Arena<'a> {
+    alloc: UnsafeCell<&'a mut DynamicAllocator>,
+    /// The next free byte of the current page, or null if there is no page yet.
+    bump: Cell<*mut u8>,
+    end: Cell<*mut u8>,
+    /// The most recently taken page. Each page links to the one taken before it.
+    pages: Cell<*mut ArenaPage>,
+    /// The most recent object allocated from the `DynamicAllocator`.
+    large: Cell<*mut ArenaLarge>,
+}
+
+/// The header of a page owned by an `Arena`.
+#[repr(C)]
+struct ArenaPage {
+    // Every page a Slag has been initialized on starts with its AllocType, and pages aligned to
+    // ELFMALLOC_PAGE_SIZE hold the stamp read by get_type.
+    ty: AllocType,
+    next: *mut ArenaPage,
+}
+
+/// A record, itself allocated in the arena, of an object allocated from the `DynamicAllocator`.
+struct ArenaLarge {
+    item: *mut u8,
+    next: *mut ArenaLarge,
+}
+
+/// An object allocated from an `Arena`, valid for as long as the arena is borrowed.
+///
+/// The object's memory is uninitialized. Its `len` bytes may be written through `as_mut_ptr`,
+/// and read once they have been written.
+pub struct ArenaObject<'b> {
+    ptr: *mut u8,
+    len: usize,
+    _marker: PhantomData<&'b mut [u8]>,
+}
+
+impl<'b> ArenaObject<'b> {
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// The number of bytes the object was allocated with.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl DynamicAllocator {
+    /// Create an `Arena` that allocates from the pages of `self`.
+    pub fn arena(&mut self) -> Arena {
+        Arena {
+            alloc: UnsafeCell::new(self),
+            bump: Cell::new(ptr::null_mut()),
+            end: Cell::new(ptr::null_mut()),
+            pages: Cell::new(ptr::null_mut()),
+            large: Cell::new(ptr::null_mut()),
+        }
+    }
+}
+
+impl<'a> Arena<'a> {
+    /// Allocate `size` bytes, aligned to the word size.
+    ///
+    /// The memory is uninitialized, and is freed when the arena is dropped.
+    pub fn alloc(&self, size: usize) -> ArenaObject {
+        self.alloc_aligned(size, mem::size_of::<usize>())
+    }
+
+    /// Allocate `size` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// The memory is uninitialized, and is freed when the arena is dropped.
+    pub fn alloc_aligned(&self, size: usize, align: usize) -> ArenaObject {
+        alloc_assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+        ArenaObject {
+            ptr: self.alloc_raw(size, align),
+            len: size,
+            _marker: PhantomData,
+        }
+    }
+
+    fn alloc_raw(&self, size: usize, align: usize) -> *mut u8 {
+        match self.bump_alloc(size, align) {
+            Some(item) => item,
+            None => unsafe { self.alloc_slow(size, align) },
+        }
+    }
+
+    /// Allocate from the current page, if there is one with enough room.
+    fn bump_alloc(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let (bump, end) = (self.bump.get() as usize, self.end.get() as usize);
+        if bump == 0 {
+            return None;
+        }
+        let start = match bump.checked_add(align - 1) {
+            Some(start) => start & !(align - 1),
+            None => return None,
+        };
+        if start <= end && size <= end - start {
+            self.bump.set((start + size) as *mut u8);
+            Some(start as *mut u8)
+        } else {
+            None
+        }
+    }
+
+    unsafe fn alloc_slow(&self, size: usize, align: usize) -> *mut u8 {
+        let alloc = &mut **self.alloc.get();
+        let page_size = alloc.small_page_size();
+        if size > page_size / 4 || align > page_size / 4 {
+            let item = alloc.alloc_aligned(size, align);
+            if item.is_null() {
+                alloc_panic!("out of memory!");
+            }
+            let (node_size, node_align) =
+                (mem::size_of::<ArenaLarge>(), mem::align_of::<ArenaLarge>());
+            let node = self.alloc_raw(node_size, node_align) as *mut ArenaLarge;
+            ptr::write(
+                node,
+                ArenaLarge {
+                    item: item,
+                    next: self.large.get(),
+                },
+            );
+            self.large.set(node);
+            return item;
+        }
+        let page = alloc.alloc_small_page();
+        if page.is_null() {
+            alloc_panic!("out of memory!");
+        }
+        // Clear any Slag header left on the page, so that the page is not mistaken for a Slag of
+        // its old class (see PageAlloc::foreach_live_slag).
+        let header_size = cmp::max(mem::size_of::<Slag>(), mem::size_of::<ArenaPage>());
+        ptr::write_bytes(page, 0, header_size);
+        ptr::write(
+            page as *mut ArenaPage,
+            ArenaPage {
+                ty: AllocType::SmallSlag,
+                next: self.pages.get(),
+            },
+        );
+        self.pages.set(page as *mut ArenaPage);
+        self.bump.set(page.offset(header_size as isize));
+        self.end.set(page.offset(page_size as isize));
+        self.bump_alloc(size, align)
+            .expect("a fresh page should fit a quarter-page object")
+    }
+}
+
+impl<'a> Drop for Arena<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            let alloc = &mut **self.alloc.get();
+            // The records of large objects are stored in the pages, so they go first.
+            let mut large = self.large.get();
+            while !large.is_null() {
+                alloc.free((*large).item);
+                large = (*large).next;
+            }
+            let mut page = self.pages.get();
+            while !page.is_null() {
+                let next = (*page).next;
+                alloc.free_small_page(page as *mut u8);
+                page = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use super::*;
+    use std::ptr::write_bytes;
+    use std::slice;
+
+    #[test]
+    fn arena() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        let mut used = Vec::new();
+        for _ in 0..10 {
+            {
+                let arena = dyn.arena();
+                let mut objs = Vec::new();
+                for i in 0..10_000 {
+                    let (size, align) = match i % 5 {
+                        0 => (1, 1),
+                        1 => (24, 8),
+                        2 => (100, 64),
+                        3 => (4 << 10, 4096),
+                        _ => (if i % 1000 == 4 { 1 << 20 } else { 0 }, 16),
+                    };
+                    let mut obj = arena.alloc_aligned(size, align);
+                    alloc_assert!(!obj.as_ptr().is_null());
+                    alloc_assert_eq!(obj.as_ptr() as usize % align, 0);
+                    alloc_assert_eq!(obj.len(), size);
+                    unsafe { write_bytes(obj.as_mut_ptr(), i as u8, size) };
+                    objs.push(obj);
+                }
+                // no object overlaps with a later one
+                for (i, obj) in objs.iter().enumerate() {
+                    let obj = unsafe { slice::from_raw_parts(obj.as_ptr(), obj.len()) };
+                    alloc_assert!(obj.iter().all(|&b| b == i as u8));
+                }
+                let word = arena.alloc(3);
+                alloc_assert_eq!(word.as_ptr() as usize % mem::size_of::<usize>(), 0);
+            }
+            used.push(dyn.memory_used());
+        }
+        // the arena's pages are reused by the next arena
+        alloc_assert!(used.iter().all(|&u| u == used[0]), "{:?}", used);
+    }
+}
@@ -10,6 +10,7 @@
 extern crate alloc;
 extern crate elfmalloc;
 extern crate num_cpus;
+use std::env;
 use std::marker;
 use alloc::heap;
 use std::mem;
@@ -20,7 +21,7 @@ use std::ptr::write_volatile;
 // use elfmalloc::slag::{AllocBuilder, LocalAllocator, MagazineAllocator};
 // use elfmalloc::general::global;
 use elfmalloc::alloc_impl::ElfMallocGlobal;
-use elfmalloc::general::DynamicAllocator;
+use elfmalloc::general::{global, DynamicAllocator, LargeFreePolicy};
 use std::sync::{Arc, Barrier};
 use std::sync::atomic::{AtomicPtr, Ordering};
 
@@ -428,6 +429,67 @@ fn bench_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thr
     a.kill()
 }
 
+/// Print the distribution of the time taken to allocate an object of `size` bytes and write to
+/// each of its pages, for `n` objects allocated from the global heap.
+///
+/// The objects are kept until the end, so that most of them are placed on pages that have never
+/// been touched. Run with `--eager-dirty background` or `--eager-dirty inline` to compare with
+/// pages touched before they are handed out.
+fn bench_first_touch_latency(size: usize, n: usize) {
+    const PAGE: usize = 4096;
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let mut ptrs = Vec::with_capacity(n);
+    let mut samples = Vec::with_capacity(n);
+    for _ in 0..n {
+        let t = time_block_once!(unsafe {
+            let p = (&ElfMallocGlobal {}).alloc(layout.clone()).unwrap();
+            for i in 0..(size / PAGE) {
+                write_volatile(p.offset((i * PAGE) as isize), 1);
+            }
+            ptrs.push(p);
+        });
+        samples.push(t);
+    }
+    for p in ptrs {
+        unsafe { (&ElfMallocGlobal {}).dealloc(p, layout.clone()) };
+    }
+    samples.sort();
+    let pct = |p: f64| samples[((samples.len() - 1) as f64 * p) as usize];
+    println!(
+        "p50 {}ns, p90 {}ns, p99 {}ns, p99.9 {}ns, max {}ns",
+        pct(0.5),
+        pct(0.9),
+        pct(0.99),
+        pct(0.999),
+        samples[samples.len() - 1]
+    );
+}
+
+/// Print the time taken by the first and by later iterations of allocating an object of `size`
+/// bytes from the global heap, writing to its first byte, and freeing it, for `n` iterations.
+///
+/// Unless the free policy is `Unmap`, freed large allocations are cached, so after the first
+/// iteration the object's region is reused rather than mapped and unmapped again.
+fn bench_large_reuse(size: usize, n: usize) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let mut samples = Vec::with_capacity(n);
+    for _ in 0..n {
+        let t = time_block_once!(unsafe {
+            let p = (&ElfMallocGlobal {}).alloc(layout.clone()).unwrap();
+            write_volatile(p, 1);
+            (&ElfMallocGlobal {}).dealloc(p, layout.clone());
+        });
+        samples.push(t);
+    }
+    let warm = &samples[1..];
+    println!(
+        "first {}ns, later mean {}ns, later max {}ns",
+        samples[0],
+        warm.iter().sum::<u64>() / warm.len() as u64,
+        warm.iter().max().unwrap()
+    );
+}
+
 macro_rules! run_bench_inner {
     ($bench:tt, $nthreads:expr, $iters:expr) => {
         let iters = $iters;
@@ -461,6 +523,15 @@ macro_rules! run_bench {
 
 fn main() {
     const ITERS: usize = 1_000_000;
+    // `--eager-dirty MODE` selects how the global heap pre-touches pages (see
+    // `elfmalloc::general::EagerDirty`). It has to be set before the heap is first used.
+    let args = env::args().collect::<Vec<_>>();
+    let eager_dirty = args.iter()
+        .position(|arg| arg == "--eager-dirty")
+        .and_then(|i| args.get(i + 1).cloned());
+    if let Some(ref mode) = eager_dirty {
+        env::set_var("ELFMALLOC_EAGER_DIRTY", mode);
+    }
     let nthreads = num_cpus::get();
     println!(
         "allocating {} bytes per thread",
@@ -469,6 +540,20 @@ fn main() {
 
     run_bench!(both "alloc/free pairs", bench_alloc_free_pairs, nthreads, ITERS);
     run_bench!(both "buffered alloc/free pairs", bench_alloc_free_pairs_buffered, nthreads, ITERS);
+    println!(
+        "\nfirst-touch latency of 128KiB objects (eager dirtying: {})",
+        eager_dirty.as_ref().map_or("none", |mode| &mode[..])
+    );
+    bench_first_touch_latency(128 << 10, 4096);
+    for &policy in &[
+        LargeFreePolicy::Unmap,
+        LargeFreePolicy::MadvFree,
+        LargeFreePolicy::MadvDontneed,
+    ] {
+        println!("\nalloc/free of a 16MiB object ({:?})", policy);
+        global::set_large_free_policy(policy);
+        bench_large_reuse(16 << 20, 10_000);
+    }
     // run_bench!(both "alloc (thread-local)", bench_alloc, nthreads, ITERS);
     // run_bench!(both "free (thread-local)", bench_free, nthreads, ITERS);
     // run_bench!(both "alloc & free (thread-local)", bench_alloc_free, nthreads, ITERS);
@@ -16,14 +16,33 @@ use super::slag::*;
 use super::sources::MmapSource;
 use super::utils::{likely, OwnedArray, LazyInitializable, mmap};
 use super::alloc_type::AllocType;
+use object_alloc::ObjectAllocStats;
 use std::marker::PhantomData;
 use std::mem;
 use std::ptr;
 use std::cmp;
 
 pub trait Frontend: LazyInitializable + Clone {
+    /// Allocate an object, or return a null pointer if no memory is available.
     unsafe fn alloc(&mut self) -> *mut u8;
+    /// Like `alloc`, but also report whether the object is known to read as zeros because it has
+    /// never been allocated since its page was mapped.
+    unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool);
     unsafe fn free(&mut self, item: *mut u8);
+    /// Report the number of cached objects, the number of objects allocated using this handle,
+    /// and the approximate number of `Slag`s held for this object class.
+    fn stats(&self) -> ObjectAllocStats;
+    /// Return cached objects to their `Slag`s until at most `target` remain cached, as reported
+    /// by `stats`.
+    unsafe fn shrink_to(&mut self, target: usize);
+    /// Allocate objects into the cache until at least `target` are cached, or until the cache is
+    /// full, returning the number of objects cached.
+    ///
+    /// Fewer objects are cached if no memory is available.
+    unsafe fn fill(&mut self, target: usize) -> usize;
+    /// Called periodically to let caches whose capacity adapts to their use shrink if they have
+    /// not been busy since the previous calls (see `MagazineCapacity`).
+    unsafe fn decay(&mut self) {}
 }
 
 /// A `LocalCache` provides thread-local data on top of a `SlagAllocator`.
@@ -38,6 +57,8 @@ pub struct LocalCache<CA: CoarseAllocator> {
     alloc: SlagAllocator<CA>,
     vals: PtrStack,
     iter: AllocIter,
+    /// The number of objects allocated from this cache.
+    n_allocs: usize,
 }
 
 impl<CA: CoarseAllocator> Drop for LocalCache<CA> {
@@ -52,6 +73,7 @@ impl<CA: CoarseAllocator> Drop for LocalCache<CA> {
                 let item = *self.vals.data.get(i);
                 self.alloc.free(item)
             }
+            self.alloc.release_slag();
         }
     }
 }
@@ -70,6 +92,7 @@ impl<CA: CoarseAllocator> LocalCache<CA> {
                 alloc: alloc,
                 vals: stack,
                 iter: iter,
+                n_allocs: 0,
             }
         }
     }
@@ -85,19 +108,116 @@ impl<CA: CoarseAllocator> Frontend for LocalCache<CA> {
     }
 
     unsafe fn alloc(&mut self) -> *mut u8 {
+        self.n_allocs += 1;
         self.vals
             .pop()
             .or_else(|| self.iter.next())
-            .unwrap_or_else(|| {
-                let next_iter = self.alloc.refresh();
-                self.iter = next_iter;
-                self.iter.next().expect("New iterator should have values")
+            .unwrap_or_else(|| match self.alloc.try_refresh() {
+                Some(next_iter) => {
+                    self.iter = next_iter;
+                    self.iter.next().expect("New iterator should have values")
+                }
+                None => ptr::null_mut(),
             })
     }
+
+    unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool) {
+        self.n_allocs += 1;
+        if let Some(ptr) = self.vals.pop() {
+            return (ptr, false);
+        }
+        if let Some(ptr) = self.iter.next() {
+            return (ptr, self.iter.fresh);
+        }
+        match self.alloc.try_refresh() {
+            Some(next_iter) => self.iter = next_iter,
+            None => return (ptr::null_mut(), false),
+        }
+        let ptr = self.iter.next().expect("New iterator should have values");
+        (ptr, self.iter.fresh)
+    }
+
+    fn stats(&self) -> ObjectAllocStats {
+        let mut stats = ObjectAllocStats::default();
+        stats.cached_objects = Some(self.vals.top);
+        stats.total_allocated = Some(self.n_allocs);
+        stats.backing_pages = Some(self.alloc.held_slags());
+        stats
+    }
+
+    unsafe fn shrink_to(&mut self, target: usize) {
+        while self.vals.top > target {
+            let item = self.vals.pop().unwrap();
+            self.alloc.free(item);
+        }
+    }
+    unsafe fn fill(&mut self, target: usize) -> usize {
+        // Only objects of the current slag are cached, so no new slags are acquired.
+        while self.vals.top < target {
+            match self.iter.next() {
+                Some(item) => self.vals.push(item),
+                None => break,
+            }
+        }
+        self.vals.top
+    }
 }
 
 
 
+/// The number of objects a `MagazineCache` for objects of `object_size` bytes holds by default.
+///
+/// Caches of small objects hold many of them, so that objects are rarely returned to their slags,
+/// while caches of the largest objects hold very few, since each of them holds a lot of memory.
+pub fn magazine_capacity(object_size: usize) -> usize {
+    const CUTOFF: usize = 32 << 10;
+    let magazine_size = match object_size {
+        0...512 => 1 << 16,
+        513...CUTOFF => (512 << 10) / object_size,
+        _ => (1 << 20) / object_size,
+    };
+    cmp::max(1, magazine_size)
+}
+
+/// The number of objects a `MagazineCache` holds.
+///
+/// A cache starts out holding `initial` objects. If `min` is below `max`, its capacity then
+/// adapts to its use, between `min` and `max`: it doubles when the cache misses often, either
+/// allocating from its slag because it is empty or returning objects because it is full, and it
+/// halves once `QUIET_PERIODS` calls to `Frontend::decay` in a row find that it has not missed
+/// in between, returning the objects beyond its new capacity to their slags. Misses are only
+/// counted on the slow paths that they take, so a cache that does not miss pays nothing for it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MagazineCapacity {
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl MagazineCapacity {
+    /// A capacity of `capacity` objects that does not adapt.
+    pub fn fixed(capacity: usize) -> MagazineCapacity {
+        MagazineCapacity {
+            initial: capacity,
+            min: capacity,
+            max: capacity,
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.min < self.max
+    }
+}
+
+/// The number of allocations over which a `MagazineCache` counts its misses.
+pub const MISS_WINDOW: usize = 4096;
+/// The number of misses within `MISS_WINDOW` allocations after which an adaptive `MagazineCache`
+/// doubles its capacity.
+pub const GROW_MISSES: usize = 64;
+/// The number of calls to `Frontend::decay` without a miss after which an adaptive
+/// `MagazineCache` halves its capacity.
+pub const QUIET_PERIODS: usize = 3;
+
 /// A different approach to caching to `LocalCache` inspired by Bonwick-style magazines.
 ///
 /// The advantage `MagazineCache` has over `LocalCache` is that it will unconditionally push to a
@@ -106,26 +226,54 @@ impl<CA: CoarseAllocator> Frontend for LocalCache<CA> {
 /// ahead of a fetch-or), reducing the number of atomic instruction that must be issued for most
 /// remote frees.
 pub struct MagazineCache<CA: CoarseAllocator> {
+    /// The number of objects the cache holds at the moment.
     stack_size: usize,
+    /// The bounds within which `stack_size` adapts to the rate of misses.
+    capacity: MagazineCapacity,
+    /// The value of `n_allocs` when the current window of misses started.
+    window_start: usize,
+    /// The number of misses in the current window.
+    misses: usize,
+    /// The number of calls to `decay` since the last miss.
+    quiet: usize,
     s: PtrStack,
     iter: AllocIter,
     alloc: SlagAllocator<CA>,
     coalescer: Coalescer,
+    /// The number of objects allocated from this cache.
+    n_allocs: usize,
 }
 
 impl<CA: CoarseAllocator> LazyInitializable for MagazineCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
-        let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
-        Self::new(salloc)
+    /// The metadata of the class, the eager decommit threshold, the page allocator and the pipe
+    /// of available slags of the class, and the number of objects the cache holds.
+    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>, MagazineCapacity);
+    fn init(params: &Self::Params) -> Self {
+        match Self::try_init(params) {
+            Some(cache) => cache,
+            None => alloc_panic!("out of memory!"),
+        }
+    }
+    fn try_init(params: &Self::Params) -> Option<Self> {
+        let (meta, decommit, ref page_alloc, ref avail, capacity) = *params;
+        SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone())
+            .map(|salloc| Self::with_capacity(salloc, capacity))
     }
 }
 
 impl<CA: CoarseAllocator> LazyInitializable for LocalCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
-        let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
-        Self::new(salloc)
+    /// The parameters of a `MagazineCache`. A `LocalCache` holds up to a slag's worth of objects
+    /// instead of the given number.
+    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>, MagazineCapacity);
+    fn init(params: &Self::Params) -> Self {
+        match Self::try_init(params) {
+            Some(cache) => cache,
+            None => alloc_panic!("out of memory!"),
+        }
+    }
+    fn try_init(&(meta, decommit, ref page_alloc, ref avail, _): &Self::Params) -> Option<Self> {
+        SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone())
+            .map(Self::new)
     }
 }
 
@@ -142,41 +290,82 @@ impl<CA: CoarseAllocator> Drop for MagazineCache<CA> {
                 let item = *self.s.data.get(i);
                 self.alloc.free(item)
             }
+            self.alloc.release_slag();
         }
     }
 }
 
 impl<CA: CoarseAllocator> Clone for MagazineCache<CA> {
     fn clone(&self) -> Self {
-        MagazineCache::new_sized(self.alloc.clone(), self.stack_size)
+        MagazineCache::with_capacity(self.alloc.clone(), self.capacity)
     }
 }
 
 impl<CA: CoarseAllocator> MagazineCache<CA> {
-    pub fn new_sized(mut alloc: SlagAllocator<CA>, magazine_size: usize) -> Self {
-        alloc_assert!(magazine_size > 0);
-        let s = PtrStack::new(magazine_size);
+    pub fn new_sized(alloc: SlagAllocator<CA>, magazine_size: usize) -> Self {
+        Self::with_capacity(alloc, MagazineCapacity::fixed(magazine_size))
+    }
+
+    /// Create a `MagazineCache` holding `capacity.initial` objects, whose capacity then adapts
+    /// within the bounds of `capacity`.
+    pub fn with_capacity(mut alloc: SlagAllocator<CA>, capacity: MagazineCapacity) -> Self {
+        alloc_assert!(capacity.min > 0, "magazine capacity must be positive");
+        alloc_assert!(
+            capacity.min <= capacity.initial && capacity.initial <= capacity.max,
+            "initial magazine capacity {} is not between {} and {}",
+            capacity.initial,
+            capacity.min,
+            capacity.max
+        );
+        // The stack is mapped with room for the largest capacity, but only the part in use is
+        // touched.
+        let s = PtrStack::new(capacity.max);
         let iter = unsafe { alloc.refresh() };
-        let buckets = Coalescer::new(magazine_size * 2);
+        let buckets = Coalescer::new(capacity.max * 2);
         MagazineCache {
-            stack_size: magazine_size,
+            stack_size: capacity.initial,
+            capacity: capacity,
+            window_start: 0,
+            misses: 0,
+            quiet: 0,
             s: s,
             iter: iter,
             alloc: alloc,
             coalescer: buckets,
+            n_allocs: 0,
         }
     }
 
+    /// Create a `MagazineCache` holding up to `magazine_capacity(object_size)` objects.
     pub fn new(alloc: SlagAllocator<CA>) -> Self {
-        use std::cmp;
         let object_size = unsafe { (*alloc.m).object_size };
-        const CUTOFF: usize = 32 << 10;
-        let magazine_size = match object_size {
-            0...512 => 1 << 16,
-            513...CUTOFF => 512 << 10 / object_size,
-            _ => 1 << 20 / object_size,
-        };
-        Self::new_sized(alloc, cmp::max(1, magazine_size))
+        Self::new_sized(alloc, magazine_capacity(object_size))
+    }
+
+    /// The number of objects the cache holds at the moment.
+    pub fn capacity(&self) -> usize {
+        self.stack_size
+    }
+
+    /// Count a miss: an allocation from the slag because the cache is empty, or a return of
+    /// objects to their slags because it is full. If a window of `MISS_WINDOW` allocations has
+    /// `GROW_MISSES` misses, an adaptive cache doubles its capacity.
+    #[inline]
+    fn count_miss(&mut self) {
+        if !self.capacity.is_adaptive() {
+            return;
+        }
+        self.quiet = 0;
+        if self.n_allocs.wrapping_sub(self.window_start) >= MISS_WINDOW {
+            self.window_start = self.n_allocs;
+            self.misses = 0;
+        }
+        self.misses += 1;
+        if self.misses >= GROW_MISSES && self.stack_size < self.capacity.max {
+            self.stack_size = cmp::min(2 * self.stack_size, self.capacity.max);
+            self.window_start = self.n_allocs;
+            self.misses = 0;
+        }
     }
 
     /// Allocate memory from the current owned `Slag`.
@@ -187,7 +376,10 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
         for _ in 0..2 {
             match self.iter.next() {
                 Some(ptr) => return ptr,
-                None => self.iter = self.alloc.refresh(),
+                None => match self.alloc.try_refresh() {
+                    Some(next_iter) => self.iter = next_iter,
+                    None => return ptr::null_mut(),
+                },
             }
         }
         alloc_panic!(
@@ -226,24 +418,85 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
 
 impl<CA: CoarseAllocator> Frontend for MagazineCache<CA> {
     unsafe fn alloc(&mut self) -> *mut u8 {
+        self.n_allocs += 1;
         if let Some(ptr) = self.s.pop() {
             trace_event!(cache_alloc);
             ptr
         } else {
             trace_event!(slag_alloc);
+            self.count_miss();
             self.slag_alloc()
         }
     }
 
+    unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool) {
+        self.n_allocs += 1;
+        if let Some(ptr) = self.s.pop() {
+            trace_event!(cache_alloc);
+            (ptr, false)
+        } else {
+            trace_event!(slag_alloc);
+            self.count_miss();
+            // slag_alloc may replace the iterator, but the object comes from the current one
+            let ptr = self.slag_alloc();
+            (ptr, self.iter.fresh)
+        }
+    }
+
     unsafe fn free(&mut self, item: *mut u8) {
         trace_event!(local_free);
         if likely(self.s.top < self.stack_size) {
             self.s.push(item);
             return;
         }
+        self.count_miss();
+        if self.s.top < self.stack_size {
+            // the capacity has just grown
+            self.s.push(item);
+            return;
+        }
         self.return_memory();
         self.s.push(item);
     }
+
+    fn stats(&self) -> ObjectAllocStats {
+        let mut stats = ObjectAllocStats::default();
+        stats.cached_objects = Some(self.s.top);
+        stats.total_allocated = Some(self.n_allocs);
+        stats.backing_pages = Some(self.alloc.held_slags());
+        stats
+    }
+
+    unsafe fn shrink_to(&mut self, target: usize) {
+        while self.s.top > target {
+            let item = self.s.pop().unwrap();
+            self.alloc.free(item);
+        }
+    }
+    unsafe fn fill(&mut self, target: usize) -> usize {
+        while self.s.top < cmp::min(target, self.stack_size) {
+            let item = self.slag_alloc();
+            if item.is_null() {
+                break;
+            }
+            self.s.push(item);
+        }
+        self.s.top
+    }
+
+    unsafe fn decay(&mut self) {
+        if !self.capacity.is_adaptive() {
+            return;
+        }
+        self.quiet += 1;
+        if self.quiet < QUIET_PERIODS {
+            return;
+        }
+        self.quiet = 0;
+        self.stack_size = cmp::max(self.stack_size / 2, self.capacity.min);
+        let capacity = self.stack_size;
+        self.shrink_to(capacity);
+    }
 }
 
 /// A set data-structure used to batch remote free operations.
@@ -372,7 +625,7 @@ impl PtrStack {
     }
 }
 
-pub use self::magazine::{Depot, DepotCache};
+pub use self::magazine::{Depot, DepotCache, DEPOT_BATCH};
 
 mod magazine {
     //! A more direct port of
@@ -410,6 +663,8 @@ mod magazine {
     use super::super::bagpipe::bag::WeakBag;
     use super::super::bagpipe::{BagPipe, BagCleanup};
     use super::super::bagpipe::queue::FAAQueueLowLevel;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     /// A Custom destructor for Magazines in a `BagPipe`.
     #[derive(Copy, Clone, Default)]
@@ -499,14 +754,64 @@ mod magazine {
         }
     }
 
-    /// A global cache for empty and full `Magazine`s.
+    /// A cache for empty and full `Magazine`s.
+    ///
+    /// A `Depot` is either a single global cache, or it is split into several *shards*: smaller
+    /// caches that are themselves backed by a global cache (their `parent`). Each `DepotCache`
+    /// exchanges magazines with a single shard, and shards only exchange magazines with their
+    /// parent, `batch_size` magazines at a time, when they have too few or too many magazines.
+    /// This reduces contention on the global cache at high thread counts.
+    ///
+    /// Cloning a `Depot` does not allocate: the clones share their magazines and shards.
     #[derive(Clone)]
     pub struct Depot {
+        own: Pipes,
+        /// The global cache backing this one, if this `Depot` is a shard.
+        parent: Option<Pipes>,
+        /// The number of `Magazine`s exchanged with `parent` at once.
+        batch_size: usize,
+        /// The shards handed out by `shard`, if this `Depot` is sharded.
+        shards: Option<Arc<Vec<Depot>>>,
+    }
+
+    /// The empty and full `Magazine`s of a cache, of which there are at most `max_size` each.
+    #[derive(Clone)]
+    struct Pipes {
         max_size: isize,
         empty: MagPipe,
         full: MagPipe,
     }
 
+    impl Pipes {
+        fn new(max_size: usize, empty: usize, full: usize) -> Pipes {
+            alloc_assert!(max_size < (isize::max_value() as usize));
+            Pipes {
+                max_size: max_size as isize,
+                empty: MagPipe::new_size(empty),
+                full: MagPipe::new_size(full),
+            }
+        }
+
+        /// Add the empty `m`, unmapping it if the cache is at capacity.
+        unsafe fn free_empty(&mut self, m: *mut Magazine) {
+            if self.empty.size_guess() >= self.max_size {
+                Magazine::destroy(m);
+            } else {
+                self.empty.push_mut(m)
+            }
+        }
+
+        /// Add the full `m`, returning `false` without adding it if the cache is at capacity.
+        fn free_full(&mut self, m: *mut Magazine) -> bool {
+            if self.full.size_guess() >= self.max_size {
+                false
+            } else {
+                self.full.push_mut(m);
+                true
+            }
+        }
+    }
+
     impl Default for Depot {
         fn default() -> Depot {
             Depot::new()
@@ -527,19 +832,71 @@ mod magazine {
         }
     }
 
+    /// The number of `Magazine`s a shard of a `Depot` exchanges with the global cache at once,
+    /// unless configured otherwise.
+    pub const DEPOT_BATCH: usize = 4;
+
+    /// Used to assign shards to `DepotCache`s in a round-robin fashion.
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
     impl Depot {
-        fn new_size(max_size: usize, empty: usize, full: usize) -> Depot {
-            alloc_assert!(max_size < (isize::max_value() as usize));
+        fn with_pipes(own: Pipes) -> Depot {
             Depot {
-                max_size: max_size as isize,
-                empty: MagPipe::new_size(empty),
-                full: MagPipe::new_size(full),
+                own: own,
+                parent: None,
+                batch_size: 0,
+                shards: None,
             }
         }
 
         fn new() -> Depot {
             use super::super::num_cpus;
-            Depot::new_size(1 << 20, num_cpus::get(), num_cpus::get())
+            Depot::with_pipes(Pipes::new(1 << 20, num_cpus::get(), num_cpus::get()))
+        }
+
+        /// Create a `Depot` split into `n_shards` shards, each of which exchanges `batch_size`
+        /// `Magazine`s at a time with a global cache.
+        ///
+        /// If `n_shards` is 0 or 1, this is equivalent to `Depot::default()`.
+        pub fn new_sharded(n_shards: usize, batch_size: usize) -> Depot {
+            use super::super::num_cpus;
+            alloc_assert!(batch_size > 0, "batch_size must be positive");
+            let mut root = Depot::new();
+            if n_shards <= 1 {
+                return root;
+            }
+            let per_shard = cmp::max(1, num_cpus::get() / n_shards);
+            let shards = (0..n_shards)
+                .map(|_| Depot {
+                    own: Pipes::new(1 << 20, per_shard, per_shard),
+                    parent: Some(root.own.clone()),
+                    batch_size: batch_size,
+                    shards: None,
+                })
+                .collect();
+            root.shards = Some(Arc::new(shards));
+            root
+        }
+
+        /// The number of shards this `Depot` is split into, or 1 if it is not sharded.
+        pub fn n_shards(&self) -> usize {
+            self.shards.as_ref().map_or(1, |shards| shards.len())
+        }
+
+        /// Get a handle to the `Depot` that a new `DepotCache` should use.
+        fn shard(&self) -> Depot {
+            match self.shards {
+                Some(ref shards) => {
+                    let i = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % shards.len();
+                    shards[i].clone()
+                }
+                None => self.clone(),
+            }
+        }
+
+        /// Whether `pipe` holds enough `Magazine`s that a batch should be moved to the parent.
+        fn overfull(&self, pipe: &MagPipe) -> bool {
+            self.parent.is_some() && pipe.size_guess() >= 2 * self.batch_size as isize
         }
 
         /// Return an empty `Magazine` to the `Depot`.
@@ -547,11 +904,16 @@ mod magazine {
         /// If the `Depot` is at capacity, the `Magazine`'s memory is unmapped.
         unsafe fn free_empty(&mut self, m: *mut Magazine) {
             alloc_debug_assert_eq!((*m).top, 0);
-            if self.empty.size_guess() >= self.max_size {
-                Magazine::destroy(m);
-            } else {
-                self.empty.push_mut(m)
+            if self.overfull(&self.own.empty) {
+                let parent = self.parent.as_mut().unwrap();
+                for _ in 0..self.batch_size {
+                    match self.own.empty.pop_mut() {
+                        Some(m) => parent.free_empty(m),
+                        None => break,
+                    }
+                }
             }
+            self.own.free_empty(m)
         }
 
         /// Return a full `Magazine` to the `Depot`.
@@ -562,17 +924,45 @@ mod magazine {
             unsafe {
                 alloc_debug_assert_eq!((*m).top, (*m).cap)
             };
-            if self.full.size_guess() >= self.max_size {
-                false
-            } else {
-                self.full.push_mut(m);
-                true
+            if self.overfull(&self.own.full) {
+                let parent = self.parent.as_mut().unwrap();
+                for _ in 0..self.batch_size {
+                    match self.own.full.pop_mut() {
+                        Some(m) => if !parent.free_full(m) {
+                            self.own.full.push_mut(m);
+                            break;
+                        },
+                        None => break,
+                    }
+                }
             }
+            self.own.free_full(m)
         }
 
         /// Allocate a full `Magazine` from the `Depot` if one is present.
+        ///
+        /// If this `Depot` is a shard with no full `Magazine`s, a batch is first moved over from
+        /// the parent.
         fn alloc_full(&mut self) -> Option<*mut Magazine> {
-            self.full.pop_mut().and_then(|r| {
+            let res = match self.own.full.pop_mut() {
+                Some(r) => Some(r),
+                None => match self.parent {
+                    Some(ref mut parent) => {
+                        let r = parent.full.pop_mut();
+                        if r.is_some() {
+                            for _ in 1..self.batch_size {
+                                match parent.full.pop_mut() {
+                                    Some(m) => self.own.full.push_mut(m),
+                                    None => break,
+                                }
+                            }
+                        }
+                        r
+                    }
+                    None => None,
+                },
+            };
+            res.and_then(|r| {
                 unsafe {
                     alloc_debug_assert_eq!((*r).top, (*r).cap)
                 };
@@ -583,11 +973,11 @@ mod magazine {
         /// Allocate a full `Magazine` from the `Depot`, constructing a new one if none are
         /// present.
         fn alloc_empty(&mut self) -> *mut Magazine {
-            let res = self.empty.pop_mut().unwrap_or_else(|| {
-                unsafe {
-                    Magazine::default()
-                }
-            });
+            let res = match (self.own.empty.pop_mut(), self.parent.as_mut()) {
+                (Some(m), _) => Some(m),
+                (None, Some(parent)) => parent.empty.pop_mut(),
+                (None, None) => None,
+            }.unwrap_or_else(|| unsafe { Magazine::default() });
             unsafe {
                 alloc_debug_assert_eq!((*res).top, 0)
             };
@@ -627,16 +1017,42 @@ mod magazine {
         depot: Depot,
         m1: *mut Magazine,
         m2: *mut Magazine,
+        /// The number of objects allocated from this cache.
+        n_allocs: usize,
     }
 
     impl<FE: Frontend> LazyInitializable for DepotCache<FE> {
         type Params = (FE::Params, Depot);
-        fn init(&(ref backing, ref depot): &(FE::Params, Depot)) -> DepotCache<FE> {
-            Self::new(FE::init(backing.clone()), depot.clone())
+        fn init(params: &(FE::Params, Depot)) -> DepotCache<FE> {
+            match Self::try_init(params) {
+                Some(cache) => cache,
+                None => alloc_panic!("out of memory!"),
+            }
+        }
+        fn try_init(&(ref backing, ref depot): &(FE::Params, Depot)) -> Option<DepotCache<FE>> {
+            FE::try_init(backing).map(|backing| Self::new(backing, depot.shard()))
         }
     }
 
     impl<FE: Frontend> DepotCache<FE> {
+        /// Trim `m` to at most `keep` objects, returning the `Magazine` to use in its place.
+        ///
+        /// If no objects are to be kept and `m` is full, it is handed to the `Depot` whole.
+        /// Otherwise (or if the `Depot` is at capacity), the objects are freed to the backing
+        /// `Frontend`.
+        unsafe fn shrink_magazine(&mut self, m: *mut Magazine, keep: usize) -> *mut Magazine {
+            if keep == 0 && (*m).top == (*m).cap {
+                if let Some(empty) = self.depot.swap_full(m) {
+                    return empty;
+                }
+            }
+            while (*m).top > keep {
+                let item = (*m).pop().unwrap();
+                self.backing.free(item);
+            }
+            m
+        }
+
         fn new(backing: FE, mut depot: Depot) -> DepotCache<FE> {
             let m1 = depot.alloc_full().unwrap_or_else(|| depot.alloc_empty());
             let m2 = depot.alloc_empty();
@@ -645,12 +1061,14 @@ mod magazine {
                 depot: depot,
                 m1: m1,
                 m2: m2,
+                n_allocs: 0,
             }
         }
     }
 
     impl<FE: Frontend> Frontend for DepotCache<FE> {
         unsafe fn alloc(&mut self) -> *mut u8 {
+            self.n_allocs += 1;
             if let Some(p) = (*self.m1).pop() {
                 return p;
             }
@@ -664,11 +1082,34 @@ mod magazine {
             } else {
                 let cap = (*self.m1).cap;
                 for _ in 0..cap {
-                    let _r = (*self.m1).push(self.backing.alloc());
+                    let p = self.backing.alloc();
+                    if p.is_null() {
+                        break;
+                    }
+                    let _r = (*self.m1).push(p);
                     alloc_debug_assert!(_r);
                 }
             }
-            (*self.m1).pop().expect("new full magazine is empty")
+            // the magazine is only empty if the backing frontend is out of memory
+            (*self.m1).pop().unwrap_or(ptr::null_mut())
+        }
+
+        unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool) {
+            // Objects in magazines have been freed, so only objects allocated directly from the
+            // backing frontend can be fresh.
+            self.n_allocs += 1;
+            if let Some(p) = (*self.m1).pop() {
+                return (p, false);
+            }
+            mem::swap(&mut self.m1, &mut self.m2);
+            if let Some(p) = (*self.m1).pop() {
+                return (p, false);
+            }
+            if let Some(m) = self.depot.swap_empty(self.m1) {
+                self.m1 = m;
+                return ((*self.m1).pop().expect("new full magazine is empty"), false);
+            }
+            self.backing.alloc_fresh()
         }
 
         unsafe fn free(&mut self, item: *mut u8) {
@@ -691,6 +1132,49 @@ mod magazine {
             let _r = (*self.m1).push(item);
             alloc_debug_assert!(_r);
         }
+
+        fn stats(&self) -> ObjectAllocStats {
+            // objects in the magazines are cached in addition to those cached by the backing
+            // frontend
+            let mut stats = self.backing.stats();
+            let in_magazines = unsafe { (*self.m1).top + (*self.m2).top };
+            stats.cached_objects = stats.cached_objects.map(|n| n + in_magazines);
+            stats.total_allocated = Some(self.n_allocs);
+            stats
+        }
+
+        unsafe fn shrink_to(&mut self, target: usize) {
+            // Objects in `m1` are allocated first, so they are the last to go. Objects removed
+            // from the magazines may be cached by the backing frontend, so it is shrunk last.
+            let (m1, m2) = (self.m1, self.m2);
+            let keep1 = cmp::min((*m1).top, target);
+            let keep2 = cmp::min((*m2).top, target - keep1);
+            self.m1 = self.shrink_magazine(m1, keep1);
+            self.m2 = self.shrink_magazine(m2, keep2);
+            self.backing.shrink_to(target - keep1 - keep2);
+        }
+
+        unsafe fn fill(&mut self, target: usize) -> usize {
+            // `m1` is allocated from first, so it is filled first. The backing frontend only
+            // caches what does not fit in the magazines.
+            let mut cached = 0;
+            for &m in &[self.m1, self.m2] {
+                while (*m).top < (*m).cap && cached + (*m).top < target {
+                    let p = self.backing.alloc();
+                    if p.is_null() {
+                        break;
+                    }
+                    let _r = (*m).push(p);
+                    alloc_debug_assert!(_r);
+                }
+                cached += (*m).top;
+            }
+            cached + self.backing.fill(target.saturating_sub(cached))
+        }
+
+        unsafe fn decay(&mut self) {
+            self.backing.decay()
+        }
     }
 
     #[cfg(test)]
@@ -713,6 +1197,63 @@ mod magazine {
                 Magazine::destroy(m);
             }
         }
+
+        #[test]
+        fn sharded_depot_imbalance() {
+            const N_MAGAZINES: usize = 32;
+            const BATCH: usize = 4;
+            unsafe fn drain_and_destroy(m: *mut Magazine) {
+                while let Some(_) = (*m).pop() {}
+                Magazine::destroy(m);
+            }
+            let depot = Depot::new_sharded(2, BATCH);
+            alloc_assert_eq!(depot.n_shards(), 2);
+            let shards = depot.shards.as_ref().unwrap();
+            let mut producer = shards[0].clone();
+            let mut consumer = shards[1].clone();
+            unsafe {
+                for _ in 0..N_MAGAZINES {
+                    let m = Magazine::new(4);
+                    for i in 1..5 {
+                        alloc_assert!((*m).push(i as *mut u8));
+                    }
+                    alloc_assert!(producer.free_full(m));
+                }
+                // The producer only keeps a bounded number of magazines; the rest are available to
+                // the consumer through the global depot.
+                let mut consumed = 0;
+                while let Some(m) = consumer.alloc_full() {
+                    drain_and_destroy(m);
+                    consumed += 1;
+                }
+                alloc_assert!(consumed >= N_MAGAZINES - 2 * BATCH, "consumed {}", consumed);
+                while let Some(m) = producer.alloc_full() {
+                    drain_and_destroy(m);
+                    consumed += 1;
+                }
+                alloc_assert_eq!(consumed, N_MAGAZINES);
+            }
+        }
+
+        #[test]
+        fn depot_cache_shrink_to() {
+            let backing = AllocBuilder::<usize>::default().build_magazine().0;
+            let mut cache = DepotCache::new(backing, Depot::default());
+            unsafe {
+                let items = (0..8192).map(|_| cache.alloc()).collect::<Vec<_>>();
+                for i in items {
+                    cache.free(i);
+                }
+                cache.shrink_to(100);
+                alloc_assert_eq!(cache.stats().cached_objects, Some(100));
+                cache.shrink_to(0);
+                alloc_assert_eq!(cache.stats().cached_objects, Some(0));
+                let items = (0..8192).map(|_| cache.alloc()).collect::<Vec<_>>();
+                for i in items {
+                    cache.free(i);
+                }
+            }
+        }
     }
 }
 
@@ -818,9 +1359,30 @@ macro_rules! typed_wrapper {
                 self.0.alloc() as *mut T
             }
 
+            /// Allocate an object whose memory is zeroed, skipping the zeroing if the object
+            /// has never been allocated before.
+            pub unsafe fn alloc_zeroed(&mut self) -> *mut T {
+                let (ptr, fresh) = self.0.alloc_fresh();
+                if !fresh {
+                    ptr::write_bytes(ptr, 0, mem::size_of::<T>());
+                }
+                ptr as *mut T
+            }
+
             pub unsafe fn free(&mut self, item: *mut T) {
                 self.0.free(item as *mut u8)
             }
+
+            /// Report statistics for this handle; see `Frontend::stats`.
+            pub fn stats(&self) -> ObjectAllocStats {
+                self.0.stats()
+            }
+
+            /// Return cached objects to the shared pool until at most `target` remain cached by
+            /// this handle.
+            pub fn shrink_to(&mut self, target: usize) {
+                unsafe { self.0.shrink_to(target) }
+            }
         }
         unsafe impl<T> Send for $name<T> {}
     };
@@ -832,7 +1394,9 @@ typed_wrapper!(MagazineAllocator, MagazineCache);
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
+    extern crate test;
     use super::*;
+    use self::test::{black_box, Bencher};
     use std::thread;
     use std::ptr::write_volatile;
     use std::collections::HashSet;
@@ -841,7 +1405,7 @@ mod tests {
     fn obj_alloc_basic() {
         let _ = env_logger::init();
         let mut oa = AllocBuilder::<usize>::default()
-            .page_size(4096)
+            .page_size(mmap::page_size())
             .build_local();
         unsafe {
             let item = oa.alloc();
@@ -865,10 +1429,203 @@ mod tests {
         obj_alloc_many_pages_single_threaded::<[u8; 32]>();
     }
 
+    #[test]
+    fn obj_alloc_stats() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 100;
+        let page_size = mmap::page_size();
+        let mut local = AllocBuilder::<usize>::default().page_size(page_size).build_local();
+        let mut magazine = AllocBuilder::<usize>::default().page_size(page_size).build_magazine();
+        unsafe {
+            let items = (0..N_ITEMS).map(|_| local.alloc()).collect::<Vec<_>>();
+            for i in items {
+                local.free(i);
+            }
+            let stats = local.stats();
+            // only objects from the current slag are kept in the cache
+            let cached = stats.cached_objects.unwrap();
+            alloc_assert!(cached > 0 && cached <= N_ITEMS, "cached {}", cached);
+            alloc_assert_eq!(stats.total_allocated, Some(N_ITEMS));
+            alloc_assert!(stats.backing_pages.unwrap() >= 1);
+
+            let items = (0..N_ITEMS).map(|_| magazine.alloc()).collect::<Vec<_>>();
+            for i in items {
+                magazine.free(i);
+            }
+            alloc_assert_eq!(magazine.stats().cached_objects, Some(N_ITEMS));
+            let items = (0..N_ITEMS / 2).map(|_| magazine.alloc()).collect::<Vec<_>>();
+            let stats = magazine.stats();
+            alloc_assert_eq!(stats.cached_objects, Some(N_ITEMS - N_ITEMS / 2));
+            alloc_assert_eq!(stats.total_allocated, Some(N_ITEMS + N_ITEMS / 2));
+            for i in items {
+                magazine.free(i);
+            }
+        }
+    }
+
+    #[test]
+    fn obj_alloc_shrink_to() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 1024;
+        let mut local = AllocBuilder::<usize>::default().build_local();
+        let mut magazine = AllocBuilder::<usize>::default().build_magazine();
+        unsafe {
+            let items = (0..N_ITEMS).map(|_| local.alloc()).collect::<Vec<_>>();
+            for i in items {
+                local.free(i);
+            }
+            local.shrink_to(256);
+            alloc_assert!(local.stats().cached_objects.unwrap() <= 256);
+
+            let items = (0..N_ITEMS).map(|_| magazine.alloc()).collect::<Vec<_>>();
+            for i in items {
+                magazine.free(i);
+            }
+            magazine.shrink_to(256);
+            alloc_assert_eq!(magazine.stats().cached_objects, Some(256));
+            magazine.shrink_to(0);
+            alloc_assert_eq!(magazine.stats().cached_objects, Some(0));
+
+            // the returned objects can be allocated again
+            let items = (0..N_ITEMS).map(|_| magazine.alloc()).collect::<Vec<_>>();
+            for &i in &items {
+                *i = 1;
+            }
+            for i in items {
+                magazine.free(i);
+            }
+            let _ = local.alloc();
+        }
+    }
+
+    #[test]
+    fn obj_alloc_fill() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 1024;
+        let mut local = AllocBuilder::<usize>::default().build_local();
+        let mut magazine = AllocBuilder::<usize>::default().build_magazine();
+        unsafe {
+            // only objects from the current slag can be cached
+            let cached = local.0.fill(N_ITEMS);
+            alloc_assert!(cached > 0 && cached <= N_ITEMS, "cached {}", cached);
+            alloc_assert_eq!(local.stats().cached_objects, Some(cached));
+
+            alloc_assert_eq!(magazine.0.fill(N_ITEMS), N_ITEMS);
+            alloc_assert_eq!(magazine.0.fill(N_ITEMS / 2), N_ITEMS);
+            let stats = magazine.stats();
+            alloc_assert_eq!(stats.cached_objects, Some(N_ITEMS));
+            alloc_assert_eq!(stats.total_allocated, Some(0));
+            let items = (0..N_ITEMS).map(|_| magazine.alloc()).collect::<Vec<_>>();
+            alloc_assert_eq!(items.iter().collect::<HashSet<_>>().len(), N_ITEMS);
+            alloc_assert_eq!(magazine.stats().cached_objects, Some(0));
+            for i in items {
+                magazine.free(i);
+            }
+        }
+    }
+
+    #[test]
+    fn obj_alloc_zeroed() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 1024;
+        let mut local = AllocBuilder::<[u8; 256]>::default().page_size(32 << 10).build_local();
+        let mut magazine = AllocBuilder::<[u8; 256]>::default().page_size(32 << 10).build_magazine();
+        // The first round is served from fresh pages; the rest are recycled and must be zeroed
+        // again.
+        macro_rules! check {
+            ($alloc:expr) => {
+                for _ in 0..3 {
+                    let items = (0..N_ITEMS).map(|_| $alloc.alloc_zeroed()).collect::<Vec<_>>();
+                    for &i in &items {
+                        alloc_assert!((*i).iter().all(|b| *b == 0));
+                        ptr::write_bytes(i, 0xff, 1);
+                    }
+                    for i in items {
+                        $alloc.free(i);
+                    }
+                }
+            }
+        }
+        unsafe {
+            check!(local);
+            check!(magazine);
+        }
+    }
+
+    const ZEROED_SIZE: usize = 1024;
+    const ZEROED_ITEMS: usize = 64;
+
+    #[bench]
+    fn bench_alloc_zeroed_fresh(b: &mut Bencher) {
+        b.iter(|| unsafe {
+            let mut a = AllocBuilder::<[u8; ZEROED_SIZE]>::default().page_size(256 << 10).build_magazine();
+            for _ in 0..ZEROED_ITEMS {
+                black_box(a.alloc_zeroed());
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_alloc_memset_fresh(b: &mut Bencher) {
+        b.iter(|| unsafe {
+            let mut a = AllocBuilder::<[u8; ZEROED_SIZE]>::default().page_size(256 << 10).build_magazine();
+            for _ in 0..ZEROED_ITEMS {
+                let item = a.alloc();
+                ptr::write_bytes(item, 0, 1);
+                black_box(item);
+            }
+        });
+    }
+
+    #[test]
+    fn obj_alloc_single_object_slags() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 256;
+        // with 4K system pages, only one 3000-byte object fits in a page
+        type Big = [u8; 3000];
+        let page_size = mmap::page_size();
+        let mut local = AllocBuilder::<Big>::default().page_size(page_size).build_local();
+        let mut remote = AllocBuilder::<Big>::default().page_size(page_size).build_magazine();
+        for _ in 0..2 {
+            let mut v = Vec::with_capacity(N_ITEMS);
+            let mut h = HashSet::new();
+            for i in 0..N_ITEMS {
+                unsafe {
+                    let item = local.alloc();
+                    write_volatile(item as *mut usize, i);
+                    alloc_assert!(h.insert(item as usize));
+                    v.push(item);
+                }
+            }
+            for i in v {
+                unsafe { local.free(i) };
+            }
+        }
+        let items = (0..N_ITEMS)
+            .map(|i| unsafe {
+                let item = remote.alloc();
+                write_volatile(item as *mut usize, i);
+                item as usize
+            })
+            .collect::<Vec<_>>();
+        let mut other = remote.clone();
+        thread::spawn(move || for i in items {
+            unsafe { other.free(i as *mut Big) };
+        }).join()
+            .unwrap();
+        for _ in 0..N_ITEMS {
+            unsafe {
+                let item = remote.alloc();
+                write_volatile(item as *mut usize, 0);
+                remote.free(item);
+            }
+        }
+    }
+
     fn obj_alloc_many_pages_single_threaded<T: 'static>() {
         let _ = env_logger::init();
         const N_ITEMS: usize = 4096 * 20;
-        let mut oa = AllocBuilder::<T>::default().page_size(4096).build_local();
+        let mut oa = AllocBuilder::<T>::default().page_size(mmap::page_size()).build_local();
         alloc_assert!(mem::size_of::<T>() >= mem::size_of::<usize>());
         // stay in a local cache
         for _ in 0..N_ITEMS {
@@ -920,7 +1677,7 @@ mod tests {
         const N_THREADS: usize = 40;
         // TODO make macros for these tests and test both MagazineAllocator and LocalAllocator
         let oa = AllocBuilder::<T>::default()
-            .page_size(4096)
+            .page_size(mmap::page_size())
             .build_magazine();
         // stay in a local cache
         alloc_assert!(mem::size_of::<T>() >= mem::size_of::<usize>());
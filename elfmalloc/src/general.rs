@@ -15,15 +15,31 @@
 //!
 //! # Using this Allocator from Rust
 //!
-//! We currently rely on some global allocator (bsalloc) to be running to service normal heap
-//! allocations. As a result, this allocator cannot be used as a global allocator via the
-//! `#[global_allocator]` attribute. Currently the only way around this is to use the `System`
+//! By default, we rely on some global allocator (bsalloc) to be running to service normal heap
+//! allocations, including elfmalloc's own. With the `global-allocator` feature enabled, bsalloc is
+//! not linked in, and `alloc_impl::ElfMallocGlobal` can be used as the global allocator via the
+//! `#[global_allocator]` attribute:
+//!
+//! ```rust,ignore
+//! #![feature(global_allocator)]
+//! extern crate elfmalloc;
+//! use elfmalloc::alloc_impl::ElfMallocGlobal;
+//!
+//! #[global_allocator]
+//! static GLOBAL: ElfMallocGlobal = ElfMallocGlobal;
+//! ```
+//!
+//! Heap allocations made by elfmalloc itself (while it is initializing or in the middle of another
+//! operation) are then served from a small static arena rather than recursing into the allocator;
+//! see the `bootstrap` module. Without the feature, the only other option is to use the `System`
 //! allocator along with `libelfc` from the `elfc` crate loaded with `LD_PRELOAD`.
 //!
 //! It is also possible to use this allocator using a `Clone`-based API. As alluded to elsewhere,
 //! the allocator is thread-safe and any handle on the allocator can be used to free a pointer from
 //! any other handle in any other thread. If you `free` a pointer `alloc`-ed by another
-//! `DynamicAllocator`, bad things will happen.
+//! `DynamicAllocator`, bad things will happen. With the `debug_ownership` feature, every
+//! `DynamicAllocator` and its clones share an id that is recorded in their pages and large
+//! allocations, and such a `free` aborts with a message naming both allocators instead.
 //!
 //! ```rust,ignore
 //! // all calls to `alloc` and `free` are unsafe
@@ -40,25 +56,44 @@
 //! This is probably a more limited use-case until custom allocators have better support in the
 //! Rust ecosystem. Even then, we suspect most programmers using a non-global allocator will
 //! instead want something more specialized, such as the `LocalAllocator` and `MagazineAllocator`
-//! object-specific allocators.
+//! object-specific allocators, or an `ElfObjectAlloc` from the `object_impl` module, which can
+//! share pages with a `DynamicAllocator`.
 
 use std::cmp;
 use std::ptr;
 use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use num_cpus;
 
 // One of MagazineCache and LocalCache is unused, depending on whether the 'local_cache' feature is
 // enabled.
 use super::sources::{MemorySource, MmapSource};
 use super::slag::{compute_metadata, CoarseAllocator, DirtyFn, Metadata, PageAlloc, RevocablePipe,
                   Slag, PageCleanup};
+pub use super::slag::{ConfigError, EagerDirty};
+pub use super::sources::HugePages;
+pub use super::arena::{Arena, ArenaObject};
+#[cfg(feature = "debug_ownership")]
+use super::slag::UNOWNED_INSTANCE;
 #[allow(unused_imports)]
-use super::frontends::{MagazineCache, LocalCache, DepotCache, Depot, Frontend};
-use super::utils::{mmap, Lazy, TypedArray, likely};
+use super::frontends::{self, MagazineCache, MagazineCapacity, LocalCache, DepotCache, Depot,
+                       Frontend};
+use super::utils::{env_flag, env_parse, env_size, mmap, Lazy, MemoryLimit, OwnedArray, RangeSet,
+                   TypedArray, likely, unlikely};
 use super::alloc_type::AllocType;
+#[cfg(feature = "owner_debug")]
+use super::owner;
+#[cfg(feature = "profiling")]
+use super::profile::Profile;
+#[cfg(feature = "profiling")]
+pub use super::profile::{ClassHistogram, SizeHistogram, SIZE_BUCKETS};
+use object_alloc::{Exhausted, ObjectAllocStats};
+use alloc::allocator::{AllocErr, Layout};
 
 type Source = MmapSource;
 
-pub(crate) mod global {
+pub mod global {
     //! A global malloc-style interface to interact with a `DynamicAllocator`. All of these
     //! structures are lazily initailized.
     //!
@@ -74,56 +109,123 @@ pub(crate) mod global {
     //! which these destructors can be run, and we have observed that crossbeam's can be run before
     //! ours, resulting in a panic.
     //!
-    //! To avoid this we spawn a background thread that services `free` operations sent from
-    //! threads in circumstances like this. While this is undoubtedly a code smell, it may be used
-    //! in the future to collect statistics regarding the running allocator.
+    //! An exiting thread's handle is torn down on that thread if `bagpipe::epoch_usable` shows that
+    //! crossbeam's TLS has not been destroyed yet, so that the objects it caches can be reused by
+    //! other threads straight away. Otherwise, the handle is sent to a background thread that
+    //! tears it down instead. While this is undoubtedly a code smell, it may be used in the future
+    //! to collect statistics regarding the running allocator. The thread, named
+    //! `elfmalloc-reclaim`, is only started when the first handle is sent to it, so a program
+    //! whose threads can all tear down their own handles never starts it. It then runs until
+    //! `shutdown` is called. If the thread cannot be started, as may happen while the process is
+    //! exiting, handles are torn down or leaked as they are after `shutdown`.
+    //!
+    //! Objects freed by a thread whose handle is unavailable are not sent to the background
+    //! thread. They are pushed onto a lock-free stack on their size class, and freed by the next
+    //! handle to allocate or free an object of that class.
     //!
     //! ## Recursive `malloc` calls
     //!
     //! When used as a standard `malloc` implementation through the `elfc` crate via `LD_PRELOAD`,
-    //! all calls to `malloc` and related functions will be routed through this module. The only
-    //! problem is that the code that enqueues destructors for pthread TSD calls `calloc`; this
-    //! causes all such calls to stack overflow.
+    //! all calls to `malloc` and related functions will be routed through this module, including
+    //! those made by libc while the global heap is being built: registering the fork handlers and
+    //! the destructors for pthread TSD both call `calloc`.
+    //!
+    //! The global heap is therefore initialized without a `lazy_static` or a lock, neither of
+    //! which tolerates being re-entered, but with an atomic state that other threads spin on. The
+    //! initializing thread always builds the heap while its handle's TLS slot is being
+    //! initialized, so that the allocations it makes in the meantime find no handle and take the
+    //! same fallback paths as allocations made during thread teardown: they are served as large
+    //! allocations, and freeing them does not consult the heap.
+    //!
+    //! Creating a thread's handle does not allocate from the heap either. A clone of the global
+    //! heap maps the array of its size classes directly, and its frontends are created lazily,
+    //! so the clone itself only copies their parameters. The one exception is crossbeam's
+    //! thread-local state, which `bagpipe` registers while the handle is created, so that the
+    //! allocation this involves happens while the handle's TLS slot is still being initialized.
     //!
-    //! The fix for this is to use the thread-local attribute to create a thread-local boolean that
-    //! indicates if the current thread's value has been initialized. If this value is false, a
-    //! slower fallback algorithm is used.
+    //! ## Sharded mode
+    //!
+    //! Where TLS is unavailable or too costly to rely on, setting `ELFMALLOC_NO_TLS` selects a
+    //! mode in which this module does not use TLS at all. Threads share a fixed array of handles
+    //! instead, each protected by a spinlock, and a thread uses the handle picked by a hash of its
+    //! thread id. Every handle is a clone of the same heap, so an object may be freed through any
+    //! of them. The mode is chosen when this module is first used, and cannot be changed later.
+    //!
+    //! ## Validating frees
+    //!
+    //! Setting `ELFMALLOC_VALIDATE_FREE` makes `free` and `realloc` check that every pointer they
+    //! are passed lies in memory mapped by the global heap before reading its header, rather than
+    //! crashing on a pointer from another allocator. The check only compares addresses against
+    //! the regions the heap has mapped, so it is cheap enough to leave on in debug builds. A
+    //! pointer that fails the check aborts the process with a message saying that it is not
+    //! owned by elfmalloc, unless `set_foreign_free` has registered a function to free such
+    //! pointers.
+    //!
+    //! ## Configuration
+    //!
+    //! The global heap is built with `DynamicAllocatorBuilder` when it is first used, from the
+    //! defaults overridden by these environment variables:
+    //!
+    //! - `ELFMALLOC_SMALL_PAGE` and `ELFMALLOC_LARGE_PAGE`: the page sizes.
+    //! - `ELFMALLOC_SMALL_CUTOFF`: the small cutoff.
+    //! - `ELFMALLOC_N_CLASSES`: the number of size classes.
+    //! - `ELFMALLOC_CUTOFF_FACTOR`: the cutoff factor, as a decimal number.
+    //! - `ELFMALLOC_MIN_ALIGN`: the minimum alignment of every object.
+    //! - `ELFMALLOC_MAGAZINE_CAPACITY`: the number of objects of each class that a thread caches.
+    //! - `ELFMALLOC_ADAPTIVE_MAGAZINES`: `MIN,MAX`, the bounds within which the capacity of each
+    //!   cache adapts to its use, as set by `DynamicAllocatorBuilder::adaptive_magazines`. The
+    //!   caches decay when `trim` is called.
+    //! - `ELFMALLOC_DEPOT_SHARDS` and `ELFMALLOC_DEPOT_BATCH`: the number of shards of the depot
+    //!   of each class and the number of magazines they exchange at once, as set by
+    //!   `DynamicAllocatorBuilder::depot_shards`. The batch size defaults to
+    //!   `frontends::DEPOT_BATCH`.
+    //! - `ELFMALLOC_MAX_BYTES`: the memory limit, as set by `DynamicAllocator::set_memory_limit`.
+    //! - `ELFMALLOC_NO_TLS`: `1` or `true` to select the sharded mode described above.
+    //! - `ELFMALLOC_SHARDS`: the number of shared handles in the sharded mode. It defaults to the
+    //!   number of CPUs.
+    //! - `ELFMALLOC_VALIDATE_FREE`: `1` or `true` to validate frees as described above.
+    //! - `ELFMALLOC_LEAK_REPORT`: `1` or `true` to print `leak_report` when the process exits.
+    //! - `ELFMALLOC_LARGE_FREE_POLICY`: `unmap`, `madv_free` or `madv_dontneed`, the
+    //!   `LargeFreePolicy` set by `set_large_free_policy`.
+    //!
+    //! Sizes are numbers of bytes, optionally followed by `K`, `M` or `G`. `ELFMALLOC_HUGE_PAGES`,
+    //! `ELFMALLOC_LARGE_HUGE_PAGES`, `ELFMALLOC_LARGE_HUGE_THRESHOLD`, `ELFMALLOC_ZERO_ON_FREE`
+    //! and `ELFMALLOC_EAGER_DIRTY` are read as for every `DynamicAllocator`; `background` is only
+    //! honoured by the global heap. A malformed value is
+    //! ignored, and a configuration that the builder rejects is replaced by the defaults; in both
+    //! cases a warning is printed to stderr. The variables are read without allocating.
     #[allow(unused_imports)]
-    use super::{CoarseAllocator, DynamicAllocator, DirtyFn, ElfMalloc, MemorySource, ObjectAlloc,
-                PageAlloc, TieredSizeClasses, TypedArray, AllocType, get_type, Source, AllocMap};
+    use super::{ClassStats, CoarseAllocator, DynamicAllocator, DynamicAllocatorBuilder, ElfMalloc,
+                LargeFreePolicy, LargeStats, MemorySource, ObjectAlloc, PageAlloc,
+                SizeClasses, TypedArray, AllocType, get_type, Source, AllocMap, Slag, freeze, remote, likely, unlikely};
+    use super::super::utils::{env_flag, env_parse, env_size, mmap, RangeSet};
+    use super::super::slag::touch_pages;
+    use super::super::bagpipe;
+    use super::super::num_cpus;
+    #[cfg(unix)]
+    extern crate libc;
     use std::ptr;
     use std::cell::UnsafeCell;
+    use std::cmp;
+    use std::fmt;
     use std::mem::{ManuallyDrop, self};
+    use std::str;
     #[allow(unused_imports)]
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::mpsc::{channel, Sender};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, Ordering};
+    use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, TrySendError};
     use std::sync::Mutex;
-    use std::thread;
+    #[cfg(unix)]
+    use std::sync::MutexGuard;
+    use std::thread::{self, JoinHandle};
+    use alloc::allocator::{AllocErr, Layout};
+    #[cfg(feature = "owner_debug")]
+    use super::super::owner;
+    #[cfg(feature = "owner_debug")]
+    pub use super::super::owner::{register_thread_name, ThreadLabel};
+    #[cfg(feature = "profiling")]
+    use super::SizeHistogram;
 
     type PA = PageAlloc<Source, ()>;
-    // For debugging purposes: run a callback to eagerly dirty several pages. This is generally bad
-    // for performance.
-    //
-    // type PA = PageAlloc<Source, BackgroundDirty>;
-
-    unsafe fn dirty_slag(mem: *mut u8) {
-        trace!("dirtying {:?}", mem);
-        let usable_size = 32 << 10;
-        let base_page = 4096;
-        let mut cur_addr = mem.offset(base_page);
-        while cur_addr < mem.offset(usable_size) {
-            cur_addr = cur_addr.offset(base_page);
-            (*(cur_addr as *mut AtomicUsize)).compare_and_swap(0, 1, Ordering::Relaxed);
-        }
-    }
-
-    #[derive(Clone)]
-    struct BackgroundDirty;
-    impl DirtyFn for BackgroundDirty {
-        fn dirty(_mem: *mut u8) {
-            let _ = unsafe { LOCAL_DESTRUCTOR_CHAN.with(|h| h.send(Husk::Slag(_mem))).unwrap() };
-        }
-    }
 
     #[derive(Clone)]
     /// A wrapper like `DynamicAllocator` in the parent module.
@@ -136,928 +238,10134 @@ pub(crate) mod global {
         // that, so if this field were of the type ElfMalloc<...>, the field's drop method would be
         // run after GlobalAllocator's drop method returned. We use ManuallyDrop to prevent that
         // from happening.
-        alloc: ManuallyDrop<ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>>,
-        // In some rare cases, we've observed that a thread-local GlobalAllocator is spuriously
-        // dropped twice. Until we figure out why and fix it, we just detect when it's happening
-        // and make the second drop call a no-op.
-        dropped: bool,
+        //
+        // A thread-local GlobalAllocator is dropped exactly once: alloc_tls moves it out of its
+        // slot before dropping it, and ignores any later attempt to tear the slot down.
+        alloc: ManuallyDrop<ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>>,
     }
     unsafe impl Send for GlobalAllocator {}
 
-    /// The type of the global instance of the allocator.
-    ///
-    /// This is used to create handles for TLS-stored `GlobalAllocator`s.
-    struct GlobalAllocProvider {
-        inner: Option<ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>>,
+    /// Build the global heap from the configuration in the environment.
+    fn build_heap() -> ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>> {
+        let mut builder = DynamicAllocatorBuilder::default();
+        if let Some(size) = env_size(b"ELFMALLOC_SMALL_PAGE\0") {
+            builder.small_page_size(size);
+        }
+        if let Some(size) = env_size(b"ELFMALLOC_LARGE_PAGE\0") {
+            builder.large_page_size(size);
+        }
+        if let Some(size) = env_size(b"ELFMALLOC_SMALL_CUTOFF\0") {
+            builder.small_cutoff(size);
+        }
+        if let Some(n) = env_parse(b"ELFMALLOC_N_CLASSES\0", parse_int) {
+            builder.n_classes(n);
+        }
+        if let Some(factor) = env_parse(b"ELFMALLOC_CUTOFF_FACTOR\0", parse_factor) {
+            builder.cutoff_factor(factor);
+        }
+        if let Some(align) = env_size(b"ELFMALLOC_MIN_ALIGN\0") {
+            builder.min_align(align);
+        }
+        if let Some(capacity) = env_parse(b"ELFMALLOC_MAGAZINE_CAPACITY\0", parse_int) {
+            builder.magazine_capacity(capacity);
+        }
+        if let Some((min, max)) = env_parse(b"ELFMALLOC_ADAPTIVE_MAGAZINES\0", parse_bounds) {
+            builder.adaptive_magazines(min, max);
+        }
+        if let Some(n_shards) = env_parse(b"ELFMALLOC_DEPOT_SHARDS\0", parse_int) {
+            let batch_size = env_parse(b"ELFMALLOC_DEPOT_BATCH\0", parse_int)
+                .unwrap_or(frontends::DEPOT_BATCH);
+            builder.depot_shards(n_shards, batch_size);
+        }
+        // The heap is never dropped, so the background thread can touch its pages.
+        builder.background_dirty = true;
+        let heap = ElfMalloc::with_builder(&builder).unwrap_or_else(|err| {
+            alloc_eprintln!("elfmalloc: ignoring configuration from the environment: {}", err);
+            ElfMalloc::new()
+        });
+        if let Some(bytes) = env_size(b"ELFMALLOC_MAX_BYTES\0") {
+            heap.limit.set_limit(bytes);
+        }
+        if let Some(policy) = LargeFreePolicy::from_env_var(b"ELFMALLOC_LARGE_FREE_POLICY\0") {
+            set_large_free_policy(policy);
+        }
+        register_leak_report();
+        #[cfg(test)]
+        {
+            let hook = INIT_HOOK.load(Ordering::Acquire);
+            if hook != 0 {
+                unsafe { mem::transmute::<usize, fn()>(hook)() };
+            }
+        }
+        heap
+    }
+
+    fn parse_int(val: &[u8]) -> Option<usize> {
+        str::from_utf8(val).ok().and_then(|s| s.parse().ok())
     }
 
-    // We need sync to have the global allocator reference live for new threads to clone. This is
-    // safe only because ElfMalloc (and PageAlloc, and TieredSizeClasses) have thread-safe clone
-    // methods.
-    unsafe impl Sync for GlobalAllocProvider {}
-    impl GlobalAllocProvider {
-        fn new() -> GlobalAllocProvider {
-            GlobalAllocProvider { inner: Some(ElfMalloc::new()) }
+    /// Parse a pair of integers separated by a comma.
+    fn parse_bounds(val: &[u8]) -> Option<(usize, usize)> {
+        let comma = match val.iter().position(|&b| b == b',') {
+            Some(comma) => comma,
+            None => return None,
+        };
+        match (parse_int(&val[..comma]), parse_int(&val[comma + 1..])) {
+            (Some(min), Some(max)) => Some((min, max)),
+            _ => None,
         }
     }
 
-    /// The type for messages sent to the background thread. These can either be arrays of size
-    /// classes to be cleaned up (in the case of thread destruction) or pointers to be freed (in
-    /// the case of a recursive call to `free`).
-    enum Husk {
-        Array(ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>),
-        #[allow(dead_code)]
-        Ptr(*mut u8),
-        #[allow(dead_code)]
-        Slag(*mut u8),
+    fn parse_factor(val: &[u8]) -> Option<f64> {
+        str::from_utf8(val)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .and_then(|f| if f.is_finite() && f > 0.0 { Some(f) } else { None })
     }
 
-    unsafe impl Send for Husk {}
+    /// A function called by `build_heap` once the heap is built, so that tests can make the
+    /// allocations that libc makes while the heap is being initialized. It is stored as a
+    /// `usize`, and 0 means that there is no hook.
+    #[cfg(test)]
+    pub(super) static INIT_HOOK: AtomicUsize = AtomicUsize::new(0);
 
-    impl Drop for GlobalAllocator {
-        fn drop(&mut self) {
-            unsafe fn with_chan<F: FnMut(&Sender<Husk>)>(mut f: F) {
-                LOCAL_DESTRUCTOR_CHAN
-                    .with(|chan| f(chan))
-                    .unwrap_or_else(|| {
-                        let chan = DESTRUCTOR_CHAN.lock().unwrap().clone();
-                        f(&chan);
-                    })
-            }
+    // The states of HEAP_STATE.
+    const HEAP_UNINIT: usize = 0;
+    const HEAP_INITIALIZING: usize = 1;
+    const HEAP_READY: usize = 2;
 
-            // XXX: Why this check?
-            //
-            // We have found that for some reason, this destructor can be called more than once on
-            // the same value. This could be a peculiarity of the TLS implementation, or it could
-            // be a bug in the code here. Regardless; without this check there are some cases in
-            // which this benchmark drops Arc-backed data-structures multiple times, leading to
-            // segfaults either here or in the background thread.
-            if self.dropped {
-                alloc_eprintln!("{:?} dropped twice!", self as *const _);
-                return;
-            }
-            unsafe {
-                with_chan(|chan| {
-                    // After we read the alloc field with ptr::read, the underlying memory should
-                    // be treated as uninitialized, but Rust doesn't know this. We use ManuallyDrop
-                    // to ensure that Rust doesn't try to drop the field after this method returns.
-                    let dyn = ManuallyDrop::into_inner(ptr::read(&self.alloc));
-                    let _ = chan.send(Husk::Array(dyn));
-                });
-                self.dropped = true;
-            };
-        }
-    }
+    /// The state of `ELF_HEAP`. `ELF_HEAP` is written once, by the thread that moves this from
+    /// `HEAP_UNINIT` to `HEAP_INITIALIZING`, and is only read once this is `HEAP_READY`.
+    static HEAP_STATE: AtomicUsize = AtomicUsize::new(HEAP_UNINIT);
 
-    pub unsafe fn get_layout(item: *mut u8) -> (usize /* size */, usize /* alignment */) {
-        let m_block = match get_type(item) {
-            // TODO(ezrosent): this duplicates some work..
-            AllocType::SmallSlag | AllocType::Large => {
-                with_local_or_clone(|h| {
-                    (*h.get())
-                        .alloc
-                        .small_pages
-                        .backing_memory()
-                })
-            }
-            AllocType::BigSlag => {
-                with_local_or_clone(|h| {
-                    (*h.get())
-                        .alloc
-                        .large_pages
-                        .backing_memory()
-                })
-            }
-        };
-        super::elfmalloc_get_layout(m_block, item)
-    }
+    /// The global heap that every thread's handle is cloned from. It is never dropped.
+    static mut ELF_HEAP: Option<ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>> = None;
 
-    fn new_handle() -> GlobalAllocator {
-        GlobalAllocator {
-            alloc: ManuallyDrop::new(ELF_HEAP.inner.as_ref().expect("heap uninitialized").clone()),
-            dropped: false,
+    /// Whether the current thread is building the global heap.
+    #[thread_local]
+    static mut BUILDING_HEAP: bool = false;
+
+    /// The global heap, or `None` if it has not been initialized yet.
+    fn ready_heap() -> Option<&'static ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>> {
+        if HEAP_STATE.load(Ordering::Acquire) == HEAP_READY {
+            unsafe { ELF_HEAP.as_ref() }
+        } else {
+            None
         }
     }
 
-    impl Drop for GlobalAllocProvider {
-        fn drop(&mut self) {
-            mem::forget(self.inner.take());
+    /// The global heap that every thread's handle is cloned from, initialized on first use.
+    fn heap() -> &'static ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>> {
+        match ready_heap() {
+            Some(heap) => heap,
+            None => init_heap(),
         }
     }
 
-    lazy_static! {
-        static ref ELF_HEAP: GlobalAllocProvider = GlobalAllocProvider::new();
-        static ref DESTRUCTOR_CHAN: Mutex<Sender<Husk>> = {
-            // Background thread code: block on a channel waiting for memory reclamation messages
-            // (Husks).
-            let (sender, receiver) = channel();
-            thread::spawn(move || unsafe {
-                let mut local_alloc = new_handle();
-                loop {
-                    if let Ok(msg) = receiver.recv() {
-                        let msg: Husk = msg;
-                        match msg {
-                            Husk::Array(alloc) => mem::drop(DynamicAllocator(alloc)),
-                            Husk::Ptr(p) => local_alloc.alloc.free(p),
-                            Husk::Slag(s) => dirty_slag(s),
-                        }
-                        continue
+    #[cold]
+    fn init_heap() -> &'static ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>> {
+        unsafe {
+            // Initialize the current thread's handle first unless that is already under way (this
+            // call is nested inside new_handle) or over (the thread is exiting). The handle's
+            // initialization comes back here, and any allocation made while the heap is built
+            // then finds the slot still being initialized and does not use the heap.
+            // In the sharded mode, with_shard provides the fallback instead.
+            if !sharded() && alloc_tls_fast_with!(LOCAL_ELF_HEAP, _h, {}).is_some() {
+                return ready_heap().expect("global heap uninitialized");
+            }
+            match HEAP_STATE.compare_and_swap(HEAP_UNINIT, HEAP_INITIALIZING, Ordering::AcqRel) {
+                HEAP_UNINIT => {
+                    BUILDING_HEAP = true;
+                    let heap = build_heap();
+                    if sharded() {
+                        let n = env_parse(b"ELFMALLOC_SHARDS\0", parse_int)
+                            .unwrap_or_else(num_cpus::get);
+                        SHARDS = Some(Shards::new(&heap, cmp::max(n, 1)));
                     }
-                    mem::forget(local_alloc);
-                    return;
+                    ELF_HEAP = Some(heap);
+                    BUILDING_HEAP = false;
+                    HEAP_STATE.store(HEAP_READY, Ordering::Release);
                 }
-            });
-            Mutex::new(sender)
-        };
+                _ => {
+                    if BUILDING_HEAP {
+                        alloc_panic!("elfmalloc: global heap used while it is being initialized");
+                    }
+                    while HEAP_STATE.load(Ordering::Acquire) != HEAP_READY {
+                        thread::yield_now();
+                    }
+                }
+            }
+            ready_heap().expect("global heap uninitialized")
+        }
     }
 
-    alloc_thread_local!{ static LOCAL_DESTRUCTOR_CHAN: Sender<Husk> = DESTRUCTOR_CHAN.lock().unwrap().clone(); }
-    alloc_thread_local!{ static LOCAL_ELF_HEAP: UnsafeCell<GlobalAllocator> = UnsafeCell::new(new_handle()); }
+    // The values of MODE.
+    const MODE_UNKNOWN: usize = 0;
+    const MODE_TLS: usize = 1;
+    const MODE_SHARDED: usize = 2;
 
-    fn with_local_or_clone<F, R>(f: F) -> R
-        where F: Fn(&UnsafeCell<GlobalAllocator>) -> R
-    {
-        unsafe {
-            alloc_tls_fast_with!(LOCAL_ELF_HEAP, h, { f(h) })
-                .unwrap_or_else(|| f(&UnsafeCell::new(new_handle())))
+    /// Whether this module uses TLS handles or shards, read from the environment on first use.
+    static MODE: AtomicUsize = AtomicUsize::new(MODE_UNKNOWN);
+
+    /// Whether the sharded mode is selected. See the module documentation.
+    #[inline(always)]
+    fn sharded() -> bool {
+        let mode = MODE.load(Ordering::Relaxed);
+        if unsafe { likely(mode == MODE_TLS) } {
+            return false;
         }
+        mode == MODE_SHARDED || read_mode()
     }
 
-    pub unsafe fn alloc(size: usize) -> *mut u8 {
-        alloc_tls_fast_with!(LOCAL_ELF_HEAP, h, { (*h.get()).alloc.alloc(size) })
-            .unwrap_or_else(|| super::large_alloc::alloc(size))
+    #[cold]
+    fn read_mode() -> bool {
+        let sharded = env_flag(b"ELFMALLOC_NO_TLS\0").unwrap_or(false);
+        // Threads racing here read the same environment, so they store the same mode.
+        MODE.store(if sharded { MODE_SHARDED } else { MODE_TLS }, Ordering::Relaxed);
+        sharded
     }
 
-    pub unsafe fn realloc(item: *mut u8, new_size: usize) -> *mut u8 {
-        aligned_realloc(item, new_size, mem::size_of::<usize>())
+    /// A handle shared by the threads whose ids hash to it.
+    struct Shard {
+        locked: AtomicBool,
+        alloc: UnsafeCell<ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>>,
     }
 
-    pub unsafe fn aligned_realloc(item: *mut u8, new_size: usize, new_alignment: usize) -> *mut u8 {
-        with_local_or_clone(|h| (*h.get()).alloc.realloc(item, new_size, new_alignment))
-    }
+    /// The handles used instead of TLS handles in the sharded mode. These are like the shards of
+    /// a `SharedDynamicAllocator`, but are locked without TLS or allocating.
+    pub(super) struct Shards(Vec<Shard>);
 
-    pub unsafe fn free(item: *mut u8) {
-        alloc_tls_fast_with!(LOCAL_ELF_HEAP, h, { (*h.get()).alloc.free(item) })
-            .unwrap_or_else(|| match get_type(item) {
-                AllocType::Large => {
-                    super::large_alloc::free(item);
+    unsafe impl Sync for Shards {}
+
+    impl Shards {
+        /// Create `n` clones of `heap`.
+        pub(super) fn new(
+            heap: &ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>,
+            n: usize,
+        ) -> Shards {
+            Shards(
+                (0..n)
+                    .map(|_| {
+                        Shard {
+                            locked: AtomicBool::new(false),
+                            alloc: UnsafeCell::new(heap.clone()),
+                        }
+                    })
+                    .collect(),
+            )
+        }
+
+        /// Run `f` with exclusive access to one of the shards.
+        ///
+        /// As in `SharedDynamicAllocator`, the shard picked for the current thread is tried
+        /// first, followed by the others, and if all of them are locked, the current thread waits
+        /// for its own. `f` must not use the global interface, or it may deadlock.
+        pub(super) unsafe fn with<R, F>(&self, f: F) -> R
+        where
+            F: FnOnce(&mut ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>) -> R,
+        {
+            let n_shards = self.0.len();
+            let hint = thread_hash() % n_shards;
+            let mut shard = None;
+            for i in 0..n_shards {
+                let candidate = &self.0[(hint + i) % n_shards];
+                if !candidate.locked.compare_and_swap(false, true, Ordering::Acquire) {
+                    shard = Some(candidate);
+                    break;
                 }
-                AllocType::SmallSlag | AllocType::BigSlag => {
-                    let chan = DESTRUCTOR_CHAN.lock().unwrap().clone();
-                    let _ = chan.send(Husk::Ptr(item));
+            }
+            let shard = shard.unwrap_or_else(|| {
+                let shard = &self.0[hint];
+                while shard.locked.compare_and_swap(false, true, Ordering::Acquire) {
+                    while shard.locked.load(Ordering::Relaxed) {
+                        thread::yield_now();
+                    }
                 }
+                shard
             });
-    }
-}
+            let res = f(&mut *shard.alloc.get());
+            shard.locked.store(false, Ordering::Release);
+            res
+        }
 
-/// A trait encapsulating the notion of an array of size classes for an allocator.
-pub(crate) trait AllocMap<T>
-where
-    Self: Sized,
-{
-    /// The type used to index size classes.
-    type Key;
+        /// Lock every shard.
+        #[cfg(unix)]
+        unsafe fn lock_all(&self) {
+            for shard in &self.0 {
+                while shard.locked.compare_and_swap(false, true, Ordering::Acquire) {
+                    thread::yield_now();
+                }
+            }
+        }
 
-    /// Create and initialize the map.
-    fn init<F: FnMut(Self::Key) -> T>(start: Self::Key, n_classes: usize, f: F) -> Self {
-        Self::init_conserve(start, n_classes, f).1
+        /// Unlock every shard locked by `lock_all`.
+        #[cfg(unix)]
+        unsafe fn unlock_all(&self) {
+            for shard in &self.0 {
+                shard.locked.store(false, Ordering::Release);
+            }
+        }
     }
 
-    /// Create and initialize the map, handing back ownership of the constructor.
-    fn init_conserve<F: FnMut(Self::Key) -> T>(
-        start: Self::Key,
-        n_classes: usize,
-        f: F,
-    ) -> (F, Self);
-
-    /// Get an unchecked raw pointer to the class corresponding to `k`.
-    unsafe fn get_raw(&self, k: Self::Key) -> *mut T;
+    /// The shards of the global heap in the sharded mode. Like `ELF_HEAP`, this is written before
+    /// `HEAP_STATE` becomes `HEAP_READY`.
+    static mut SHARDS: Option<Shards> = None;
 
-    /// Get an unchecked reference to the class corresponding to `k`.
-    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
-    #[inline(always)]
-    unsafe fn get(&self, k: Self::Key) -> &T {
-        &*self.get_raw(k)
+    /// A cheap hash of the current thread's id, computed without TLS.
+    fn thread_hash() -> usize {
+        // Thread ids are usually the addresses of per-thread structures, whose low bits are alike.
+        let h = (thread_id() >> 12).wrapping_mul(0x9e37_79b9);
+        h ^ (h >> 16)
     }
 
-    /// Get an unchecked mutable reference to the class corresponding to `k`.
-    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
-    #[inline(always)]
-    unsafe fn get_mut(&mut self, k: Self::Key) -> &mut T {
-        &mut *self.get_raw(k)
+    #[cfg(unix)]
+    fn thread_id() -> usize {
+        unsafe { libc::pthread_self() as usize }
     }
 
-    /// Iterate over the map's contents.
-    ///
-    /// This is used to clean up the contents of the map.
-    fn foreach<F: Fn(*mut T)>(&self, f: F);
-
-    /// Get the `Key` with a "maximum" value.
-    ///
-    /// This method is most useful when the `Key` type is a numeric type representing a "size
-    /// class".
-    fn max_key(&self) -> Self::Key;
-}
-
-// Note on the C API:
-//
-// The C allocation API guarantees a minimum alignment for all allocations. On some systems, this
-// 8, while on others, 16. By default, our minimum size class is 8 bytes in size and all
-// allocations are 8 byte aligned. On systems where the minimum alignment is 8, this means that we
-// don't need to explicitly round up allocation size - the returned objects will always be properly
-// aligned. However, on systems where the minimum alignment is 16, more work needs to be done.
-// Thus, on these systems, when the "c-api" feature is enabled, we eliminate the 8-byte size class,
-// making the smallest size class 16, and thus retaining this "aligned for free" property.
-
-/// Size classes from the `scalloc` and `tcmalloc` allocators.
-///
-/// This includes two runs of size classes: the first (smaller) size classes are multiples of 16.
-/// The larger classes are powers of two.
-struct TieredSizeClasses<T> {
-    // When compiling for the C API, the minimum alignment is 16 on Mac and 64-bit Windows.
-    #[cfg(any(not(feature = "c-api"),
-                not(any(target_os = "macos", all(windows, target_pointer_width = "64")))))]
-    word_objs: Option<T>,
-    small_objs: Multiples<T>,
-    medium_objs: PowersOfTwo<T>,
-}
-
-impl<T> AllocMap<T> for TieredSizeClasses<T> {
-    type Key = usize;
-    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
-        let n_small_classes = cmp::min((ELFMALLOC_SMALL_CUTOFF / MULTIPLE) - (start / MULTIPLE), n_classes / 2);
-        let n_medium_classes = n_classes - n_small_classes;
-        let (f2, small_classes) = Multiples::init_conserve(start, n_small_classes, f);
-        // mutability is unnecessary when we don't execute the 'let word_objs = f3(8)' line
-        #[allow(unused_mut)]
-        let (mut f3, medium_classes) =
-            PowersOfTwo::init_conserve(small_classes.max_key() + 1, n_medium_classes, f2);
-        #[cfg(any(not(feature = "c-api"),
-                    not(any(target_os = "macos",
-                                all(windows, target_pointer_width = "64")))))]
-        let word_objs = f3(8);
-        (
-            f3,
-            TieredSizeClasses {
-                // When compiling for the C API, the minimum alignment is 16 on Mac and 64-bit Windows.
-                #[cfg(any(not(feature = "c-api"),
-                            not(any(target_os = "macos",
-                                        all(windows, target_pointer_width = "64")))))]
-                word_objs: Some(word_objs),
-                small_objs: small_classes,
-                medium_objs: medium_classes,
-            },
-        )
+    #[cfg(not(unix))]
+    fn thread_id() -> usize {
+        // Each thread has its own stack, and this function is called at similar depths.
+        let marker = 0u8;
+        (&marker as *const u8 as usize) >> 8
     }
 
-    unsafe fn get_raw(&self, n: usize) -> *mut T {
-        // When compiling for the C API, the minimum alignment is 16 on Mac and 64-bit Windows.
-        #[cfg(any(not(feature = "c-api"),
-                    not(any(target_os = "macos",
-                                all(windows, target_pointer_width = "64")))))]
-        {
-            if n <= 8 {
-                self.word_objs.as_ref().unwrap() as *const _ as *mut T
-            } else if n <= self.small_objs.max_key() {
-                self.small_objs.get_raw(n)
-            } else {
-                self.medium_objs.get_raw(n)
+    /// Run `f` with the current thread's shard of the global heap, or return `None` if the current
+    /// thread is building the heap.
+    unsafe fn with_shard<R, F>(f: F) -> Option<R>
+    where
+        F: FnOnce(&mut ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>) -> R,
+    {
+        if unlikely(ready_heap().is_none()) {
+            // allocations made while the heap is built fall back as they do in the TLS mode
+            if BUILDING_HEAP {
+                return None;
             }
+            init_heap();
         }
+        Some(SHARDS.as_ref().expect("shards uninitialized").with(f))
+    }
 
-        #[cfg(all(feature = "c-api",
-                    any(target_os = "macos", all(windows, target_pointer_width = "64"))))]
-        {
-            if n <= self.small_objs.max_key() {
-                self.small_objs.get_raw(n)
+    /// Evaluate `$body` with `$h` bound to the current thread's handle to the global heap: its
+    /// shard in the sharded mode, and its TLS handle otherwise. This gives an `Option` which is
+    /// `None` if the handle is unavailable.
+    macro_rules! with_handle {
+        ($h:ident, $body:expr) => {{
+            #[cfg(test)]
+            note_call();
+            if unlikely(sharded()) {
+                with_shard(|$h| $body)
             } else {
-                self.medium_objs.get_raw(n)
+                alloc_tls_fast_with!(LOCAL_ELF_HEAP, tls, {
+                    let $h = &mut *(*tls.get()).alloc;
+                    $body
+                })
             }
-        }
+        }};
     }
 
-    #[inline]
-    fn max_key(&self) -> usize {
-        self.medium_objs.max_key()
-    }
+    /// Whether the current thread is in `new_handle`.
+    #[cfg(test)]
+    #[thread_local]
+    static mut CREATING_HANDLE: bool = false;
 
-    fn foreach<F: Fn(*mut T)>(&self, f: F) {
-        #[cfg(any(not(feature = "c-api"),
-                    not(any(target_os = "macos",
-                                all(windows, target_pointer_width = "64")))))]
-        {
-            if let Some(r) = self.word_objs.as_ref() {
-                f(r as *const _ as *mut T);
+    /// The number of calls into this module that the current thread made while it was creating
+    /// a handle, for tests to check that creating a handle does not allocate from the heap.
+    #[cfg(test)]
+    #[thread_local]
+    pub(super) static mut CALLS_CREATING_HANDLE: usize = 0;
+
+    #[cfg(test)]
+    fn note_call() {
+        unsafe {
+            if CREATING_HANDLE {
+                CALLS_CREATING_HANDLE += 1;
             }
         }
-        self.small_objs.foreach(&f);
-        self.medium_objs.foreach(f);
     }
-}
 
-// Once this can be a type parameter, it should be.
-pub(crate) const MULTIPLE: usize = 16;
+    /// The type for messages sent to the background thread. These can either be arrays of size
+    /// classes to be cleaned up (in the case of thread destruction) or the pages of slags to be
+    /// dirtied, given by their start and length.
+    ///
+    /// A `Sync` message is answered once every message sent before it has been handled.
+    enum Husk {
+        Array(ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>),
+        Slag(*mut u8, usize),
+        Sync(Sender<()>),
+    }
 
-/// An array of size classes where sizes are multiples of 16.
-pub(crate) struct Multiples<T> {
-    starting_size: usize,
-    max_size: usize,
-    pub classes: TypedArray<T>,
-}
+    unsafe impl Send for Husk {}
 
-impl<T: Clone> Clone for Multiples<T> {
-    fn clone(&self) -> Self {
-        Multiples::init(self.starting_size, self.classes.len(), |size| unsafe {
-            self.get(size).clone()
-        })
+    /// The number of messages that the channel to the background thread holds.
+    pub(super) const HUSK_QUEUE_CAPACITY: usize = 1024;
+
+    /// The number of messages sent to the background thread that it has not yet handled.
+    static PENDING_HUSKS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A delay in milliseconds added to the background thread's handling of each `Husk::Array`,
+    /// so that tests can fill its channel.
+    #[cfg(test)]
+    pub(super) static BACKGROUND_DELAY_MS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Send `husk` to the background thread.
+    ///
+    /// If the channel is full, a handle is torn down by the current thread if crossbeam's TLS
+    /// allows it, and a slag is dirtied right away; neither waits for the background thread. A
+    /// handle that must be torn down elsewhere is kept in the overflow list instead, which the
+    /// background thread empties as it receives messages, since handles are never dropped.
+    ///
+    /// The background thread is started by the first call. If it cannot be started, `husk` is
+    /// handled by the current thread where possible, and the thread is started by a later call.
+    ///
+    /// Once `shutdown` has stopped the background thread, or if it cannot be started, a handle
+    /// that cannot be torn down on the current thread is leaked instead: a handle cannot safely
+    /// be torn down while its thread's TLS is being destroyed, for the reasons given in the module
+    /// documentation.
+    fn send_husk(husk: Husk) {
+        let rejected = send_locked(&mut BACKGROUND.lock().unwrap(), husk);
+        handle_rejected(rejected);
     }
-}
 
-/// Round up to the closest multiple of 16 greater than or equal to `n`.
-#[inline]
-fn round_up(n: usize) -> usize {
-    (n + (MULTIPLE - 1)) & !(MULTIPLE - 1)
-}
+    /// Send the pages of a slag, `len` bytes at `mem`, to the background thread to be touched
+    /// there, for `EagerDirty::Background`.
+    ///
+    /// Pages are handed out while the background thread's lock may already be held by the
+    /// current thread, such as while it starts the background thread. If the lock is held, the
+    /// pages are touched right away instead.
+    pub(crate) fn dirty_in_background(mem: *mut u8, len: usize) {
+        let husk = Husk::Slag(mem, len);
+        let rejected = match BACKGROUND.try_lock() {
+            Ok(mut background) => send_locked(&mut background, husk),
+            Err(_) => Some(husk),
+        };
+        handle_rejected(rejected);
+    }
 
-impl<T> AllocMap<T> for Multiples<T> {
-    type Key = usize;
-    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, mut f: F) -> (F, Self) {
-        alloc_debug_assert!(n_classes >= 1);
-        let starting_size = round_up(start);
-        let res = Multiples {
-            starting_size: starting_size,
-            max_size: n_classes * MULTIPLE + starting_size - MULTIPLE,
-            classes: TypedArray::new(n_classes),
+    /// Send `husk` to the background thread, starting it if it is idle, and return `husk` if it
+    /// should be handled by the caller instead.
+    fn send_locked(background: &mut Background, husk: Husk) -> Option<Husk> {
+        let idle = match *background {
+            Background::Idle => true,
+            _ => false,
         };
-        let mut cur_size = res.starting_size;
-        for p in res.classes.iter() {
-            unsafe {
-                ptr::write(p, f(cur_size));
+        if idle {
+            if let Some((sender, handle)) = spawn_background() {
+                *background = Background::Running {
+                    sender: sender,
+                    handle: handle,
+                    overflow: Vec::new(),
+                };
             }
-            cur_size += MULTIPLE;
         }
-        alloc_debug_assert_eq!(res.max_size, cur_size - MULTIPLE);
-        (f, res)
+        let running = match *background {
+            Background::Running { .. } => true,
+            _ => false,
+        };
+        if running {
+            enqueue(background, husk)
+        } else {
+            Some(husk)
+        }
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
-    #[inline(always)]
-    unsafe fn get_raw(&self, n: usize) -> *mut T {
-        let class = round_up(n);
-        alloc_debug_assert!(class <= self.max_size);
-        self.classes.get(
-            (round_up(n) - self.starting_size) / MULTIPLE,
-        )
+    /// Handle a husk returned by `send_locked` on the current thread.
+    ///
+    /// This is done without holding the lock, which the background thread takes after receiving
+    /// each message. A handle is only handed back by enqueue if it can be torn down here.
+    fn handle_rejected(rejected: Option<Husk>) {
+        match rejected {
+            Some(Husk::Array(alloc)) => {
+                if bagpipe::epoch_usable() {
+                    mem::drop(DynamicAllocator(alloc))
+                } else {
+                    mem::forget(alloc)
+                }
+            }
+            Some(Husk::Sync(done)) => {
+                let _ = done.send(());
+            }
+            Some(Husk::Slag(mem, len)) => unsafe { touch_pages(mem, len) },
+            None => {}
+        }
     }
 
-    #[inline]
-    fn max_key(&self) -> usize {
-        self.max_size
+    /// Queue `husk` for the background thread, returning it if the channel is full and the caller
+    /// should handle it instead.
+    fn enqueue(background: &mut Background, husk: Husk) -> Option<Husk> {
+        match *background {
+            Background::Running {
+                ref sender,
+                ref mut overflow,
+                ..
+            } => {
+                PENDING_HUSKS.fetch_add(1, Ordering::Relaxed);
+                match sender.try_send(husk) {
+                    Ok(()) => None,
+                    Err(TrySendError::Full(husk)) => {
+                        let direct = match husk {
+                            Husk::Array(_) => bagpipe::epoch_usable(),
+                            Husk::Slag(..) => true,
+                            Husk::Sync(_) => false,
+                        };
+                        if direct {
+                            PENDING_HUSKS.fetch_sub(1, Ordering::Relaxed);
+                            Some(husk)
+                        } else {
+                            overflow.push(husk);
+                            None
+                        }
+                    }
+                    Err(TrySendError::Disconnected(husk)) => {
+                        // the background thread has panicked
+                        PENDING_HUSKS.fetch_sub(1, Ordering::Relaxed);
+                        abandon(husk);
+                        None
+                    }
+                }
+            }
+            _ => {
+                abandon(husk);
+                None
+            }
+        }
     }
 
-    fn foreach<F: Fn(*mut T)>(&self, f: F) {
-        for class in self.classes.iter() {
-            f(class)
+    /// Dispose of `husk` without handling it. Handles are leaked rather than dropped.
+    fn abandon(husk: Husk) {
+        match husk {
+            Husk::Array(alloc) => mem::forget(alloc),
+            husk => mem::drop(husk),
         }
     }
-}
 
-/// Size classes that are just the powers of two.
-///
-/// This is useful mostly for testing purposes: it is a very simple implementation, but it can also
-/// be rather wasteful.
-pub(crate) struct PowersOfTwo<T> {
-    starting_size: usize,
-    max_size: usize,
-    pub classes: TypedArray<T>,
-}
-
-impl<T: Clone> Clone for PowersOfTwo<T> {
-    fn clone(&self) -> Self {
-        PowersOfTwo::init(self.starting_size, self.classes.len(), |size| unsafe {
-            self.get(size).clone()
-        })
+    /// The state of the background thread.
+    enum Background {
+        /// The thread has not been started, or did not survive a `fork`. It is started by the next
+        /// call to `send_husk`.
+        Idle,
+        /// The thread is running, and `sender` is the only sender for its channel. `overflow`
+        /// holds the husks that did not fit in the channel.
+        Running {
+            sender: SyncSender<Husk>,
+            handle: JoinHandle<()>,
+            overflow: Vec<Husk>,
+        },
+        /// `shutdown` has stopped the thread.
+        Stopped,
     }
-}
 
-impl Drop for DynamicAllocator {
-    fn drop(&mut self) {
-        self.0.allocs.foreach(|x| unsafe { ptr::drop_in_place(x) });
-        unsafe {
-            self.0.allocs.medium_objs.classes.destroy();
-            self.0.allocs.small_objs.classes.destroy();
-            #[cfg(any(not(feature = "c-api"),
-                        not(any(target_os = "macos",
-                                    all(windows, target_pointer_width = "64")))))]
-            ptr::write(&mut self.0.allocs.word_objs, None);
-        }
+    /// The name of the background thread.
+    pub(super) const BACKGROUND_THREAD_NAME: &str = "elfmalloc-reclaim";
+
+    /// Start the background thread, or return `None` if it cannot be started.
+    fn spawn_background() -> Option<(SyncSender<Husk>, JoinHandle<()>)> {
+        // Background thread code: block on a channel waiting for memory reclamation messages
+        // (Husks). The loop ends once the sender is dropped and every message has been received.
+        let (sender, receiver) = sync_channel(HUSK_QUEUE_CAPACITY);
+        let spawned = thread::Builder::new()
+            .name(String::from(BACKGROUND_THREAD_NAME))
+            .spawn(move || for msg in receiver {
+                // Husks are only added to the overflow list while the channel is full, so at
+                // least one more message is received after each of them.
+                for husk in take_overflow() {
+                    handle_husk(husk);
+                }
+                handle_husk(msg);
+            });
+        spawned.ok().map(|handle| (sender, handle))
     }
-}
 
-impl<T> PowersOfTwo<T> {
-    fn new(start_from: usize, n_classes: usize) -> PowersOfTwo<T> {
-        PowersOfTwo {
-            starting_size: start_from.next_power_of_two(),
-            max_size: 0, // currently uninitialized
-            classes: TypedArray::new(n_classes),
+    fn take_overflow() -> Vec<Husk> {
+        match *BACKGROUND.lock().unwrap() {
+            Background::Running { ref mut overflow, .. } => mem::replace(overflow, Vec::new()),
+            _ => Vec::new(),
         }
     }
-}
 
-impl<T> AllocMap<T> for PowersOfTwo<T> {
-    type Key = usize;
-    fn init_conserve<F: FnMut(Self::Key) -> T>(
-        start: usize,
-        n_classes: usize,
-        mut f: F,
-    ) -> (F, Self) {
-        let mut res = Self::new(start, n_classes);
-        let mut cur_size = res.starting_size;
-        unsafe {
-            for item in res.classes.iter() {
-                ptr::write(item, f(cur_size));
-                cur_size *= 2;
+    fn handle_husk(husk: Husk) {
+        match husk {
+            Husk::Array(alloc) => {
+                #[cfg(test)]
+                thread::sleep(::std::time::Duration::from_millis(
+                    BACKGROUND_DELAY_MS.load(Ordering::Relaxed) as u64,
+                ));
+                mem::drop(DynamicAllocator(alloc))
+            }
+            Husk::Slag(mem, len) => unsafe { touch_pages(mem, len) },
+            Husk::Sync(done) => {
+                let _ = done.send(());
             }
         }
-        res.max_size = cur_size / 2;
-        (f, res)
+        PENDING_HUSKS.fetch_sub(1, Ordering::Relaxed);
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
-    #[inline(always)]
-    unsafe fn get_raw(&self, k: usize) -> *mut T {
-        alloc_debug_assert!(k <= self.max_size);
-        let log = (k.next_power_of_two().trailing_zeros() -
-            self.starting_size.trailing_zeros()) as usize;
-        alloc_debug_assert!(
-            log < self.classes.len(),
-            "log={} len={}",
-            log,
-            self.classes.len()
-        );
-        self.classes.get(log)
+    /// The number of messages sent to the background thread that it has not yet handled. Almost
+    /// all of these are the handles of exited threads, whose cached objects cannot be reused
+    /// until they are torn down.
+    ///
+    /// The background thread's channel is bounded: once it holds `HUSK_QUEUE_CAPACITY` (1024)
+    /// messages, handles are torn down by the threads that send them where possible, as described
+    /// for `shutdown`. The rest are kept in an overflow list, and are also counted here.
+    pub fn pending_remote_frees() -> usize {
+        PENDING_HUSKS.load(Ordering::Relaxed)
     }
 
-    #[inline]
-    fn max_key(&self) -> usize {
-        self.max_size
+    /// Send `alloc` to the background thread to be dropped there.
+    ///
+    /// This is how handles that may be dropped while TLS destructors are running are torn down,
+    /// for the reasons given in the module documentation.
+    pub(crate) fn retire(alloc: DynamicAllocator) {
+        let husk = Husk::Array(unsafe { ptr::read(&alloc.0) });
+        mem::forget(alloc);
+        send_husk(husk);
     }
 
-    fn foreach<F: Fn(*mut T)>(&self, f: F) {
-        for class in self.classes.iter() {
-            f(class)
-        }
+    /// A new handle to the global heap, for tests to pass to `retire`.
+    #[cfg(test)]
+    pub(super) fn clone_heap() -> DynamicAllocator {
+        DynamicAllocator(heap().clone())
     }
-}
-/// A Dynamic memory allocator, instantiated with sane defaults for various `ElfMalloc` type
-/// parameters.
-#[derive(Clone)]
-pub struct DynamicAllocator(ElfMalloc<PageAlloc<Source>, TieredSizeClasses<ObjectAlloc<PageAlloc<Source>>>>);
 
-unsafe impl Send for DynamicAllocator {}
-
-impl DynamicAllocator {
-    pub fn new() -> Self {
-        DynamicAllocator(ElfMalloc::new())
-    }
-    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
-        self.0.alloc(size)
-    }
-    pub unsafe fn free(&mut self, item: *mut u8) {
-        self.0.free(item)
+    impl Drop for GlobalAllocator {
+        fn drop(&mut self) {
+            // After we read the alloc field with ptr::read, the underlying memory should be
+            // treated as uninitialized, but Rust doesn't know this. We use ManuallyDrop to ensure
+            // that Rust doesn't try to drop the field after this method returns.
+            let dyn = unsafe { ManuallyDrop::into_inner(ptr::read(&self.alloc)) };
+            if bagpipe::epoch_usable() {
+                mem::drop(DynamicAllocator(dyn));
+            } else {
+                send_husk(Husk::Array(dyn));
+            }
+        }
     }
 
-    pub unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
-        self.0.realloc(item, new_size, mem::size_of::<usize>())
+    /// Get the size and alignment of `item`, which must have been allocated by this module and
+    /// not yet freed.
+    ///
+    /// The pages of every handle are those of the global heap, so this reads the heap directly
+    /// rather than using the current thread's handle, and is just as cheap while the handle is
+    /// unavailable.
+    pub unsafe fn get_layout(item: *mut u8) -> (usize /* size */, usize /* alignment */) {
+        match ready_heap() {
+            Some(heap) => heap.get_layout(item),
+            // only large allocations can have been made before the heap was ready
+            None => (super::large_alloc::get_size(item), mmap::page_size()),
+        }
     }
 
-    pub unsafe fn aligned_realloc(
-        &mut self,
-        item: *mut u8,
-        new_size: usize,
-        new_alignment: usize,
-    ) -> *mut u8 {
-        self.0.realloc(item, new_size, new_alignment)
+    /// Get the number of bytes of `item` that may be used, which is at least the size it was
+    /// allocated with. This is what `malloc_usable_size` reports: the size of the object's class,
+    /// or for a large allocation, the rest of its mapped region after its header.
+    ///
+    /// Returns 0 if `item` is null. Otherwise, `item` must have been allocated by this module and
+    /// not yet freed. Like `get_layout`, this does not use the current thread's handle.
+    pub unsafe fn usable_size(item: *mut u8) -> usize {
+        if item.is_null() {
+            return 0;
+        }
+        get_layout(item).0
     }
-}
 
+    /// Get the thread that allocated `item`.
+    ///
+    /// Returns `None` if the allocating thread is unknown; this happens for allocations made
+    /// during thread creation or teardown. `item` must be an object allocated by elfmalloc that
+    /// has not yet been freed.
+    #[cfg(feature = "owner_debug")]
+    pub unsafe fn owner_of(item: *mut u8) -> Option<ThreadLabel> {
+        owner::label(*heap().owner_slot(item))
+    }
 
-// Frontends are currently feature-gated in the following fashion:
+    pub use super::large_alloc::ReservationToken;
 
-#[cfg(not(feature = "local_cache"))]
-type Inner<CA> = MagazineCache<CA>;
-#[cfg(feature = "local_cache")]
-type Inner<CA> = LocalCache<CA>;
+    /// Free the large allocation `item`, keeping its address range reserved for later reuse.
+    ///
+    /// The pages backing `item` are decommitted and made inaccessible: its contents are lost, and
+    /// any access to the range faults. The range can be reused (at the same address) with
+    /// `realloc_from_reservation`, or unmapped with `release_reservation`. Reserved ranges are
+    /// counted by `reserved_bytes`.
+    ///
+    /// `item` must be a live allocation larger than the largest size class.
+    pub unsafe fn free_keep_reservation(item: *mut u8) -> ReservationToken {
+        super::large_alloc::free_keep_reservation(item)
+    }
 
-#[cfg(not(feature = "magazine_layer"))]
-pub(crate) type ObjectAlloc<CA> = Lazy<Inner<CA>>;
-#[cfg(feature = "magazine_layer")]
-pub(crate) type ObjectAlloc<CA> = Lazy<DepotCache<Inner<CA>>>;
+    /// Allocate `size` bytes from the range reserved by `token`.
+    ///
+    /// On success, the returned pointer is the pointer that was passed to `free_keep_reservation`.
+    /// It is a normal large allocation, and freeing it with `free` also releases the range. If
+    /// `size` exceeds the size of the original allocation, `token` is returned unchanged.
+    pub unsafe fn realloc_from_reservation(
+        token: ReservationToken,
+        size: usize,
+    ) -> Result<*mut u8, ReservationToken> {
+        super::large_alloc::realloc_from_reservation(token, size)
+    }
 
+    /// Unmap the range reserved by `token`.
+    pub unsafe fn release_reservation(token: ReservationToken) {
+        super::large_alloc::release_reservation(token)
+    }
 
-/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
-/// `AllocMap`.
-///
-/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
-/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
-/// type parameters.
-struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>> {
-    /// A cache of pages for all small allocations.
-    small_pages: CA,
-    /// A cache of pages for all medium allocations.
-    large_pages: CA,
-    /// An `AllocMap` of size classes of individual fixed-size object allocator.
-    allocs: AM,
-    /// The maximum size of a "non-large" object. Objects larger than `max_size` are allocated
-    /// directly with mmap.
-    max_size: usize,
+    /// The total number of bytes in ranges reserved by `free_keep_reservation` that have not yet
+    /// been reused or released.
+    pub fn reserved_bytes() -> usize {
+        super::large_alloc::reserved_bytes()
+    }
 
-    start_from: usize,
-    n_classes: usize,
-}
+    /// The total size of the regions of all live large allocations, made by any thread through
+    /// the global heap or any `DynamicAllocator`.
+    ///
+    /// Unlike the counts returned by `stats`, this is exact even when allocations are freed by a
+    /// different thread than the one that made them, and it is kept with the `no_stats` feature.
+    /// It requires the `large_registry` feature.
+    #[cfg(any(test, feature = "large_registry"))]
+    pub fn live_large_bytes() -> usize {
+        let mut total = 0;
+        super::large_alloc::foreach_registered(|_, len| total += len);
+        total
+    }
 
-impl Default for DynamicAllocator {
-    fn default() -> Self {
-        Self::new()
+    /// Abort, after printing the address and size of each remaining region, if any large
+    /// allocation is live.
+    ///
+    /// This is meant for the end of a test, once everything it allocated should have been freed.
+    /// Large allocations made or freed concurrently by other threads may or may not be seen. It
+    /// requires the `large_registry` feature.
+    #[cfg(any(test, feature = "large_registry"))]
+    pub fn assert_no_live_large_allocs() {
+        let mut live = 0;
+        super::large_alloc::foreach_registered(|base, len| {
+            live += 1;
+            #[cfg(not(feature = "owner_debug"))]
+            alloc_eprintln!("elfmalloc: live large region of {} bytes at {:?}", len, base);
+            #[cfg(feature = "owner_debug")]
+            alloc_eprintln!(
+                "elfmalloc: live large region of {} bytes at {:?}, allocated by {}",
+                len,
+                base,
+                owner::Owner(unsafe { super::large_alloc::region_owner(base) })
+            );
+        });
+        if live != 0 {
+            alloc_panic!("elfmalloc: large allocations still live: {}", live);
+        }
     }
-}
 
-// TODO(ezrosent): move this to a type parameter when const generics are in.
-const ELFMALLOC_PAGE_SIZE: usize = 2 << 20;
-const ELFMALLOC_SMALL_PAGE_SIZE: usize = 256 << 10;
-const ELFMALLOC_SMALL_CUTOFF: usize = ELFMALLOC_SMALL_PAGE_SIZE / 4;
+    /// The number of handles created by the current thread, for tests to check that a path does
+    /// not create one.
+    #[cfg(test)]
+    #[thread_local]
+    pub(super) static mut HANDLES_CREATED: usize = 0;
 
-impl<M: MemorySource, D: DirtyFn>
-    ElfMalloc<PageAlloc<M, D>, TieredSizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
-    fn new() -> Self {
-        let pa_large = PageAlloc::new(ELFMALLOC_PAGE_SIZE, 1 << 20, 8, AllocType::BigSlag);
-        // The small pages are allocated in groups where the first page is aligned to
-        // ELFMALLOC_PAGE_SIZE; this page will be stamped with AllocType::SmallSlag, allowing type
-        // lookups to work as expected.
-        let pa_small = PageAlloc::new_aligned(
-            ELFMALLOC_SMALL_PAGE_SIZE,
-            1 << 20,
-            8,
-            ELFMALLOC_PAGE_SIZE,
-            AllocType::SmallSlag,
-        );
-        Self::new_internal(0.6, pa_small, pa_large, 8, 25)
+    fn new_handle() -> GlobalAllocator {
+        #[cfg(test)]
+        unsafe {
+            HANDLES_CREATED += 1;
+            CREATING_HANDLE = true;
+        }
+        let handle = GlobalAllocator {
+            alloc: ManuallyDrop::new(heap().clone()),
+        };
+        #[cfg(test)]
+        unsafe {
+            CREATING_HANDLE = false;
+        }
+        handle
     }
-}
-
-#[inline(always)]
-unsafe fn round_to_page<T>(item: *mut T) -> *mut T {
-    ((item as usize) & !(ELFMALLOC_PAGE_SIZE - 1)) as *mut T
-}
 
-/// We ensure that for every pointer returned from a call to `alloc`, rounding that pointer down to
-/// a 2MiB boundary yields the location of an `AllocType`. This is enforced separately in the
-/// `PageAlloc` code, the `large_alloc` code, and the `Slag` code.
-///
-/// All of this allows us to run elfmalloc with a full malloc-style interface without resorting to
-/// any sort of global ownership check on the underlying `MemorySource`. This method thus breaks
-/// our dependency on the `Creek`.
-#[inline(always)]
-unsafe fn get_type(item: *mut u8) -> AllocType {
-    *round_to_page(item.offset(-1) as *mut AllocType)
-}
+    lazy_static! {
+        static ref BACKGROUND: Mutex<Background> = Mutex::new(Background::Idle);
+    }
 
-impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>> Clone
-    for ElfMalloc<PageAlloc<M, D>, AM> {
-    fn clone(&self) -> Self {
-        let new_map = AM::init(self.start_from, self.n_classes, |size: usize| unsafe {
-            self.allocs.get(size).clone()
-        });
-        ElfMalloc {
-            small_pages: self.small_pages.clone(),
-            large_pages: self.large_pages.clone(),
-            allocs: new_map,
-            max_size: self.max_size,
-            start_from: self.start_from,
-            n_classes: self.n_classes,
+    /// Stop the background thread that tears down the handles of exiting threads, once it has
+    /// torn down the handles it was already sent.
+    ///
+    /// This is meant to be called just before the process exits, so that tools that check for
+    /// leaked threads or memory do not report the background thread. Allocating and freeing keep
+    /// working afterwards. However, the handles of threads that exit after `shutdown` and that
+    /// cannot be torn down on their own thread, as described in the module documentation, are
+    /// leaked, along with the objects they cache. Calling `shutdown` again has no effect.
+    pub fn shutdown() {
+        let background = mem::replace(&mut *BACKGROUND.lock().unwrap(), Background::Stopped);
+        if let Background::Running {
+            sender,
+            handle,
+            overflow,
+        } = background
+        {
+            // Dropping the only sender ends the background thread's loop.
+            mem::drop(sender);
+            let _ = handle.join();
+            for husk in overflow {
+                handle_husk(husk);
+            }
         }
     }
-}
 
-unsafe fn elfmalloc_get_layout<M: MemorySource>(m_block: &M, item: *mut u8) -> (usize, usize) {
-    match get_type(item) {
-        AllocType::SmallSlag | AllocType::BigSlag => {
-            let meta = (*Slag::find(item, m_block.page_size())).get_metadata();
-            (
-                meta.object_size,
-                if meta.object_size.is_power_of_two() {
-                    meta.object_size
-                } else {
-                    mem::size_of::<usize>()
-                },
-            )
+    /// The lock on `BACKGROUND` held across a `fork`.
+    #[cfg(unix)]
+    static mut FORK_GUARD: Option<MutexGuard<'static, Background>> = None;
+
+    /// Acquire the global heap's locks before a `fork`, so that the child does not inherit them
+    /// held by threads that do not exist in the child.
+    #[cfg(unix)]
+    pub(super) unsafe fn prepare_fork() {
+        #[cfg(feature = "owner_debug")]
+        owner::prepare_fork();
+        FORK_GUARD = Some(BACKGROUND.lock().unwrap());
+        if let Some(shards) = ready_shards() {
+            shards.lock_all();
         }
-        AllocType::Large => (large_alloc::get_size(item), mmap::page_size()),
     }
-}
 
-impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>>
-    ElfMalloc<PageAlloc<M, D>, AM> {
-    fn new_internal(
-        // usable_size: usize,
-        cutoff_factor: f64,
-        pa_small: PageAlloc<M, D>,
-        pa_large: PageAlloc<M, D>,
-        start_from: usize,
-        n_classes: usize,
-    ) -> Self {
-        use self::mmap::map;
-        let mut meta_pointer = map(mem::size_of::<Metadata>() * n_classes) as *mut Metadata;
-        let small_page_size = pa_small.backing_memory().page_size();
-        let am = AM::init(start_from, n_classes, |size: usize| {
-            let (u_size, pa, ty) = if size < ELFMALLOC_SMALL_CUTOFF {
-                (small_page_size, pa_small.clone(), AllocType::SmallSlag)
-            } else {
-                (
-                    pa_large.backing_memory().page_size(),
-                    pa_large.clone(),
-                    AllocType::BigSlag,
-                )
-            };
-            let m_ptr = meta_pointer;
-            unsafe {
-                meta_pointer = meta_pointer.offset(1);
-                ptr::write(
-                    m_ptr,
-                    compute_metadata(
-                        size,
-                        pa.backing_memory().page_size(),
-                        0,
-                        cutoff_factor,
-                        u_size,
-                        ty,
-                    ),
-                );
-            }
-            let clean = PageCleanup::new(pa.backing_memory().page_size());
-            // TODO(ezrosent); new_size(8) is a good default, but a better one would take
-            // num_cpus::get() into account when picking this size, as in principle this will run
-            // into scaling limits at some point.
-            let params = (
-                m_ptr,
-                1 << 20,
-                pa,
-                RevocablePipe::new_size_cleanup(16, clean),
-            );
-            #[cfg(not(feature = "magazine_layer"))]
-            {
-                ObjectAlloc::new(params)
-            }
-            #[cfg(feature = "magazine_layer")]
-            {
-                ObjectAlloc::new((params, Depot::default()))
-            }
-        });
-        let max_size = am.max_key();
-        ElfMalloc {
-            small_pages: pa_small.clone(),
-            large_pages: pa_large.clone(),
-            allocs: am,
-            max_size: max_size,
-            start_from: start_from,
-            n_classes: n_classes,
+    /// The shards of the global heap, if it is ready and in the sharded mode.
+    #[cfg(unix)]
+    unsafe fn ready_shards() -> Option<&'static Shards> {
+        ready_heap().and_then(|_| SHARDS.as_ref())
+    }
+
+    /// Release the locks acquired by `prepare_fork` in the parent.
+    #[cfg(unix)]
+    pub(super) unsafe fn parent_after_fork() {
+        if let Some(shards) = ready_shards() {
+            shards.unlock_all();
         }
+        FORK_GUARD = None;
+        #[cfg(feature = "owner_debug")]
+        owner::after_fork();
     }
 
-    #[inline]
-    unsafe fn get_page_size(&self, item: *mut u8) -> Option<usize> {
-        // We have carfeully orchestrated things so that allocation sizes above the cutoff are
-        // aligned to at least that cutoff:
-        // - Medium objects are powers of two, all of which are aligned to their size.
-        // - Large objects are allocated using an MmapSource with page size equivalent to the
-        //   cutoff.
-        // As a result, we do not have to dereference an extra pointer for small objects that are
-        // not aligned to the small cutoff (this is going to be most of them). This netted
-        // small-but-noticeable performance gains.
-        if (item as usize) % ELFMALLOC_SMALL_CUTOFF != 0 {
-            return Some(ELFMALLOC_SMALL_PAGE_SIZE);
+    /// Release the locks acquired by `prepare_fork` in the child, and reset the state that refers
+    /// to threads of the parent.
+    #[cfg(unix)]
+    pub(super) unsafe fn child_after_fork() {
+        {
+            let mut background = FORK_GUARD.take().expect("fork guard missing");
+            // The background thread was not copied into the child. Its sender and handle are
+            // forgotten rather than dropped, since both refer to the parent's thread; a new thread
+            // is started when one is next needed.
+            // The husks waiting for it are leaked like the handles of the parent's other threads.
+            match mem::replace(&mut *background, Background::Idle) {
+                Background::Running {
+                    sender,
+                    handle,
+                    overflow,
+                } => mem::forget((sender, handle, overflow)),
+                Background::Stopped => *background = Background::Stopped,
+                Background::Idle => {}
+            }
+            PENDING_HUSKS.store(0, Ordering::Relaxed);
         }
-        match get_type(item) {
-            AllocType::SmallSlag => {
-                alloc_debug_assert_eq!(self.small_pages.backing_memory().page_size(), ELFMALLOC_SMALL_PAGE_SIZE);
-                Some(ELFMALLOC_SMALL_PAGE_SIZE)
-            },
-            AllocType::BigSlag => {
-                alloc_debug_assert_eq!(self.large_pages.backing_memory().page_size(), ELFMALLOC_PAGE_SIZE);
-                Some(ELFMALLOC_PAGE_SIZE)
-            },
-            AllocType::Large => None,
+        #[cfg(feature = "owner_debug")]
+        owner::after_fork();
+        // Shards are not tied to threads, so the child can keep using them.
+        if let Some(shards) = ready_shards() {
+            shards.unlock_all();
+            return;
+        }
+        if ready_heap().is_none() {
+            return;
+        }
+        // The forking thread's handle is the only one that was copied into the child. It is
+        // leaked, along with the objects it caches, and replaced by a fresh clone of the heap, so
+        // that the child's thread starts with empty caches like any other new thread.
+        let fresh = new_handle();
+        if let Some(stale) =
+            alloc_tls_fast_with!(LOCAL_ELF_HEAP, h, { mem::replace(&mut *h.get(), fresh) })
+        {
+            mem::forget(stale);
         }
     }
+    alloc_thread_local!{ static LOCAL_ELF_HEAP: UnsafeCell<GlobalAllocator> = UnsafeCell::new(new_handle()); }
 
-    unsafe fn alloc(&mut self, bytes: usize) -> *mut u8 {
-        if likely(bytes <= self.max_size) {
-            self.allocs.get_mut(bytes).alloc()
-        } else {
-            large_alloc::alloc(bytes)
+    fn with_local_or_clone<F, R>(f: F) -> R
+        where F: Fn(&mut ElfMalloc<PA, SizeClasses<ObjectAlloc<PA>>>) -> R
+    {
+        unsafe {
+            with_handle!(h, f(h)).unwrap_or_else(|| f(&mut *new_handle().alloc))
         }
     }
 
-    unsafe fn realloc(
-        &mut self,
-        item: *mut u8,
-        mut new_size: usize,
-        new_alignment: usize,
-    ) -> *mut u8 {
-        if item.is_null() {
-            let alloc_size = if new_alignment <= mem::size_of::<usize>() {
-                new_size
-            } else {
-                new_size.next_power_of_two()
-            };
-            return self.alloc(alloc_size);
+    /// The large allocations made before the global heap was ready, which are not recorded by
+    /// the heap itself.
+    static EARLY_REGIONS: RangeSet = RangeSet::new();
+
+    /// Allocate `size` bytes aligned to `align` for a thread whose handle is unavailable.
+    ///
+    /// Once the heap is ready, this is a large allocation made through it, so that it is counted
+    /// against the memory limit and in `stats`, and is found by `DynamicAllocator::contains`.
+    unsafe fn fallback_alloc(size: usize, align: usize) -> *mut u8 {
+        if let Some(heap) = ready_heap() {
+            return heap.alloc_large(size, align);
         }
-        if new_size == 0 {
-            self.free(item);
-            return ptr::null_mut();
+        early_alloc(super::large_alloc::alloc_aligned(size, align))
+    }
+
+    /// Like `fallback_alloc`, but the allocation reads as zeros.
+    unsafe fn fallback_alloc_zeroed(size: usize) -> *mut u8 {
+        if let Some(heap) = ready_heap() {
+            return heap.alloc_large_zeroed(size, 1);
         }
-        let (old_size, old_alignment) = global::get_layout(item);
-        if old_alignment >= new_alignment && old_size >= new_size {
-            return item;
+        early_alloc(super::large_alloc::alloc_zeroed(size))
+    }
+
+    /// Record `item`, allocated by `large_alloc` before the heap was ready, in `EARLY_REGIONS`.
+    unsafe fn early_alloc(item: *mut u8) -> *mut u8 {
+        if !item.is_null() {
+            let (region_size, base) = super::large_alloc::get_commitment(item);
+            EARLY_REGIONS.insert(base, region_size);
         }
-        if new_alignment > mem::size_of::<usize>() {
-            new_size = new_size.next_power_of_two();
+        item
+    }
+
+    // The values of VALIDATE.
+    const VALIDATE_UNKNOWN: usize = 0;
+    const VALIDATE_OFF: usize = 1;
+    const VALIDATE_ON: usize = 2;
+
+    /// Whether frees are validated, read from the environment on first use.
+    static VALIDATE: AtomicUsize = AtomicUsize::new(VALIDATE_UNKNOWN);
+
+    /// The function registered with `set_foreign_free`, stored as a `usize`, or 0.
+    static FOREIGN_FREE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Whether frees are validated. See the module documentation.
+    #[inline(always)]
+    fn validating() -> bool {
+        let validate = VALIDATE.load(Ordering::Relaxed);
+        if unsafe { likely(validate == VALIDATE_OFF) } {
+            return false;
         }
-        let new_mem = self.alloc(new_size);
-        ptr::copy_nonoverlapping(item, new_mem, ::std::cmp::min(old_size, new_size));
-        self.free(item);
-        #[cfg(debug_assertions)]
-        {
-            let (size, _) = global::get_layout(new_mem);
-            alloc_debug_assert!(new_size <= size, "Realloc for {} got memory with size {}", new_size, size);
+        validate == VALIDATE_ON || read_validate()
+    }
+
+    #[cold]
+    fn read_validate() -> bool {
+        let validate = env_flag(b"ELFMALLOC_VALIDATE_FREE\0").unwrap_or(false);
+        VALIDATE.store(if validate { VALIDATE_ON } else { VALIDATE_OFF }, Ordering::Relaxed);
+        validate
+    }
+
+    /// Register `f` to be called on the pointers that `free` finds are not owned by elfmalloc
+    /// when frees are validated, instead of aborting. This lets a program that mixes allocators
+    /// hand such pointers back to the allocator they came from.
+    pub fn set_foreign_free(f: unsafe fn(*mut u8)) {
+        FOREIGN_FREE.store(f as usize, Ordering::Release);
+    }
+
+    /// Set what happens to the regions of large allocations freed by any allocator in the
+    /// process, including the global heap. See `LargeFreePolicy`.
+    ///
+    /// Regions freed before the call are not affected, except that switching to
+    /// `LargeFreePolicy::Unmap` unmaps every region in the cache.
+    pub fn set_large_free_policy(policy: LargeFreePolicy) {
+        unsafe { super::large_alloc::set_free_policy(policy) }
+    }
+
+    /// Whether `item`, which is not null, is in memory mapped by the global heap. Only addresses
+    /// are compared, so `item` is never dereferenced.
+    fn owned(item: *mut u8) -> bool {
+        ready_heap().map_or(false, |heap| heap.contains(item)) || EARLY_REGIONS.contains(item)
+    }
+
+    /// Free `item` if it is not an ordinary object of the global heap, returning whether it was
+    /// freed. This is called by `free` when frees are validated.
+    #[cold]
+    unsafe fn free_unusual(item: *mut u8) -> bool {
+        if ready_heap().map_or(false, |heap| heap.contains(item)) {
+            return false;
         }
-        new_mem
+        if EARLY_REGIONS.contains(item) {
+            // Freeing it through a handle would leave its range in EARLY_REGIONS.
+            free_early(item);
+            return true;
+        }
+        let foreign = FOREIGN_FREE.load(Ordering::Acquire);
+        if foreign == 0 {
+            not_owned(item);
+        }
+        mem::transmute::<usize, unsafe fn(*mut u8)>(foreign)(item);
+        true
     }
 
-    unsafe fn free(&mut self, item: *mut u8) {
-        match self.get_page_size(item) {
-            Some(page_size) => {
-                let slag = &*Slag::find(item, page_size);
-                self.allocs.get_mut(slag.get_metadata().object_size).free(
-                    item,
-                )
+    /// Unmap `item`, which was allocated by `fallback_alloc` before the heap was ready.
+    unsafe fn free_early(item: *mut u8) {
+        let (_, base) = super::large_alloc::get_commitment(item);
+        EARLY_REGIONS.remove(base);
+        super::large_alloc::free(item)
+    }
+
+    #[cold]
+    fn not_owned(item: *mut u8) -> ! {
+        alloc_panic!("elfmalloc: pointer not owned by elfmalloc: {:?}", item)
+    }
+
+    pub unsafe fn alloc(size: usize) -> *mut u8 {
+        with_handle!(h, h.alloc(size)).unwrap_or_else(|| fallback_alloc(size, 1))
+    }
+
+    /// Allocate an object of `size` bytes that reads as zeros.
+    ///
+    /// Only recycled objects are written to: objects that have never been allocated before, and
+    /// large allocations whose regions are known to be zero, already read as zeros.
+    pub unsafe fn alloc_zeroed(size: usize) -> *mut u8 {
+        with_handle!(h, h.alloc_zeroed(size)).unwrap_or_else(|| fallback_alloc_zeroed(size))
+    }
+
+    /// Allocate `size` bytes aligned to `align`. See `DynamicAllocator::alloc_aligned`.
+    ///
+    /// Returns a null pointer if `align` is not a power of two.
+    pub unsafe fn alloc_aligned(size: usize, align: usize) -> *mut u8 {
+        if !align.is_power_of_two() {
+            return ptr::null_mut();
+        }
+        with_handle!(h, h.alloc_aligned(size, align))
+            .unwrap_or_else(|| fallback_alloc(size, align))
+    }
+
+    /// Like `alloc`, but also return the number of bytes of the object that may be used, as
+    /// reported by `get_layout`. A null pointer is returned with a size of 0.
+    pub unsafe fn alloc_excess(size: usize) -> (*mut u8, usize) {
+        with_handle!(h, h.alloc_excess(size)).unwrap_or_else(|| {
+                let item = fallback_alloc(size, 1);
+                if item.is_null() {
+                    (item, 0)
+                } else {
+                    (item, super::large_alloc::get_size(item))
+                }
+            })
+    }
+
+    /// Allocate an object of size `sizes[i]` into `out[i]` for each `i`.
+    ///
+    /// This is faster than calling `alloc` once per request: the thread's handle is looked up
+    /// once, and requests are grouped by size class. Each returned object is an ordinary
+    /// allocation that can be freed individually with `free`.
+    ///
+    /// The operation is atomic: if any request cannot be satisfied, all objects allocated by this
+    /// call are freed, `out` is filled with null pointers, and the error is returned. It is an
+    /// error for `out` to be shorter than `sizes`; any elements of `out` past `sizes.len()` are
+    /// left unchanged.
+    pub unsafe fn alloc_vectored(sizes: &[usize], out: &mut [*mut u8]) -> Result<(), AllocErr> {
+        if out.len() < sizes.len() {
+            return Err(AllocErr::Unsupported { details: "output slice is shorter than the requests" });
+        }
+        with_handle!(h, h.alloc_vectored(sizes, out))
+            .unwrap_or_else(|| {
+                // As in alloc, fall back to large allocations.
+                for i in 0..sizes.len() {
+                    out[i] = fallback_alloc(sizes[i], 1);
+                    if out[i].is_null() {
+                        for p in out[..i].iter_mut() {
+                            free_without_handle(*p);
+                            *p = ptr::null_mut();
+                        }
+                        return Err(Layout::from_size_align(sizes[i], 1)
+                            .map(|l| AllocErr::Exhausted { request: l })
+                            .unwrap_or(AllocErr::Unsupported { details: "allocation size overflows" }));
+                    }
+                }
+                Ok(())
+            })
+    }
+
+    /// Get the number of bytes that may be used in objects allocated by `alloc(size)`, which is
+    /// at least `size`.
+    pub fn usable_size_for(size: usize) -> usize {
+        heap().usable_size_aligned(size, mem::size_of::<usize>())
+    }
+
+    /// Get the number of bytes that may be used in objects allocated by
+    /// `alloc_aligned(size, align)`, which is at least `size`.
+    pub fn usable_size_aligned(size: usize, align: usize) -> usize {
+        heap().usable_size_aligned(size, align)
+    }
+
+    /// The size of the class that `alloc(size)` rounds `size` up to, or `None` if `size` is
+    /// larger than `large_threshold` and gets a large allocation; see
+    /// `DynamicAllocator::size_class_for`.
+    pub fn size_class_for(size: usize) -> Option<usize> {
+        heap().size_class_for(size)
+    }
+
+    /// The largest size that the global heap allocates from a size class. Larger allocations are
+    /// mapped directly.
+    pub fn large_threshold() -> usize {
+        heap().max_size
+    }
+
+    /// A snapshot of the memory used by the global heap, returned by `stats` and
+    /// `stats_detailed`.
+    ///
+    /// The counts are shared by the handles of all threads, including those that have exited:
+    /// the objects cached by an exited thread's handle are counted in `cached_bytes` until the
+    /// background thread has torn the handle down. Each count is read separately while other
+    /// threads may be allocating, so the fields need not be consistent with one another. If the
+    /// `no_stats` feature is enabled, every count is 0.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct GlobalStats {
+        /// The bytes of objects of size classes that are allocated and not yet freed. Each object
+        /// counts as the size of its class.
+        pub live_bytes: usize,
+        /// The bytes of free objects of size classes that are held by handles rather than
+        /// returned to their pages.
+        pub cached_bytes: usize,
+        /// The bytes mapped for large allocations that have not been freed.
+        pub large_bytes: usize,
+        /// The counts of the large allocations of every allocator in the process.
+        pub large: LargeStats,
+        /// The bytes mapped for the pages of size classes and for large allocations. Pages are
+        /// counted from when they are first mapped, including those that have since been
+        /// uncommitted.
+        pub mapped_bytes: usize,
+        /// The counts of each size class, in increasing order of size. This is only filled in by
+        /// `stats_detailed`.
+        pub classes: Vec<ClassStats>,
+    }
+
+    /// Take a snapshot of the memory used by the global heap.
+    ///
+    /// The counts are kept up to date by every thread as it allocates and frees, so this takes
+    /// time proportional to the number of size classes, and does not visit other threads.
+    pub fn stats() -> GlobalStats {
+        let stats = heap().stats();
+        GlobalStats {
+            live_bytes: stats.live_bytes,
+            cached_bytes: stats.cached_bytes,
+            large_bytes: stats.large_bytes,
+            mapped_bytes: stats.small_page_bytes + stats.large_page_bytes +
+                stats.huge_page_bytes + stats.large_bytes,
+            large: stats.large,
+            classes: Vec::new(),
+        }
+    }
+
+    /// Like `stats`, but also report each size class, once the background thread has torn down
+    /// the handles of the threads that exited before this call.
+    ///
+    /// This blocks until the background thread has caught up, so it is much slower than
+    /// `stats`.
+    pub fn stats_detailed() -> GlobalStats {
+        wait_for_background();
+        let mut stats = stats();
+        stats.classes = heap().class_stats();
+        stats
+    }
+
+    /// Get the sizes requested from the global heap by the current thread and by the threads
+    /// that exited before this call, by size class. See `DynamicAllocator::size_histogram`.
+    ///
+    /// Like `stats_detailed`, this waits for the background thread to tear down the handles of
+    /// the exited threads, whose counts are added when their handles are dropped. The counts of
+    /// other running threads are not included. A report suitable for printing when the process
+    /// exits is the `Display` rendering of the result.
+    #[cfg(feature = "profiling")]
+    pub fn size_histogram() -> SizeHistogram {
+        wait_for_background();
+        unsafe { with_handle!(h, h.profile.histogram()) }
+            .unwrap_or_else(|| heap().profile.histogram())
+    }
+
+    /// Wait until the background thread has handled every message sent to it before this call.
+    fn wait_for_background() {
+        let (sender, receiver) = channel();
+        let running = {
+            let mut background = BACKGROUND.lock().unwrap();
+            let running = match *background {
+                Background::Running { .. } => true,
+                _ => false,
+            };
+            if running {
+                // a Sync message is never handed back
+                let _ = enqueue(&mut background, Husk::Sync(sender));
             }
-            None => large_alloc::free(item),
+            running
         };
+        if running {
+            // this fails if the message was dropped because the background thread has panicked
+            let _ = receiver.recv();
+        }
     }
-}
 
-mod large_alloc {
-    //! This module governs "large" allocations that are beyond the size of the largest size class
-    //! of a dynamic allocator.
-    //!
-    //! Large allocations are implemented by mapping a region of memory of the indicated size, with
-    //! an additional page of padding to store the size information.
-    #[cfg(test)]
-    use std::collections::HashMap;
-    #[cfg(test)]
-    use std::cell::RefCell;
-    use std::cmp;
-    use std::ptr;
-    use super::super::sources::{MemorySource, MmapSource};
-    use super::{ELFMALLOC_PAGE_SIZE, ELFMALLOC_SMALL_CUTOFF, round_to_page};
-    use super::super::alloc_type::AllocType;
+    /// Print a report of the objects of the global heap that have not been freed to stderr. See
+    /// `write_leak_report`.
+    ///
+    /// If `ELFMALLOC_LEAK_REPORT` is set to `1` or `true` when the heap is built, this is called
+    /// when the process exits, from an `atexit` handler (on Unix only).
+    pub fn leak_report() {
+        struct Stderr;
+        impl fmt::Write for Stderr {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                alloc_eprint!("{}", s);
+                Ok(())
+            }
+        }
+        let _ = write_leak_report(&mut Stderr);
+    }
 
-    // For debugging, we keep around a thread-local map of pointers to lengths. This helps us
-    // scrutinize if various header data is getting propagated correctly.
-    #[cfg(test)]
-    thread_local! {
-        pub static SEEN_PTRS: RefCell<HashMap<*mut u8, usize>> = RefCell::new(HashMap::new());
+    /// Write a report of the objects of the global heap that have not been freed to `out`: a
+    /// line giving the total bytes, followed by a line of the form `class SIZE: N objects, BYTES
+    /// bytes` for each size class with live objects, and `large allocations: BYTES bytes` if any
+    /// large allocations are live.
+    ///
+    /// The report is taken with `stats_detailed`, so the handles of exited threads have been torn
+    /// down and the objects they cached are not reported. Objects cached by the handles of
+    /// running threads are not reported either, since they are free. Objects that are still
+    /// reachable, such as those held by other threads or by statics, are reported along with
+    /// those that have been leaked. If the `no_stats` feature is enabled, no size class has live
+    /// objects.
+    pub fn write_leak_report<W: fmt::Write>(out: &mut W) -> fmt::Result {
+        let stats = stats_detailed();
+        let class_bytes = stats.classes
+            .iter()
+            .map(|class| class.live_objects * class.object_size)
+            .sum::<usize>();
+        writeln!(
+            out,
+            "elfmalloc leak report: {} bytes live",
+            class_bytes + stats.large_bytes
+        )?;
+        for class in stats.classes.iter().filter(|class| class.live_objects > 0) {
+            writeln!(
+                out,
+                "  class {}: {} objects, {} bytes",
+                class.object_size,
+                class.live_objects,
+                class.live_objects * class.object_size
+            )?;
+        }
+        if stats.large_bytes > 0 {
+            writeln!(out, "  large allocations: {} bytes", stats.large_bytes)?;
+        }
+        Ok(())
     }
-    use super::mmap::unmap;
-    #[cfg(debug_assertions)]
-    use super::mmap::page_size;
 
-    #[repr(C)]
-    #[derive(Copy, Clone)]
-    pub struct AllocInfo {
-        pub ty: AllocType,
-        base: *mut u8,
-        region_size: usize,
+    /// Register `leak_report` to be called at exit if `ELFMALLOC_LEAK_REPORT` is set.
+    ///
+    /// This is called while the heap is being built. `atexit` may allocate, which is served by
+    /// the fallback paths like the other allocations made then.
+    fn register_leak_report() {
+        if !env_flag(b"ELFMALLOC_LEAK_REPORT\0").unwrap_or(false) {
+            return;
+        }
+        #[cfg(unix)]
+        {
+            extern "C" fn report_at_exit() {
+                leak_report();
+            }
+            unsafe {
+                alloc_assert_eq!(libc::atexit(report_at_exit), 0);
+            }
+        }
+        #[cfg(not(unix))]
+        alloc_eprintln!("elfmalloc: ELFMALLOC_LEAK_REPORT is only supported on Unix");
     }
 
-    pub unsafe fn alloc(size: usize) -> *mut u8 {
-        // TODO(ezrosent) round up to page size
-        let region_size = size + ELFMALLOC_PAGE_SIZE;
-        // We need a pointer aligned to the SMALL_CUTOFF, so we use an `MmapSource` to map the
-        // memory. See the comment in get_page_size.
-        let src = MmapSource::new(ELFMALLOC_SMALL_CUTOFF);
-        let n_pages = region_size / ELFMALLOC_SMALL_CUTOFF + cmp::min(1, region_size % ELFMALLOC_SMALL_CUTOFF);
-        let mem = src.carve(n_pages).expect("[lage_alloc::alloc] mmap failed");
-        let res = mem.offset(ELFMALLOC_PAGE_SIZE as isize);
-        let addr = get_commitment_mut(res);
-        ptr::write(
-            addr,
-            AllocInfo {
-                ty: AllocType::Large,
-                base: mem,
-                region_size: region_size,
-            },
-        );
+    pub unsafe fn realloc(item: *mut u8, new_size: usize) -> *mut u8 {
+        aligned_realloc(item, new_size, mem::size_of::<usize>())
+    }
 
-        // begin extra debugging information
-        alloc_debug_assert!(!mem.is_null());
-        alloc_debug_assert_eq!(mem as usize % ELFMALLOC_SMALL_CUTOFF, 0);
-        let upage: usize = 4096;
-        alloc_debug_assert_eq!(mem as usize % upage, 0);
-        alloc_debug_assert_eq!(res as usize % upage, 0);
-        alloc_debug_assert_eq!(get_commitment(res), (size + ELFMALLOC_PAGE_SIZE, mem));
-        #[cfg(test)] SEEN_PTRS.with(|hs| hs.borrow_mut().insert(mem, region_size));
-        // end extra debugging information
-        res
+    /// Like `realloc`, but the object is aligned to `new_alignment`.
+    ///
+    /// When frees are validated, this aborts if `item` is not owned by elfmalloc, whether or not
+    /// a function is registered with `set_foreign_free`.
+    pub unsafe fn aligned_realloc(item: *mut u8, new_size: usize, new_alignment: usize) -> *mut u8 {
+        if unlikely(validating()) && !item.is_null() && !owned(item) {
+            not_owned(item);
+        }
+        with_local_or_clone(|h| h.realloc(item, new_size, new_alignment))
     }
 
     pub unsafe fn free(item: *mut u8) {
-        let (size, base_ptr) = get_commitment(item);
-        use std::intrinsics::unlikely;
-        if unlikely(size == 0 && base_ptr.is_null()) {
+        if unlikely(validating()) && !item.is_null() && free_unusual(item) {
             return;
         }
+        with_handle!(h, h.free(item)).unwrap_or_else(|| free_without_handle(item));
+    }
 
-        trace!("size={}, base_ptr={:?}", size, base_ptr);
-        // begin extra debugging information:
-        #[cfg(debug_assertions)]
-        {
-            ptr::write_volatile(item, 10);
-            alloc_debug_assert_eq!(
-                base_ptr as usize % page_size(),
-                0,
-                "base_ptr ({:?}) not a multiple of the page size ({})",
-                base_ptr,
-                page_size()
-            );
+    /// Return the objects cached by the current thread's handle, so that other threads can use
+    /// them. See `DynamicAllocator::flush_thread_cache`. In the sharded mode, this flushes the
+    /// handle that the current thread shares.
+    pub fn flush_thread_cache() {
+        unsafe {
+            let _ = with_handle!(h, h.flush_caches());
         }
-        #[cfg(test)]
-        {
-            SEEN_PTRS.with(|hm| {
-                let mut hmap = hm.borrow_mut();
-                {
-                    if let Some(len) = hmap.get(&base_ptr) {
-                        alloc_assert_eq!(*len, size);
-                    }
-                }
-                hmap.remove(&base_ptr);
-            });
+    }
+
+    /// Let the adaptive caches of the current thread's handle decay, and return the memory of the
+    /// global heap's cached free pages to the operating system, returning the number of bytes
+    /// released. See `DynamicAllocator::trim`.
+    ///
+    /// This is meant to be called periodically, for example between the requests of a server,
+    /// by each thread that allocates in bursts.
+    pub fn trim() -> usize {
+        unsafe { with_handle!(h, h.trim()).unwrap_or(0) }
+    }
+
+    /// Free `item` without using the current thread's handle.
+    ///
+    /// Large objects are unmapped directly. Other objects are pushed onto their class's stack of
+    /// remote frees, to be freed by the next handle that uses the class.
+    pub(crate) unsafe fn free_without_handle(item: *mut u8) {
+        // Until the heap is ready, the only objects that can have been allocated are the large
+        // allocations made while it was being initialized.
+        let heap = match ready_heap() {
+            Some(heap) => heap,
+            None => return free_early(item),
+        };
+        heap.check_double_free(item);
+        let page_size = match heap.get_page_size(item) {
+            Some(page_size) => page_size,
+            None if EARLY_REGIONS.contains(item) => return free_early(item),
+            None => return heap.free_large(item),
+        };
+        let meta = (*Slag::find(item, page_size)).get_metadata();
+        // the pages of a frozen class are read-only, so the object cannot be linked into the stack
+        if !(unlikely(freeze::any_frozen()) && freeze::queue_free(meta, item)) {
+            remote::push(meta, item);
         }
-        // end extra debugging information
-        unmap(base_ptr, size);
     }
+}
 
-    pub unsafe fn get_size(item: *mut u8) -> usize {
-        let (size, _) = get_commitment(item);
-        size - ELFMALLOC_PAGE_SIZE
+/// A trait encapsulating the notion of an array of size classes for an allocator.
+pub(crate) trait AllocMap<T>
+where
+    Self: Sized,
+{
+    /// The type used to index size classes.
+    type Key;
+
+    /// Create and initialize the map.
+    fn init<F: FnMut(Self::Key) -> T>(start: Self::Key, n_classes: usize, f: F) -> Self {
+        Self::init_conserve(start, n_classes, f).1
     }
 
-    unsafe fn get_commitment(item: *mut u8) -> (usize, *mut u8) {
-        let meta_addr = get_commitment_mut(item);
-        let base_ptr = (*meta_addr).base;
-        let size = (*meta_addr).region_size;
-        (size, base_ptr)
+    /// Create and initialize the map, handing back ownership of the constructor.
+    fn init_conserve<F: FnMut(Self::Key) -> T>(
+        start: Self::Key,
+        n_classes: usize,
+        f: F,
+    ) -> (F, Self);
+
+    /// Create a map with the same classes as `self`, initializing each one with `f`, which is
+    /// called with the key of each class in ascending order.
+    ///
+    /// This is used to clone a map whose classes do not all follow from `start` and `n_classes`.
+    fn init_like<F: FnMut(Self::Key) -> T>(&self, f: F) -> Self;
+
+    /// Get an unchecked raw pointer to the class corresponding to `k`.
+    ///
+    /// `k` must be at most `max_key()`. Maps that round keys up to a class by arithmetic
+    /// (`Multiples`, `PowersOfTwo` and `Geometric`) also require `k` to round up to their first
+    /// class or a later one. Both are only checked in debug builds; other callers should use
+    /// `try_get_raw`.
+    unsafe fn get_raw(&self, k: Self::Key) -> *mut T;
+
+    /// Get a raw pointer to the class corresponding to `k`, or `None` if `k` is larger than
+    /// `max_key()`. Keys smaller than the first class get the first class.
+    fn try_get_raw(&self, k: Self::Key) -> Option<*mut T>;
+
+    /// Get an unchecked reference to the class corresponding to `k`.
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get(&self, k: Self::Key) -> &T {
+        &*self.get_raw(k)
     }
 
-    pub unsafe fn get_commitment_mut(item: *mut u8) -> *mut AllocInfo {
-        round_to_page(item.offset(-1) as *mut AllocInfo)
+    /// Get an unchecked mutable reference to the class corresponding to `k`.
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_mut(&mut self, k: Self::Key) -> &mut T {
+        &mut *self.get_raw(k)
+    }
+
+    /// Iterate over the classes along with the largest size that maps to each, in ascending
+    /// order of size.
+    fn iter(&self) -> Iter<T>;
+
+    /// Call `f` on each class, in ascending order of key.
+    ///
+    /// This is used to clean up the contents of the map.
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, f: F);
+
+    /// Call `f` on each class in ascending order of key, stopping at the first error.
+    fn try_foreach<E, F: FnMut(&T) -> Result<(), E>>(&self, mut f: F) -> Result<(), E> {
+        for (_, class) in self.iter() {
+            f(class)?;
+        }
+        Ok(())
+    }
+
+    /// Get the `Key` with a "maximum" value.
+    ///
+    /// This method is most useful when the `Key` type is a numeric type representing a "size
+    /// class".
+    fn max_key(&self) -> Self::Key;
+
+    /// Append `class` as a new largest class, twice the size of the current one (see
+    /// `DynamicAllocator::extend_classes`). Maps that cannot grow hand `class` back.
+    fn append_class(&mut self, class: T) -> Result<(), T> {
+        Err(class)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
-    use super::*;
-    use std::ptr::{write_bytes, write_volatile};
+/// How the keys of a run of classes follow from their indices.
+#[derive(Copy, Clone)]
+enum RunKeys<'a> {
+    /// The `i`th class is `start + i * multiple` bytes.
+    Multiples(usize, usize),
+    /// The `i`th class is `start << i` bytes.
+    PowersOfTwo(usize),
+    /// The `i`th class is the `i`th entry of a table of sizes.
+    Table(&'a OwnedArray<usize>),
+}
 
+/// The classes of a `TypedArray`, in ascending order of key.
+struct Run<'a, T: 'a> {
+    keys: RunKeys<'a>,
+    classes: &'a TypedArray<T>,
+}
 
-    #[test]
-    fn layout_lookup() {
-        fn test_and_free<F: Fn(usize, usize)>(inp: usize, tester: F) {
-            unsafe {
-                let obj = global::alloc(inp);
-                let (size, align) = global::get_layout(obj);
-                tester(size, align);
-                global::free(obj);
+impl<'a, T> Clone for Run<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for Run<'a, T> {}
+
+/// An iterator over the classes of an `AllocMap` and their keys, in ascending order of key.
+///
+/// The classes are in up to two runs.
+pub(crate) struct Iter<'a, T: 'a> {
+    runs: [Option<Run<'a, T>>; 2],
+    run: usize,
+    index: usize,
+}
+
+impl<'a, T> Iter<'a, T> {
+    fn new(keys: RunKeys<'a>, classes: &'a TypedArray<T>) -> Iter<'a, T> {
+        Iter {
+            runs: [
+                Some(Run {
+                    keys: keys,
+                    classes: classes,
+                }),
+                None,
+            ],
+            run: 0,
+            index: 0,
+        }
+    }
+
+    /// Iterate over the classes of `small` and then those of `medium`, which must each have been
+    /// created with `new`.
+    fn pair(small: Iter<'a, T>, medium: Iter<'a, T>) -> Iter<'a, T> {
+        Iter {
+            runs: [small.runs[0], medium.runs[0]],
+            run: 0,
+            index: 0,
+        }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<(usize, &'a T)> {
+        while self.run < self.runs.len() {
+            if let Some(run) = self.runs[self.run] {
+                if self.index < run.classes.len() {
+                    let i = self.index;
+                    self.index += 1;
+                    let key = match run.keys {
+                        RunKeys::Multiples(start, multiple) => start + i * multiple,
+                        RunKeys::PowersOfTwo(start) => start << i,
+                        RunKeys::Table(sizes) => unsafe { *sizes.get(i) },
+                    };
+                    return Some((key, unsafe { &*run.classes.get(i) }));
+                }
+            }
+            self.run += 1;
+            self.index = 0;
+        }
+        None
+    }
+}
+
+// Note on the C API:
+//
+// The C allocation API guarantees a minimum alignment for all allocations. On some systems, this
+// is 8, while on others, 16. Objects whose size is a multiple of 16 are aligned to 16 bytes (see
+// `compute_metadata`), so raising the smallest class to 16 bytes makes every object suitably
+// aligned without rounding up allocation sizes. `DynamicAllocatorBuilder::min_align` does this at
+// runtime: with an alignment of 16, `TieredSizeClasses` is created without its 8-byte class. When
+// the "c-api" feature is enabled, `DEFAULT_MIN_ALIGN` makes 16 the default on the systems that
+// require it.
+
+/// The default value of `DynamicAllocatorBuilder::min_align`: 16 when compiling for the C API on
+/// Mac and 64-bit Windows, and the word size otherwise.
+#[cfg(all(feature = "c-api",
+            any(target_os = "macos", all(windows, target_pointer_width = "64"))))]
+const DEFAULT_MIN_ALIGN: usize = 16;
+#[cfg(not(all(feature = "c-api",
+                any(target_os = "macos", all(windows, target_pointer_width = "64")))))]
+const DEFAULT_MIN_ALIGN: usize = mem::size_of::<usize>();
+
+/// The classes below the word size added by `DynamicAllocatorBuilder::tiny_classes`.
+const TINY_CLASSES: [usize; 3] = [1, 2, 4];
+
+/// The sizes of the small classes of `TieredSizeClasses` and the table their classes are looked
+/// up in, which are shared by the maps of an allocator's clones.
+struct SmallTable {
+    /// The size of each class, in ascending order: the `TINY_CLASSES` if they were requested, 8
+    /// bytes if the smallest class requested is at most 8 bytes, and then multiples of the
+    /// allocator's multiple (16 by default, and always a multiple of 8).
+    sizes: OwnedArray<usize>,
+    /// The index of the class of every size up to the largest class, indexed by the size shifted
+    /// right by `shift` and rounded up.
+    index: OwnedArray<u16>,
+    /// 3, so that there is an entry for every 8 bytes, or 0 with the `TINY_CLASSES`, which are
+    /// not multiples of 8.
+    shift: usize,
+}
+
+impl SmallTable {
+    fn new(
+        start: usize,
+        n_small_classes: usize,
+        multiple: usize,
+        tiny_classes: bool,
+    ) -> SmallTable {
+        alloc_debug_assert!(n_small_classes >= 1);
+        alloc_debug_assert!(multiple.is_power_of_two() && multiple >= 8);
+        let tiny: &[usize] = if tiny_classes { &TINY_CLASSES } else { &[] };
+        let starting_size = round_up(start, multiple);
+        // with a multiple of 8, the first multiple is the class for 8-byte objects
+        let word_class = if start <= 8 && starting_size > 8 { 1 } else { 0 };
+        let first_multiple = tiny.len() + word_class;
+        let n_classes = first_multiple + n_small_classes;
+        alloc_debug_assert!(n_classes <= u16::max_value() as usize + 1);
+        let max_size = starting_size + (n_small_classes - 1) * multiple;
+        let shift = if tiny_classes { 0 } else { 3 };
+        let sizes = OwnedArray::new(n_classes);
+        let index = OwnedArray::new((max_size >> shift) + 1);
+        unsafe {
+            for (i, &size) in tiny.iter().enumerate() {
+                ptr::write(sizes.get(i), size);
+            }
+            if word_class == 1 {
+                ptr::write(sizes.get(tiny.len()), 8);
+            }
+            for i in 0..n_small_classes {
+                ptr::write(sizes.get(first_multiple + i), starting_size + i * multiple);
+            }
+            let mut class = 0;
+            for i in 0..index.len() {
+                while *sizes.get(class) < i << shift {
+                    class += 1;
+                }
+                ptr::write(index.get(i), class as u16);
+            }
+        }
+        SmallTable {
+            sizes: sizes,
+            index: index,
+            shift: shift,
+        }
+    }
+
+    fn max_size(&self) -> usize {
+        unsafe { *self.sizes.get(self.sizes.len() - 1) }
+    }
+
+    /// Create and initialize a class for each of the sizes.
+    fn init_classes<T, F: FnMut(usize) -> T>(&self, mut f: F) -> (F, TypedArray<T>) {
+        let classes = TypedArray::new(self.sizes.len());
+        unsafe {
+            for (i, class) in classes.iter().enumerate() {
+                ptr::write(class, f(*self.sizes.get(i)));
+            }
+        }
+        (f, classes)
+    }
+}
+
+/// Size classes from the `scalloc` and `tcmalloc` allocators.
+///
+/// This includes two runs of size classes: the first (smaller) size classes are multiples of 16,
+/// or of another multiple passed to `init_with`. The larger classes are powers of two, or grow
+/// more slowly if `M` is `Geometric`. There is also a class for 8-byte objects if the smallest
+/// class requested is at most 8 bytes, and optionally the `TINY_CLASSES` in front of it.
+///
+/// The class of a small size is found with a load from the `SmallTable` and an index into
+/// `small_objs`, rather than by rounding the size up as `Multiples` does.
+struct TieredSizeClasses<T, M = PowersOfTwo<T>> {
+    table: Arc<SmallTable>,
+    small_max: usize,
+    /// The classes of `table.sizes`.
+    small_objs: TypedArray<T>,
+    medium_objs: M,
+}
+
+impl<T> TieredSizeClasses<T> {
+    /// The number of small classes of `TieredSizeClasses` initialized with `start`, `n_classes`,
+    /// `multiple` and `small_cutoff`, not counting the tiny classes or the class for 8-byte
+    /// objects: half of the classes, but no more than there are multiples of `multiple` up to
+    /// `small_cutoff`, which must be larger than `start`.
+    fn small_classes(
+        start: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+    ) -> usize {
+        cmp::min((small_cutoff / multiple) - (start / multiple), n_classes / 2)
+    }
+
+    /// The size of the first medium class of `TieredSizeClasses` initialized with `start`,
+    /// `n_classes`, `multiple` and `small_cutoff` (before it is rounded up to a power of two),
+    /// and the number of medium classes.
+    fn medium_classes(
+        start: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+    ) -> (usize, usize) {
+        // this mirrors init_with
+        let n_small_classes = Self::small_classes(start, n_classes, multiple, small_cutoff);
+        let small_max = n_small_classes * multiple + round_up(start, multiple) - multiple;
+        (small_max + 1, n_classes - n_small_classes)
+    }
+
+    /// The largest class of `TieredSizeClasses` initialized with `start`, `n_classes`,
+    /// `multiple` and `small_cutoff`, or `usize::max_value()` if it would overflow.
+    fn max_key_for(start: usize, n_classes: usize, multiple: usize, small_cutoff: usize) -> usize {
+        let (medium_start, n_medium_classes) =
+            Self::medium_classes(start, n_classes, multiple, small_cutoff);
+        let medium_start = medium_start.next_power_of_two();
+        if n_medium_classes - 1 > medium_start.leading_zeros() as usize {
+            usize::max_value()
+        } else {
+            medium_start << (n_medium_classes - 1)
+        }
+    }
+}
+
+impl<T> TieredSizeClasses<T, Geometric<T>> {
+    /// Create and initialize classes whose medium classes grow by `ratio`; see `Geometric`.
+    fn init_geometric<F>(
+        start: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+        tiny_classes: bool,
+        ratio: f64,
+        f: F,
+    ) -> Self
+    where
+        F: FnMut(usize) -> T,
+    {
+        let init_medium = |start, n_classes, f| Geometric::init_ratio(start, n_classes, ratio, f);
+        Self::init_with(start, n_classes, multiple, small_cutoff, tiny_classes, f, init_medium).1
+    }
+}
+
+impl<T, M: AllocMap<T, Key = usize>> TieredSizeClasses<T, M> {
+    /// Create and initialize the classes, with small classes that are multiples of `multiple` (a
+    /// power of two of at least 8) up to `small_cutoff` and the `TINY_CLASSES` if `tiny_classes`
+    /// is set, creating the medium classes with `init_medium(start, n_classes, f)`.
+    fn init_with<F, I>(
+        start: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+        tiny_classes: bool,
+        f: F,
+        init_medium: I,
+    ) -> (F, Self)
+    where
+        F: FnMut(usize) -> T,
+        I: FnOnce(usize, usize, F) -> (F, M),
+    {
+        let n_small_classes =
+            TieredSizeClasses::<()>::small_classes(start, n_classes, multiple, small_cutoff);
+        let n_medium_classes = n_classes - n_small_classes;
+        let table = SmallTable::new(start, n_small_classes, multiple, tiny_classes);
+        let small_max = table.max_size();
+        let (f2, small_classes) = table.init_classes(f);
+        let (f3, medium_classes) = init_medium(small_max + 1, n_medium_classes, f2);
+        (
+            f3,
+            TieredSizeClasses {
+                table: Arc::new(table),
+                small_max: small_max,
+                small_objs: small_classes,
+                medium_objs: medium_classes,
+            },
+        )
+    }
+
+    /// Unmap the array of the small classes, which must already have been dropped.
+    unsafe fn destroy_small(&mut self) {
+        self.small_objs.destroy();
+    }
+}
+
+impl<T, M: AllocMap<T, Key = usize>> AllocMap<T> for TieredSizeClasses<T, M> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+        let cutoff = ELFMALLOC_SMALL_CUTOFF;
+        Self::init_with(start, n_classes, MULTIPLE, cutoff, false, f, M::init_conserve)
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        let (f, small_objs) = self.table.init_classes(f);
+        let medium_objs = self.medium_objs.init_like(f);
+        TieredSizeClasses {
+            table: self.table.clone(),
+            small_max: self.small_max,
+            small_objs: small_objs,
+            medium_objs: medium_objs,
+        }
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        if likely(n <= self.small_max) {
+            let shift = self.table.shift;
+            self.small_objs.get(*self.table.index.get((n + (1 << shift) - 1) >> shift) as usize)
+        } else {
+            self.medium_objs.get_raw(n)
+        }
+    }
+
+    fn try_get_raw(&self, n: usize) -> Option<*mut T> {
+        if n <= self.small_max {
+            Some(unsafe { self.get_raw(n) })
+        } else {
+            self.medium_objs.try_get_raw(n)
+        }
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.medium_objs.max_key()
+    }
+
+    fn iter(&self) -> Iter<T> {
+        Iter::pair(
+            Iter::new(RunKeys::Table(&self.table.sizes), &self.small_objs),
+            self.medium_objs.iter(),
+        )
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for class in self.small_objs.iter() {
+            f(unsafe { &mut *class })
+        }
+        self.medium_objs.foreach_mut(f);
+    }
+
+    fn append_class(&mut self, class: T) -> Result<(), T> {
+        self.medium_objs.append_class(class)
+    }
+}
+
+/// The default multiple of the small size classes. Whatever their multiple, classes that are not
+/// powers of two are aligned to at most `MULTIPLE` bytes (see `class_alignment`).
+pub(crate) const MULTIPLE: usize = 16;
+
+/// An array of size classes where sizes are multiples of `multiple`, which is `MULTIPLE` unless
+/// the array is created with `init_multiple`.
+pub(crate) struct Multiples<T> {
+    starting_size: usize,
+    max_size: usize,
+    multiple: usize,
+    pub classes: TypedArray<T>,
+}
+
+impl<T: Clone> Clone for Multiples<T> {
+    fn clone(&self) -> Self {
+        let mut classes = self.iter();
+        self.init_like(|_| classes.next().unwrap().1.clone())
+    }
+}
+
+/// Round up to the closest multiple of `multiple`, a power of two, greater than or equal to `n`.
+#[inline]
+fn round_up(n: usize, multiple: usize) -> usize {
+    (n + (multiple - 1)) & !(multiple - 1)
+}
+
+impl<T> Multiples<T> {
+    /// Create and initialize `n_classes` classes of sizes that are multiples of `multiple`,
+    /// starting from `start` rounded up to a multiple. `multiple` must be a power of two of at
+    /// least 8.
+    fn init_multiple<F: FnMut(usize) -> T>(
+        start: usize,
+        n_classes: usize,
+        multiple: usize,
+        mut f: F,
+    ) -> (F, Self) {
+        alloc_debug_assert!(n_classes >= 1);
+        alloc_assert!(
+            multiple.is_power_of_two() && multiple >= 8,
+            "multiple {} is not a power of two of at least 8",
+            multiple
+        );
+        let starting_size = round_up(start, multiple);
+        let res = Multiples {
+            starting_size: starting_size,
+            max_size: n_classes * multiple + starting_size - multiple,
+            multiple: multiple,
+            classes: TypedArray::new(n_classes),
+        };
+        let mut cur_size = res.starting_size;
+        for p in res.classes.iter() {
+            unsafe {
+                ptr::write(p, f(cur_size));
+            }
+            cur_size += multiple;
+        }
+        alloc_debug_assert_eq!(res.max_size, cur_size - multiple);
+        (f, res)
+    }
+}
+
+impl<T> AllocMap<T> for Multiples<T> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+        Self::init_multiple(start, n_classes, MULTIPLE, f)
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        Multiples::init_multiple(self.starting_size, self.classes.len(), self.multiple, f).1
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        let class = round_up(n, self.multiple);
+        alloc_debug_assert!(class <= self.max_size, "size {} above the classes", n);
+        alloc_debug_assert!(class >= self.starting_size, "size {} below the classes", n);
+        // the multiple is a power of two, so this divides by it
+        self.classes.get(
+            (class - self.starting_size) >> self.multiple.trailing_zeros(),
+        )
+    }
+
+    fn try_get_raw(&self, n: usize) -> Option<*mut T> {
+        if n > self.max_size {
+            return None;
+        }
+        Some(unsafe { self.get_raw(cmp::max(n, self.starting_size)) })
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.max_size
+    }
+
+    fn iter(&self) -> Iter<T> {
+        Iter::new(RunKeys::Multiples(self.starting_size, self.multiple), &self.classes)
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for class in self.classes.iter() {
+            f(unsafe { &mut *class })
+        }
+    }
+}
+
+/// Size classes that are just the powers of two.
+///
+/// This is useful mostly for testing purposes: it is a very simple implementation, but it can also
+/// be rather wasteful.
+pub(crate) struct PowersOfTwo<T> {
+    starting_size: usize,
+    max_size: usize,
+    pub classes: TypedArray<T>,
+}
+
+impl<T: Clone> Clone for PowersOfTwo<T> {
+    fn clone(&self) -> Self {
+        let mut classes = self.iter();
+        self.init_like(|_| classes.next().unwrap().1.clone())
+    }
+}
+
+impl<T> PowersOfTwo<T> {
+    fn new(start_from: usize, n_classes: usize) -> PowersOfTwo<T> {
+        PowersOfTwo {
+            starting_size: start_from.next_power_of_two(),
+            max_size: 0, // currently uninitialized
+            classes: TypedArray::new(n_classes),
+        }
+    }
+}
+
+impl<T> AllocMap<T> for PowersOfTwo<T> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(Self::Key) -> T>(
+        start: usize,
+        n_classes: usize,
+        mut f: F,
+    ) -> (F, Self) {
+        let mut res = Self::new(start, n_classes);
+        let mut cur_size = res.starting_size;
+        unsafe {
+            for item in res.classes.iter() {
+                ptr::write(item, f(cur_size));
+                cur_size *= 2;
+            }
+        }
+        res.max_size = cur_size / 2;
+        (f, res)
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        PowersOfTwo::init(self.starting_size, self.classes.len(), f)
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, k: usize) -> *mut T {
+        alloc_debug_assert!(k <= self.max_size, "size {} above the classes", k);
+        // the logarithm below would underflow
+        alloc_debug_assert!(
+            k.next_power_of_two() >= self.starting_size,
+            "size {} below the classes",
+            k
+        );
+        let log = (k.next_power_of_two().trailing_zeros() -
+            self.starting_size.trailing_zeros()) as usize;
+        alloc_debug_assert!(
+            log < self.classes.len(),
+            "log={} len={}",
+            log,
+            self.classes.len()
+        );
+        self.classes.get(log)
+    }
+
+    fn try_get_raw(&self, k: usize) -> Option<*mut T> {
+        if k > self.max_size {
+            return None;
+        }
+        Some(unsafe { self.get_raw(cmp::max(k, self.starting_size)) })
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.max_size
+    }
+
+    fn iter(&self) -> Iter<T> {
+        Iter::new(RunKeys::PowersOfTwo(self.starting_size), &self.classes)
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for class in self.classes.iter() {
+            f(unsafe { &mut *class })
+        }
+    }
+
+    /// The classes are moved to a new array one longer than the old one, which is unmapped.
+    fn append_class(&mut self, class: T) -> Result<(), T> {
+        let n_classes = self.classes.len();
+        let classes = TypedArray::new(n_classes + 1);
+        unsafe {
+            ptr::copy_nonoverlapping(self.classes.get(0), classes.get(0), n_classes);
+            ptr::write(classes.get(n_classes), class);
+            self.classes.destroy();
+        }
+        self.classes = classes;
+        self.max_size *= 2;
+        Ok(())
+    }
+}
+/// The granularity of `Geometric` classes of at least this size, and of the table their sizes are
+/// looked up in.
+const GEOMETRIC_GRANULE: usize = 4 << 10;
+
+/// The ratio by which `Geometric` classes grow if none is given.
+const GEOMETRIC_RATIO: f64 = 1.25;
+
+/// The size of the `Geometric` class after one of `size` bytes, or `usize::max_value()` if it
+/// would overflow.
+fn next_geometric(size: usize, ratio: f64) -> usize {
+    if size < GEOMETRIC_GRANULE {
+        return size * 2;
+    }
+    let grown = size as f64 * ratio;
+    if grown >= (usize::max_value() / 2) as f64 {
+        return usize::max_value();
+    }
+    // rounding may lose the growth of small ratios, but every class is at least a granule larger
+    let grown = cmp::max(grown as usize, size + 1);
+    (grown + (GEOMETRIC_GRANULE - 1)) & !(GEOMETRIC_GRANULE - 1)
+}
+
+/// The sizes of a run of `Geometric` classes and the table that sizes are looked up in, which are
+/// shared by the maps of an allocator's clones.
+struct GeometricTable {
+    starting_size: usize,
+    /// The size of each class, in ascending order.
+    sizes: OwnedArray<usize>,
+    /// The index of the smallest class of at least `i * GEOMETRIC_GRANULE` bytes at index `i`.
+    granules: OwnedArray<u32>,
+}
+
+impl GeometricTable {
+    fn new(start: usize, n_classes: usize, ratio: f64) -> GeometricTable {
+        alloc_debug_assert!(n_classes >= 1);
+        let starting_size = start.next_power_of_two();
+        let sizes = OwnedArray::new(n_classes);
+        let mut size = starting_size;
+        unsafe {
+            for i in 0..n_classes {
+                ptr::write(sizes.get(i), size);
+                size = next_geometric(size, ratio);
+            }
+            let max_size = *sizes.get(n_classes - 1);
+            let granules = OwnedArray::new(max_size / GEOMETRIC_GRANULE + 1);
+            let mut class = 0;
+            for i in 0..granules.len() {
+                while *sizes.get(class) < i * GEOMETRIC_GRANULE {
+                    class += 1;
+                }
+                ptr::write(granules.get(i), class as u32);
+            }
+            GeometricTable {
+                starting_size: starting_size,
+                sizes: sizes,
+                granules: granules,
+            }
+        }
+    }
+
+    /// The largest class of a `GeometricTable` created with the same arguments, or
+    /// `usize::max_value()` if it would overflow.
+    fn max_size_for(start: usize, n_classes: usize, ratio: f64) -> usize {
+        (1..n_classes).fold(start.next_power_of_two(), |size, _| next_geometric(size, ratio))
+    }
+}
+
+/// Size classes that grow by a constant ratio.
+///
+/// Classes below `GEOMETRIC_GRANULE` are powers of two, as in `PowersOfTwo`. From there on, each
+/// class is `ratio` times the size of the one before it, rounded up to a multiple of the granule.
+/// This wastes less memory than doubling: with a ratio of 1.25, objects of uniformly distributed
+/// sizes between 64KiB and 256KiB waste 11% of their classes rather than 25%.
+///
+/// The class of sizes above the granule is found by indexing a table by the number of granules
+/// the size spans, so lookups do not need floating point. Objects of classes that are not powers
+/// of two are only aligned to 16 bytes (see `compute_metadata`).
+pub(crate) struct Geometric<T> {
+    table: Arc<GeometricTable>,
+    max_size: usize,
+    pub classes: TypedArray<T>,
+}
+
+impl<T> Geometric<T> {
+    /// Create and initialize `n_classes` classes starting from `start` and growing by `ratio`,
+    /// handing back ownership of the constructor.
+    fn init_ratio<F: FnMut(usize) -> T>(
+        start: usize,
+        n_classes: usize,
+        ratio: f64,
+        f: F,
+    ) -> (F, Self) {
+        Geometric::with_table(Arc::new(GeometricTable::new(start, n_classes, ratio)), f)
+    }
+
+    fn with_table<F: FnMut(usize) -> T>(table: Arc<GeometricTable>, mut f: F) -> (F, Self) {
+        let n_classes = table.sizes.len();
+        let classes = TypedArray::new(n_classes);
+        unsafe {
+            for (i, class) in classes.iter().enumerate() {
+                ptr::write(class, f(*table.sizes.get(i)));
+            }
+            let res = Geometric {
+                max_size: *table.sizes.get(n_classes - 1),
+                table: table,
+                classes: classes,
+            };
+            (f, res)
+        }
+    }
+}
+
+impl<T> AllocMap<T> for Geometric<T> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+        Geometric::init_ratio(start, n_classes, GEOMETRIC_RATIO, f)
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        Geometric::with_table(self.table.clone(), f).1
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        alloc_debug_assert!(n <= self.max_size, "size {} above the classes", n);
+        let class = if n <= GEOMETRIC_GRANULE {
+            // the logarithm below would underflow
+            alloc_debug_assert!(
+                n.next_power_of_two() >= self.table.starting_size,
+                "size {} below the classes",
+                n
+            );
+            (n.next_power_of_two().trailing_zeros() -
+                self.table.starting_size.trailing_zeros()) as usize
+        } else {
+            *self.table
+                .granules
+                .get((n + (GEOMETRIC_GRANULE - 1)) / GEOMETRIC_GRANULE) as usize
+        };
+        alloc_debug_assert!(
+            class < self.classes.len(),
+            "class={} len={}",
+            class,
+            self.classes.len()
+        );
+        self.classes.get(class)
+    }
+
+    fn try_get_raw(&self, n: usize) -> Option<*mut T> {
+        if n > self.max_size {
+            return None;
+        }
+        Some(unsafe { self.get_raw(cmp::max(n, self.table.starting_size)) })
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.max_size
+    }
+
+    fn iter(&self) -> Iter<T> {
+        Iter::new(RunKeys::Table(&self.table.sizes), &self.classes)
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for class in self.classes.iter() {
+            f(unsafe { &mut *class })
+        }
+    }
+}
+
+/// The granularity of the table that `CustomSizeClasses` looks up small sizes in.
+const CUSTOM_GRANULE: usize = 8;
+
+/// The lookup tables of a `CustomSizeClasses`, which are shared by the maps of an allocator's
+/// clones.
+struct ClassTable {
+    /// The size of each class, in ascending order.
+    sizes: OwnedArray<usize>,
+    /// The index of the class of every size up to `fine_max`, indexed by the size divided by
+    /// `CUSTOM_GRANULE` and rounded up.
+    fine: OwnedArray<u32>,
+    fine_max: usize,
+    /// The index of the smallest class of at least `1 << i` bytes at index `i`. Every class
+    /// larger than `fine_max` is a power of two, so this finds the class of larger sizes.
+    coarse: [u32; 64],
+}
+
+impl ClassTable {
+    fn new(sizes: &[usize]) -> ClassTable {
+        alloc_debug_assert!(!sizes.is_empty());
+        alloc_debug_assert!(sizes.windows(2).all(|w| w[0] < w[1]), "unsorted classes");
+        let max = sizes[sizes.len() - 1];
+        let fine_max = cmp::min(max, ELFMALLOC_SMALL_CUTOFF);
+        alloc_debug_assert!(sizes.iter().all(|&size| size <= fine_max || size.is_power_of_two()));
+        let table_sizes = OwnedArray::new(sizes.len());
+        let fine = OwnedArray::new(fine_max / CUSTOM_GRANULE + 1);
+        let mut coarse = [0; 64];
+        unsafe {
+            for (i, &size) in sizes.iter().enumerate() {
+                ptr::write(table_sizes.get(i), size);
+            }
+            let mut class = 0;
+            for i in 0..fine.len() {
+                while sizes[class] < i * CUSTOM_GRANULE {
+                    class += 1;
+                }
+                ptr::write(fine.get(i), class as u32);
+            }
+        }
+        let mut class = 0;
+        for (log, slot) in coarse.iter_mut().enumerate().take(mem::size_of::<usize>() * 8) {
+            while class + 1 < sizes.len() && sizes[class] < 1 << log {
+                class += 1;
+            }
+            *slot = class as u32;
+        }
+        ClassTable {
+            sizes: table_sizes,
+            fine: fine,
+            fine_max: fine_max,
+            coarse: coarse,
+        }
+    }
+
+    /// The index of the class of `n` bytes, which must be at most the largest class.
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn class_of(&self, n: usize) -> usize {
+        if likely(n <= self.fine_max) {
+            *self.fine.get((n + CUSTOM_GRANULE - 1) / CUSTOM_GRANULE) as usize
+        } else {
+            let log = mem::size_of::<usize>() * 8 - (n - 1).leading_zeros() as usize;
+            self.coarse[log] as usize
+        }
+    }
+}
+
+/// Size classes listed by the user with `DynamicAllocatorBuilder::size_classes`.
+///
+/// The class of a size is found with a single load from a table, rather than by rounding the
+/// size as `Multiples` and `PowersOfTwo` do: sizes up to `ELFMALLOC_SMALL_CUTOFF` index a table
+/// with an entry for every multiple of 8 bytes, and larger sizes, whose classes are all powers of
+/// two, index a table by their logarithm.
+pub(crate) struct CustomSizeClasses<T> {
+    table: Arc<ClassTable>,
+    max_size: usize,
+    classes: TypedArray<T>,
+}
+
+impl<T> CustomSizeClasses<T> {
+    /// Create a map with a class for each of `sizes`, which must be in ascending order, and of
+    /// which every size above `ELFMALLOC_SMALL_CUTOFF` must be a power of two.
+    fn new<F: FnMut(usize) -> T>(sizes: &[usize], f: F) -> Self {
+        CustomSizeClasses::with_table(Arc::new(ClassTable::new(sizes)), f)
+    }
+
+    fn with_table<F: FnMut(usize) -> T>(table: Arc<ClassTable>, mut f: F) -> Self {
+        let n_classes = table.sizes.len();
+        let classes = TypedArray::new(n_classes);
+        unsafe {
+            for (i, class) in classes.iter().enumerate() {
+                ptr::write(class, f(*table.sizes.get(i)));
+            }
+            CustomSizeClasses {
+                max_size: *table.sizes.get(n_classes - 1),
+                table: table,
+                classes: classes,
+            }
+        }
+    }
+}
+
+impl<T> AllocMap<T> for CustomSizeClasses<T> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(_start: usize, _n_classes: usize, _f: F) -> (F, Self) {
+        alloc_panic!("custom size classes are constructed from a list of sizes")
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        CustomSizeClasses::with_table(self.table.clone(), f)
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        alloc_debug_assert!(n <= self.max_size, "size {} above the classes", n);
+        self.classes.get(self.table.class_of(n))
+    }
+
+    fn try_get_raw(&self, n: usize) -> Option<*mut T> {
+        if n > self.max_size {
+            return None;
+        }
+        Some(unsafe { self.get_raw(n) })
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.max_size
+    }
+
+    fn iter(&self) -> Iter<T> {
+        Iter::new(RunKeys::Table(&self.table.sizes), &self.classes)
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for class in self.classes.iter() {
+            f(unsafe { &mut *class })
+        }
+    }
+}
+
+/// The size classes an allocator is constructed with.
+#[derive(Copy, Clone, Debug)]
+enum ClassSpec<'a> {
+    /// The classes of `TieredSizeClasses` initialized with `start_from`, `n_classes`, `multiple`
+    /// and `small_cutoff`, with the `TINY_CLASSES` if `tiny_classes` is set.
+    Tiered {
+        start_from: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+        tiny_classes: bool,
+    },
+    /// The same classes, except that the medium classes grow by `ratio`; see `Geometric`.
+    Geometric {
+        start_from: usize,
+        n_classes: usize,
+        multiple: usize,
+        small_cutoff: usize,
+        tiny_classes: bool,
+        ratio: f64,
+    },
+    /// The listed classes; see `CustomSizeClasses`.
+    Custom(&'a [usize]),
+}
+
+impl<'a> ClassSpec<'a> {
+    /// The number of classes, which is at most one more than `n_classes` for `Tiered`, along
+    /// with any `TINY_CLASSES`: there may be a class for word-sized objects on top of the others.
+    fn max_classes(&self) -> usize {
+        match *self {
+            ClassSpec::Tiered { n_classes, tiny_classes, .. } |
+            ClassSpec::Geometric { n_classes, tiny_classes, .. } => {
+                n_classes + 1 + if tiny_classes { TINY_CLASSES.len() } else { 0 }
+            }
+            ClassSpec::Custom(sizes) => sizes.len(),
+        }
+    }
+
+    /// The size of the largest class.
+    fn max_class_size(&self) -> usize {
+        match *self {
+            ClassSpec::Tiered { start_from, n_classes, multiple, small_cutoff, .. } => {
+                TieredSizeClasses::<()>::max_key_for(start_from, n_classes, multiple, small_cutoff)
+            }
+            ClassSpec::Geometric { start_from, n_classes, multiple, small_cutoff, ratio, .. } => {
+                let (start, n_medium) = TieredSizeClasses::<()>::medium_classes(
+                    start_from,
+                    n_classes,
+                    multiple,
+                    small_cutoff,
+                );
+                GeometricTable::max_size_for(start, n_medium, ratio)
+            }
+            ClassSpec::Custom(sizes) => sizes[sizes.len() - 1],
+        }
+    }
+
+    /// The number of classes that `DynamicAllocator::extend_classes` may append: the powers of
+    /// two after the largest class, up to `ELFMALLOC_MAX_CLASS_SIZE`. Only `Tiered` classes can be
+    /// extended.
+    fn max_appended_classes(&self) -> usize {
+        match *self {
+            ClassSpec::Tiered { .. } => {
+                let max_log = self.max_class_size().trailing_zeros();
+                (ELFMALLOC_MAX_CLASS_SIZE.trailing_zeros().saturating_sub(max_log)) as usize
+            }
+            ClassSpec::Geometric { .. } | ClassSpec::Custom(_) => 0,
+        }
+    }
+
+    /// Whether every class at or above the small cutoff has to be a power of two. `Geometric`
+    /// classes need not be, at the cost of a slower `get_page_size`.
+    fn requires_aligned_medium(&self) -> bool {
+        match *self {
+            ClassSpec::Geometric { .. } => false,
+            ClassSpec::Tiered { .. } | ClassSpec::Custom(_) => true,
+        }
+    }
+}
+
+/// The size classes of a `DynamicAllocator`: the default `TieredSizeClasses` (optionally with
+/// `Geometric` medium classes), or a list of classes supplied by the user.
+///
+/// `init` and `init_conserve` create `TieredSizeClasses`; the others are created with `new`.
+pub(crate) enum SizeClasses<T> {
+    Tiered(TieredSizeClasses<T>),
+    Geometric(TieredSizeClasses<T, Geometric<T>>),
+    Custom(CustomSizeClasses<T>),
+}
+
+impl<T> SizeClasses<T> {
+    fn new<F: FnMut(usize) -> T>(spec: ClassSpec, f: F) -> Self {
+        match spec {
+            ClassSpec::Tiered { start_from, n_classes, multiple, small_cutoff, tiny_classes } => {
+                SizeClasses::Tiered(TieredSizeClasses::init_with(
+                    start_from,
+                    n_classes,
+                    multiple,
+                    small_cutoff,
+                    tiny_classes,
+                    f,
+                    PowersOfTwo::init_conserve,
+                ).1)
+            }
+            ClassSpec::Geometric {
+                start_from,
+                n_classes,
+                multiple,
+                small_cutoff,
+                tiny_classes,
+                ratio,
+            } => {
+                SizeClasses::Geometric(TieredSizeClasses::init_geometric(
+                    start_from,
+                    n_classes,
+                    multiple,
+                    small_cutoff,
+                    tiny_classes,
+                    ratio,
+                    f,
+                ))
+            }
+            ClassSpec::Custom(sizes) => SizeClasses::Custom(CustomSizeClasses::new(sizes, f)),
+        }
+    }
+
+    /// Drop the classes and unmap the arrays holding them.
+    ///
+    /// `self` must not be used afterwards, other than to drop it.
+    unsafe fn destroy(&mut self) {
+        self.foreach_mut(|x| ptr::drop_in_place(x));
+        match *self {
+            SizeClasses::Tiered(ref mut classes) => {
+                classes.medium_objs.classes.destroy();
+                classes.destroy_small();
+            }
+            SizeClasses::Geometric(ref mut classes) => {
+                classes.medium_objs.classes.destroy();
+                classes.destroy_small();
+            }
+            SizeClasses::Custom(ref classes) => classes.classes.destroy(),
+        }
+    }
+}
+
+impl<T> AllocMap<T> for SizeClasses<T> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+        let (f, classes) = TieredSizeClasses::init_conserve(start, n_classes, f);
+        (f, SizeClasses::Tiered(classes))
+    }
+
+    fn init_like<F: FnMut(usize) -> T>(&self, f: F) -> Self {
+        match *self {
+            SizeClasses::Tiered(ref classes) => SizeClasses::Tiered(classes.init_like(f)),
+            SizeClasses::Geometric(ref classes) => SizeClasses::Geometric(classes.init_like(f)),
+            SizeClasses::Custom(ref classes) => SizeClasses::Custom(classes.init_like(f)),
+        }
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        match *self {
+            SizeClasses::Tiered(ref classes) => classes.get_raw(n),
+            SizeClasses::Geometric(ref classes) => classes.get_raw(n),
+            SizeClasses::Custom(ref classes) => classes.get_raw(n),
+        }
+    }
+
+    fn try_get_raw(&self, n: usize) -> Option<*mut T> {
+        match *self {
+            SizeClasses::Tiered(ref classes) => classes.try_get_raw(n),
+            SizeClasses::Geometric(ref classes) => classes.try_get_raw(n),
+            SizeClasses::Custom(ref classes) => classes.try_get_raw(n),
+        }
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        match *self {
+            SizeClasses::Tiered(ref classes) => classes.max_key(),
+            SizeClasses::Geometric(ref classes) => classes.max_key(),
+            SizeClasses::Custom(ref classes) => classes.max_key(),
+        }
+    }
+
+    fn iter(&self) -> Iter<T> {
+        match *self {
+            SizeClasses::Tiered(ref classes) => classes.iter(),
+            SizeClasses::Geometric(ref classes) => classes.iter(),
+            SizeClasses::Custom(ref classes) => classes.iter(),
+        }
+    }
+
+    fn foreach_mut<F: FnMut(&mut T)>(&mut self, f: F) {
+        match *self {
+            SizeClasses::Tiered(ref mut classes) => classes.foreach_mut(f),
+            SizeClasses::Geometric(ref mut classes) => classes.foreach_mut(f),
+            SizeClasses::Custom(ref mut classes) => classes.foreach_mut(f),
+        }
+    }
+
+    fn append_class(&mut self, class: T) -> Result<(), T> {
+        match *self {
+            SizeClasses::Tiered(ref mut classes) => classes.append_class(class),
+            SizeClasses::Geometric(_) | SizeClasses::Custom(_) => Err(class),
+        }
+    }
+}
+
+/// A Dynamic memory allocator, instantiated with sane defaults for various `ElfMalloc` type
+/// parameters.
+///
+/// Pages for size classes are mapped with `mmap` unless the allocator is constructed with
+/// `with_source`, which takes them from another `MemorySource` `M`.
+///
+/// Dropping the last clone of an allocator unmaps its pages and metadata, so none of the objects
+/// it allocated from size classes may be used afterwards.
+#[derive(Clone)]
+pub struct DynamicAllocator<M: MemorySource = MmapSource>(
+    ElfMalloc<PageAlloc<M>, SizeClasses<ObjectAlloc<PageAlloc<M>>>>
+);
+
+unsafe impl<M: MemorySource + Send> Send for DynamicAllocator<M> {}
+
+impl<M: MemorySource> Drop for DynamicAllocator<M> {
+    fn drop(&mut self) {
+        unsafe { self.0.destroy_classes() }
+    }
+}
+
+impl DynamicAllocator {
+    pub fn new() -> Self {
+        DynamicAllocator(ElfMalloc::new())
+    }
+}
+
+impl<M: MemorySource> DynamicAllocator<M> {
+    /// Create an allocator whose size classes get their pages from `small_source` (for objects
+    /// below the small cutoff) and `large_source`, using the default configuration.
+    ///
+    /// See `DynamicAllocatorBuilder::build_with_source`.
+    pub fn with_source(small_source: M, large_source: M) -> Self {
+        DynamicAllocatorBuilder::default()
+            .build_with_source(small_source, large_source)
+            .expect("default size classes should be valid")
+    }
+    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        match self.try_alloc(size) {
+            Ok(item) => item,
+            Err(Exhausted) => alloc_panic!("out of memory!"),
+        }
+    }
+    /// Allocate `size` bytes, returning an error rather than aborting if no memory can be mapped.
+    ///
+    /// If no memory can be mapped and a handler was set with `set_oom_handler`, the handler
+    /// decides what happens next. This includes the first allocation of a given size class from
+    /// a handle, which fails if the handle's cache for the class cannot get its first slag.
+    pub unsafe fn try_alloc(&mut self, size: usize) -> Result<*mut u8, Exhausted> {
+        let item = self.0.alloc(size);
+        if likely(!item.is_null()) {
+            return Ok(item);
+        }
+        let handler = match self.0.oom_handler {
+            Some(handler) => handler,
+            None => return Err(Exhausted),
+        };
+        let ctx = OomContext {
+            size: size,
+            mapped: self.memory_used(),
+        };
+        match handler(ctx) {
+            OomAction::Retry => {
+                // A second failure is not passed to the handler, so that a handler that always
+                // retries cannot loop forever.
+                let item = self.0.alloc(size);
+                if item.is_null() {
+                    Err(Exhausted)
+                } else {
+                    Ok(item)
+                }
+            }
+            OomAction::Fail => Err(Exhausted),
+            OomAction::Abort => alloc_panic!("out of memory allocating {} bytes!", size),
+        }
+    }
+    /// Call `handler` whenever an allocation from `self` fails because no memory can be mapped.
+    ///
+    /// The handler is called with the size of the failed request, and returns what the allocator
+    /// should do: `OomAction::Retry` retries the allocation once, `OomAction::Fail` fails it, and
+    /// `OomAction::Abort` aborts the process. A failed allocation from `alloc` aborts whatever
+    /// the handler returns, unless a retry succeeds.
+    ///
+    /// Clones of `self` made after this call use the same handler; existing clones are not
+    /// affected.
+    pub fn set_oom_handler(&mut self, handler: fn(OomContext) -> OomAction) {
+        self.0.oom_handler = Some(handler);
+    }
+    /// Allocate `size` bytes of zeroed memory.
+    ///
+    /// Large allocations and objects that have never been allocated before already read as
+    /// zeros, so only recycled objects are written to.
+    pub unsafe fn alloc_zeroed(&mut self, size: usize) -> *mut u8 {
+        self.0.alloc_zeroed(size)
+    }
+    /// Allocate `size` bytes, also returning the number of bytes of the object that may be used.
+    ///
+    /// The returned size is the one `usable_size` reports for the object: the size of its class,
+    /// or the rest of the mapped region for large objects. Unlike calling `usable_size` after
+    /// `alloc`, this does not need to look up the object's slag.
+    pub unsafe fn alloc_excess(&mut self, size: usize) -> (*mut u8, usize) {
+        let (item, usable) = self.0.alloc_excess(size);
+        if item.is_null() {
+            alloc_panic!("out of memory!");
+        }
+        (item, usable)
+    }
+    pub unsafe fn free(&mut self, item: *mut u8) {
+        self.0.free(item)
+    }
+
+    /// Limit the memory used by `self` and all of its clones to `bytes`.
+    ///
+    /// A page counts as used from when it is first allocated from until it is uncommitted (for
+    /// example by `trim`), and a large allocation counts its whole mapped region until it is
+    /// freed. Allocations that would exceed the limit fail: `try_alloc` returns an error, and
+    /// `alloc` aborts. This includes the first allocation of a size class from a new handle, if
+    /// its cache cannot be set up within the limit.
+    ///
+    /// Memory that is already in use is not released when the new limit is lower.
+    pub fn set_memory_limit(&self, bytes: usize) {
+        self.0.limit.set_limit(bytes)
+    }
+
+    /// The number of bytes used by `self` and all of its clones, as counted by
+    /// `set_memory_limit`.
+    pub fn memory_used(&self) -> usize {
+        self.0.limit.used()
+    }
+
+    /// Take a snapshot of the memory held by `self` and all of its clones.
+    ///
+    /// The counts are kept up to date as objects and pages are allocated, so this takes time
+    /// proportional to the number of size classes rather than to the number of objects. Pages
+    /// are counted from when they are first mapped, including those that `trim` has since
+    /// uncommitted.
+    pub fn stats(&self) -> ElfStats {
+        self.0.stats()
+    }
+
+    /// Take a snapshot of the counts of each size class, in increasing order of size, to find
+    /// the classes that hold the most memory.
+    ///
+    /// Like `stats`, this takes time proportional to the number of size classes. Classes that
+    /// `self` has not used yet are reported as not `initialized`, and are left that way.
+    pub fn class_stats(&self) -> Vec<ClassStats> {
+        self.0.class_stats()
+    }
+
+    /// Get the sizes requested from `self` and from the clones of `self` that have been dropped,
+    /// by size class. Clones that are still alive add their counts when they are dropped.
+    ///
+    /// Only available with the `profiling` feature, which counts every allocation, including
+    /// the one made by a `realloc` that moves its object.
+    #[cfg(feature = "profiling")]
+    pub fn size_histogram(&self) -> SizeHistogram {
+        self.0.profile.histogram()
+    }
+
+    /// Return the memory of cached free pages to the operating system, returning the number of
+    /// bytes released.
+    ///
+    /// Pages are cached once all of their objects have been freed. All but a few of the cached
+    /// pages are uncommitted; they stay mapped, and are reused for later allocations. Objects
+    /// cached by handles, and pages that still hold live objects, are not affected, except that
+    /// this counts a period for this handle's adaptive caches, as `decay_caches` does. This may
+    /// be called while clones of `self` are in use on other threads.
+    ///
+    /// The regions of freed large allocations, which are cached (already released as
+    /// `global::set_large_free_policy` asks) so that allocations of the same size can reuse them,
+    /// are unmapped. That cache is shared by all allocators, so this also unmaps the regions
+    /// freed by unrelated allocators.
+    pub fn trim(&mut self) -> usize {
+        self.0.trim()
+    }
+
+    /// Return the memory this handle holds for the size class of `size` to the class, so that
+    /// its empty pages can be used by other classes.
+    ///
+    /// This drops the handle's cache for the class, whose objects are returned to their slags,
+    /// and gives up the slag the handle is allocating from. Slags with no allocated objects are
+    /// returned to the page allocator shared with other classes, where `trim` can uncommit them.
+    /// The cache is set up again the next time the class is used. Objects of the class that are
+    /// still allocated are not affected.
+    ///
+    /// This happens automatically when a free leaves the class with no live objects while this
+    /// handle caches at least a slag's worth of them (unless the `no_stats` feature is enabled).
+    /// Only the handle calling this is affected: clones keep their own caches.
+    pub fn reclaim_class(&mut self, size: usize) {
+        unsafe { self.0.reclaim_class(size) }
+    }
+
+    /// Start a new generation of this allocator and its clones for `reclaim_cold`, returning its
+    /// number. A new allocator starts in generation 0.
+    ///
+    /// This is meant to be called periodically, for example by a monitoring thread holding a
+    /// clone of the allocator, so that how long a class has gone unused can be told in
+    /// generations.
+    pub fn advance_generation(&self) -> usize {
+        self.0.generation.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    /// Return the memory this handle holds for the size classes it has not allocated from in
+    /// the last `older_than` generations (see `advance_generation`) to the classes, returning
+    /// the number of classes whose memory was returned.
+    ///
+    /// This is `reclaim_class` for every cold class: a class used while a program starts up and
+    /// never again otherwise keeps the objects and the slag of each handle that used it, which no
+    /// other class can use. Whether a class is cold is judged by the number of objects allocated
+    /// from this handle's cache for it, as seen by the previous call, so a class this handle has
+    /// allocated from since then is never cold, whatever `older_than` is. A class is only judged
+    /// cold once an earlier call has seen it, so this is meant to be called periodically, for
+    /// example before `trim` so that the pages returned to the page allocator are uncommitted.
+    ///
+    /// Only this handle's caches are dropped, which does not wait for any other handle: classes
+    /// that are hot on other threads are not slowed down. The caches of classes frozen by
+    /// `freeze_class` are kept.
+    pub fn reclaim_cold(&mut self, older_than: usize) -> usize {
+        unsafe { self.0.reclaim_cold(older_than) }
+    }
+
+    /// Does `item` point into memory mapped by this allocator or one of its clones?
+    ///
+    /// This is true for pointers into the pages of the allocator's size classes, whether or not
+    /// they currently hold an allocated object, and into the large allocations made through the
+    /// allocator and not yet freed through it. Only the addresses of the mapped regions are
+    /// compared, so `item` may be any pointer, including one from another allocator or into an
+    /// unmapped page. Mapped regions are scanned one after another, so this takes time
+    /// proportional to the allocator's memory use.
+    pub unsafe fn contains(&self, item: *mut u8) -> bool {
+        self.0.contains(item)
+    }
+
+    /// Return the objects cached by this handle to the structures shared with its clones.
+    ///
+    /// Cached objects are otherwise only returned when the cache overflows or the handle is
+    /// dropped, so a handle that goes idle keeps memory that no other handle can use. Objects are
+    /// returned to their slags (or, with the `magazine_layer` feature, whole magazines to the
+    /// depot), after which empty pages can be reused by any clone or released with `trim`. The
+    /// handle can still be used afterwards. This is cheap if the caches are already empty.
+    pub fn flush_thread_cache(&mut self) {
+        unsafe { self.0.flush_caches() }
+    }
+
+    /// Count a period of this handle's use for the adaptive caches set up with
+    /// `DynamicAllocatorBuilder::adaptive_magazines`.
+    ///
+    /// A cache whose handle has not missed it in the last `frontends::QUIET_PERIODS` calls
+    /// halves its capacity, down to the minimum, and returns the objects cached beyond it, so the
+    /// memory a burst left in the cache goes back to the class once the burst is over. This is
+    /// meant to be called periodically by the thread using the handle, for example between
+    /// requests of a server; `trim` also does this. Caches with a fixed capacity are left alone,
+    /// as are those of classes frozen by `freeze_class`.
+    pub fn decay_caches(&mut self) {
+        unsafe { self.0.decay_caches() }
+    }
+
+    /// Set up this handle's cache for every size class; see `warm_up_range`.
+    pub fn warm_up(&mut self) -> usize {
+        self.warm_up_range(0, usize::max_value())
+    }
+
+    /// Set up this handle's cache for each size class serving sizes from `min` to `max` bytes,
+    /// returning the number of caches set up. Caches that are already set up are left alone.
+    ///
+    /// A cache is otherwise set up by the first allocation from its class, which makes that
+    /// allocation much slower than the ones after it. Setting up a cache also takes a slag for
+    /// the handle to allocate from, mapping a page if the class has no partially full slag, so
+    /// warming up every class maps a page for each of them. Classes frozen by `freeze_class` are
+    /// skipped, as are classes whose caches cannot get a page. Only the handle calling this is
+    /// affected: clones set up caches of their own, and a cache dropped by `reclaim_class` is set
+    /// up again by the next allocation.
+    pub fn warm_up_range(&mut self, min: usize, max: usize) -> usize {
+        self.0.warm_up(min, max)
+    }
+
+    /// Prepare the size class for `size` for a burst of `count` allocations from this handle.
+    ///
+    /// Enough pages to hold `count` objects are taken from the memory source, and this handle's
+    /// cache for the class is set up and filled with as many of the objects as it can hold. The
+    /// rest of the burst is allocated from the reserved pages, so allocating `count` objects of
+    /// `size` bytes afterwards does not take any memory from the source. Reserved pages may also
+    /// be used by clones, and count against the memory limit once they are allocated from.
+    ///
+    /// Sizes larger than the largest size class, and classes frozen by `freeze_class`, have
+    /// nothing to reserve. An error is returned, and the cache is left alone, if the memory source
+    /// or the memory limit cannot provide the pages.
+    pub fn reserve_for_class(&mut self, size: usize, count: usize) -> Result<(), Exhausted> {
+        if unsafe { self.0.reserve_for_class(size, count) } {
+            Ok(())
+        } else {
+            Err(Exhausted)
+        }
+    }
+
+    /// Allocate objects of size `size` into `out`, returning the number of objects allocated.
+    ///
+    /// This is faster than calling `alloc` once per object, as the size class is only looked up
+    /// once. Running out of memory does not abort: the objects allocated so far are in
+    /// `out[..n]`, where `n` is the returned count, and `out[n..]` is left unchanged. Each object
+    /// is an ordinary allocation that can be freed individually or with `free_batch`.
+    pub unsafe fn alloc_batch(&mut self, size: usize, out: &mut [*mut u8]) -> usize {
+        self.0.alloc_batch(size, out)
+    }
+
+    /// Free every object in `items`, none of which may be null.
+    ///
+    /// This is faster than calling `free` once per object when many of the objects come from the
+    /// same slag: objects are grouped by the slag they belong to, and each slag's metadata and
+    /// size class are only looked up once per group. Large objects may be mixed in freely.
+    /// Otherwise, each object is freed just as `free` would, including the check for a large
+    /// object being freed twice.
+    pub unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        self.0.free_batch(items)
+    }
+
+    pub unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
+        self.0.realloc(item, new_size, mem::size_of::<usize>())
+    }
+
+    /// Get the size and alignment of the object `item`, which must have been allocated by `self`
+    /// (or one of its clones) and not yet freed.
+    ///
+    /// Unlike `global::get_layout`, this uses the pages of `self`, and so also works for
+    /// allocators built with a `DynamicAllocatorBuilder`.
+    pub unsafe fn layout_of(&self, item: *mut u8) -> (usize /* size */, usize /* alignment */) {
+        self.0.get_layout(item)
+    }
+
+    /// Get the number of bytes of `item` that may be used, which is at least the size it was
+    /// allocated with. `item` must have been allocated by `self` (or one of its clones) and not
+    /// yet freed.
+    pub unsafe fn usable_size(&self, item: *mut u8) -> usize {
+        self.layout_of(item).0
+    }
+
+    /// The size of the largest size class. Larger objects are mapped directly, and unmapped
+    /// when they are freed.
+    pub fn max_class_size(&self) -> usize {
+        self.0.max_size
+    }
+
+    /// Append power-of-two size classes until the largest class is at least `new_max_size`, so
+    /// that allocations up to that size are served from slags rather than mapped directly.
+    ///
+    /// Objects allocated before the call, including large allocations that the new classes would
+    /// now serve, are freed as usual. Existing clones of `self` keep working: each one picks up
+    /// the new classes the first time it allocates a size larger than its own largest class, or
+    /// frees an object of one of the new classes. Until then, its `max_class_size` and
+    /// `size_class_for` report the classes it had. Nothing is done if the largest class is
+    /// already large enough.
+    ///
+    /// Only the default size classes can be extended, and not those chosen with `medium_ratio`
+    /// or `size_classes`. Classes larger than half the large page size get pages of their own,
+    /// which are created by the first call that needs them, sized for the classes up to
+    /// `new_max_size`; larger classes cannot be appended later if they do not fit in those pages.
+    pub fn extend_classes(&mut self, new_max_size: usize) -> Result<(), ConfigError> {
+        self.0.extend_classes(new_max_size)
+    }
+
+    /// The capacity of the object that `alloc(size)` will return, without allocating it: the
+    /// size of the class that `size` is rounded up to, or `None` if `size` is larger than
+    /// `large_threshold` and gets a large allocation.
+    ///
+    /// This is what `usable_size` reports for the object. The only exception is an object
+    /// allocated while its class is frozen (see `freeze_class`), which is taken from a larger
+    /// class.
+    pub fn size_class_for(&self, size: usize) -> Option<usize> {
+        self.0.size_class_for(size)
+    }
+
+    /// The largest size that is allocated from a size class, which is `max_class_size`. Larger
+    /// allocations are mapped directly.
+    pub fn large_threshold(&self) -> usize {
+        self.0.max_size
+    }
+
+    /// Allocate `size` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// The object comes from the smallest size class that holds `size` bytes and is aligned to
+    /// `align`: classes that are powers of two are aligned to their size, and all others to 16
+    /// bytes. If no class qualifies, the object is aligned within a region of memory that is
+    /// mapped for it. Any alignment is supported this way, with only the allocation itself left
+    /// mapped.
+    pub unsafe fn alloc_aligned(&mut self, size: usize, align: usize) -> *mut u8 {
+        self.0.alloc_aligned(size, align)
+    }
+
+    pub unsafe fn aligned_realloc(
+        &mut self,
+        item: *mut u8,
+        new_size: usize,
+        new_alignment: usize,
+    ) -> *mut u8 {
+        self.0.realloc(item, new_size, new_alignment)
+    }
+
+    /// Get the number of bytes that may be used in objects allocated by
+    /// `alloc_aligned(size, align)`, which is at least `size`.
+    pub fn usable_size_aligned(&self, size: usize, align: usize) -> usize {
+        self.0.usable_size_aligned(size, align)
+    }
+}
+
+impl DynamicAllocator {
+    /// Get a handle on the class used to allocate objects of size `size` and alignment `align`,
+    /// along with the size and alignment of the objects it allocates.
+    ///
+    /// Class handles share their pages with `self`: objects allocated from a handle may be freed
+    /// using `self` (or any of its clones), and vice versa. `None` is returned if `align` is not a
+    /// power of two, or if rounding `size` up to the objects' size overflows.
+    ///
+    /// Size classes and large allocations are aligned to at most the small cutoff. Objects with a
+    /// larger alignment are allocated with the large-allocation path, over-allocating so
+    /// that they can be aligned within the allocated region.
+    ///
+    /// There is no class for zero-sized objects. If `size` is 0, the returned handle hands out
+    /// dangling pointers aligned to `align` without allocating any memory.
+    pub(crate) fn class_for(&self, size: usize, align: usize) -> Option<(ClassHandle, usize, usize)> {
+        if !align.is_power_of_two() {
+            return None;
+        }
+        if size == 0 {
+            return Some((ClassHandle::ZeroSized(align), 0, align));
+        }
+        let cutoff = self.0.small_cutoff;
+        if align > cutoff {
+            let size = match size.checked_add(align - 1) {
+                Some(size) => size & !(align - 1),
+                None => return None,
+            };
+            return Some((ClassHandle::Large(size, align), size, align));
+        }
+        // Object sizes are only guaranteed to be word-aligned, except for powers of two, which are
+        // aligned to their size. Objects of tiny classes are smaller than a word. Larger
+        // alignments get the class chosen by `aligned_class`, just as `alloc_aligned` does.
+        let word = mem::size_of::<usize>();
+        let (size, align) = if align <= word {
+            let size = cmp::max(size, align);
+            let object_size = self.0.size_class_for(size).unwrap_or(word);
+            (size, cmp::min(class_alignment(object_size), word))
+        } else {
+            match self.0.aligned_class(size, align) {
+                Some(class_size) => (class_size, class_alignment(class_size)),
+                None => {
+                    let size = match size.checked_add(align - 1) {
+                        Some(size) => size & !(align - 1),
+                        None => return None,
+                    };
+                    return Some((ClassHandle::Large(size, cutoff), size, cutoff));
+                }
+            }
+        };
+        if size > self.0.max_size {
+            return Some((ClassHandle::Large(size, cutoff), size, cutoff));
+        }
+        let class = unsafe { self.0.allocs.get(size).clone() };
+        Some((ClassHandle::Class(class), size, align))
+    }
+
+    /// Freeze the size class used for allocations of `size` bytes, optionally making its pages
+    /// read-only.
+    ///
+    /// This is meant for snapshotting the heap (e.g. by relying on copy-on-write after `fork`)
+    /// without stopping all allocation. While the class is frozen:
+    ///
+    /// - No objects are allocated from it: allocations that would normally use it (from any
+    ///   handle on this allocator) are served from the next power-of-two class that is not
+    ///   frozen, or from the large-allocation path if there is none. Because the overflow class
+    ///   is a power of two, alignment guarantees are preserved.
+    /// - Frees of its objects are queued, and are applied when the class is thawed.
+    /// - If `protect` is true, every page holding at least one of its live objects is read-only,
+    ///   and any write to such an object faults.
+    ///
+    /// Objects of the class that are cached by a handle stay in that cache; they are simply not
+    /// handed out until the class is thawed. The only guarantee made is that no memory in a
+    /// frozen class's pages is written by the allocator. In particular, with `protect` enabled,
+    /// handles holding objects of the class must not be dropped while it is frozen, as dropping a
+    /// handle returns its cached objects to their pages.
+    ///
+    /// Freezing is not synchronized with allocations and frees that are already in progress on
+    /// other threads: callers should make sure that no such operation on the class is running
+    /// while `freeze_class` is called.
+    ///
+    /// The class is thawed when the returned guard is dropped. This returns `None` if `size` is
+    /// larger than the largest size class, or if its class is already frozen. It requires the
+    /// `freeze` feature.
+    #[cfg(feature = "freeze")]
+    pub fn freeze_class(&self, size: usize, protect: bool) -> Option<FrozenClassGuard> {
+        let class = match self.0.allocs.try_get_raw(size) {
+            Some(class) => class,
+            None => return None,
+        };
+        unsafe {
+            let meta = class_metadata(&*class);
+            if !freeze::freeze(meta) {
+                return None;
+            }
+            let pages = match (*meta).ty {
+                AllocType::SmallSlag => &self.0.small_pages,
+                AllocType::HugeSlag => self.0.huge_pages.as_ref().expect("huge class without pages"),
+                _ => &self.0.large_pages,
+            };
+            let page_size = pages.backing_memory().page_size();
+            if protect {
+                pages.foreach_live_slag(meta, |slag| freeze::protect(meta, slag as *mut u8, page_size));
+            }
+            Some(FrozenClassGuard {
+                alloc: self.clone(),
+                meta: meta,
+                page_size: page_size,
+            })
+        }
+    }
+}
+
+/// A snapshot of the memory held by a `DynamicAllocator` and its clones, returned by
+/// `DynamicAllocator::stats`.
+///
+/// Each count is read separately while other handles may be allocating, so the fields need not be
+/// consistent with one another. If the `no_stats` feature is enabled, every field is 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ElfStats {
+    /// The bytes of objects of size classes that are allocated and not yet freed. Each object
+    /// counts as the size of its class.
+    pub live_bytes: usize,
+    /// The bytes of free objects of size classes that are held by handles (in their caches, or
+    /// in the slags they are allocating from) rather than returned to their slags.
+    pub cached_bytes: usize,
+    /// The bytes of pages mapped for classes below the small cutoff.
+    pub small_page_bytes: usize,
+    /// The bytes of pages mapped for classes from the small cutoff up.
+    pub large_page_bytes: usize,
+    /// The bytes of pages mapped for classes too large for the pages of `large_page_bytes`, each
+    /// of which holds a single object.
+    pub huge_page_bytes: usize,
+    /// The bytes mapped for large allocations, which are too large for any size class, that have
+    /// not been freed.
+    pub large_bytes: usize,
+    /// The counts of the large allocations of every allocator in the process.
+    pub large: LargeStats,
+}
+
+/// Counts of the large allocations made by every allocator in the process, including the global
+/// heap, as reported in `ElfStats::large` and `global::GlobalStats::large`.
+///
+/// The counts are kept by the `large_alloc` module and are updated however an allocation is
+/// freed, including by the global heap's background thread. If the `no_stats` feature is
+/// enabled, every field is 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LargeStats {
+    /// The number of large allocations that are allocated and not yet freed.
+    pub live_regions: usize,
+    /// The bytes of the regions of those allocations.
+    pub live_bytes: usize,
+    /// The largest value that `live_bytes` has had.
+    pub peak_bytes: usize,
+    /// The number of regions mapped with `mmap`. Regions reused from the cache of freed regions
+    /// are not counted.
+    pub maps: usize,
+    /// The number of regions unmapped with `munmap`, whether when their allocation was freed or
+    /// when they were flushed from the cache. Pages unmapped from the end of a region that is
+    /// shrunk are not counted.
+    pub unmaps: usize,
+    /// The number of allocations that reused a region from the cache instead of mapping one.
+    pub reused: usize,
+}
+
+/// What happens to the region of a large allocation when it is freed, as set by
+/// `global::set_large_free_policy`.
+///
+/// The policy applies to every allocator in the process, since they share the cache of freed
+/// regions. Regions are only ever cached on Linux; elsewhere, every policy unmaps them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LargeFreePolicy {
+    /// Unmap the region, so the next large allocation maps a fresh one.
+    Unmap,
+    /// Keep the region mapped in the cache and advise it with `MADV_FREE`, so the kernel only
+    /// reclaims its memory if it runs short. Until then, the memory counts as resident, and a
+    /// region reused before it is reclaimed avoids page faults as well as the `mmap`. Kernels
+    /// older than 4.5 refuse the advice, in which case the region is released as with
+    /// `MadvDontneed`.
+    MadvFree,
+    /// Keep the region mapped in the cache and release its memory at once with `MADV_DONTNEED`.
+    /// This is the default.
+    MadvDontneed,
+}
+
+impl LargeFreePolicy {
+    /// Read a policy from the environment variable `name`, which must end with a nul byte:
+    /// `unmap`, `madv_free` or `madv_dontneed`. Returns `None` if the variable is unset or
+    /// malformed; see `utils::env_parse`.
+    pub fn from_env_var(name: &[u8]) -> Option<LargeFreePolicy> {
+        env_parse(name, |val| match val {
+            val if val == b"unmap" => Some(LargeFreePolicy::Unmap),
+            val if val == b"madv_free" => Some(LargeFreePolicy::MadvFree),
+            val if val == b"madv_dontneed" => Some(LargeFreePolicy::MadvDontneed),
+            _ => None,
+        })
+    }
+}
+
+/// The counts of one size class, as reported by `DynamicAllocator::class_stats` and
+/// `global::stats_detailed`.
+///
+/// The counts are shared by all handles on the class. If the `no_stats` feature is enabled, the
+/// object and slag counts are 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// The size of the class's objects.
+    pub object_size: usize,
+    /// The number of objects of the class that are allocated and not yet freed.
+    pub live_objects: usize,
+    /// The number of free objects of the class that are held by handles rather than returned to
+    /// their pages.
+    pub cached_objects: usize,
+    /// The number of pages ("slags") that the class holds: those it allocates from, those with
+    /// allocated objects, and those it keeps available for later allocations. Pages whose objects
+    /// have all been freed are returned to the page allocator shared by the classes.
+    pub slags: usize,
+    /// Whether the handle that collected the counts has set up its cache for the class. Classes
+    /// are set up when they are first used, and collecting the counts does not set them up; the
+    /// counts of a class that is not set up may still include objects of other handles.
+    pub initialized: bool,
+}
+
+/// The failed allocation passed to a handler set with `DynamicAllocator::set_oom_handler`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OomContext {
+    /// The number of bytes requested.
+    pub size: usize,
+    /// The number of bytes used by the allocator and its clones when the allocation failed, as
+    /// reported by `DynamicAllocator::memory_used`.
+    pub mapped: usize,
+}
+
+/// What a `DynamicAllocator` does after an allocation fails, as decided by its out-of-memory
+/// handler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OomAction {
+    /// Try the allocation again, once. The handler is expected to have freed some memory.
+    Retry,
+    /// Fail the allocation.
+    Fail,
+    /// Abort the process.
+    Abort,
+}
+
+/// A size class of a `DynamicAllocator` frozen by `freeze_class`.
+///
+/// Dropping the guard (or calling `thaw`) restores the class's page protections, re-admits it for
+/// allocation, and applies the frees queued while it was frozen.
+#[cfg(feature = "freeze")]
+pub struct FrozenClassGuard {
+    /// A handle on the allocator the class belongs to, used to apply queued frees.
+    alloc: DynamicAllocator,
+    meta: *mut Metadata,
+    page_size: usize,
+}
+
+#[cfg(feature = "freeze")]
+unsafe impl Send for FrozenClassGuard {}
+
+#[cfg(feature = "freeze")]
+impl FrozenClassGuard {
+    /// The size of the objects in the frozen class.
+    pub fn object_size(&self) -> usize {
+        unsafe { (*self.meta).object_size }
+    }
+
+    /// Thaw the class, returning the number of queued frees that were applied.
+    pub fn thaw(mut self) -> usize {
+        let res = self.thaw_inner();
+        mem::forget(self);
+        res
+    }
+
+    fn thaw_inner(&mut self) -> usize {
+        let (meta, page_size) = (self.meta, self.page_size);
+        let alloc = &mut self.alloc;
+        let mut n_frees = 0;
+        unsafe {
+            freeze::thaw(meta, page_size, |item| {
+                alloc.free(item);
+                n_frees += 1;
+            })
+        };
+        n_frees
+    }
+}
+
+#[cfg(feature = "freeze")]
+impl Drop for FrozenClassGuard {
+    fn drop(&mut self) {
+        self.thaw_inner();
+    }
+}
+
+/// A handle on a single class of a `DynamicAllocator`.
+///
+/// Like `DynamicAllocator`, cloning a `ClassHandle` yields a handle with its own thread-local
+/// cache on the same underlying class.
+pub(crate) enum ClassHandle {
+    /// Objects are allocated from a size class.
+    Class(ObjectAlloc<PageAlloc<Source>>),
+    /// Objects of the given size and alignment are too large or too aligned for any size class,
+    /// and are allocated directly.
+    Large(usize, usize),
+    /// Objects are zero-sized: every object is the same dangling pointer with the given alignment.
+    ZeroSized(usize),
+}
+
+unsafe impl Send for ClassHandle {}
+
+impl Clone for ClassHandle {
+    fn clone(&self) -> Self {
+        match *self {
+            ClassHandle::Class(ref class) => ClassHandle::Class(class.clone()),
+            ClassHandle::Large(size, align) => ClassHandle::Large(size, align),
+            ClassHandle::ZeroSized(align) => ClassHandle::ZeroSized(align),
+        }
+    }
+}
+
+impl ClassHandle {
+    pub(crate) unsafe fn alloc(&mut self) -> *mut u8 {
+        let res = match *self {
+            ClassHandle::Class(ref mut class) => {
+                let meta = class_metadata(class);
+                if unlikely(freeze::any_frozen()) && freeze::is_frozen(meta) {
+                    // The class's alignment is at most that of large allocations.
+                    large_alloc::alloc((*meta).object_size)
+                } else {
+                    let res = class_alloc(class);
+                    if !res.is_null() {
+                        count_live(meta, 1);
+                    }
+                    res
+                }
+            }
+            ClassHandle::Large(size, align) => large_alloc::alloc_aligned(size, align),
+            ClassHandle::ZeroSized(align) => return align as *mut u8,
+        };
+        #[cfg(feature = "owner_debug")]
+        {
+            if !res.is_null() {
+                let page_size = self.page_size();
+                *owner_slot(res, page_size, page_size, page_size) = owner::current();
+            }
+        }
+        res
+    }
+
+    /// The page size of the class's slags, or 0 if objects are not allocated from slags.
+    #[cfg(feature = "owner_debug")]
+    unsafe fn page_size(&self) -> usize {
+        match *self {
+            ClassHandle::Class(ref class) => (*class_metadata(class)).total_bytes,
+            ClassHandle::Large(..) | ClassHandle::ZeroSized(_) => 0,
+        }
+    }
+
+    /// Allocate an object whose first `size` bytes are zeroed, where `size` is at most the size
+    /// of the class's objects.
+    ///
+    /// Objects that have never been allocated before already read as zeros, so only recycled
+    /// objects are written to.
+    pub(crate) unsafe fn alloc_zeroed(&mut self, size: usize) -> *mut u8 {
+        let res = match *self {
+            ClassHandle::Class(ref mut class) => {
+                let meta = class_metadata(class);
+                if unlikely(freeze::any_frozen()) && freeze::is_frozen(meta) {
+                    large_alloc::alloc_zeroed((*meta).object_size)
+                } else {
+                    let (res, fresh) = class_alloc_fresh(class);
+                    if !res.is_null() {
+                        count_live(meta, 1);
+                        if !fresh {
+                            ptr::write_bytes(res, 0, size);
+                        }
+                    }
+                    res
+                }
+            }
+            ClassHandle::Large(class_size, align) => {
+                large_alloc::alloc_limited_zeroed(class_size, align, None, HugePages::Never)
+            }
+            ClassHandle::ZeroSized(align) => return align as *mut u8,
+        };
+        #[cfg(feature = "owner_debug")]
+        {
+            if !res.is_null() {
+                let page_size = self.page_size();
+                *owner_slot(res, page_size, page_size, page_size) = owner::current();
+            }
+        }
+        res
+    }
+
+    /// Free `item`, which must have been allocated from this class.
+    pub(crate) unsafe fn free(&mut self, item: *mut u8) {
+        match *self {
+            ClassHandle::Class(ref mut class) => {
+                if (item as usize) % ELFMALLOC_SMALL_CUTOFF == 0 && get_type(item) == AllocType::Large {
+                    // allocated while the class was frozen
+                    large_alloc::free(item)
+                } else if !(unlikely(freeze::any_frozen()) &&
+                                freeze::queue_free(class_metadata(class), item))
+                {
+                    scrub(&*class_metadata(class), item);
+                    let drained = count_freed(class_metadata(class), 1);
+                    class.free(item);
+                    if unlikely(drained) {
+                        reclaim_drained(class);
+                    }
+                }
+            }
+            ClassHandle::Large(..) => large_alloc::free(item),
+            ClassHandle::ZeroSized(_) => {}
+        }
+    }
+
+    /// Return cached objects to the class until at most `target` remain cached by this handle.
+    pub(crate) unsafe fn shrink_to(&mut self, target: usize) {
+        if let ClassHandle::Class(ref mut class) = *self {
+            // returning objects would write to a frozen class's pages
+            if freeze::any_frozen() && freeze::is_frozen(class_metadata(class)) {
+                return;
+            }
+            if let Some(cache) = class.try_get_mut() {
+                cache.shrink_to(target);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> ObjectAllocStats {
+        match *self {
+            // avoid initializing the cache just to report on it
+            ClassHandle::Class(ref class) => match class.try_get() {
+                Some(cache) => cache.stats(),
+                None => {
+                    let mut stats = ObjectAllocStats::default();
+                    stats.cached_objects = Some(0);
+                    stats.total_allocated = Some(0);
+                    stats
+                }
+            },
+            ClassHandle::Large(..) | ClassHandle::ZeroSized(_) => {
+                let mut stats = ObjectAllocStats::default();
+                stats.cached_objects = Some(0);
+                stats
+            }
+        }
+    }
+}
+
+
+// Frontends are currently feature-gated in the following fashion:
+
+#[cfg(not(feature = "local_cache"))]
+type Inner<CA> = MagazineCache<CA>;
+#[cfg(feature = "local_cache")]
+type Inner<CA> = LocalCache<CA>;
+
+#[cfg(not(feature = "magazine_layer"))]
+pub(crate) type ObjectAlloc<CA> = Lazy<Inner<CA>>;
+#[cfg(feature = "magazine_layer")]
+pub(crate) type ObjectAlloc<CA> = Lazy<DepotCache<Inner<CA>>>;
+
+/// Get the metadata of `class` without initializing it.
+fn class_metadata<CA: CoarseAllocator>(class: &ObjectAlloc<CA>) -> *mut Metadata {
+    #[cfg(not(feature = "magazine_layer"))]
+    {
+        class.params().0
+    }
+    #[cfg(feature = "magazine_layer")]
+    {
+        (class.params().0).0
+    }
+}
+
+
+/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
+/// `AllocMap`.
+///
+/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
+/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
+/// type parameters.
+struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>> {
+    /// A cache of pages for all small allocations.
+    small_pages: CA,
+    /// A cache of pages for all medium allocations.
+    large_pages: CA,
+    /// A cache of pages for classes too large for `large_pages`, if there are any. Each page
+    /// holds a single object.
+    huge_pages: Option<CA>,
+    /// An `AllocMap` of size classes of individual fixed-size object allocator.
+    allocs: AM,
+    /// The maximum size of a "non-large" object. Objects larger than `max_size` are allocated
+    /// directly with mmap.
+    max_size: usize,
+
+    /// Objects smaller than `small_cutoff` are allocated from `small_pages`.
+    small_cutoff: usize,
+    /// Whether every class at or above `small_cutoff` is a power of two, so that its objects are
+    /// aligned to the cutoff (see `get_page_size`).
+    medium_aligned: bool,
+    small_page_size: usize,
+    large_page_size: usize,
+    /// The page size of `huge_pages`, or 0 if there are no huge classes.
+    huge_page_size: usize,
+    /// Whether large allocations of at least `large_huge_threshold` bytes are backed by huge
+    /// pages.
+    large_huge_pages: HugePages,
+    large_huge_threshold: usize,
+    /// The memory used by this allocator and its clones.
+    limit: Arc<MemoryLimit>,
+    /// The mapped regions of the large allocations made by this allocator and its clones that
+    /// have not been freed through one of them.
+    large_regions: Arc<RangeSet>,
+    /// The handler called when an allocation fails, set with `DynamicAllocator::set_oom_handler`.
+    oom_handler: Option<fn(OomContext) -> OomAction>,
+    /// The classes appended by `DynamicAllocator::extend_classes` through this allocator and its
+    /// clones, or `None` if its classes cannot be extended.
+    extension: Option<Arc<Extension<CA>>>,
+    /// The id shared by this allocator and its clones, recorded in the metadata of its classes and
+    /// in its large allocations.
+    #[cfg(feature = "debug_ownership")]
+    instance: usize,
+    /// The sizes requested from this handle, for `DynamicAllocator::size_histogram`.
+    #[cfg(feature = "profiling")]
+    profile: Profile,
+    /// The generation of this allocator and its clones, set with
+    /// `DynamicAllocator::advance_generation`.
+    generation: Arc<AtomicUsize>,
+    /// What `reclaim_cold` last saw of the cache of each class, in increasing order of size.
+    /// This is empty until `reclaim_cold` is first called.
+    class_uses: Vec<ClassUse>,
+}
+
+/// What `ElfMalloc::reclaim_cold` last saw of a handle's cache for a class.
+#[derive(Copy, Clone)]
+struct ClassUse {
+    /// The number of objects allocated from the cache, or `None` if it was not set up.
+    allocs: Option<usize>,
+    /// The generation in which the count was last seen to change.
+    generation: usize,
+}
+
+/// The classes appended to an allocator by `DynamicAllocator::extend_classes`, shared by the
+/// allocator and all of its clones.
+///
+/// Classes are only ever appended, in increasing order of size. Each handle has its own copy of
+/// every class up to its `max_size`, and clones the classes it lacks from `state` the next time it
+/// needs one of them (see `ElfMalloc::catch_up`).
+struct Extension<CA: CoarseAllocator> {
+    /// The size of the largest class appended so far.
+    max_size: AtomicUsize,
+    /// The page size of the huge pages created by `extend_classes`, or 0 if it has not created
+    /// any. Handles that have not caught up yet need it to free the objects of the new classes.
+    huge_page_size: AtomicUsize,
+    state: Mutex<ExtensionState<CA>>,
+}
+
+struct ExtensionState<CA: CoarseAllocator> {
+    /// The appended classes. Handles clone these rather than create their own, so that all of the
+    /// handles of a class share its slags.
+    classes: Vec<ObjectAlloc<CA>>,
+    /// The pages of the appended classes too large for the large pages, if the allocator had no
+    /// huge classes to begin with.
+    huge_pages: Option<CA>,
+    /// Where the metadata of the next appended class is written. Slots for every class up to
+    /// `ELFMALLOC_MAX_CLASS_SIZE` are reserved after those of the allocator's own classes.
+    next_info: *mut ClassInfo,
+    cutoff_factor: f64,
+    /// The capacity of the caches of the allocator's classes.
+    magazines: MagazineSpec,
+    /// The huge page policy of the pages of the allocator's classes.
+    huge_page_policy: HugePages,
+}
+
+/// The caches of an allocator's classes, as set with `DynamicAllocatorBuilder::magazine_capacity`,
+/// `DynamicAllocatorBuilder::adaptive_magazines` and `DynamicAllocatorBuilder::depot_shards`.
+#[derive(Copy, Clone, Debug, Default)]
+struct MagazineSpec {
+    /// The initial capacity of every class, rather than one that depends on its size.
+    capacity: Option<usize>,
+    /// The bounds of adaptive capacities.
+    bounds: Option<(usize, usize)>,
+    /// The number of shards of each class's depot, and the number of magazines they exchange
+    /// at once.
+    depot_shards: Option<(usize, usize)>,
+}
+
+impl MagazineSpec {
+    /// The capacity of the caches of the class of `size` bytes.
+    fn for_class(&self, size: usize) -> MagazineCapacity {
+        let initial = self.capacity.unwrap_or_else(|| frontends::magazine_capacity(size));
+        match self.bounds {
+            Some((min, max)) => MagazineCapacity {
+                initial: cmp::max(min, cmp::min(initial, max)),
+                min: min,
+                max: max,
+            },
+            None => MagazineCapacity::fixed(initial),
+        }
+    }
+
+    /// A depot for the magazines of a new class.
+    #[cfg(feature = "magazine_layer")]
+    fn depot(&self) -> Depot {
+        match self.depot_shards {
+            Some((n_shards, batch_size)) => Depot::new_sharded(n_shards, batch_size),
+            None => Depot::default(),
+        }
+    }
+}
+
+impl Default for DynamicAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `DynamicAllocator` that can be shared between threads.
+///
+/// A `DynamicAllocator` needs a separately cloned handle on every thread that uses it. A
+/// `SharedDynamicAllocator` keeps a fixed number of handles ("shards") internally, each behind
+/// its own lock, so that it can be used through a shared reference, for example from an `Arc`.
+/// Each thread prefers one shard, and tries the others before waiting for a busy one, so there is
+/// little contention until more threads allocate at once than there are shards.
+///
+/// Objects may be freed through any thread. When a `SharedDynamicAllocator` is dropped, its
+/// handles are torn down by the `global` module's background thread, as are the handles of
+/// exiting threads.
+pub struct SharedDynamicAllocator {
+    shards: Vec<Mutex<DynamicAllocator>>,
+}
+
+/// The preferred shard of the next thread to use a `SharedDynamicAllocator`, modulo the number of
+/// shards.
+static NEXT_SHARD_HINT: AtomicUsize = AtomicUsize::new(0);
+
+alloc_thread_local!{ static SHARD_HINT: usize = NEXT_SHARD_HINT.fetch_add(1, Ordering::Relaxed); }
+
+impl SharedDynamicAllocator {
+    /// Create a `SharedDynamicAllocator` with the default configuration and one shard per CPU.
+    pub fn new() -> Self {
+        Self::with_shards(DynamicAllocator::new(), num_cpus::get())
+    }
+
+    /// Create a `SharedDynamicAllocator` from `n_shards` clones of `alloc`.
+    ///
+    /// `alloc` may come from a `DynamicAllocatorBuilder`, and objects allocated by any of its
+    /// other clones may be freed through the shared allocator (and vice versa).
+    pub fn with_shards(alloc: DynamicAllocator, n_shards: usize) -> Self {
+        alloc_assert!(n_shards > 0, "a SharedDynamicAllocator needs at least one shard");
+        let mut shards = Vec::with_capacity(n_shards);
+        for _ in 1..n_shards {
+            shards.push(Mutex::new(alloc.clone()));
+        }
+        shards.push(Mutex::new(alloc));
+        SharedDynamicAllocator { shards: shards }
+    }
+
+    /// Run `f` with exclusive access to one of the shards.
+    fn with_shard<R, F: FnOnce(&mut DynamicAllocator) -> R>(&self, f: F) -> R {
+        let n_shards = self.shards.len();
+        let hint = unsafe { alloc_tls_fast_with!(SHARD_HINT, h, { *h }) }.unwrap_or(0) % n_shards;
+        let mut guard = None;
+        for i in 0..n_shards {
+            if let Ok(shard) = self.shards[(hint + i) % n_shards].try_lock() {
+                guard = Some(shard);
+                break;
+            }
+        }
+        let mut guard = guard.unwrap_or_else(|| self.shards[hint].lock().unwrap());
+        f(&mut *guard)
+    }
+
+    pub unsafe fn alloc(&self, size: usize) -> *mut u8 {
+        self.with_shard(|alloc| alloc.alloc(size))
+    }
+
+    /// Allocate `size` bytes, returning an error rather than aborting if no memory can be mapped.
+    pub unsafe fn try_alloc(&self, size: usize) -> Result<*mut u8, Exhausted> {
+        self.with_shard(|alloc| alloc.try_alloc(size))
+    }
+
+    pub unsafe fn free(&self, item: *mut u8) {
+        self.with_shard(|alloc| alloc.free(item))
+    }
+
+    pub unsafe fn realloc(&self, item: *mut u8, new_size: usize) -> *mut u8 {
+        self.with_shard(|alloc| alloc.realloc(item, new_size))
+    }
+
+    /// Get the size and alignment of the object `item`, which must have been allocated by `self`
+    /// and not yet freed.
+    pub unsafe fn layout_of(&self, item: *mut u8) -> (usize /* size */, usize /* alignment */) {
+        // layout_of does not modify the handle, but handles are not Sync.
+        self.with_shard(|alloc| alloc.layout_of(item))
+    }
+}
+
+impl Default for SharedDynamicAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SharedDynamicAllocator {
+    fn drop(&mut self) {
+        for shard in self.shards.drain(..) {
+            global::retire(shard.into_inner().unwrap_or_else(|err| err.into_inner()));
+        }
+    }
+}
+
+impl DynamicAllocator {
+    /// The size of the small pages of `self`, which `Arena`s take whole.
+    pub(crate) fn small_page_size(&self) -> usize {
+        self.0.small_page_size
+    }
+
+    /// Take a small page from the page allocator of `self`, or return null if none is available.
+    pub(crate) unsafe fn alloc_small_page(&mut self) -> *mut u8 {
+        self.0.small_pages.alloc()
+    }
+
+    /// Return `page`, which was taken with `alloc_small_page`, to the page allocator of `self`.
+    pub(crate) unsafe fn free_small_page(&mut self, page: *mut u8) {
+        self.0.small_pages.free(page, false)
+    }
+}
+
+/// A builder for a `DynamicAllocator` with non-default page sizes or size classes.
+///
+/// Objects smaller than the small cutoff are allocated from small pages, and all other objects up
+/// to the largest size class are allocated from large pages. Classes too large for two of their
+/// objects to fit in a large page get a third kind of page, twice the size of the largest class,
+/// that holds one object each. Objects larger than the largest class are mapped directly.
+/// `build` checks that the parameters fit together, returning a `ConfigError` describing the
+/// first problem it finds:
+///
+/// - Both page sizes must be powers of two, at least the system page size, and at most 2MiB. The
+///   small page size must not exceed the large page size.
+/// - The small cutoff must be a power of two, no larger than 64KiB and below the large page size.
+///   It defaults to a quarter of the small page size, but to at most 64KiB.
+/// - There must be at least two size classes, and the smallest must be below the small cutoff.
+///   Every class at or above the small cutoff must be a power of two (unless the medium classes
+///   are geometric; see `medium_ratio`), and every class must fit in a page of the appropriate
+///   size. The largest class may be at most 1GiB; it is chosen with either `n_classes` or
+///   `max_class_size`.
+///
+/// Objects allocated from a custom-built allocator must be freed, reallocated, and inspected
+/// with that allocator (or its clones), and not with the functions in the `global` module, which
+/// use the global heap. The global heap is configured from environment variables, as described in
+/// the `global` module.
+#[derive(Clone, Debug)]
+pub struct DynamicAllocatorBuilder {
+    small_page_size: usize,
+    large_page_size: usize,
+    small_cutoff: Option<usize>,
+    cutoff_factor: f64,
+    start_from: usize,
+    n_classes: usize,
+    max_class_size: Option<usize>,
+    huge_pages: HugePages,
+    large_huge_pages: HugePages,
+    large_huge_threshold: usize,
+    zero_on_free: bool,
+    min_align: usize,
+    multiple: usize,
+    tiny_classes: bool,
+    medium_ratio: Option<f64>,
+    size_classes: Option<Vec<usize>>,
+    magazine_capacity: Option<usize>,
+    magazine_bounds: Option<(usize, usize)>,
+    depot_shards: Option<(usize, usize)>,
+    eager_dirty: EagerDirty,
+    /// Whether `EagerDirty::Background` is honoured, which is only the case for the global heap.
+    background_dirty: bool,
+}
+
+impl Default for DynamicAllocatorBuilder {
+    fn default() -> DynamicAllocatorBuilder {
+        DynamicAllocatorBuilder {
+            small_page_size: ELFMALLOC_SMALL_PAGE_SIZE,
+            large_page_size: ELFMALLOC_PAGE_SIZE,
+            small_cutoff: None,
+            cutoff_factor: 0.6,
+            start_from: 8,
+            n_classes: 25,
+            max_class_size: None,
+            huge_pages: HugePages::Never,
+            large_huge_pages: HugePages::Never,
+            large_huge_threshold: 4 << 20,
+            zero_on_free: false,
+            min_align: DEFAULT_MIN_ALIGN,
+            multiple: MULTIPLE,
+            tiny_classes: false,
+            medium_ratio: None,
+            size_classes: None,
+            magazine_capacity: None,
+            magazine_bounds: None,
+            depot_shards: None,
+            eager_dirty: EagerDirty::None,
+            background_dirty: false,
+        }
+    }
+}
+
+impl DynamicAllocatorBuilder {
+    /// The page size used for objects below the small cutoff.
+    pub fn small_page_size(&mut self, small_page_size: usize) -> &mut DynamicAllocatorBuilder {
+        self.small_page_size = small_page_size;
+        self
+    }
+    /// The page size used for objects at or above the small cutoff.
+    pub fn large_page_size(&mut self, large_page_size: usize) -> &mut DynamicAllocatorBuilder {
+        self.large_page_size = large_page_size;
+        self
+    }
+    /// The size separating small objects from medium objects.
+    pub fn small_cutoff(&mut self, small_cutoff: usize) -> &mut DynamicAllocatorBuilder {
+        self.small_cutoff = Some(small_cutoff);
+        self
+    }
+    /// The fraction of a page's objects that must be free before the page is reused.
+    pub fn cutoff_factor(&mut self, cutoff_factor: f64) -> &mut DynamicAllocatorBuilder {
+        self.cutoff_factor = cutoff_factor;
+        self
+    }
+    /// The size of the smallest size class.
+    pub fn start_from(&mut self, start_from: usize) -> &mut DynamicAllocatorBuilder {
+        self.start_from = start_from;
+        self
+    }
+    /// The number of size classes.
+    pub fn n_classes(&mut self, n_classes: usize) -> &mut DynamicAllocatorBuilder {
+        self.n_classes = n_classes;
+        self.max_class_size = None;
+        self
+    }
+    /// The size of the largest size class, which must be a power of two. This picks the number of
+    /// size classes, replacing any set with `n_classes`. With `medium_ratio`, the largest class is
+    /// instead the first class of at least `max_class_size` bytes, which may be any size.
+    pub fn max_class_size(&mut self, max_class_size: usize) -> &mut DynamicAllocatorBuilder {
+        self.max_class_size = Some(max_class_size);
+        self
+    }
+    /// Whether pages for objects at or above the small cutoff are backed by huge pages. Small
+    /// pages always use the system's normal pages. The `ELFMALLOC_HUGE_PAGES` environment
+    /// variable (`never`, `thp` or `hugetlb`) overrides this setting.
+    pub fn huge_pages(&mut self, huge_pages: HugePages) -> &mut DynamicAllocatorBuilder {
+        self.huge_pages = huge_pages;
+        self
+    }
+    /// Whether large allocations of at least `threshold` bytes are backed by huge pages (by
+    /// default, none are). With `HugePages::HugeTlb`, their regions are rounded up to a multiple
+    /// of 2MiB and mapped with `MAP_HUGETLB`; if hugetlbfs has no pages configured or free, they
+    /// fall back to normal pages advised with `MADV_HUGEPAGE`, as with `Transparent`. The
+    /// `ELFMALLOC_LARGE_HUGE_PAGES` (`never`, `thp` or `hugetlb`) and
+    /// `ELFMALLOC_LARGE_HUGE_THRESHOLD` environment variables override these settings.
+    pub fn large_huge_pages(
+        &mut self,
+        huge_pages: HugePages,
+        threshold: usize,
+    ) -> &mut DynamicAllocatorBuilder {
+        self.large_huge_pages = huge_pages;
+        self.large_huge_threshold = threshold;
+        self
+    }
+    /// Whether objects in size classes are zeroed when they are freed, so that their contents
+    /// cannot be read through later allocations. Large objects are not zeroed: their memory is
+    /// unmapped or uncommitted when they are freed. The `ELFMALLOC_ZERO_ON_FREE` environment variable (`1` or
+    /// `0`) overrides this setting.
+    pub fn zero_on_free(&mut self, zero_on_free: bool) -> &mut DynamicAllocatorBuilder {
+        self.zero_on_free = zero_on_free;
+        self
+    }
+    /// The minimum alignment of allocations of at least `min_align` bytes, which is either the
+    /// word size or 16. With an alignment of 16, there is no class for 8-byte objects, and the
+    /// smallest class is at least 16 bytes. The default is the word size, except when the "c-api"
+    /// feature is enabled on Mac and 64-bit Windows, whose `malloc` guarantees 16.
+    pub fn min_align(&mut self, min_align: usize) -> &mut DynamicAllocatorBuilder {
+        self.min_align = min_align;
+        self
+    }
+    /// The multiple that the sizes of the small classes are spaced by, which must be a power of two
+    /// of at least 8 (and at least `min_align`), no larger than half the small cutoff. The default
+    /// is 16. A larger multiple gives fewer, coarser small classes; with a multiple of 8, there is
+    /// no separate class for 8-byte objects. Objects of classes that are not powers of two are
+    /// aligned to the largest power of two dividing their size, up to 16 bytes, whatever the
+    /// multiple. This is ignored if `size_classes` is used.
+    pub fn small_class_multiple(&mut self, multiple: usize) -> &mut DynamicAllocatorBuilder {
+        self.multiple = multiple;
+        self
+    }
+    /// The number of objects of each size class that a handle caches, which must be positive.
+    /// Freed objects beyond it are returned to their slags. By default, the capacity depends on
+    /// the size of the class's objects (see `frontends::magazine_capacity`): caches of small
+    /// objects hold thousands of them, and caches of the largest objects only a few. This is
+    /// ignored with the `local_cache` feature, whose caches hold a slag's worth of objects.
+    pub fn magazine_capacity(&mut self, capacity: usize) -> &mut DynamicAllocatorBuilder {
+        self.magazine_capacity = Some(capacity);
+        self
+    }
+    /// Let the capacity of each cache adapt to its use, between `min` and `max` objects (see
+    /// `frontends::MagazineCapacity`). Caches start out with the capacity `magazine_capacity`
+    /// would give them, brought within the bounds. The capacity doubles when a handle keeps
+    /// missing its cache, as a bursty thread does, and halves when `DynamicAllocator::decay_caches`
+    /// finds that the handle has not missed it for a while, returning the objects cached beyond
+    /// the new capacity. The bounds must satisfy `0 < min <= max`; capacities are fixed by
+    /// default. This is ignored with the `local_cache` feature.
+    pub fn adaptive_magazines(&mut self, min: usize, max: usize) -> &mut DynamicAllocatorBuilder {
+        self.magazine_bounds = Some((min, max));
+        self
+    }
+    /// Split the depot of each size class into `n_shards` shards, each of which exchanges
+    /// `batch_size` magazines at a time with the global depot of the class (see
+    /// `frontends::Depot`). Each handle exchanges magazines with one of the shards, so fewer
+    /// threads contend on each depot. The batch size must be positive; a single shard, the
+    /// default, is an unsharded depot. This only has an effect with the `magazine_layer` feature.
+    pub fn depot_shards(
+        &mut self,
+        n_shards: usize,
+        batch_size: usize,
+    ) -> &mut DynamicAllocatorBuilder {
+        self.depot_shards = Some((n_shards, batch_size));
+        self
+    }
+    /// Whether there are size classes for objects of 1, 2 and 4 bytes, below the smallest class
+    /// of 8 bytes (or 16; see `min_align`). Without them, each allocation of a few bytes takes a
+    /// whole word. The objects of these classes are only aligned to their size, so the global
+    /// heap, whose objects must be aligned as the C allocation API requires, never has them. This
+    /// is ignored if `size_classes` is used.
+    pub fn tiny_classes(&mut self, tiny_classes: bool) -> &mut DynamicAllocatorBuilder {
+        self.tiny_classes = tiny_classes;
+        self
+    }
+    /// Grow the medium size classes (those above the classes that are multiples of 16; see
+    /// `small_class_multiple`) by
+    /// `ratio`, which must be in the range `(1, 2]`, instead of doubling them. From 4KiB on,
+    /// each class is `ratio` times the size of the one before it, rounded up to a multiple of
+    /// 4KiB, so that fewer bytes are wasted rounding sizes up to their classes. For sizes
+    /// uniformly distributed between 64KiB and 256KiB, a ratio of 1.25 wastes 11% of the memory
+    /// of their classes on average (and at most 21% for any size), where doubling wastes 25% (and
+    /// at most 50%).
+    ///
+    /// More classes are needed to reach the same largest class, so this is best combined with
+    /// `max_class_size`. Classes that are not powers of two are only aligned to 16 bytes, and
+    /// finding the page of an object takes an extra load from memory when any class at or above
+    /// the small cutoff is not a power of two. This is ignored if `size_classes` is used.
+    pub fn medium_ratio(&mut self, ratio: f64) -> &mut DynamicAllocatorBuilder {
+        self.medium_ratio = Some(ratio);
+        self
+    }
+    /// Use exactly the listed size classes, in place of the default classes picked by
+    /// `start_from` and `n_classes` (or `max_class_size`). This suits programs dominated by a
+    /// few sizes that fit poorly in the default classes.
+    ///
+    /// The sizes must be in ascending order and multiples of the minimum alignment (see
+    /// `min_align`), and those at or above the small cutoff must be powers of two. The largest
+    /// class may be at most 1GiB. Objects of a class that is not a power of two are only aligned
+    /// to the largest power of two dividing its size, up to 16 bytes.
+    pub fn size_classes(&mut self, sizes: &[usize]) -> &mut DynamicAllocatorBuilder {
+        self.size_classes = Some(sizes.to_vec());
+        self
+    }
+    /// Whether the pages of size classes are touched as they are handed out, so that the page
+    /// faults that commit them are not taken by the first accesses to their objects. Only the
+    /// global heap touches pages on its background thread; `EagerDirty::Background` is treated
+    /// as `EagerDirty::Inline` by other allocators. The `ELFMALLOC_EAGER_DIRTY` environment
+    /// variable (`none`, `background` or `inline`) overrides this setting.
+    pub fn eager_dirty(&mut self, eager_dirty: EagerDirty) -> &mut DynamicAllocatorBuilder {
+        self.eager_dirty = eager_dirty;
+        self
+    }
+
+    /// The size of the smallest size class, raised to `min_align` if it is smaller.
+    ///
+    /// The small classes are multiples of `small_class_multiple`, which is at least the minimum
+    /// alignment, and every larger class is aligned to `MULTIPLE`. So the only class that has to
+    /// be dropped for a minimum alignment of 16 is the one for 8-byte objects.
+    fn get_start_from(&self) -> usize {
+        cmp::max(self.start_from, self.min_align)
+    }
+
+    /// The classes picked by `start_from`, `medium_ratio`, the small cutoff and `n_classes`.
+    fn tiered_classes(&self, n_classes: usize) -> ClassSpec<'static> {
+        let (start_from, small_cutoff) = (self.get_start_from(), self.get_small_cutoff());
+        match self.medium_ratio {
+            Some(ratio) => ClassSpec::Geometric {
+                start_from: start_from,
+                n_classes: n_classes,
+                multiple: self.multiple,
+                small_cutoff: small_cutoff,
+                tiny_classes: self.tiny_classes,
+                ratio: ratio,
+            },
+            None => ClassSpec::Tiered {
+                start_from: start_from,
+                n_classes: n_classes,
+                multiple: self.multiple,
+                small_cutoff: small_cutoff,
+                tiny_classes: self.tiny_classes,
+            },
+        }
+    }
+
+    /// The number of size classes, found from `max_class_size` if it is set.
+    ///
+    /// Adding a class grows the largest class by at most a factor of two, so every power of two
+    /// from the largest class with two classes upwards is the largest class for some number of
+    /// classes. Geometric classes need not reach `max_class_size` exactly, and take the fewest
+    /// classes that reach at least that size.
+    fn get_n_classes(&self) -> Result<usize, ConfigError> {
+        let max_class_size = match self.max_class_size {
+            Some(max_class_size) => max_class_size,
+            None => return Ok(self.n_classes),
+        };
+        let max_key_for = |n| self.tiered_classes(n).max_class_size();
+        // The largest class grows with the number of classes, and geometric classes may need
+        // thousands of them, so search for the number of classes rather than counting up.
+        let (mut n_classes, mut above) = (2, 2);
+        while max_key_for(above) < max_class_size {
+            n_classes = above + 1;
+            above *= 2;
+        }
+        while n_classes < above {
+            let mid = n_classes + (above - n_classes) / 2;
+            if max_key_for(mid) < max_class_size {
+                n_classes = mid + 1;
+            } else {
+                above = mid;
+            }
+        }
+        if self.medium_ratio.is_none() && max_key_for(n_classes) != max_class_size {
+            return Err(ConfigError::InvalidMaxClassSize {
+                max_class_size: max_class_size,
+                min: max_key_for(2),
+            });
+        }
+        Ok(n_classes)
+    }
+
+    /// The size classes to construct the allocator with.
+    fn get_classes(&self) -> Result<ClassSpec, ConfigError> {
+        Ok(match self.size_classes {
+            Some(ref sizes) => ClassSpec::Custom(sizes),
+            None => self.tiered_classes(self.get_n_classes()?),
+        })
+    }
+
+    /// Check a list of classes passed to `size_classes`.
+    fn validate_custom_classes(
+        &self,
+        sizes: &[usize],
+        small_cutoff: usize,
+    ) -> Result<(), ConfigError> {
+        if sizes.len() < 2 {
+            return Err(ConfigError::TooFewClasses { n_classes: sizes.len() });
+        }
+        let mut previous = 0;
+        for &size in sizes {
+            if size == 0 {
+                return Err(ConfigError::ZeroObjectSize);
+            }
+            if size <= previous {
+                return Err(ConfigError::UnsortedClasses {
+                    size: size,
+                    previous: previous,
+                });
+            }
+            if size % self.min_align != 0 {
+                return Err(ConfigError::MisalignedClass {
+                    size: size,
+                    min_align: self.min_align,
+                });
+            }
+            if size >= small_cutoff && !size.is_power_of_two() {
+                return Err(ConfigError::NonPowerOfTwoClass {
+                    size: size,
+                    small_cutoff: small_cutoff,
+                });
+            }
+            previous = size;
+        }
+        Ok(())
+    }
+
+    fn get_small_cutoff(&self) -> usize {
+        self.small_cutoff
+            .unwrap_or(cmp::min(self.small_page_size / 4, ELFMALLOC_SMALL_CUTOFF))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let system_page_size = mmap::page_size();
+        check_system_page_size(system_page_size)?;
+        for &page_size in &[self.small_page_size, self.large_page_size] {
+            if !page_size.is_power_of_two() {
+                return Err(ConfigError::InvalidPageSize { page_size: page_size });
+            }
+            // Both are powers of two, so the page size is also a multiple of the system's.
+            if page_size < system_page_size {
+                return Err(ConfigError::PageBelowSystemPage {
+                    page_size: page_size,
+                    system_page_size: system_page_size,
+                });
+            }
+            if page_size > ELFMALLOC_PAGE_SIZE {
+                return Err(ConfigError::PageTooLarge {
+                    page_size: page_size,
+                    max: ELFMALLOC_PAGE_SIZE,
+                });
+            }
+        }
+        if self.small_page_size > self.large_page_size {
+            return Err(ConfigError::PageSizeOrder {
+                small_page_size: self.small_page_size,
+                large_page_size: self.large_page_size,
+            });
+        }
+        let small_cutoff = self.get_small_cutoff();
+        let max_cutoff = cmp::min(ELFMALLOC_SMALL_CUTOFF, self.large_page_size / 2);
+        if !small_cutoff.is_power_of_two() || small_cutoff > max_cutoff {
+            return Err(ConfigError::InvalidSmallCutoff {
+                small_cutoff: small_cutoff,
+                max: max_cutoff,
+            });
+        }
+        if !self.min_align.is_power_of_two() || self.min_align < mem::size_of::<usize>() ||
+            self.min_align > MULTIPLE
+        {
+            return Err(ConfigError::InvalidMinAlign {
+                min_align: self.min_align,
+                max: MULTIPLE,
+            });
+        }
+        match self.size_classes {
+            Some(ref sizes) => self.validate_custom_classes(sizes, small_cutoff)?,
+            None => {
+                if self.max_class_size.is_none() && self.n_classes < 2 {
+                    return Err(ConfigError::TooFewClasses { n_classes: self.n_classes });
+                }
+                let (min_multiple, max_multiple) = (cmp::max(8, self.min_align), small_cutoff / 2);
+                if !self.multiple.is_power_of_two() || self.multiple < min_multiple ||
+                    self.multiple > max_multiple
+                {
+                    return Err(ConfigError::InvalidMultiple {
+                        multiple: self.multiple,
+                        min: min_multiple,
+                        max: max_multiple,
+                    });
+                }
+                let start_from = self.get_start_from();
+                if start_from >= small_cutoff {
+                    return Err(ConfigError::StartAboveCutoff {
+                        start_from: start_from,
+                        small_cutoff: small_cutoff,
+                    });
+                }
+                if let Some(ratio) = self.medium_ratio {
+                    // NaN fails both comparisons.
+                    if !(ratio > 1.0 && ratio <= 2.0) {
+                        return Err(ConfigError::InvalidMediumRatio { ratio: ratio });
+                    }
+                }
+            }
+        }
+        if self.magazine_capacity == Some(0) {
+            return Err(ConfigError::ZeroMagazineCapacity);
+        }
+        if let Some((min, max)) = self.magazine_bounds {
+            if min == 0 || min > max {
+                return Err(ConfigError::InvalidMagazineBounds { min: min, max: max });
+            }
+        }
+        if let Some((_, 0)) = self.depot_shards {
+            return Err(ConfigError::ZeroDepotBatch);
+        }
+        let max_class_size = self.get_classes()?.max_class_size();
+        if max_class_size > ELFMALLOC_MAX_CLASS_SIZE {
+            return Err(ConfigError::ClassTooLarge {
+                size: max_class_size,
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Result<DynamicAllocator, ConfigError> {
+        ElfMalloc::with_builder(self).map(DynamicAllocator)
+    }
+
+    /// Build an allocator whose size classes get their pages from `small_source` (for objects
+    /// below the small cutoff) and `large_source` rather than from `mmap`.
+    ///
+    /// Sources for each page size are derived with `MemorySource::with_page_size`, and groups of
+    /// pages are carved from them aligned to at least 2MiB, the alignment that elfmalloc relies
+    /// on to find the start of each group (see `MemorySource::carve`). Objects larger than the
+    /// largest size class, and the metadata of the size classes, are still mapped directly.
+    pub fn build_with_source<M: MemorySource>(
+        &self,
+        small_source: M,
+        large_source: M,
+    ) -> Result<DynamicAllocator<M>, ConfigError> {
+        ElfMalloc::with_sources(self, small_source, large_source).map(DynamicAllocator)
+    }
+}
+
+// TODO(ezrosent): move this to a type parameter when const generics are in.
+//
+// ELFMALLOC_PAGE_SIZE is also the alignment of the AllocType stamps used by get_type, and so the
+// largest supported page size. The other two constants are defaults for DynamicAllocatorBuilder.
+// Large allocations are aligned to ELFMALLOC_SMALL_CUTOFF, which makes it the largest supported
+// small cutoff: every object at or above the cutoff has to be aligned to it.
+pub(crate) const ELFMALLOC_PAGE_SIZE: usize = 2 << 20;
+const ELFMALLOC_SMALL_PAGE_SIZE: usize = 256 << 10;
+const ELFMALLOC_SMALL_CUTOFF: usize = ELFMALLOC_SMALL_PAGE_SIZE / 4;
+// The largest size class a DynamicAllocatorBuilder accepts. Huge classes get pages of twice their
+// size, which have to be mapped.
+const ELFMALLOC_MAX_CLASS_SIZE: usize = 1 << 30;
+
+/// Check that `system_page_size` divides the sizes above that must be made up of whole system
+/// pages: the alignment of `AllocType` stamps, the default small page size, and the small cutoff,
+/// the unit in which large allocations are mapped.
+fn check_system_page_size(system_page_size: usize) -> Result<(), ConfigError> {
+    let sizes = [ELFMALLOC_PAGE_SIZE, ELFMALLOC_SMALL_PAGE_SIZE, ELFMALLOC_SMALL_CUTOFF];
+    let divides = |size: &usize| size % system_page_size == 0;
+    if system_page_size.is_power_of_two() && sizes.iter().all(divides) {
+        Ok(())
+    } else {
+        Err(ConfigError::UnsupportedSystemPageSize { system_page_size: system_page_size })
+    }
+}
+
+/// The id of the next allocator constructed with `new_internal`.
+#[cfg(feature = "debug_ownership")]
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(UNOWNED_INSTANCE + 1);
+
+impl<M: MemorySource, D: DirtyFn>
+    ElfMalloc<PageAlloc<M, D>, SizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
+    fn new() -> Self {
+        Self::with_builder(&DynamicAllocatorBuilder::default())
+            .expect("default size classes should be valid")
+    }
+
+    fn with_builder(builder: &DynamicAllocatorBuilder) -> Result<Self, ConfigError> {
+        // Pages smaller than ELFMALLOC_PAGE_SIZE are allocated in groups where the first page is
+        // aligned to ELFMALLOC_PAGE_SIZE; this page will be stamped with the page's AllocType,
+        // allowing type lookups to work as expected.
+        Self::with_page_allocs(builder, |page_size, ty| if page_size >= ELFMALLOC_PAGE_SIZE {
+            PageAlloc::new(page_size, 1 << 20, 8, ty)
+        } else {
+            PageAlloc::new_aligned(page_size, 1 << 20, 8, ELFMALLOC_PAGE_SIZE, ty)
+        })
+    }
+
+    /// Like `with_builder`, but carve the pages of small objects from `small_source` and those of
+    /// all other objects from `large_source`.
+    fn with_sources(
+        builder: &DynamicAllocatorBuilder,
+        small_source: M,
+        large_source: M,
+    ) -> Result<Self, ConfigError> {
+        Self::with_page_allocs(builder, |page_size, ty| {
+            let source = match ty {
+                AllocType::SmallSlag => &small_source,
+                _ => &large_source,
+            };
+            let align = cmp::max(page_size, ELFMALLOC_PAGE_SIZE);
+            PageAlloc::from_source(source, page_size, 1 << 20, 8, align, ty)
+        })
+    }
+
+    /// Construct an allocator configured by `builder`, creating the `PageAlloc` for pages of a
+    /// given size and type with `new_pages`.
+    fn with_page_allocs<F>(
+        builder: &DynamicAllocatorBuilder,
+        new_pages: F,
+    ) -> Result<Self, ConfigError>
+    where
+        F: Fn(usize, AllocType) -> PageAlloc<M, D>,
+    {
+        builder.validate()?;
+        #[cfg(unix)]
+        register_fork_handlers();
+        let huge_pages = HugePages::from_env().unwrap_or(builder.huge_pages);
+        let eager_dirty = match EagerDirty::from_env().unwrap_or(builder.eager_dirty) {
+            EagerDirty::Background if !builder.background_dirty => EagerDirty::Inline,
+            eager_dirty => eager_dirty,
+        };
+        let mut pa_large = new_pages(builder.large_page_size, AllocType::BigSlag);
+        pa_large.set_huge_pages(huge_pages);
+        pa_large.set_eager_dirty(eager_dirty);
+        let mut pa_small = new_pages(builder.small_page_size, AllocType::SmallSlag);
+        pa_small.set_eager_dirty(eager_dirty);
+        let classes = builder.get_classes()?;
+        let max_class_size = classes.max_class_size();
+        let pa_huge = if max_class_size > builder.large_page_size / 2 {
+            // page sizes are powers of two, even if the largest class is not
+            let page_size = (2 * max_class_size).next_power_of_two();
+            let mut pa_huge = new_pages(page_size, AllocType::HugeSlag);
+            pa_huge.set_huge_pages(huge_pages);
+            pa_huge.set_eager_dirty(eager_dirty);
+            Some(pa_huge)
+        } else {
+            None
+        };
+        let mut elf = Self::new_internal(
+            builder.cutoff_factor,
+            MagazineSpec {
+                capacity: builder.magazine_capacity,
+                bounds: builder.magazine_bounds,
+                depot_shards: builder.depot_shards,
+            },
+            builder.get_small_cutoff(),
+            pa_small,
+            pa_large,
+            pa_huge,
+            huge_pages,
+            classes,
+        )?;
+        elf.large_huge_pages = HugePages::from_env_var(b"ELFMALLOC_LARGE_HUGE_PAGES\0")
+            .unwrap_or(builder.large_huge_pages);
+        elf.large_huge_threshold = env_size(b"ELFMALLOC_LARGE_HUGE_THRESHOLD\0")
+            .unwrap_or(builder.large_huge_threshold);
+        if env_flag(b"ELFMALLOC_ZERO_ON_FREE\0").unwrap_or(builder.zero_on_free) {
+            // No clones exist yet, so nothing else reads the metadata.
+            elf.allocs.foreach_mut(|class| unsafe {
+                (*class_metadata(class)).zero_on_free = true;
+            });
+        }
+        Ok(elf)
+    }
+}
+
+#[inline(always)]
+unsafe fn round_to_page<T>(item: *mut T) -> *mut T {
+    ((item as usize) & !(ELFMALLOC_PAGE_SIZE - 1)) as *mut T
+}
+
+/// We ensure that for every pointer returned from a call to `alloc` on a size class, rounding that
+/// pointer down to a 2MiB boundary yields the location of an `AllocType`. This is enforced
+/// separately in the `PageAlloc` code and the `Slag` code. Large allocations are instead found in
+/// the side table of the `large_alloc` module, which is only consulted for pointers aligned to
+/// `ELFMALLOC_SMALL_CUTOFF`, as every large allocation is.
+///
+/// All of this allows us to run elfmalloc with a full malloc-style interface without resorting to
+/// any sort of global ownership check on the underlying `MemorySource`. This method thus breaks
+/// our dependency on the `Creek`.
+#[inline(always)]
+unsafe fn get_type(item: *mut u8) -> AllocType {
+    if large_alloc::is_large(item) {
+        return AllocType::Large;
+    }
+    *round_to_page(item.offset(-1) as *mut AllocType)
+}
+
+/// Zero `item`, an object described by `meta`, if its class zeroes objects when they are freed.
+///
+/// This is done before the object is returned to its class. Pages whose objects have all been
+/// freed may later be uncommitted, but their objects have already been zeroed by then, so
+/// uncommitting does not have to do so again.
+#[inline(always)]
+unsafe fn scrub(meta: &Metadata, item: *mut u8) {
+    if unlikely(meta.zero_on_free) {
+        ptr::write_bytes(item, 0, meta.object_size);
+    }
+}
+
+/// Count `n` objects of the class of `meta` as allocated, for `DynamicAllocator::stats`.
+#[inline(always)]
+unsafe fn count_live(meta: *const Metadata, n: usize) {
+    if let Some(counters) = (*meta).counters.as_ref() {
+        counters.live.add(n)
+    }
+}
+
+/// Count `n` objects of the class of `meta` as freed, for `DynamicAllocator::stats`. Returns
+/// whether the class no longer has any live objects.
+///
+/// This is never true if the `no_stats` feature is enabled.
+#[inline(always)]
+unsafe fn count_freed(meta: *const Metadata, n: usize) -> bool {
+    match (*meta).counters.as_ref() {
+        Some(counters) => counters.live.sub_to_zero(n),
+        None => false,
+    }
+}
+
+/// Allocate an object from `class`, or return null if there is no memory for it. If this handle
+/// has not used the class yet, its cache for the class is set up first, which can fail the same
+/// way.
+#[inline(always)]
+unsafe fn class_alloc<CA: CoarseAllocator>(class: &mut ObjectAlloc<CA>) -> *mut u8 {
+    if unlikely(class.ensure_initialized().is_none()) {
+        return ptr::null_mut();
+    }
+    class.alloc()
+}
+
+/// Like `class_alloc`, but also report whether the object reads as zeros; see
+/// `Frontend::alloc_fresh`.
+#[inline(always)]
+unsafe fn class_alloc_fresh<CA: CoarseAllocator>(class: &mut ObjectAlloc<CA>) -> (*mut u8, bool) {
+    if unlikely(class.ensure_initialized().is_none()) {
+        return (ptr::null_mut(), false);
+    }
+    class.alloc_fresh()
+}
+
+/// Return `item` to `class`, the size class described by `meta`, or queue its free if the class
+/// is frozen. This is the part of freeing a small object shared by `free` and `free_batch`.
+#[inline(always)]
+unsafe fn free_to_class<CA: CoarseAllocator>(
+    meta: &Metadata,
+    class: &mut ObjectAlloc<CA>,
+    item: *mut u8,
+) {
+    if unlikely(freeze::any_frozen()) && freeze::queue_free(meta, item) {
+        return;
+    }
+    scrub(meta, item);
+    let drained = count_freed(meta, 1);
+    class.free(item);
+    if unlikely(drained) {
+        reclaim_drained(class);
+    }
+}
+
+/// Called when `class` no longer has any live objects: if this handle's cache holds at least a
+/// slag's worth of them, it is dropped with `reclaim_cache`.
+///
+/// Workloads that keep allocating and freeing a few objects of a class pass through zero live
+/// objects all the time, but their caches stay small, so they are not affected.
+#[cold]
+unsafe fn reclaim_drained<CA: CoarseAllocator>(class: &mut ObjectAlloc<CA>) {
+    let n_objects = (*class_metadata(class)).n_objects;
+    let cached = class.try_get().and_then(|cache| cache.stats().cached_objects);
+    if cached.unwrap_or(0) >= n_objects {
+        reclaim_cache(class);
+    }
+}
+
+/// Drop this handle's cache for `class`, returning its objects, and the slag it is allocating
+/// from, to the class. Slags whose objects are all free are returned to the page allocator, where
+/// any class can use them. The cache is set up again the next time the class is used.
+unsafe fn reclaim_cache<CA: CoarseAllocator>(class: &mut ObjectAlloc<CA>) {
+    // returning objects would write to a frozen class's pages
+    if freeze::any_frozen() && freeze::is_frozen(class_metadata(class)) {
+        return;
+    }
+    class.reset();
+}
+
+/// Register the `pthread_atfork` handlers that keep the global heap usable in the child of a
+/// `fork`. This is done when the first allocator is created, so that programs using only their own
+/// `DynamicAllocator`s can fork safely too.
+#[cfg(unix)]
+fn register_fork_handlers() {
+    extern crate libc;
+    use std::sync::{Once, ONCE_INIT};
+    static REGISTER: Once = ONCE_INIT;
+    unsafe extern "C" fn prepare() {
+        global::prepare_fork()
+    }
+    unsafe extern "C" fn parent() {
+        global::parent_after_fork()
+    }
+    unsafe extern "C" fn child() {
+        global::child_after_fork()
+    }
+    REGISTER.call_once(|| unsafe {
+        alloc_assert_eq!(libc::pthread_atfork(Some(prepare), Some(parent), Some(child)), 0);
+    });
+}
+
+/// Get a pointer to the id of the thread that allocated `item`, which came from an allocator with
+/// the given page sizes.
+#[cfg(feature = "owner_debug")]
+unsafe fn owner_slot(
+    item: *mut u8,
+    small_page_size: usize,
+    large_page_size: usize,
+    huge_page_size: usize,
+) -> *mut u16 {
+    let page_size = match get_type(item) {
+        AllocType::SmallSlag => small_page_size,
+        AllocType::BigSlag => large_page_size,
+        AllocType::HugeSlag => huge_page_size,
+        AllocType::Large => return &mut (*large_alloc::get_commitment_mut(item)).owner,
+    };
+    let slag = Slag::find(item, page_size);
+    &mut (*Slag::side_entry(slag, item, (*slag).get_metadata())).owner
+}
+
+impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>> Clone
+    for ElfMalloc<PageAlloc<M, D>, AM> {
+    fn clone(&self) -> Self {
+        let mut classes = self.allocs.iter();
+        let new_map = self.allocs.init_like(|size: usize| {
+            let (key, class) = classes.next().unwrap();
+            alloc_debug_assert_eq!(key, size);
+            class.clone()
+        });
+        ElfMalloc {
+            small_pages: self.small_pages.clone(),
+            large_pages: self.large_pages.clone(),
+            huge_pages: self.huge_pages.clone(),
+            allocs: new_map,
+            max_size: self.max_size,
+            small_cutoff: self.small_cutoff,
+            medium_aligned: self.medium_aligned,
+            small_page_size: self.small_page_size,
+            large_page_size: self.large_page_size,
+            huge_page_size: self.huge_page_size,
+            large_huge_pages: self.large_huge_pages,
+            large_huge_threshold: self.large_huge_threshold,
+            limit: self.limit.clone(),
+            large_regions: self.large_regions.clone(),
+            oom_handler: self.oom_handler,
+            extension: self.extension.clone(),
+            #[cfg(feature = "debug_ownership")]
+            instance: self.instance,
+            #[cfg(feature = "profiling")]
+            profile: self.profile.like(),
+            generation: self.generation.clone(),
+            class_uses: Vec::new(),
+        }
+    }
+}
+
+impl<CA: CoarseAllocator> ElfMalloc<CA, SizeClasses<ObjectAlloc<CA>>> {
+    /// Drop the classes of `self`, returning their pages to the page allocators. Once the last
+    /// clone's classes are dropped, dropping the page allocators releases all of the memory.
+    ///
+    /// `self` must not be used afterwards, other than to drop it.
+    unsafe fn destroy_classes(&mut self) {
+        self.allocs.destroy()
+    }
+}
+
+/// The alignment of the objects of a class of `object_size` bytes; see `compute_metadata`.
+fn class_alignment(object_size: usize) -> usize {
+    if object_size.is_power_of_two() {
+        object_size
+    } else {
+        cmp::min(object_size & object_size.wrapping_neg(), MULTIPLE)
+    }
+}
+
+unsafe fn elfmalloc_get_layout(page_size: usize, item: *mut u8) -> (usize, usize) {
+    match get_type(item) {
+        AllocType::SmallSlag | AllocType::BigSlag | AllocType::HugeSlag => {
+            let meta = (*Slag::find(item, page_size)).get_metadata();
+            (meta.object_size, class_alignment(meta.object_size))
+        }
+        AllocType::Large => (large_alloc::get_size(item), mmap::page_size()),
+    }
+}
+
+impl<M: MemorySource, D: DirtyFn>
+    ElfMalloc<PageAlloc<M, D>, SizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    fn new_internal(
+        // usable_size: usize,
+        cutoff_factor: f64,
+        magazines: MagazineSpec,
+        small_cutoff: usize,
+        mut pa_small: PageAlloc<M, D>,
+        mut pa_large: PageAlloc<M, D>,
+        mut pa_huge: Option<PageAlloc<M, D>>,
+        huge_page_policy: HugePages,
+        classes: ClassSpec,
+    ) -> Result<Self, ConfigError> {
+        // The pages and large allocations of this allocator and all of its clones count against
+        // the same limit.
+        let limit = Arc::new(MemoryLimit::new());
+        // The metadata of the classes stays mapped until the last page that may refer to it is
+        // released. This includes the classes that may be appended by extend_classes.
+        let n_info = classes.max_classes() + classes.max_appended_classes();
+        let info_size = mem::size_of::<ClassInfo>() * n_info;
+        let info = Arc::new(OwnedArray::<u8>::new(info_size));
+        pa_small.set_limit(limit.clone());
+        pa_small.retain(info.clone());
+        pa_large.set_limit(limit.clone());
+        pa_large.retain(info.clone());
+        if let Some(ref mut pa_huge) = pa_huge {
+            pa_huge.set_limit(limit.clone());
+            pa_huge.retain(info.clone());
+        }
+        #[cfg(feature = "debug_ownership")]
+        let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+        let first_info = unsafe { info.get(0) } as *mut ClassInfo;
+        let mut info_pointer = first_info;
+        let small_page_size = pa_small.backing_memory().page_size();
+        // The first error encountered while constructing a class. `SizeClasses::new` cannot be
+        // short-circuited, so classes after a failure are still constructed. They are never
+        // initialized, and are dropped before the error is returned.
+        let mut err = None;
+        let mut medium_aligned = true;
+        let mut am = SizeClasses::new(classes, |size: usize| {
+            if size >= small_cutoff && !size.is_power_of_two() {
+                medium_aligned = false;
+                if classes.requires_aligned_medium() {
+                    err = err.or(Some(ConfigError::NonPowerOfTwoClass {
+                        size: size,
+                        small_cutoff: small_cutoff,
+                    }));
+                }
+            }
+            let pages =
+                Self::class_pages(size, small_cutoff, &pa_small, &pa_large, pa_huge.as_ref());
+            let class_info = info_pointer;
+            unsafe {
+                info_pointer = info_pointer.offset(1);
+                let (class, res) =
+                    Self::new_class(size, class_info, cutoff_factor, magazines, pages);
+                match res {
+                    Ok(()) => {
+                        #[cfg(feature = "debug_ownership")]
+                        {
+                            (*class_info).meta.instance = instance;
+                        }
+                    }
+                    Err(e) => err = err.or(Some(e)),
+                }
+                class
+            }
+        });
+        if let Some(e) = err {
+            // The metadata of the classes is unmapped once the page allocators, which hold the
+            // last references to it, are dropped on return.
+            unsafe { am.destroy() };
+            return Err(e);
+        }
+        let max_size = am.max_key();
+        let extension = match classes {
+            ClassSpec::Tiered { .. } => Some(Arc::new(Extension {
+                max_size: AtomicUsize::new(max_size),
+                huge_page_size: AtomicUsize::new(0),
+                state: Mutex::new(ExtensionState {
+                    classes: Vec::new(),
+                    huge_pages: None,
+                    next_info: unsafe { first_info.offset(classes.max_classes() as isize) },
+                    cutoff_factor: cutoff_factor,
+                    magazines: magazines,
+                    huge_page_policy: huge_page_policy,
+                }),
+            })),
+            ClassSpec::Geometric { .. } | ClassSpec::Custom(_) => None,
+        };
+        let huge_page_size = pa_huge.as_ref().map_or(0, |pa| pa.backing_memory().page_size());
+        #[cfg(feature = "profiling")]
+        let profile = {
+            let sizes: Vec<usize> = am.iter().map(|(size, _)| size).collect();
+            Profile::new(&sizes, classes.max_appended_classes())
+        };
+        Ok(ElfMalloc {
+            small_pages: pa_small.clone(),
+            large_pages: pa_large.clone(),
+            huge_pages: pa_huge,
+            allocs: am,
+            max_size: max_size,
+            small_cutoff: small_cutoff,
+            medium_aligned: medium_aligned,
+            small_page_size: small_page_size,
+            large_page_size: pa_large.backing_memory().page_size(),
+            huge_page_size: huge_page_size,
+            large_huge_pages: HugePages::Never,
+            large_huge_threshold: usize::max_value(),
+            limit: limit,
+            large_regions: Arc::new(RangeSet::new()),
+            oom_handler: None,
+            extension: extension,
+            #[cfg(feature = "debug_ownership")]
+            instance: instance,
+            #[cfg(feature = "profiling")]
+            profile: profile,
+            generation: Arc::new(AtomicUsize::new(0)),
+            class_uses: Vec::new(),
+        })
+    }
+
+    /// Append power-of-two classes until the largest class is at least `new_max_size`; see
+    /// `DynamicAllocator::extend_classes`.
+    fn extend_classes(&mut self, new_max_size: usize) -> Result<(), ConfigError> {
+        let extension = match self.extension {
+            Some(ref extension) => extension.clone(),
+            None => return Err(ConfigError::FixedClasses),
+        };
+        if new_max_size > ELFMALLOC_MAX_CLASS_SIZE {
+            return Err(ConfigError::ClassTooLarge {
+                size: new_max_size,
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            });
+        }
+        let mut state = extension.state.lock().unwrap();
+        self.catch_up_with(&state);
+        while self.max_size < new_max_size {
+            let size = self.max_size * 2;
+            if self.huge_pages.is_none() && size > self.large_page_size / 2 {
+                // Each huge page holds a single object, so pages of twice the largest class fit
+                // every class up to it.
+                let page_size = 2 * new_max_size.next_power_of_two();
+                let align = cmp::max(page_size, ELFMALLOC_PAGE_SIZE);
+                let mut pa_huge =
+                    self.large_pages.with_page_size(page_size, 8, align, AllocType::HugeSlag);
+                pa_huge.set_huge_pages(state.huge_page_policy);
+                extension.huge_page_size.store(page_size, Ordering::Release);
+                self.huge_page_size = page_size;
+                self.huge_pages = Some(pa_huge.clone());
+                state.huge_pages = Some(pa_huge);
+            }
+            let pages = Self::class_pages(
+                size,
+                self.small_cutoff,
+                &self.small_pages,
+                &self.large_pages,
+                self.huge_pages.as_ref(),
+            );
+            let info = state.next_info;
+            let (cutoff_factor, magazines) = (state.cutoff_factor, state.magazines);
+            let (class, res) =
+                unsafe { Self::new_class(size, info, cutoff_factor, magazines, pages) };
+            res?;
+            unsafe {
+                let largest = class_metadata(self.allocs.get(self.max_size));
+                (*info).meta.zero_on_free = (*largest).zero_on_free;
+                #[cfg(feature = "debug_ownership")]
+                {
+                    (*info).meta.instance = self.instance;
+                }
+                state.next_info = info.offset(1);
+            }
+            let appended = self.allocs.append_class(class.clone()).is_ok();
+            alloc_debug_assert!(appended);
+            state.classes.push(class);
+            self.max_size = size;
+            extension.max_size.store(size, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// The pages of the class of `size` bytes, along with the usable size of each page and their
+    /// type: `pa_small` below `small_cutoff`, `pa_huge` (if there is one) for objects larger than
+    /// half a large page, and `pa_large` for all others.
+    fn class_pages(
+        size: usize,
+        small_cutoff: usize,
+        pa_small: &PageAlloc<M, D>,
+        pa_large: &PageAlloc<M, D>,
+        pa_huge: Option<&PageAlloc<M, D>>,
+    ) -> (usize, PageAlloc<M, D>, AllocType) {
+        let large_page_size = pa_large.backing_memory().page_size();
+        match pa_huge {
+            _ if size < small_cutoff => {
+                (pa_small.backing_memory().page_size(), pa_small.clone(), AllocType::SmallSlag)
+            }
+            // A usable size of 0 puts a single object in each page.
+            Some(pa_huge) if size > large_page_size / 2 => {
+                (0, pa_huge.clone(), AllocType::HugeSlag)
+            }
+            _ => (large_page_size, pa_large.clone(), AllocType::BigSlag),
+        }
+    }
+
+    /// Create the class of `size` bytes whose pages are handed out by `pa`, writing its metadata
+    /// to `info`. `u_size` and `ty` are the usable size and type of the pages, as returned by
+    /// `class_pages`. The capacity of the class's caches is taken from `magazines`.
+    ///
+    /// The class is returned even if its metadata cannot be computed, along with the error. Such
+    /// a class must not be used other than to drop it.
+    unsafe fn new_class(
+        size: usize,
+        info: *mut ClassInfo,
+        cutoff_factor: f64,
+        magazines: MagazineSpec,
+        (u_size, pa, ty): (usize, PageAlloc<M, D>, AllocType),
+    ) -> (ObjectAlloc<PageAlloc<M, D>>, Result<(), ConfigError>) {
+        let m_ptr = &mut (*info).meta as *mut Metadata;
+        let counters = &(*info).counters as *const _;
+        let page_size = pa.backing_memory().page_size();
+        let res = compute_metadata(size, page_size, 0, cutoff_factor, u_size, ty).map(|meta| {
+            ptr::write(m_ptr, meta);
+            (*m_ptr).counters = counters;
+        });
+        let clean = PageCleanup::retained();
+        // TODO(ezrosent); new_size(8) is a good default, but a better one would take
+        // num_cpus::get() into account when picking this size, as in principle this will run
+        // into scaling limits at some point.
+        let params = (
+            m_ptr,
+            1 << 20,
+            pa,
+            RevocablePipe::new_size_cleanup(16, clean),
+            magazines.for_class(size),
+        );
+        #[cfg(not(feature = "magazine_layer"))]
+        {
+            (ObjectAlloc::new(params), res)
+        }
+        #[cfg(feature = "magazine_layer")]
+        {
+            (ObjectAlloc::new((params, magazines.depot())), res)
+        }
+    }
+}
+
+impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>>
+    ElfMalloc<PageAlloc<M, D>, AM> {
+    /// Let this handle's adaptive caches decay, then uncommit the cached free pages of
+    /// `small_pages`, `large_pages` and `huge_pages` beyond `TRIM_RETAINED_PAGES` each,
+    /// returning the number of bytes released.
+    fn trim(&mut self) -> usize {
+        // the huge pages may have been created through a clone
+        self.catch_up();
+        // objects returned by shrinking caches may leave pages to uncommit
+        unsafe { self.decay_caches() };
+        self.small_pages.trim(TRIM_RETAINED_PAGES) + self.large_pages.trim(TRIM_RETAINED_PAGES) +
+            self.huge_pages.as_mut().map_or(0, |pa| pa.trim(TRIM_RETAINED_PAGES)) +
+            unsafe { large_alloc::flush_cache() }
+    }
+
+    /// Drop this handle's cache for the class of `bytes`; see `reclaim_cache`.
+    unsafe fn reclaim_class(&mut self, bytes: usize) {
+        if let Some(class) = self.allocs.try_get_raw(bytes) {
+            reclaim_cache(&mut *class);
+        }
+    }
+
+    /// Drop this handle's caches for the classes it has not allocated from in the last
+    /// `older_than` generations, returning the number of caches dropped; see
+    /// `DynamicAllocator::reclaim_cold`.
+    unsafe fn reclaim_cold(&mut self, older_than: usize) -> usize {
+        self.catch_up();
+        let generation = self.generation.load(Ordering::Relaxed);
+        let mut uses = mem::replace(&mut self.class_uses, Vec::new());
+        let (mut i, mut n_reclaimed) = (0, 0);
+        self.allocs.foreach_mut(|class| {
+            let allocs = class.try_get().and_then(|cache| cache.stats().total_allocated);
+            let last = ClassUse {
+                allocs: allocs,
+                generation: generation,
+            };
+            if i == uses.len() {
+                // we do not know when a class we have not seen before was last used
+                uses.push(last);
+            } else if uses[i].allocs != allocs {
+                uses[i] = last;
+            } else if allocs.is_some() &&
+                generation.wrapping_sub(uses[i].generation) >= older_than
+            {
+                reclaim_cache(class);
+                // the caches of frozen classes are kept
+                if class.try_get().is_none() {
+                    uses[i].allocs = None;
+                    n_reclaimed += 1;
+                }
+            }
+            i += 1;
+        });
+        self.class_uses = uses;
+        n_reclaimed
+    }
+
+    /// Empty the caches of the classes this handle has used, after freeing the objects of any
+    /// class that were freed by threads without a handle.
+    unsafe fn flush_caches(&mut self) {
+        self.allocs.foreach_mut(|class| {
+            let meta = class_metadata(class);
+            // returning objects would write to a frozen class's pages
+            if freeze::any_frozen() && freeze::is_frozen(meta) {
+                return;
+            }
+            if remote::any_pending() {
+                // the cache is emptied right after, so reclaim_drained is not needed
+                remote::drain(meta, |item| {
+                    scrub(&*meta, item);
+                    count_freed(meta, 1);
+                    class.free(item);
+                });
+            }
+            if let Some(cache) = class.try_get_mut() {
+                cache.shrink_to(0);
+            }
+        });
+    }
+
+    /// Let the adaptive caches of this handle shrink; see `DynamicAllocator::decay_caches`.
+    unsafe fn decay_caches(&mut self) {
+        self.allocs.foreach_mut(|class| {
+            // shrinking a cache would write to a frozen class's pages
+            if freeze::any_frozen() && freeze::is_frozen(class_metadata(class)) {
+                return;
+            }
+            if let Some(cache) = class.try_get_mut() {
+                cache.decay();
+            }
+        });
+    }
+
+    fn stats(&self) -> ElfStats {
+        let (mut live, mut taken) = (0, 0);
+        self.foreach_class(|class| {
+            let meta = unsafe { &*class_metadata(class) };
+            if let Some(counters) = meta.counters.as_ref() {
+                live += counters.live.get() * meta.object_size;
+                taken += counters.taken.get() * meta.object_size;
+            }
+        });
+        ElfStats {
+            live_bytes: live,
+            // objects may be taken and allocated between reading the two counts
+            cached_bytes: taken.saturating_sub(live),
+            small_page_bytes: self.small_pages.mapped_bytes(),
+            large_page_bytes: self.large_pages.mapped_bytes(),
+            huge_page_bytes: self.huge_pages.as_ref().map_or(0, |pa| pa.mapped_bytes()),
+            large_bytes: self.limit.large.get(),
+            large: large_alloc::stats(),
+        }
+    }
+
+    /// The counts of each size class, in increasing order of size.
+    fn class_stats(&self) -> Vec<ClassStats> {
+        let mut res = Vec::new();
+        self.foreach_class(|class| {
+            let meta = unsafe { &*class_metadata(class) };
+            let (live, taken, slags) = meta.counters.as_ref().map_or((0, 0, 0), |counters| {
+                (counters.live.get(), counters.taken.get(), counters.slags.get())
+            });
+            res.push(ClassStats {
+                object_size: meta.object_size,
+                live_objects: live,
+                cached_objects: taken.saturating_sub(live),
+                slags: slags,
+                initialized: class.try_get().is_some(),
+            });
+        });
+        res
+    }
+
+    /// Set up this handle's cache for each class serving sizes from `min` to `max`, returning
+    /// the number of caches set up; see `DynamicAllocator::warm_up_range`.
+    fn warm_up(&mut self, min: usize, max: usize) -> usize {
+        // warm up the classes appended through clones too
+        self.catch_up();
+        let mut n_caches = 0;
+        let mut size = min;
+        while size <= max {
+            let class = match self.allocs.try_get_raw(size) {
+                Some(class) => unsafe { &*class },
+                None => break,
+            };
+            let meta = class_metadata(class);
+            // setting up the cache would take a slag of a frozen class
+            let frozen = freeze::any_frozen() && unsafe { freeze::is_frozen(meta) };
+            if !frozen && class.ensure_initialized() == Some(true) {
+                n_caches += 1;
+            }
+            size = unsafe { (*meta).object_size } + 1;
+        }
+        n_caches
+    }
+
+    /// Get enough clean pages for `count` objects of the class for `bytes`, then fill this
+    /// handle's cache for the class with up to `count` objects, returning false if the pages
+    /// cannot be had.
+    unsafe fn reserve_for_class(&mut self, bytes: usize, count: usize) -> bool {
+        let class = match self.allocs.try_get_raw(bytes) {
+            Some(class) if count > 0 => class,
+            _ => return true,
+        };
+        let meta = &*class_metadata(&*class);
+        if unlikely(freeze::any_frozen()) && freeze::is_frozen(meta) {
+            return true;
+        }
+        // The same choice of pages as in new_internal.
+        let size = meta.object_size;
+        let huge = self.huge_pages.is_some() && size > self.large_page_size / 2;
+        let pages = if size < self.small_cutoff {
+            &mut self.small_pages
+        } else if huge {
+            self.huge_pages.as_mut().unwrap()
+        } else {
+            &mut self.large_pages
+        };
+        // Reserving before the cache is set up means setting it up cannot run out of memory.
+        if !pages.reserve((count - 1) / meta.n_objects + 1) ||
+            (*class).ensure_initialized().is_none()
+        {
+            return false;
+        }
+        (*class).fill(count);
+        true
+    }
+
+    /// Map a large allocation of `bytes` bytes aligned to `align`, counting it against `limit`.
+    unsafe fn alloc_large(&self, bytes: usize, align: usize) -> *mut u8 {
+        let huge_pages = self.large_huge_pages_for(bytes);
+        self.track_large(large_alloc::alloc_limited(bytes, align, Some(&self.limit), huge_pages))
+    }
+
+    /// Like `alloc_large`, but the allocation reads as zeros. It is only written to if its region
+    /// is not known to be zero.
+    unsafe fn alloc_large_zeroed(&self, bytes: usize, align: usize) -> *mut u8 {
+        let huge_pages = self.large_huge_pages_for(bytes);
+        let res = large_alloc::alloc_limited_zeroed(bytes, align, Some(&self.limit), huge_pages);
+        self.track_large(res)
+    }
+
+    /// The huge page policy for a large allocation of `bytes` bytes.
+    fn large_huge_pages_for(&self, bytes: usize) -> HugePages {
+        if bytes >= self.large_huge_threshold {
+            self.large_huge_pages
+        } else {
+            HugePages::Never
+        }
+    }
+
+    /// Record `res`, a large allocation just made for this allocator, in `large_regions`.
+    unsafe fn track_large(&self, res: *mut u8) -> *mut u8 {
+        if !res.is_null() {
+            let (region_size, base) = large_alloc::get_commitment(res);
+            self.large_regions.insert(base, region_size);
+        }
+        #[cfg(feature = "debug_ownership")]
+        {
+            if !res.is_null() {
+                (*large_alloc::get_commitment_mut(res)).instance = self.instance;
+            }
+        }
+        res
+    }
+
+    /// Unmap the large allocation `item`.
+    unsafe fn free_large(&self, item: *mut u8) {
+        let (_, base) = large_alloc::get_commitment(item);
+        self.large_regions.remove(base);
+        large_alloc::free(item)
+    }
+
+    /// Does `item` point into memory mapped by this allocator or one of its clones?
+    ///
+    /// Only the addresses of mapped regions are compared, so `item` is never dereferenced.
+    fn contains(&self, item: *mut u8) -> bool {
+        self.pages_contain(item) || self.large_regions.contains(item)
+    }
+
+    /// Does `item` point into the pages of this allocator's size classes?
+    fn pages_contain(&self, item: *mut u8) -> bool {
+        if self.small_pages.contains(item) || self.large_pages.contains(item) {
+            return true;
+        }
+        match (&self.huge_pages, &self.extension) {
+            (&Some(ref pa), _) => pa.contains(item),
+            // the huge pages may have been created by extend_classes through a clone
+            (&None, &Some(ref extension)) => {
+                let state = extension.state.lock().unwrap();
+                state.huge_pages.as_ref().map_or(false, |pa| pa.contains(item))
+            }
+            (&None, &None) => false,
+        }
+    }
+
+    /// The page size of `huge_pages`.
+    ///
+    /// A handle without huge pages reads it from `extension` instead: it may have to free objects
+    /// of the classes appended through its clones before it catches up with them.
+    #[inline]
+    fn huge_page_size(&self) -> usize {
+        let page_size = match self.extension {
+            Some(ref extension) if self.huge_page_size == 0 => {
+                extension.huge_page_size.load(Ordering::Acquire)
+            }
+            _ => self.huge_page_size,
+        };
+        alloc_debug_assert!(page_size > 0, "huge slag without huge pages");
+        page_size
+    }
+
+    /// Clone the classes appended by `extend_classes` through clones of `self` since `self` was
+    /// cloned or last caught up, returning whether there were any.
+    ///
+    /// This is done the first time `self` allocates a size larger than its largest class, or
+    /// frees an object of a class that it does not have.
+    #[cold]
+    fn catch_up(&mut self) -> bool {
+        let extension = match self.extension {
+            Some(ref extension) if extension.max_size.load(Ordering::Acquire) > self.max_size => {
+                extension.clone()
+            }
+            _ => return false,
+        };
+        self.catch_up_with(&extension.state.lock().unwrap());
+        true
+    }
+
+    /// Clone the classes of `state` larger than `max_size`, along with its huge pages if `self`
+    /// has none.
+    fn catch_up_with(&mut self, state: &ExtensionState<PageAlloc<M, D>>) {
+        for class in &state.classes {
+            let size = unsafe { (*class_metadata(class)).object_size };
+            if size > self.max_size {
+                let appended = self.allocs.append_class(class.clone()).is_ok();
+                alloc_debug_assert!(appended);
+                self.max_size = size;
+            }
+        }
+        if self.huge_pages.is_none() {
+            if let Some(ref pa) = state.huge_pages {
+                self.huge_page_size = pa.backing_memory().page_size();
+                self.huge_pages = Some(pa.clone());
+            }
+        }
+    }
+
+    /// Call `f` on each class of `self`, and on each class appended through its clones that
+    /// `self` has not caught up with yet, in increasing order of size.
+    fn foreach_class<F: FnMut(&ObjectAlloc<PageAlloc<M, D>>)>(&self, mut f: F) {
+        for (_, class) in self.allocs.iter() {
+            f(class);
+        }
+        let extension = match self.extension {
+            Some(ref extension) if extension.max_size.load(Ordering::Acquire) > self.max_size => {
+                extension
+            }
+            _ => return,
+        };
+        for class in &extension.state.lock().unwrap().classes {
+            if unsafe { (*class_metadata(class)).object_size } > self.max_size {
+                f(class);
+            }
+        }
+    }
+
+    /// Abort if `item` is a large allocation that has already been freed, rather than read the
+    /// header of a page that may no longer be mapped.
+    ///
+    /// The side table remembers that the last large allocation at an address was freed. If the
+    /// pages of one of our size classes have been mapped there since, `item` is one of their
+    /// objects instead, and is freed as usual.
+    #[inline]
+    unsafe fn check_double_free(&self, item: *mut u8) {
+        if (item as usize) % ELFMALLOC_SMALL_CUTOFF == 0 &&
+            unlikely(large_alloc::was_freed(item)) && !self.pages_contain(item)
+        {
+            large_alloc::double_free(item);
+        }
+    }
+
+    /// Get a pointer to the id of the thread that allocated `item`.
+    #[cfg(feature = "owner_debug")]
+    unsafe fn owner_slot(&self, item: *mut u8) -> *mut u16 {
+        owner_slot(item, self.small_page_size, self.large_page_size, self.huge_page_size())
+    }
+
+    /// Abort if `item`, whose page size is given by `get_page_size`, was allocated by an
+    /// allocator other than this one and its clones.
+    #[cfg(feature = "debug_ownership")]
+    unsafe fn check_owner(&self, item: *mut u8, page_size: Option<usize>) {
+        let instance = match page_size {
+            Some(page_size) => (*Slag::find(item, page_size)).get_metadata().instance,
+            None => (*large_alloc::get_commitment_mut(item)).instance,
+        };
+        if instance != self.instance && instance != UNOWNED_INSTANCE {
+            #[cfg(feature = "owner_debug")]
+            alloc_panic!(
+                "ownership check failed: {:?} was allocated by allocator {} on {}, but freed by \
+                 allocator {}",
+                item,
+                instance,
+                owner::Owner(*self.owner_slot(item)),
+                self.instance
+            );
+            #[cfg(not(feature = "owner_debug"))]
+            alloc_panic!(
+                "ownership check failed: {:?} was allocated by allocator {}, but freed by allocator {}",
+                item,
+                instance,
+                self.instance
+            );
+        }
+    }
+
+    #[inline]
+    unsafe fn get_page_size(&self, item: *mut u8) -> Option<usize> {
+        // We have carfeully orchestrated things so that allocation sizes above the cutoff are
+        // aligned to at least that cutoff:
+        // - Medium objects are powers of two, all of which are aligned to their size. This includes
+        //   objects of huge classes, however large the largest class is.
+        // - Large objects are allocated using an MmapSource with page size equivalent to the
+        //   cutoff.
+        // As a result, we do not have to dereference an extra pointer for small objects that are
+        // not aligned to the small cutoff (this is going to be most of them). This netted
+        // small-but-noticeable performance gains.
+        //
+        // Geometric medium classes are not powers of two, so their objects may be anywhere in
+        // their pages. Allocators with such classes always read the page's type.
+        if likely(self.medium_aligned) && (item as usize) % self.small_cutoff != 0 {
+            return Some(self.small_page_size);
+        }
+        match get_type(item) {
+            AllocType::SmallSlag => {
+                alloc_debug_assert_eq!(self.small_pages.backing_memory().page_size(), self.small_page_size);
+                Some(self.small_page_size)
+            },
+            AllocType::BigSlag => {
+                alloc_debug_assert_eq!(self.large_pages.backing_memory().page_size(), self.large_page_size);
+                Some(self.large_page_size)
+            },
+            AllocType::HugeSlag => Some(self.huge_page_size()),
+            AllocType::Large => None,
+        }
+    }
+
+    unsafe fn alloc(&mut self, bytes: usize) -> *mut u8 {
+        let res = if likely(bytes <= self.max_size) {
+            #[cfg(feature = "profiling")]
+            self.count_requests(bytes, 1);
+            if unlikely(freeze::any_frozen()) {
+                self.alloc_overflow(bytes)
+            } else {
+                if unlikely(remote::any_pending()) {
+                    let meta = class_metadata(&*self.allocs.get_raw(bytes));
+                    self.free_remote(meta);
+                }
+                let class = self.allocs.get_mut(bytes);
+                let res = class_alloc(class);
+                if likely(!res.is_null()) {
+                    count_live(class_metadata(class), 1);
+                }
+                res
+            }
+        } else if self.catch_up() {
+            return self.alloc(bytes);
+        } else {
+            #[cfg(feature = "profiling")]
+            self.count_requests(bytes, 1);
+            self.alloc_large(bytes, ELFMALLOC_SMALL_CUTOFF)
+        };
+        #[cfg(feature = "owner_debug")]
+        {
+            if !res.is_null() {
+                *self.owner_slot(res) = owner::current();
+            }
+        }
+        res
+    }
+
+    /// Like `alloc`, but also return the size `get_layout` reports for the object, or 0 if no
+    /// object could be allocated.
+    unsafe fn alloc_excess(&mut self, bytes: usize) -> (*mut u8, usize) {
+        if unlikely(bytes > self.max_size || freeze::any_frozen()) {
+            // Objects of frozen classes come from larger classes, so the class is only known
+            // after the allocation.
+            let res = self.alloc(bytes);
+            return (res, if res.is_null() { 0 } else { self.get_layout(res).0 });
+        }
+        #[cfg(feature = "profiling")]
+        self.count_requests(bytes, 1);
+        let (res, meta) = {
+            let class = self.allocs.get_mut(bytes);
+            (class_alloc(class), class_metadata(class))
+        };
+        if res.is_null() {
+            return (res, 0);
+        }
+        count_live(meta, 1);
+        #[cfg(feature = "owner_debug")]
+        {
+            *self.owner_slot(res) = owner::current();
+        }
+        (res, (*meta).object_size)
+    }
+
+    unsafe fn alloc_zeroed(&mut self, bytes: usize) -> *mut u8 {
+        let res = if likely(bytes <= self.max_size) {
+            #[cfg(feature = "profiling")]
+            self.count_requests(bytes, 1);
+            if unlikely(freeze::any_frozen()) {
+                let res = self.alloc_overflow(bytes);
+                if !res.is_null() {
+                    ptr::write_bytes(res, 0, bytes);
+                }
+                res
+            } else {
+                if unlikely(remote::any_pending()) {
+                    let meta = class_metadata(&*self.allocs.get_raw(bytes));
+                    self.free_remote(meta);
+                }
+                let class = self.allocs.get_mut(bytes);
+                let (res, fresh) = class_alloc_fresh(class);
+                if !res.is_null() {
+                    count_live(class_metadata(class), 1);
+                    if !fresh {
+                        ptr::write_bytes(res, 0, bytes);
+                    }
+                }
+                res
+            }
+        } else if self.catch_up() {
+            return self.alloc_zeroed(bytes);
+        } else {
+            #[cfg(feature = "profiling")]
+            self.count_requests(bytes, 1);
+            self.alloc_large_zeroed(bytes, ELFMALLOC_SMALL_CUTOFF)
+        };
+        #[cfg(feature = "owner_debug")]
+        {
+            if !res.is_null() {
+                *self.owner_slot(res) = owner::current();
+            }
+        }
+        res
+    }
+
+    /// Allocate `bytes` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// The object is allocated from the class chosen by `aligned_class`. Objects that no class
+    /// holds are allocated with the large-allocation path, which aligns them without rounding up
+    /// their size.
+    unsafe fn alloc_aligned(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        alloc_debug_assert!(align.is_power_of_two());
+        if align <= mem::size_of::<usize>() {
+            // objects of tiny classes are only aligned to their size
+            return self.alloc(cmp::max(bytes, align));
+        }
+        if let Some(class) = self.aligned_class(bytes, align) {
+            return self.alloc(class);
+        }
+        #[cfg(feature = "profiling")]
+        self.count_requests(bytes, 1);
+        let res = self.alloc_large(bytes, align);
+        #[cfg(feature = "owner_debug")]
+        {
+            if !res.is_null() {
+                *self.owner_slot(res) = owner::current();
+            }
+        }
+        res
+    }
+
+    /// The object size of the smallest class whose objects hold `bytes` bytes and are aligned to
+    /// `align`, or `None` if there is no such class.
+    ///
+    /// Classes that are powers of two are aligned to their size, and all other classes to at most
+    /// `MULTIPLE` bytes (see `class_alignment`). So a class that is not aligned enough is skipped
+    /// for the next class or, if `align` is larger than `MULTIPLE`, the next power of two. With
+    /// the default classes, this is the class of `max(bytes, align)` rounded up to a power of two
+    /// if `align` is larger than `MULTIPLE`, and the class of `max(bytes, align)` otherwise.
+    /// Objects are aligned to at most the small cutoff, and larger alignments have no class.
+    fn aligned_class(&self, bytes: usize, align: usize) -> Option<usize> {
+        if align > self.small_cutoff {
+            return None;
+        }
+        let mut size = cmp::max(bytes, align);
+        loop {
+            let class = match self.size_class_for(size) {
+                Some(class) => class,
+                None => return None,
+            };
+            if class_alignment(class) >= align {
+                return Some(class);
+            }
+            // classes are no larger than ELFMALLOC_MAX_CLASS_SIZE, so this cannot overflow
+            size = if align > MULTIPLE {
+                (class + 1).next_power_of_two()
+            } else {
+                class + 1
+            };
+        }
+    }
+
+    /// The number of usable bytes in objects allocated by `alloc_aligned(bytes, align)`.
+    ///
+    /// This is the size of the class the objects are allocated from, or `bytes` if they are
+    /// allocated with the large-allocation path.
+    fn usable_size_aligned(&self, bytes: usize, align: usize) -> usize {
+        // this mirrors alloc_aligned
+        if align <= mem::size_of::<usize>() {
+            self.size_class_for(cmp::max(bytes, align)).unwrap_or(bytes)
+        } else {
+            self.aligned_class(bytes, align).unwrap_or(bytes)
+        }
+    }
+
+    /// The size of the objects of the class that `alloc(bytes)` allocates from, or `None` if
+    /// `bytes` is too large for any class.
+    ///
+    /// The class is looked up just as `alloc` does, so this is the usable size of the objects
+    /// `alloc(bytes)` returns, unless the class is frozen.
+    fn size_class_for(&self, bytes: usize) -> Option<usize> {
+        self.allocs
+            .try_get_raw(bytes)
+            .map(|class| unsafe { (*class_metadata(&*class)).object_size })
+    }
+
+    unsafe fn realloc(&mut self, item: *mut u8, new_size: usize, new_alignment: usize) -> *mut u8 {
+        if item.is_null() {
+            return self.alloc_aligned(new_size, new_alignment);
+        }
+        if new_size == 0 {
+            self.free(item);
+            return ptr::null_mut();
+        }
+        #[cfg(feature = "debug_ownership")]
+        {
+            let page_size = self.get_page_size(item);
+            self.check_owner(item, page_size);
+        }
+        let (old_size, _) = self.get_layout(item);
+        if (item as usize) % new_alignment == 0 && old_size >= new_size {
+            return self.shrink(item, old_size, new_size, new_alignment);
+        }
+        if new_size > self.max_size && self.get_page_size(item).is_none() {
+            let (_, old_base) = large_alloc::get_commitment(item);
+            let grown = large_alloc::grow(item, new_size, new_alignment);
+            if !grown.is_null() {
+                if self.large_regions.remove(old_base) {
+                    let (region_size, base) = large_alloc::get_commitment(grown);
+                    self.large_regions.insert(base, region_size);
+                }
+                return grown;
+            }
+        }
+        let new_mem = self.alloc_aligned(new_size, new_alignment);
+        if new_mem.is_null() {
+            // as with C's realloc, the original object is left untouched
+            return new_mem;
+        }
+        #[cfg(test)] REALLOC_COPIES.with(|c| c.set(c.get() + 1));
+        ptr::copy_nonoverlapping(item, new_mem, ::std::cmp::min(old_size, new_size));
+        self.free(item);
+        #[cfg(debug_assertions)]
+        {
+            let (size, _) = self.get_layout(new_mem);
+            alloc_debug_assert!(new_size <= size, "Realloc for {} got memory with size {}", new_size, size);
+        }
+        new_mem
+    }
+
+    /// Shrink `item`, an object of size `old_size` that already has the requested size and
+    /// alignment.
+    ///
+    /// Large objects are shrunk in place by releasing the pages past their new end (see
+    /// `large_alloc::shrink`). Objects of a class at or above the small cutoff are moved to a
+    /// class below it if that at least halves the memory they use. All other objects are left as
+    /// they are.
+    unsafe fn shrink(
+        &mut self,
+        item: *mut u8,
+        old_size: usize,
+        new_size: usize,
+        new_alignment: usize,
+    ) -> *mut u8 {
+        if self.get_page_size(item).is_none() {
+            large_alloc::shrink(item, new_size);
+            let (region_size, base) = large_alloc::get_commitment(item);
+            if self.large_regions.remove(base) {
+                self.large_regions.insert(base, region_size);
+            }
+            return item;
+        }
+        if old_size < self.small_cutoff || new_size >= self.small_cutoff ||
+            old_size < 2 * self.usable_size_aligned(new_size, new_alignment)
+        {
+            return item;
+        }
+        let new_mem = self.alloc_aligned(new_size, new_alignment);
+        if new_mem.is_null() {
+            // keeping the larger object is always correct
+            return item;
+        }
+        ptr::copy_nonoverlapping(item, new_mem, new_size);
+        self.free(item);
+        new_mem
+    }
+
+    unsafe fn get_layout(&self, item: *mut u8) -> (usize, usize) {
+        let page_size = match get_type(item) {
+            AllocType::BigSlag => self.large_page_size,
+            AllocType::HugeSlag => self.huge_page_size(),
+            AllocType::SmallSlag | AllocType::Large => self.small_page_size,
+        };
+        elfmalloc_get_layout(page_size, item)
+    }
+
+    unsafe fn free(&mut self, item: *mut u8) {
+        self.check_double_free(item);
+        let page_size = self.get_page_size(item);
+        #[cfg(feature = "debug_ownership")]
+        self.check_owner(item, page_size);
+        match page_size {
+            Some(page_size) => {
+                let meta = (*Slag::find(item, page_size)).get_metadata();
+                self.free_small(meta, item);
+                if unlikely(remote::any_pending()) {
+                    self.free_remote(meta);
+                }
+            }
+            None => self.free_large(item),
+        };
+    }
+
+    /// Free `item`, an object of the class of `meta`.
+    #[inline(always)]
+    unsafe fn free_small(&mut self, meta: &Metadata, item: *mut u8) {
+        if unlikely(meta.object_size > self.max_size) {
+            // the class was appended through a clone
+            self.catch_up();
+        }
+        let class = self.allocs.get_raw(meta.object_size);
+        free_to_class(meta, &mut *class, item);
+    }
+
+    /// Free the objects of the class of `meta` that were freed by threads without a handle (see
+    /// the `remote` module).
+    #[cold]
+    unsafe fn free_remote(&mut self, meta: *const Metadata) {
+        remote::drain(meta, |item| self.free_small(&*meta, item));
+    }
+
+    /// Allocate objects of size `bytes` into `out`, returning the number of objects allocated.
+    ///
+    /// The size class is looked up once, and objects are then taken from it one after another.
+    /// If memory runs out part of the way through, the objects allocated so far are kept in
+    /// `out[..n]`, where `n` is the returned count, and `out[n..]` is left unchanged.
+    unsafe fn alloc_batch(&mut self, bytes: usize, out: &mut [*mut u8]) -> usize {
+        if bytes > self.max_size ||
+            (unlikely(freeze::any_frozen()) &&
+                 freeze::is_frozen(class_metadata(&*self.allocs.get_raw(bytes))))
+        {
+            for (i, p) in out.iter_mut().enumerate() {
+                let item = self.alloc(bytes);
+                if item.is_null() {
+                    return i;
+                }
+                *p = item;
+            }
+            return out.len();
+        }
+        #[cfg(feature = "profiling")]
+        self.count_requests(bytes, out.len());
+        let class = self.allocs.get_raw(bytes);
+        self.alloc_from_class(class, out)
+    }
+
+    /// Allocate objects of `class`, which must not be frozen, into `out`, returning the number of
+    /// objects allocated; see `alloc_batch`. Requests are not counted for profiling.
+    unsafe fn alloc_from_class(
+        &mut self,
+        class: *mut ObjectAlloc<PageAlloc<M, D>>,
+        out: &mut [*mut u8],
+    ) -> usize {
+        let meta = class_metadata(&*class);
+        if unlikely(remote::any_pending()) {
+            self.free_remote(meta);
+        }
+        for (i, p) in out.iter_mut().enumerate() {
+            let item = class_alloc(&mut *class);
+            if item.is_null() {
+                count_live(meta, i);
+                return i;
+            }
+            #[cfg(feature = "owner_debug")]
+            {
+                *self.owner_slot(item) = owner::current();
+            }
+            *p = item;
+        }
+        count_live(meta, out.len());
+        out.len()
+    }
+
+    /// Free every object in `items`.
+    ///
+    /// Objects are freed in chunks of `FREE_BATCH_CHUNK`, each of which is sorted by address so
+    /// that objects from the same slag are adjacent. A slag's metadata and size class are then
+    /// looked up once for each run of its objects, rather than once per object.
+    unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        let mut buf = [ptr::null_mut(); FREE_BATCH_CHUNK];
+        for chunk in items.chunks(FREE_BATCH_CHUNK) {
+            let sorted = &mut buf[..chunk.len()];
+            sorted.copy_from_slice(chunk);
+            sorted.sort_unstable();
+            let mut i = 0;
+            while i < sorted.len() {
+                self.check_double_free(sorted[i]);
+                let page_size = self.get_page_size(sorted[i]);
+                // Every object in a slag has the same owner, so only the first one of each run
+                // is checked.
+                #[cfg(feature = "debug_ownership")]
+                self.check_owner(sorted[i], page_size);
+                let page_size = match page_size {
+                    Some(page_size) => page_size,
+                    None => {
+                        self.free_large(sorted[i]);
+                        i += 1;
+                        continue;
+                    }
+                };
+                let slag = Slag::find(sorted[i], page_size);
+                let meta = (*slag).get_metadata();
+                if unlikely(meta.object_size > self.max_size) {
+                    self.catch_up();
+                }
+                let class = self.allocs.get_raw(meta.object_size);
+                // Slags do not overlap large allocations, so every object up to the end of this
+                // slag belongs to it.
+                let end = slag as usize + page_size;
+                free_to_class(meta, &mut *class, sorted[i]);
+                i += 1;
+                while i < sorted.len() && (sorted[i] as usize) < end {
+                    self.check_double_free(sorted[i]);
+                    free_to_class(meta, &mut *class, sorted[i]);
+                    i += 1;
+                }
+                if unlikely(remote::any_pending()) {
+                    self.free_remote(meta);
+                }
+            }
+        }
+    }
+
+    /// Count `n` requests for `bytes` bytes in this handle's size histogram, under the class for
+    /// `bytes`, or as large requests if there is none.
+    #[cfg(feature = "profiling")]
+    fn count_requests(&mut self, bytes: usize, n: usize) {
+        let class = self.allocs
+            .try_get_raw(bytes)
+            .map(|class| unsafe { (*class_metadata(&*class)).object_size });
+        self.profile.record(bytes, class, n);
+    }
+
+    /// Allocate `bytes` bytes while some class may be frozen.
+    ///
+    /// If the class for `bytes` is frozen, the next power-of-two class is tried, and so on. Classes
+    /// that are powers of two are aligned to their size, so this preserves the alignment of the
+    /// request.
+    #[cold]
+    unsafe fn alloc_overflow(&mut self, mut bytes: usize) -> *mut u8 {
+        while bytes <= self.max_size {
+            let class = self.allocs.get_raw(bytes);
+            let meta = class_metadata(&*class);
+            if !freeze::is_frozen(meta) {
+                let res = class_alloc(&mut *class);
+                if !res.is_null() {
+                    count_live(meta, 1);
+                }
+                return res;
+            }
+            bytes = ((*meta).object_size + 1).next_power_of_two();
+        }
+        self.alloc_large(bytes, ELFMALLOC_SMALL_CUTOFF)
+    }
+
+    /// Allocate an object of size `sizes[i]` into `out[i]` for each `i`.
+    ///
+    /// Requests are sorted by size in chunks of `VECTORED_CHUNK`, so that the requests for each
+    /// size class are adjacent, and each class's requests are then served in bulk with
+    /// `alloc_from_class`. Large requests, and requests for frozen classes, are served one at a
+    /// time by `alloc`. If any allocation fails, every object allocated by this call is freed,
+    /// `out` is filled with null pointers, and the error is returned.
+    unsafe fn alloc_vectored(&mut self, sizes: &[usize], out: &mut [*mut u8]) -> Result<(), AllocErr> {
+        alloc_debug_assert!(out.len() >= sizes.len());
+        let out = &mut out[..sizes.len()];
+        for p in out.iter_mut() {
+            *p = ptr::null_mut();
+        }
+        let mut requests = [(0, 0); VECTORED_CHUNK];
+        let mut batch = [ptr::null_mut(); VECTORED_CHUNK];
+        for (n, chunk) in sizes.chunks(VECTORED_CHUNK).enumerate() {
+            // each request's size and index in `out`
+            let requests = &mut requests[..chunk.len()];
+            for (i, &size) in chunk.iter().enumerate() {
+                requests[i] = (size, n * VECTORED_CHUNK + i);
+            }
+            requests.sort_unstable();
+            let mut i = 0;
+            while i < requests.len() {
+                let size = requests[i].0;
+                if size > self.max_size ||
+                    (unlikely(freeze::any_frozen()) &&
+                         freeze::is_frozen(class_metadata(&*self.allocs.get_raw(size))))
+                {
+                    let item = self.alloc(size);
+                    if item.is_null() {
+                        return Err(self.abort_vectored(size, out));
+                    }
+                    out[requests[i].1] = item;
+                    i += 1;
+                    continue;
+                }
+                // the class serves every size up to its object size
+                let class = self.allocs.get_raw(size);
+                let object_size = (*class_metadata(&*class)).object_size;
+                let mut end = i + 1;
+                while end < requests.len() && requests[end].0 <= object_size {
+                    end += 1;
+                }
+                #[cfg(feature = "profiling")]
+                for &(size, _) in requests[i..end].iter() {
+                    self.count_requests(size, 1);
+                }
+                let allocated = self.alloc_from_class(class, &mut batch[..end - i]);
+                for (k, &item) in batch[..allocated].iter().enumerate() {
+                    out[requests[i + k].1] = item;
+                }
+                if i + allocated < end {
+                    return Err(self.abort_vectored(requests[i + allocated].0, out));
+                }
+                i = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo a failed call to `alloc_vectored` in which the request for `size` bytes failed,
+    /// returning the error to report.
+    #[cold]
+    unsafe fn abort_vectored(&mut self, size: usize, out: &mut [*mut u8]) -> AllocErr {
+        for p in out.iter_mut() {
+            if !p.is_null() {
+                self.free(*p);
+                *p = ptr::null_mut();
+            }
+        }
+        Layout::from_size_align(size, 1)
+            .map(|l| AllocErr::Exhausted { request: l })
+            .unwrap_or(AllocErr::Unsupported { details: "allocation size overflows" })
+    }
+}
+
+/// The number of free pages of each size that `trim` keeps committed.
+const TRIM_RETAINED_PAGES: usize = 2;
+
+/// The number of objects `free_batch` sorts at a time.
+const FREE_BATCH_CHUNK: usize = 256;
+
+/// The number of requests `alloc_vectored` sorts at a time.
+const VECTORED_CHUNK: usize = 64;
+
+#[cfg(test)]
+thread_local! {
+    /// The number of times `realloc` has copied an object to a new allocation on this thread.
+    static REALLOC_COPIES: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
+
+use self::freeze::ClassInfo;
+
+mod freeze {
+    //! Per-class state used by `DynamicAllocator::freeze_class`.
+    //!
+    //! Each size class of an `ElfMalloc` has a `ClassInfo` holding its `Metadata` along with the
+    //! state in this module and the class's stack of remote frees. Every `Slag` of the class points to the metadata, so the free path can
+    //! get at a class's state without any extra lookups.
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    #[cfg(feature = "freeze")]
+    use std::thread;
+    use super::super::slag::Metadata;
+    use super::super::utils::PointerLog;
+    #[cfg(feature = "freeze")]
+    use super::super::utils::mmap;
+    use super::super::stats::ClassCounters;
+    use super::remote::RemoteFrees;
+
+    /// The number of frozen classes across all allocators.
+    ///
+    /// The allocation and free paths only look at per-class state if this is non-zero, so the
+    /// cost of supporting freezing is a single load of a rarely-written word. Without the
+    /// `freeze` feature, `any_frozen` does not even load it.
+    #[cfg(feature = "freeze")]
+    static N_FROZEN: AtomicUsize = AtomicUsize::new(0);
+
+    /// The metadata for a size class, along with state shared by all handles on the class.
+    ///
+    /// `ClassInfo`s are stored in freshly mapped memory, so their initial state is all zeros.
+    #[repr(C)]
+    pub struct ClassInfo {
+        // This must be the first field: `state` converts a pointer to it into a pointer to the
+        // enclosing `ClassInfo`.
+        pub meta: Metadata,
+        /// The counts of objects of the class, which `meta` refers to.
+        pub counters: ClassCounters,
+        /// Objects freed by threads without a handle.
+        pub remote: RemoteFrees,
+        frozen: AtomicBool,
+        /// The number of threads deciding whether to queue a free.
+        in_flight: AtomicUsize,
+        /// Objects freed while the class was frozen.
+        pending: PointerLog,
+        /// Pages made read-only when the class was frozen.
+        #[cfg(feature = "freeze")]
+        protected: PointerLog,
+    }
+
+    unsafe fn state(meta: *const Metadata) -> &'static ClassInfo {
+        &*(meta as *const ClassInfo)
+    }
+
+    #[cfg(feature = "freeze")]
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn any_frozen() -> bool {
+        N_FROZEN.load(Ordering::Relaxed) != 0
+    }
+
+    /// No class can be frozen, so the checks that follow a call to this are compiled out.
+    #[cfg(not(feature = "freeze"))]
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn any_frozen() -> bool {
+        false
+    }
+
+    pub unsafe fn is_frozen(meta: *const Metadata) -> bool {
+        state(meta).frozen.load(Ordering::Acquire)
+    }
+
+    /// Mark the class of `meta` as frozen, returning false if it already was.
+    #[cfg(feature = "freeze")]
+    pub unsafe fn freeze(meta: *const Metadata) -> bool {
+        if state(meta).frozen.compare_and_swap(false, true, Ordering::SeqCst) {
+            return false;
+        }
+        N_FROZEN.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Make the page `page` of the frozen class of `meta` read-only until the class is thawed.
+    #[cfg(feature = "freeze")]
+    pub unsafe fn protect(meta: *const Metadata, page: *mut u8, page_size: usize) {
+        mmap::protect_read(page, page_size);
+        state(meta).protected.push(page);
+    }
+
+    /// Queue the free of `item` if the class of `meta` is frozen, returning whether it was.
+    pub unsafe fn queue_free(meta: *const Metadata, item: *mut u8) -> bool {
+        let state = state(meta);
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let frozen = state.frozen.load(Ordering::SeqCst);
+        if frozen {
+            state.pending.push(item);
+        }
+        state.in_flight.fetch_sub(1, Ordering::Release);
+        frozen
+    }
+
+    /// Thaw the frozen class of `meta`, calling `free` on each object whose free was queued.
+    #[cfg(feature = "freeze")]
+    pub unsafe fn thaw<F: FnMut(*mut u8)>(meta: *const Metadata, page_size: usize, free: F) {
+        let state = state(meta);
+        state.protected.foreach(|page| mmap::protect_read_write(page, page_size));
+        state.protected.clear();
+        state.frozen.store(false, Ordering::SeqCst);
+        // A thread that saw the class as frozen may still be about to queue a free. Once it is
+        // done, no more frees will be queued.
+        while state.in_flight.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        N_FROZEN.fetch_sub(1, Ordering::SeqCst);
+        state.pending.foreach(free);
+        state.pending.clear();
+    }
+}
+
+mod remote {
+    //! Frees of objects by threads without a handle.
+    //!
+    //! A thread whose thread-local handle is unavailable, for example because it is being torn
+    //! down, cannot return an object to its size class. Instead, it pushes the object onto a
+    //! lock-free stack on the class's `ClassInfo`, storing the link in the object's first word.
+    //! The next handle to allocate or free an object of the class takes the whole stack and frees
+    //! its objects. Taking every object at once means that objects are never popped individually,
+    //! so the stack is not subject to ABA problems.
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+    use super::super::slag::Metadata;
+    use super::freeze::ClassInfo;
+
+    /// The number of objects on the stacks of all classes.
+    ///
+    /// As with frozen classes, the allocation and free paths only look at a class's stack if this
+    /// is non-zero.
+    static N_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+    /// A stack of objects of a class freed by threads without a handle. The all-zeros stack is
+    /// empty.
+    pub struct RemoteFrees {
+        head: AtomicPtr<u8>,
+    }
+
+    unsafe fn stack(meta: *const Metadata) -> &'static RemoteFrees {
+        // `meta` is the first field of its `ClassInfo`, as in `freeze::state`.
+        &(*(meta as *const ClassInfo)).remote
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn any_pending() -> bool {
+        N_PENDING.load(Ordering::Relaxed) != 0
+    }
+
+    /// Push `item`, an object of the class of `meta` that is no longer in use, onto the class's
+    /// stack.
+    pub unsafe fn push(meta: *const Metadata, item: *mut u8) {
+        let stack = stack(meta);
+        N_PENDING.fetch_add(1, Ordering::Relaxed);
+        let mut head = stack.head.load(Ordering::Relaxed);
+        loop {
+            ptr::write(item as *mut *mut u8, head);
+            let cur = stack.head.compare_and_swap(head, item, Ordering::Release);
+            if cur == head {
+                return;
+            }
+            head = cur;
+        }
+    }
+
+    /// Take every object on the stack of the class of `meta`, calling `free` on each one.
+    pub unsafe fn drain<F: FnMut(*mut u8)>(meta: *const Metadata, mut free: F) {
+        let stack = stack(meta);
+        if stack.head.load(Ordering::Relaxed).is_null() {
+            return;
+        }
+        let mut item = stack.head.swap(ptr::null_mut(), Ordering::Acquire);
+        let mut n_items = 0;
+        while !item.is_null() {
+            // `free` may overwrite the link
+            let next = ptr::read(item as *mut *mut u8);
+            free(item);
+            item = next;
+            n_items += 1;
+        }
+        N_PENDING.fetch_sub(n_items, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "global-allocator")]
+pub(crate) mod bootstrap {
+    //! Support for using elfmalloc as the global allocator.
+    //!
+    //! elfmalloc uses the Rust heap itself: to initialize its global state, to create a handle for
+    //! a new thread, and to send memory to the background thread. When elfmalloc is the global
+    //! allocator, those allocations would re-enter the allocator on a thread that is already in
+    //! the middle of an operation, possibly while the state it needs is still being initialized.
+    //! Instead, `ElfMallocGlobal` marks the current thread as active for the duration of each
+    //! operation, and any nested allocation is served from a static bump arena. Once the arena is
+    //! exhausted, nested allocations are mapped directly as large allocations.
+    //!
+    //! Memory in the arena is never reused: freeing an arena object is a no-op. elfmalloc's own
+    //! allocations are few and mostly live as long as the process or the thread that made them,
+    //! so little memory is lost this way.
+    use std::cmp;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use alloc::allocator::Layout;
+
+    const ARENA_SIZE: usize = 4 << 20;
+
+    // The arena is zero-initialized, so its pages are only committed once they are used.
+    static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+    static ARENA_USED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Whether the current thread is in the middle of an allocator operation.
+    #[thread_local]
+    static mut ACTIVE: bool = false;
+
+    /// Run `f` as an allocator operation, or `nested` if the current thread is already in the
+    /// middle of one.
+    pub unsafe fn guarded<R, F, N>(f: F, nested: N) -> R
+    where
+        F: FnOnce() -> R,
+        N: FnOnce() -> R,
+    {
+        if ACTIVE {
+            return nested();
+        }
+        ACTIVE = true;
+        let res = f();
+        ACTIVE = false;
+        res
+    }
+
+    /// Allocate memory for `l` from the arena, or map it directly if the arena is exhausted.
+    ///
+    /// A null pointer is returned if no memory is available.
+    pub unsafe fn alloc(l: &Layout) -> *mut u8 {
+        let base = ARENA.as_ptr() as usize;
+        let mut used = ARENA_USED.load(Ordering::Relaxed);
+        loop {
+            let start = (base + used + l.align() - 1) & !(l.align() - 1);
+            let end = match start.checked_add(l.size()) {
+                Some(end) if end <= base + ARENA_SIZE => end,
+                _ => {
+                    return super::large_alloc::alloc_aligned(cmp::max(l.size(), 1), l.align())
+                }
+            };
+            match ARENA_USED.compare_exchange_weak(
+                used,
+                end - base,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return start as *mut u8,
+                Err(cur) => used = cur,
+            }
+        }
+    }
+
+    /// Whether `item` was allocated from the arena.
+    pub fn owns(item: *mut u8) -> bool {
+        let base = unsafe { ARENA.as_ptr() as usize };
+        (item as usize).wrapping_sub(base) < ARENA_SIZE
+    }
+}
+
+mod large_alloc {
+    //! This module governs "large" allocations that are beyond the size of the largest size class
+    //! of a dynamic allocator.
+    //!
+    //! Large allocations are implemented by mapping a region of memory of the indicated size.
+    //! Allocations aligned to more than `ELFMALLOC_SMALL_CUTOFF` map extra memory so that an
+    //! aligned address can be found within it, and then unmap the excess on either side of the
+    //! allocation, so any power-of-two alignment is supported without keeping the padding mapped.
+    //! The start of the region is recorded along with its size. Mapping and unmapping go through
+    //! the `Backing` of the platform: Windows cannot unmap the padding, which instead stays part
+    //! of the region, with the start of the reservation recorded as the start of the region so
+    //! that the reservation is released as a whole.
+    //!
+    //! The size information is kept in a side table rather than in the region itself, since
+    //! storing it at the 2MiB boundary below the allocation, where `get_type` finds the stamps of
+    //! slags, would take a whole `ELFMALLOC_PAGE_SIZE` of padding per allocation. The table is a
+    //! two-level radix tree over addresses in units of `ELFMALLOC_SMALL_CUTOFF`, to which every
+    //! large allocation is aligned, and `get_type` consults it for pointers with that alignment.
+    //! Both levels are mapped when they are first needed and are never unmapped, and only the
+    //! pages of a leaf that hold entries are ever committed.
+    //!
+    //! An allocation may ask for huge pages. With `HugePages::Transparent`, its region is mapped
+    //! as usual and then advised with `MADV_HUGEPAGE`. With `HugePages::HugeTlb`, its region is
+    //! rounded up to a multiple of 2MiB and mapped with `MAP_HUGETLB`, falling back to
+    //! `Transparent` if that fails, which it does whenever hugetlbfs has no free pages (the pool
+    //! is empty unless the administrator reserved pages for it). Either way, the recorded region
+    //! size is the length that was mapped, so `free` unmaps exactly that, and the side table
+    //! entry is found from the allocation's address as for any other allocation. Regions mapped
+    //! with `MAP_HUGETLB` cannot be uncommitted or partially unmapped at the granularity of
+    //! normal pages, so they are never cached, shrunk or grown in place.
+    //!
+    //! Freed regions are kept in a small cache rather than unmapped, so that a program that
+    //! repeatedly allocates and frees a buffer of the same size does not map and unmap it every
+    //! time (and, since unmapping shoots down the TLBs of every thread, does not stall the other
+    //! threads either). A cached region is released as the `LargeFreePolicy` set with
+    //! `set_free_policy` asks: by default it is uncommitted with `MADV_DONTNEED`, so it takes up
+    //! address space but no memory, while with `MADV_FREE` the kernel only reclaims its memory
+    //! when it needs to. With `LargeFreePolicy::Unmap`, nothing is cached. A cached region is not
+    //! counted against any `MemoryLimit`; reusing it counts it against the limit of the new
+    //! allocation, exactly like mapping a fresh region. The cache holds at most `CACHE_SLOTS`
+    //! regions of at most `CACHE_BYTES` in total, and is emptied by `flush_cache`, which
+    //! `DynamicAllocator::trim` calls whatever the policy. Regions are only cached on Linux, where
+    //! uncommitted memory reads as zeros, so that a reused region is known to be zero like a
+    //! freshly mapped one; a region advised with `MADV_FREE` may or may not have been reclaimed,
+    //! and so is not. Nothing about a region is stored in the region itself, so the kernel
+    //! reclaiming (and zeroing) its pages lazily cannot corrupt its metadata: the side table entry
+    //! is written afresh when the region is reused.
+    //!
+    //! Allocating also reports whether the new allocation's region is known to read as zeros,
+    //! which lets `alloc_zeroed` skip writing to freshly mapped regions. The flag is not kept in
+    //! `AllocInfo`: it only describes the region as it is handed out, so paths that reuse a
+    //! region's memory later (`grow`, `realloc_from_reservation`) never see a stale one.
+    use std::cmp;
+    use std::mem;
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    #[cfg(not(windows))]
+    use super::super::sources::{MemorySource, MmapSource};
+    use super::super::sources::{HugePages, HUGE_PAGE_SIZE};
+    use super::super::stats::Counter;
+    use super::super::utils::MemoryLimit;
+    #[cfg(any(test, feature = "large_registry"))]
+    use super::super::utils::RangeSet;
+    use super::{ELFMALLOC_SMALL_CUTOFF, LargeFreePolicy, LargeStats, get_type};
+    #[cfg(feature = "debug_ownership")]
+    use super::UNOWNED_INSTANCE;
+    use super::super::alloc_type::AllocType;
+
+    /// The region of every live large allocation, for checking that the side table's sizes are
+    /// propagated correctly and for `global::live_large_bytes`. It is shared by all threads, so
+    /// it follows regions that are freed on a different thread than the one that allocated them,
+    /// and it gets its memory directly from mmap, so recording a region never allocates through
+    /// elfmalloc.
+    #[cfg(any(test, feature = "large_registry"))]
+    static REGISTRY: RangeSet = RangeSet::new();
+
+    /// Remove the region starting at `base` from `REGISTRY`, checking that it was recorded with
+    /// a size of `region_size`.
+    #[cfg(any(test, feature = "large_registry"))]
+    fn unregister(base: *mut u8, region_size: usize) {
+        match REGISTRY.take(base) {
+            Some(len) => alloc_assert_eq!(len, region_size, "size of the region at {:?}", base),
+            None => alloc_panic!("elfmalloc: the large region at {:?} is not registered", base),
+        }
+    }
+
+    /// Call `f` with the base and size of the region of every live large allocation.
+    #[cfg(any(test, feature = "large_registry"))]
+    pub fn foreach_registered<F: FnMut(*mut u8, usize)>(f: F) {
+        REGISTRY.foreach(f)
+    }
+
+    /// The size of the live region starting at `base`, or `None` if there is none.
+    #[cfg(test)]
+    pub fn registered_size(base: *mut u8) -> Option<usize> {
+        let mut res = None;
+        REGISTRY.foreach(|start, len| if start == base {
+            res = Some(len);
+        });
+        res
+    }
+    use super::mmap::{advise_huge_pages, lazy_free, page_size, unmap, uncommit};
+    #[cfg(not(windows))]
+    use super::mmap::{fallible_map, protect_none, protect_read_write};
+    #[cfg(windows)]
+    use super::mmap::{commit, fallible_map_committed};
+    #[cfg(target_os = "linux")]
+    use super::mmap::{fallible_map_huge_tlb, remap};
+
+    /// How the regions of large allocations are mapped and unmapped.
+    ///
+    /// POSIX systems can unmap any page-aligned range of a mapping, so the padding used to align
+    /// a region is unmapped as soon as it is mapped, and a shrunk region's tail is unmapped.
+    /// Windows can only release a whole reservation, with `MEM_RELEASE` on the base address that
+    /// `VirtualAlloc` returned, so there a region keeps its padding: `AllocInfo::base` is the
+    /// base of the reservation rather than the allocation, a shrunk region's tail is only
+    /// decommitted, and the region is released wholesale.
+    trait Backing {
+        /// Whether `release_part` may be called.
+        fn releases_parts() -> bool;
+        /// Map `len` bytes, a multiple of `ELFMALLOC_SMALL_CUTOFF`, aligned to
+        /// `ELFMALLOC_SMALL_CUTOFF`.
+        unsafe fn map(len: usize) -> Option<*mut u8>;
+        /// Unmap the whole mapping `[base, base + len)` returned by `map`.
+        unsafe fn release(base: *mut u8, len: usize);
+        /// Unmap `[ptr, ptr + len)`, a page-aligned part of a mapping returned by `map`.
+        unsafe fn release_part(ptr: *mut u8, len: usize);
+        /// Discard the memory of the mapping `[base, base + len)` and make it inaccessible,
+        /// keeping its address range reserved.
+        unsafe fn decommit(base: *mut u8, len: usize);
+        /// Make a mapping passed to `decommit` accessible again. Its old contents are lost.
+        unsafe fn recommit(base: *mut u8, len: usize);
+    }
+
+    #[cfg(not(windows))]
+    struct Posix;
+
+    #[cfg(not(windows))]
+    impl Backing for Posix {
+        fn releases_parts() -> bool {
+            true
+        }
+
+        unsafe fn map(len: usize) -> Option<*mut u8> {
+            // We need a pointer aligned to the SMALL_CUTOFF, so we use an `MmapSource` to map the
+            // memory. See the comment in get_page_size.
+            MmapSource::new(ELFMALLOC_SMALL_CUTOFF).carve(len / ELFMALLOC_SMALL_CUTOFF)
+        }
+
+        unsafe fn release(base: *mut u8, len: usize) {
+            unmap(base, len)
+        }
+
+        unsafe fn release_part(ptr: *mut u8, len: usize) {
+            unmap(ptr, len)
+        }
+
+        unsafe fn decommit(base: *mut u8, len: usize) {
+            uncommit(base, len);
+            protect_none(base, len);
+        }
+
+        unsafe fn recommit(base: *mut u8, len: usize) {
+            protect_read_write(base, len)
+        }
+    }
+
+    #[cfg(windows)]
+    struct Windows;
+
+    #[cfg(windows)]
+    impl Backing for Windows {
+        fn releases_parts() -> bool {
+            false
+        }
+
+        unsafe fn map(len: usize) -> Option<*mut u8> {
+            // Reservations are aligned to the allocation granularity, which is 64KiB, the
+            // SMALL_CUTOFF. They must be committed before they can be accessed.
+            let mem = fallible_map_committed(len);
+            if let Some(mem) = mem {
+                alloc_debug_assert_eq!(mem as usize % ELFMALLOC_SMALL_CUTOFF, 0);
+            }
+            mem
+        }
+
+        unsafe fn release(base: *mut u8, len: usize) {
+            // `unmap` releases the reservation with `MEM_RELEASE`, which needs its base.
+            unmap(base, len)
+        }
+
+        unsafe fn release_part(ptr: *mut u8, _len: usize) {
+            alloc_panic!("elfmalloc: cannot release part of a reservation at {:?}", ptr)
+        }
+
+        unsafe fn decommit(base: *mut u8, len: usize) {
+            // decommitted memory faults when it is accessed
+            uncommit(base, len)
+        }
+
+        unsafe fn recommit(base: *mut u8, len: usize) {
+            commit(base, len)
+        }
+    }
+
+    /// The backing of the current platform.
+    #[cfg(not(windows))]
+    type System = Posix;
+    #[cfg(windows)]
+    type System = Windows;
+
+    /// The total size of all regions reserved with `free_keep_reservation`.
+    static RESERVED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    /// The counters reported by `stats`; see `LargeStats` for their meaning.
+    struct Counts {
+        live_regions: Counter,
+        live_bytes: Counter,
+        peak_bytes: Counter,
+        maps: Counter,
+        unmaps: Counter,
+        reused: Counter,
+    }
+
+    static COUNTS: Counts = Counts {
+        live_regions: Counter::new(),
+        live_bytes: Counter::new(),
+        peak_bytes: Counter::new(),
+        maps: Counter::new(),
+        unmaps: Counter::new(),
+        reused: Counter::new(),
+    };
+
+    /// Count a region of `region_size` bytes as live.
+    fn count_live(region_size: usize) {
+        COUNTS.live_regions.add(1);
+        add_live_bytes(region_size);
+    }
+
+    fn add_live_bytes(bytes: usize) {
+        COUNTS.live_bytes.add(bytes);
+        COUNTS.peak_bytes.raise_to(COUNTS.live_bytes.get());
+    }
+
+    /// Stop counting a region of `region_size` bytes as live.
+    fn count_freed(region_size: usize) {
+        COUNTS.live_regions.sub(1);
+        COUNTS.live_bytes.sub(region_size);
+    }
+
+    /// A snapshot of the counts of large allocations.
+    pub fn stats() -> LargeStats {
+        LargeStats {
+            live_regions: COUNTS.live_regions.get(),
+            live_bytes: COUNTS.live_bytes.get(),
+            peak_bytes: COUNTS.peak_bytes.get(),
+            maps: COUNTS.maps.get(),
+            unmaps: COUNTS.unmaps.get(),
+            reused: COUNTS.reused.get(),
+        }
+    }
+
+    /// The number of freed regions that the cache can hold.
+    const CACHE_SLOTS: usize = 8;
+    /// The largest total size of the regions in the cache.
+    const CACHE_BYTES: usize = 64 << 20;
+    /// Whether freed regions are cached; see the module documentation.
+    const CACHE_ENABLED: bool = cfg!(target_os = "linux");
+
+    // The values of `CacheSlot::base` that are not the start of a region.
+    const SLOT_VACANT: usize = 0;
+    const SLOT_CLAIMED: usize = 1;
+
+    /// Whether memory reads as zeros after it is uncommitted.
+    const UNCOMMIT_ZEROES: bool = cfg!(target_os = "linux");
+
+    /// The `LargeFreePolicy` of freed regions, as a `usize`.
+    static FREE_POLICY: AtomicUsize = AtomicUsize::new(LargeFreePolicy::MadvDontneed as usize);
+
+    /// The policy for regions freed from now on.
+    pub fn free_policy() -> LargeFreePolicy {
+        match FREE_POLICY.load(Ordering::Relaxed) {
+            x if x == LargeFreePolicy::Unmap as usize => LargeFreePolicy::Unmap,
+            x if x == LargeFreePolicy::MadvFree as usize => LargeFreePolicy::MadvFree,
+            _ => LargeFreePolicy::MadvDontneed,
+        }
+    }
+
+    /// Set the policy for regions freed from now on. With `LargeFreePolicy::Unmap`, the regions
+    /// already in the cache are unmapped, since nothing would reuse them otherwise.
+    pub unsafe fn set_free_policy(policy: LargeFreePolicy) {
+        FREE_POLICY.store(policy as usize, Ordering::Relaxed);
+        if policy == LargeFreePolicy::Unmap {
+            flush_cache();
+        }
+    }
+
+    /// A slot of the cache of freed regions.
+    struct CacheSlot {
+        /// The start of the cached region, `SLOT_VACANT`, or `SLOT_CLAIMED` while a thread fills
+        /// or empties the slot.
+        base: AtomicUsize,
+        region_size: AtomicUsize,
+        /// Whether the cached region is known to read as zeros.
+        zeroed: AtomicBool,
+    }
+
+    impl CacheSlot {
+        const fn new() -> CacheSlot {
+            CacheSlot {
+                base: AtomicUsize::new(SLOT_VACANT),
+                region_size: AtomicUsize::new(0),
+                zeroed: AtomicBool::new(false),
+            }
+        }
+
+        /// Claim the slot if it holds a region, returning the region's start and size.
+        fn claim(&self) -> Option<(*mut u8, usize)> {
+            let base = self.base.load(Ordering::Acquire);
+            if base <= SLOT_CLAIMED ||
+                self.base.compare_and_swap(base, SLOT_CLAIMED, Ordering::Acquire) != base
+            {
+                return None;
+            }
+            Some((base as *mut u8, self.region_size.load(Ordering::Relaxed)))
+        }
+    }
+
+    static CACHE: [CacheSlot; CACHE_SLOTS] = [
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+        CacheSlot::new(),
+    ];
+
+    /// The total size of the regions in the cache, including those whose slots are being filled.
+    static CACHED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Add the freed region `[base, base + region_size)` to the cache, releasing its memory as
+    /// the free policy asks if `release_memory` is set, and return whether it was added. If it
+    /// was not, the caller must unmap it.
+    unsafe fn cache_region(base: *mut u8, region_size: usize, release_memory: bool) -> bool {
+        let policy = free_policy();
+        if !CACHE_ENABLED || policy == LargeFreePolicy::Unmap {
+            return false;
+        }
+        let mut cached = CACHED_BYTES.load(Ordering::Relaxed);
+        loop {
+            if region_size > CACHE_BYTES - cached {
+                return false;
+            }
+            match CACHED_BYTES.compare_exchange_weak(
+                cached,
+                cached + region_size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(cur) => cached = cur,
+            }
+        }
+        for slot in CACHE.iter() {
+            if slot.base.compare_and_swap(SLOT_VACANT, SLOT_CLAIMED, Ordering::Acquire) ==
+                SLOT_VACANT
+            {
+                // Memory advised with MADV_FREE reads as zeros only once it has been reclaimed.
+                let zeroed = if !release_memory {
+                    false
+                } else if policy == LargeFreePolicy::MadvFree && lazy_free(base, region_size) {
+                    false
+                } else {
+                    uncommit(base, region_size);
+                    UNCOMMIT_ZEROES
+                };
+                slot.region_size.store(region_size, Ordering::Relaxed);
+                slot.zeroed.store(zeroed, Ordering::Relaxed);
+                slot.base.store(base as usize, Ordering::Release);
+                return true;
+            }
+        }
+        CACHED_BYTES.fetch_sub(region_size, Ordering::Relaxed);
+        false
+    }
+
+    /// Take a region of exactly `region_size` bytes aligned to `align` from the cache, returning
+    /// its start and whether it is known to read as zeros, or return null if there is none.
+    unsafe fn take_cached(region_size: usize, align: usize) -> (*mut u8, bool) {
+        if !CACHE_ENABLED || CACHED_BYTES.load(Ordering::Relaxed) == 0 {
+            return (ptr::null_mut(), false);
+        }
+        for slot in CACHE.iter() {
+            // check the region before claiming the slot, so as not to disturb other regions
+            if slot.region_size.load(Ordering::Relaxed) != region_size ||
+                slot.base.load(Ordering::Relaxed) % align != 0
+            {
+                continue;
+            }
+            if let Some((base, size)) = slot.claim() {
+                if size != region_size || base as usize % align != 0 {
+                    // the region was replaced after it was checked
+                    slot.base.store(base as usize, Ordering::Release);
+                    continue;
+                }
+                let zeroed = slot.zeroed.load(Ordering::Relaxed);
+                slot.base.store(SLOT_VACANT, Ordering::Release);
+                CACHED_BYTES.fetch_sub(region_size, Ordering::Relaxed);
+                return (base, zeroed);
+            }
+        }
+        (ptr::null_mut(), false)
+    }
+
+    /// Unmap every region in the cache, returning their total size.
+    pub unsafe fn flush_cache() -> usize {
+        let mut released = 0;
+        for slot in CACHE.iter() {
+            if let Some((base, region_size)) = slot.claim() {
+                slot.base.store(SLOT_VACANT, Ordering::Release);
+                CACHED_BYTES.fetch_sub(region_size, Ordering::Relaxed);
+                System::release(base, region_size);
+                COUNTS.unmaps.add(1);
+                released += region_size;
+            }
+        }
+        released
+    }
+
+    /// Is `base` the start of a region in the cache?
+    #[cfg(test)]
+    pub fn is_cached(base: *mut u8) -> bool {
+        CACHE.iter().any(|slot| slot.base.load(Ordering::Relaxed) == base as usize)
+    }
+
+    /// The address range of a large allocation freed with `free_keep_reservation`.
+    ///
+    /// The range stays reserved until the token is passed to `realloc_from_reservation` or
+    /// `release_reservation`; dropping the token leaks the reservation.
+    #[derive(Debug)]
+    pub struct ReservationToken {
+        base: *mut u8,
+        region_size: usize,
+        /// The offset of the freed allocation from `base`.
+        offset: usize,
+        huge_tlb: bool,
+    }
+
+    unsafe impl Send for ReservationToken {}
+
+    impl ReservationToken {
+        /// The address that `realloc_from_reservation` will return for this reservation; this
+        /// is the address of the freed allocation.
+        pub fn addr(&self) -> *mut u8 {
+            unsafe { self.base.offset(self.offset as isize) }
+        }
+
+        /// The largest size that can be allocated from this reservation.
+        pub fn max_size(&self) -> usize {
+            self.region_size - self.offset
+        }
+    }
+
+    /// The metadata of a large allocation, stored in the side table.
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct AllocInfo {
+        /// The start of the mapped region, null if the table entry has never been used, or
+        /// `FREED_BASE` if its allocation was freed.
+        base: *mut u8,
+        region_size: usize,
+        /// The limit that `region_size` is counted against, obtained with `Arc::into_raw`, or
+        /// null if it is not counted.
+        limit: *const MemoryLimit,
+        /// Whether the region was mapped with `MAP_HUGETLB`.
+        huge_tlb: bool,
+        #[cfg(feature = "owner_debug")]
+        pub owner: u16,
+        /// The id of the allocator that owns this allocation, or `UNOWNED_INSTANCE` if it was not
+        /// allocated by a particular `DynamicAllocator`.
+        #[cfg(feature = "debug_ownership")]
+        pub instance: usize,
+    }
+
+    /// The log base 2 of `ELFMALLOC_SMALL_CUTOFF`, the unit of addresses in the side table.
+    const TABLE_SHIFT: usize = 16;
+    /// The number of bits of an address (after `TABLE_SHIFT`) that index each level of the table.
+    /// Two levels cover 48-bit addresses.
+    const LEVEL_BITS: usize = 16;
+    const LEVEL_LEN: usize = 1 << LEVEL_BITS;
+
+    /// The root of the side table, an array of `LEVEL_LEN` pointers to leaves stored as
+    /// `AtomicUsize`s, or 0 if it has not been mapped.
+    ///
+    /// A leaf is an array of `LEVEL_LEN` `AllocInfo`s. Its entries are only written by the thread
+    /// that maps or unmaps the allocation they describe, and any thread that looks an allocation
+    /// up has synchronized with that thread in order to get the pointer, so the entries
+    /// themselves need not be atomic.
+    static TABLE_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Get the entry of the side table for an allocation at `item`. If `create` is set, the
+    /// levels of the table leading to it are mapped if they do not exist yet; otherwise, null is
+    /// returned if they do not. Null is also returned if the levels cannot be mapped, or if `item`
+    /// lies beyond the addresses that the table covers.
+    unsafe fn table_entry(item: *mut u8, create: bool) -> *mut AllocInfo {
+        alloc_debug_assert_eq!(1 << TABLE_SHIFT, ELFMALLOC_SMALL_CUTOFF);
+        let key = item as usize >> TABLE_SHIFT;
+        let (hi, lo) = (key >> LEVEL_BITS, key & (LEVEL_LEN - 1));
+        if hi >= LEVEL_LEN {
+            return ptr::null_mut();
+        }
+        let root = table_level(&TABLE_ROOT, LEVEL_LEN * mem::size_of::<AtomicUsize>(), create);
+        if root.is_null() {
+            return ptr::null_mut();
+        }
+        let slot = &*(root as *const AtomicUsize).offset(hi as isize);
+        let leaf = table_level(slot, LEVEL_LEN * mem::size_of::<AllocInfo>(), create);
+        if leaf.is_null() {
+            return ptr::null_mut();
+        }
+        (leaf as *mut AllocInfo).offset(lo as isize)
+    }
+
+    /// Load the level of the side table stored in `slot`. If there is none and `create` is set,
+    /// map `len` bytes for it, which read as zeros and so hold no entries.
+    unsafe fn table_level(slot: &AtomicUsize, len: usize, create: bool) -> *mut u8 {
+        let cur = slot.load(Ordering::Acquire);
+        if cur != 0 || !create {
+            return cur as *mut u8;
+        }
+        // Reserved memory cannot be accessed on Windows until it is committed.
+        #[cfg(not(windows))]
+        let new = fallible_map(len);
+        #[cfg(windows)]
+        let new = fallible_map_committed(len);
+        let new = match new {
+            Some(new) => new,
+            None => return ptr::null_mut(),
+        };
+        let prev = slot.compare_and_swap(0, new as usize, Ordering::AcqRel);
+        if prev == 0 {
+            new
+        } else {
+            // another thread mapped the level first
+            System::release(new, len);
+            prev as *mut u8
+        }
+    }
+
+    /// The `AllocInfo::base` of the entry of a large allocation that has been freed.
+    ///
+    /// The entry keeps this value until the address is reused by another large allocation, so
+    /// that freeing the allocation again can be told apart from freeing a pointer that was never
+    /// a large allocation. Once the region is unmapped, its address may also be reused by the
+    /// pages of a size class, whose objects have to be told apart from the freed allocation by
+    /// checking which pages are mapped (see `ElfMalloc::check_double_free`).
+    const FREED_BASE: usize = 1;
+
+    /// Invalidate the entry of the large allocation `item`, which is being freed.
+    unsafe fn mark_freed(item: *mut u8) {
+        (*get_commitment_mut(item)).base = FREED_BASE as *mut u8;
+    }
+
+    /// Was the last large allocation at `item` freed? Only the side table is read, so `item` is
+    /// never dereferenced.
+    #[inline]
+    pub unsafe fn was_freed(item: *mut u8) -> bool {
+        let info = table_entry(item, false);
+        !info.is_null() && (*info).base as usize == FREED_BASE
+    }
+
+    /// Abort after `item`, a large allocation, was freed twice.
+    #[cold]
+    pub fn double_free(item: *mut u8) -> ! {
+        alloc_panic!("elfmalloc: double free of large allocation {:?}", item)
+    }
+
+    /// Get the entry of the live large allocation `item`, or null if `item` is not one.
+    #[inline]
+    unsafe fn lookup(item: *mut u8) -> *mut AllocInfo {
+        let info = table_entry(item, false);
+        if info.is_null() || (*info).base as usize <= FREED_BASE {
+            ptr::null_mut()
+        } else {
+            info
+        }
+    }
+
+    /// Is `item` a live large allocation? Only the side table is read, so `item` is never
+    /// dereferenced.
+    #[inline]
+    pub unsafe fn is_large(item: *mut u8) -> bool {
+        (item as usize) % ELFMALLOC_SMALL_CUTOFF == 0 && !lookup(item).is_null()
+    }
+
+    pub unsafe fn alloc(size: usize) -> *mut u8 {
+        alloc_aligned(size, ELFMALLOC_SMALL_CUTOFF)
+    }
+
+    /// Allocate `size` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// A null pointer is returned if the memory cannot be mapped.
+    pub unsafe fn alloc_aligned(size: usize, align: usize) -> *mut u8 {
+        alloc_limited(size, align, None, HugePages::Never)
+    }
+
+    /// Allocate `size` bytes that read as zeros. See `alloc_limited_zeroed`.
+    pub unsafe fn alloc_zeroed(size: usize) -> *mut u8 {
+        alloc_limited_zeroed(size, ELFMALLOC_SMALL_CUTOFF, None, HugePages::Never)
+    }
+
+    /// Like `alloc_aligned`, but count the mapped region against `limit` until it is freed, and
+    /// back it with huge pages as `huge_pages` asks (see the module documentation).
+    ///
+    /// A cached region of the right size is reused if there is one; otherwise, a fresh region is
+    /// mapped, after emptying the cache if mapping fails at first. A null pointer is returned if
+    /// the region would exceed the limit.
+    pub unsafe fn alloc_limited(
+        size: usize,
+        align: usize,
+        limit: Option<&Arc<MemoryLimit>>,
+        huge_pages: HugePages,
+    ) -> *mut u8 {
+        alloc_region(size, align, limit, huge_pages).0
+    }
+
+    /// Like `alloc_limited`, but the allocation reads as zeros. Its first `size` bytes are only
+    /// written to if its region is not known to be zero.
+    pub unsafe fn alloc_limited_zeroed(
+        size: usize,
+        align: usize,
+        limit: Option<&Arc<MemoryLimit>>,
+        huge_pages: HugePages,
+    ) -> *mut u8 {
+        let (res, zeroed) = alloc_region(size, align, limit, huge_pages);
+        if !res.is_null() && !zeroed {
+            ptr::write_bytes(res, 0, size);
+        }
+        res
+    }
+
+    /// Like `alloc_limited`, but also return whether the allocation is known to read as zeros.
+    unsafe fn alloc_region(
+        size: usize,
+        align: usize,
+        limit: Option<&Arc<MemoryLimit>>,
+        huge_pages: HugePages,
+    ) -> (*mut u8, bool) {
+        alloc_debug_assert!(align.is_power_of_two());
+        let align = cmp::max(align, ELFMALLOC_SMALL_CUTOFF);
+        let unit = if huge_pages == HugePages::HugeTlb {
+            HUGE_PAGE_SIZE
+        } else {
+            page_size()
+        };
+        // No mapping can be larger than isize::MAX, and rejecting larger sizes here keeps the
+        // padding added by map_region from overflowing.
+        let region_size = match checked_round_up(size, unit) {
+            Some(region_size) if region_size <= isize::max_value() as usize => region_size,
+            _ => return (ptr::null_mut(), false),
+        };
+        // Where the backing cannot unmap the padding used to align the region, the allocation
+        // keeps it, and is charged for it.
+        let charged = if System::releases_parts() {
+            region_size
+        } else {
+            match padded_len(region_size, align) {
+                Some(len) => len,
+                None => return (ptr::null_mut(), false),
+            }
+        };
+        if let Some(limit) = limit {
+            if !limit.try_acquire(charged) {
+                return (ptr::null_mut(), false);
+            }
+            limit.large.add(charged);
+        }
+        let mapping = match map_region(region_size, align, huge_pages) {
+            Some(mapping) => mapping,
+            None => {
+                if let Some(limit) = limit {
+                    limit.release(charged);
+                    limit.large.sub(charged);
+                }
+                return (ptr::null_mut(), false);
+            }
+        };
+        alloc_debug_assert_eq!(mapping.len, charged);
+        let (mem, res) = (mapping.base, mapping.item);
+        let addr = table_entry(res, true);
+        if addr.is_null() {
+            System::release(mem, charged);
+            COUNTS.unmaps.add(1);
+            if let Some(limit) = limit {
+                limit.release(charged);
+                limit.large.sub(charged);
+            }
+            return (ptr::null_mut(), false);
+        }
+        ptr::write(
+            addr,
+            AllocInfo {
+                base: mem,
+                region_size: charged,
+                limit: limit.map_or(ptr::null(), |limit| Arc::into_raw(limit.clone())),
+                huge_tlb: mapping.huge_tlb,
+                #[cfg(feature = "owner_debug")]
+                owner: super::owner::UNKNOWN_OWNER,
+                #[cfg(feature = "debug_ownership")]
+                instance: UNOWNED_INSTANCE,
+            },
+        );
+
+        // begin extra debugging information
+        alloc_debug_assert!(!mem.is_null());
+        alloc_debug_assert_eq!(mem as usize % ELFMALLOC_SMALL_CUTOFF, 0);
+        alloc_debug_assert_eq!(mem as usize % page_size(), 0);
+        alloc_debug_assert_eq!(res as usize % page_size(), 0);
+        alloc_debug_assert_eq!(res as usize % align, 0);
+        alloc_debug_assert_eq!(get_commitment(res), (charged, mem));
+        count_live(charged);
+        #[cfg(any(test, feature = "large_registry"))] REGISTRY.insert(mem, charged);
+        // end extra debugging information
+        (res, mapping.zeroed)
+    }
+
+    /// A region mapped, or taken from the cache, for a new allocation.
+    struct Mapping {
+        /// The start of the region. This is the allocation itself, unless the backing cannot unmap
+        /// the padding used to align it.
+        base: *mut u8,
+        len: usize,
+        /// The allocation, aligned as requested.
+        item: *mut u8,
+        /// Whether the region was mapped with `MAP_HUGETLB`.
+        huge_tlb: bool,
+        /// Whether the region is known to read as zeros.
+        zeroed: bool,
+    }
+
+    /// The length to map for a region of `region_size` bytes aligned to `align`: mappings start
+    /// at a multiple of the SMALL_CUTOFF, so this is enough padding to align the region.
+    fn padded_len(region_size: usize, align: usize) -> Option<usize> {
+        region_size
+            .checked_add(align - ELFMALLOC_SMALL_CUTOFF)
+            .and_then(|len| checked_round_up(len, ELFMALLOC_SMALL_CUTOFF))
+    }
+
+    /// Get a region of (at least) `region_size` bytes aligned to `align` for a new allocation.
+    unsafe fn map_region(
+        region_size: usize,
+        align: usize,
+        huge_pages: HugePages,
+    ) -> Option<Mapping> {
+        #[cfg(target_os = "linux")]
+        {
+            if huge_pages == HugePages::HugeTlb {
+                // Huge pages are aligned to their size, so only larger alignments need padding,
+                // and the excess is a whole number of huge pages.
+                let len = region_size.checked_add(align.saturating_sub(HUGE_PAGE_SIZE));
+                if let Some(len) = len {
+                    if let Some(mem) = fallible_map_huge_tlb(len) {
+                        COUNTS.maps.add(1);
+                        return Some(Mapping {
+                            huge_tlb: true,
+                            ..trim_to_aligned(mem, len, region_size, align)
+                        });
+                    }
+                }
+            }
+        }
+        let len = match padded_len(region_size, align) {
+            Some(len) => len,
+            None => return None,
+        };
+        let map = || System::map(len).map(|mem| trim_to_aligned(mem, len, region_size, align));
+        let (cached, zeroed) = take_cached(region_size, align);
+        let mapping = if cached.is_null() {
+            let mapping = map().or_else(|| if flush_cache() > 0 { map() } else { None });
+            if mapping.is_some() {
+                COUNTS.maps.add(1);
+            }
+            mapping
+        } else {
+            COUNTS.reused.add(1);
+            Some(Mapping {
+                base: cached,
+                len: region_size,
+                item: cached,
+                huge_tlb: false,
+                zeroed: zeroed,
+            })
+        };
+        if let Some(ref mapping) = mapping {
+            if huge_pages != HugePages::Never {
+                // If the kernel refuses, the region is simply backed by normal pages.
+                advise_huge_pages(mapping.item, region_size);
+            }
+        }
+        mapping
+    }
+
+    /// Find the first address aligned to `align` in the fresh mapping `[mem, mem + mapped)`, and
+    /// unmap the parts of the mapping before it and past the `region_size` bytes from there if
+    /// the backing can. Otherwise, the whole mapping is kept as the allocation's region.
+    unsafe fn trim_to_aligned(
+        mem: *mut u8,
+        mapped: usize,
+        region_size: usize,
+        align: usize,
+    ) -> Mapping {
+        let res = round_up(mem as usize, align);
+        let head = res - mem as usize;
+        let tail = mapped - head - region_size;
+        let (base, len) = if System::releases_parts() {
+            if head > 0 {
+                System::release_part(mem, head);
+            }
+            if tail > 0 {
+                System::release_part((res + region_size) as *mut u8, tail);
+            }
+            (res as *mut u8, region_size)
+        } else {
+            (mem, mapped)
+        };
+        // fresh mappings read as zeros
+        Mapping {
+            base: base,
+            len: len,
+            item: res as *mut u8,
+            huge_tlb: false,
+            zeroed: true,
+        }
+    }
+
+    /// Round `size` up to a multiple of `unit`, which must be a power of two.
+    fn round_up(size: usize, unit: usize) -> usize {
+        (size + unit - 1) & !(unit - 1)
+    }
+
+    /// Like `round_up`, but return `None` if the result does not fit in a `usize`.
+    fn checked_round_up(size: usize, unit: usize) -> Option<usize> {
+        size.checked_add(unit - 1).map(|size| size & !(unit - 1))
+    }
+
+    /// Stop counting the region of `item` against its limit, if it has one.
+    unsafe fn release_limit(item: *mut u8) {
+        let info = get_commitment_mut(item);
+        if !(*info).limit.is_null() {
+            let limit = Arc::from_raw((*info).limit);
+            limit.release((*info).region_size);
+            limit.large.sub((*info).region_size);
+            (*info).limit = ptr::null();
+        }
+    }
+
+    pub unsafe fn free(item: *mut u8) {
+        free_region(item, true)
+    }
+
+    /// Free `item` like `free`, but cache its region without uncommitting it, so that a later
+    /// allocation that reuses it finds the old contents.
+    #[cfg(test)]
+    pub unsafe fn free_dirty(item: *mut u8) {
+        free_region(item, false)
+    }
+
+    /// Free `item`, caching its region (released as the free policy asks, if `release_memory` is
+    /// set) if the policy and the cache allow it, and unmapping it otherwise.
+    ///
+    /// This aborts if `item` was already freed, or is not a large allocation at all.
+    unsafe fn free_region(item: *mut u8, release_memory: bool) {
+        use std::intrinsics::unlikely;
+        if item.is_null() {
+            return;
+        }
+        let (size, base_ptr) = get_commitment(item);
+        if unlikely(base_ptr.is_null()) {
+            if was_freed(item) {
+                double_free(item);
+            }
+            alloc_panic!("elfmalloc: freeing {:?}, which is not a large allocation", item);
+        }
+        release_limit(item);
+        let huge_tlb = (*get_commitment_mut(item)).huge_tlb;
+        // The entry must be invalidated before the region is unmapped, after which its address
+        // may be reused by pages of size classes or by another large allocation.
+        mark_freed(item);
+
+        trace!("size={}, base_ptr={:?}", size, base_ptr);
+        // begin extra debugging information:
+        #[cfg(debug_assertions)]
+        {
+            ptr::write_volatile(item, 10);
+            alloc_debug_assert_eq!(
+                base_ptr as usize % page_size(),
+                0,
+                "base_ptr ({:?}) not a multiple of the page size ({})",
+                base_ptr,
+                page_size()
+            );
+        }
+        #[cfg(any(test, feature = "large_registry"))] unregister(base_ptr, size);
+        // end extra debugging information
+        count_freed(size);
+        if huge_tlb || !cache_region(base_ptr, size, release_memory) {
+            System::release(base_ptr, size);
+            COUNTS.unmaps.add(1);
+        }
+    }
+
+    /// Shrink the large allocation `item` to `new_size` bytes in place by releasing the pages
+    /// past its new end, and record the smaller region so that `get_size` and `free` use it.
+    ///
+    /// The tail is unmapped, or only decommitted (with `MADV_DONTNEED` or `MEM_DECOMMIT`) if the
+    /// backing cannot unmap part of a mapping, in which case it stays reserved until the whole
+    /// region is released. Either way, the region's base and the allocation's entry in the side
+    /// table are kept. Regions mapped with `MAP_HUGETLB` can only release whole huge pages.
+    pub unsafe fn shrink(item: *mut u8, new_size: usize) {
+        let info = get_commitment_mut(item);
+        let granule = if (*info).huge_tlb {
+            HUGE_PAGE_SIZE
+        } else {
+            page_size()
+        };
+        let base = (*info).base;
+        let old_end = base as usize + (*info).region_size;
+        let new_end = (item as usize + new_size + granule - 1) & !(granule - 1);
+        if new_end >= old_end {
+            return;
+        }
+        let released = old_end - new_end;
+        if System::releases_parts() {
+            System::release_part(new_end as *mut u8, released);
+        } else {
+            System::decommit(new_end as *mut u8, released);
+        }
+        COUNTS.live_bytes.sub(released);
+        if !(*info).limit.is_null() {
+            (*(*info).limit).release(released);
+            (*(*info).limit).large.sub(released);
+        }
+        #[cfg(any(test, feature = "large_registry"))]
+        {
+            unregister(base, (*info).region_size);
+            REGISTRY.insert(base, (*info).region_size - released);
+        }
+        (*info).region_size -= released;
+    }
+
+    /// Grow the large allocation `item` to `new_size` bytes aligned to `align` without copying
+    /// its contents, returning the grown allocation.
+    ///
+    /// The region is grown in place if the address space after it is free. Otherwise, it is moved
+    /// with `mremap` to an address congruent to its old one modulo `align` and
+    /// `ELFMALLOC_SMALL_CUTOFF`, so that the allocation keeps its alignment, and its entry in the
+    /// side table is moved with it.
+    /// A null pointer is returned, and `item` is left unchanged, if neither is possible, if
+    /// `item` is not aligned to `align`, or if its region was mapped with `MAP_HUGETLB`.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn grow(item: *mut u8, new_size: usize, align: usize) -> *mut u8 {
+        if (item as usize) % align != 0 {
+            return ptr::null_mut();
+        }
+        let align = cmp::max(align, ELFMALLOC_SMALL_CUTOFF);
+        let info = get_commitment_mut(item);
+        if (*info).huge_tlb {
+            return ptr::null_mut();
+        }
+        let (base, old_region, limit) = ((*info).base, (*info).region_size, (*info).limit);
+        let offset = item as usize - base as usize;
+        let new_end = offset.checked_add(new_size);
+        let new_region = match new_end.and_then(|end| checked_round_up(end, page_size())) {
+            Some(end) if end <= isize::max_value() as usize => end,
+            _ => return ptr::null_mut(),
+        };
+        if new_region <= old_region {
+            return item;
+        }
+        if !limit.is_null() && !(*limit).try_acquire(new_region - old_region) {
+            return ptr::null_mut();
+        }
+        // Once the region is moved, its old address may be mapped and registered by another
+        // thread, so it is unregistered first.
+        #[cfg(any(test, feature = "large_registry"))] unregister(base, old_region);
+        let new_base = if remap(base, old_region, new_region, None) {
+            base
+        } else {
+            move_region(base, old_region, new_region, align)
+        };
+        if new_base.is_null() {
+            #[cfg(any(test, feature = "large_registry"))] REGISTRY.insert(base, old_region);
+            if !limit.is_null() {
+                (*limit).release(new_region - old_region);
+            }
+            return ptr::null_mut();
+        }
+        if !limit.is_null() {
+            (*limit).large.add(new_region - old_region);
+        }
+        add_live_bytes(new_region - old_region);
+        let res = new_base.offset(offset as isize);
+        let mut moved = *info;
+        moved.base = new_base;
+        moved.region_size = new_region;
+        if res != item {
+            mark_freed(item);
+        }
+        // The move cannot be undone, so failing to map the table's leaf for the new address is
+        // fatal, like failing to map any other metadata.
+        let entry = table_entry(res, true);
+        alloc_assert!(!entry.is_null(), "could not map the large allocation table");
+        ptr::write(entry, moved);
+        #[cfg(any(test, feature = "large_registry"))] REGISTRY.insert(new_base, new_region);
+        res
+    }
+
+    /// Move the mapping `[base, base + old_region)` to a new mapping of `new_region` bytes whose
+    /// address is congruent to `base` modulo `align`, returning the new address or null.
+    #[cfg(target_os = "linux")]
+    unsafe fn move_region(base: *mut u8, old_region: usize, new_region: usize, align: usize) -> *mut u8 {
+        let reserved_len = match new_region.checked_add(align) {
+            Some(len) => len,
+            None => return ptr::null_mut(),
+        };
+        let reserved = match fallible_map(reserved_len) {
+            Some(reserved) => reserved as usize,
+            None => return ptr::null_mut(),
+        };
+        let dest = reserved + ((base as usize).wrapping_sub(reserved) & (align - 1));
+        if !remap(base, old_region, new_region, Some(dest as *mut u8)) {
+            unmap(reserved as *mut u8, reserved_len);
+            return ptr::null_mut();
+        }
+        // unmap the parts of the reservation that the moved region did not replace
+        if dest > reserved {
+            unmap(reserved as *mut u8, dest - reserved);
+        }
+        let tail = reserved + reserved_len - (dest + new_region);
+        if tail > 0 {
+            unmap((dest + new_region) as *mut u8, tail);
+        }
+        dest as *mut u8
+    }
+
+    /// `mremap` is only available on Linux, so large allocations are never grown in place
+    /// elsewhere.
+    #[cfg(not(target_os = "linux"))]
+    pub unsafe fn grow(_item: *mut u8, _new_size: usize, _align: usize) -> *mut u8 {
+        ptr::null_mut()
+    }
+
+    /// Free the large allocation `item`, but keep its address range reserved.
+    ///
+    /// The range's pages are decommitted and made inaccessible, so its contents are lost and any
+    /// access faults until it is reused with `realloc_from_reservation`.
+    pub unsafe fn free_keep_reservation(item: *mut u8) -> ReservationToken {
+        alloc_assert_eq!(
+            get_type(item),
+            AllocType::Large,
+            "free_keep_reservation called on a small allocation"
+        );
+        let (region_size, base) = get_commitment(item);
+        #[cfg(any(test, feature = "large_registry"))] unregister(base, region_size);
+        release_limit(item);
+        let huge_tlb = (*get_commitment_mut(item)).huge_tlb;
+        mark_freed(item);
+        System::decommit(base, region_size);
+        count_freed(region_size);
+        RESERVED_BYTES.fetch_add(region_size, Ordering::Relaxed);
+        ReservationToken {
+            base: base,
+            region_size: region_size,
+            offset: item as usize - base as usize,
+            huge_tlb: huge_tlb,
+        }
+    }
+
+    /// Allocate `size` bytes from a reservation, at the address of the allocation that was freed
+    /// to create it.
+    ///
+    /// If `size` is larger than the original allocation, the reservation is handed back
+    /// unchanged.
+    pub unsafe fn realloc_from_reservation(
+        token: ReservationToken,
+        size: usize,
+    ) -> Result<*mut u8, ReservationToken> {
+        let res = token.addr();
+        let info = table_entry(res, true);
+        if size > token.max_size() || info.is_null() {
+            return Err(token);
+        }
+        System::recommit(token.base, token.region_size);
+        RESERVED_BYTES.fetch_sub(token.region_size, Ordering::Relaxed);
+        // The allocation keeps the entire region, so that `free` unmaps all of it.
+        ptr::write(
+            info,
+            AllocInfo {
+                base: token.base,
+                region_size: token.region_size,
+                limit: ptr::null(),
+                huge_tlb: token.huge_tlb,
+                #[cfg(feature = "owner_debug")]
+                owner: super::owner::UNKNOWN_OWNER,
+                #[cfg(feature = "debug_ownership")]
+                instance: UNOWNED_INSTANCE,
+            },
+        );
+        count_live(token.region_size);
+        #[cfg(any(test, feature = "large_registry"))]
+        REGISTRY.insert(token.base, token.region_size);
+        Ok(res)
+    }
+
+    /// Unmap a reservation's address range.
+    pub unsafe fn release_reservation(token: ReservationToken) {
+        RESERVED_BYTES.fetch_sub(token.region_size, Ordering::Relaxed);
+        System::release(token.base, token.region_size);
+        COUNTS.unmaps.add(1);
+    }
+
+    /// The total size of all outstanding reservations.
+    pub fn reserved_bytes() -> usize {
+        RESERVED_BYTES.load(Ordering::Relaxed)
+    }
+
+    /// Get the usable size of the large allocation `item`: the bytes from `item` to the end of
+    /// its region, which is at least its size rounded up to the system page size.
+    pub unsafe fn get_size(item: *mut u8) -> usize {
+        let (size, base) = get_commitment(item);
+        base as usize + size - item as usize
+    }
+
+    /// Get the size and start of the region mapped for `item`, or `(0, null)` if `item` is not a
+    /// live large allocation.
+    pub unsafe fn get_commitment(item: *mut u8) -> (usize, *mut u8) {
+        let meta_addr = lookup(item);
+        if meta_addr.is_null() {
+            return (0, ptr::null_mut());
+        }
+        let base_ptr = (*meta_addr).base;
+        let size = (*meta_addr).region_size;
+        (size, base_ptr)
+    }
+
+    /// The id of the thread that made the live large allocation whose region starts at `base`.
+    #[cfg(feature = "owner_debug")]
+    pub unsafe fn region_owner(base: *mut u8) -> u16 {
+        let info = lookup(base);
+        if info.is_null() {
+            super::owner::UNKNOWN_OWNER
+        } else {
+            (*info).owner
+        }
+    }
+
+    /// Get the metadata of `item`, which must be a live large allocation.
+    pub unsafe fn get_commitment_mut(item: *mut u8) -> *mut AllocInfo {
+        let info = lookup(item);
+        alloc_debug_assert!(!info.is_null(), "{:?} is not a large allocation", item);
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    extern crate test;
+    use super::*;
+    use self::test::Bencher;
+    use std::ptr::{write_bytes, write_volatile};
+    use std::slice;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+
+    #[test]
+    fn layout_lookup() {
+        fn test_and_free<F: Fn(usize, usize)>(inp: usize, tester: F) {
+            unsafe {
+                let obj = global::alloc(inp);
+                let (size, align) = global::get_layout(obj);
+                tester(size, align);
+                global::free(obj);
+            }
+        }
+
+        test_and_free(8, |size, align| {
+            alloc_assert!(size >= 8);
+            alloc_assert!(align >= 8);
+        });
+        test_and_free(24, |size, align| {
+            alloc_assert!(size >= 24);
+            alloc_assert!(align >= 8);
+        });
+        test_and_free(512, |size, align| {
+            alloc_assert!(size >= 512);
+            alloc_assert!(align >= 512);
+        });
+        test_and_free(4 << 20, |size, align| {
+            alloc_assert_eq!((size, align), (4 << 20, mmap::page_size()))
+        });
+    }
+
+    #[test]
+    fn large_alloc_side_table() {
+        const N: usize = 64;
+        const SIZE: usize = 4 << 20;
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            let before = dyn.stats().large_bytes;
+            let items = (0..N).map(|_| dyn.alloc(SIZE)).collect::<Vec<_>>();
+            // each allocation maps its own size, without a page for its metadata
+            #[cfg(not(feature = "no_stats"))]
+            alloc_assert_eq!(dyn.stats().large_bytes - before, N * SIZE);
+            for (i, &item) in items.iter().enumerate() {
+                alloc_assert_eq!(get_type(item), AllocType::Large);
+                alloc_assert_eq!(dyn.layout_of(item), (SIZE, mmap::page_size()));
+                write_bytes(item, i as u8, SIZE);
+            }
+            // realloc copies the whole allocation, whose size it reads from the side table
+            let grown = dyn.realloc(items[1], 2 * SIZE);
+            alloc_assert!(slice::from_raw_parts(grown, SIZE).iter().all(|&b| b == 1));
+            alloc_assert_eq!(large_alloc::get_size(grown), 2 * SIZE);
+            dyn.free(grown);
+            for &item in items.iter().filter(|&&item| item != items[1]) {
+                dyn.free(item);
+                alloc_assert!(!large_alloc::is_large(item));
+            }
+            #[cfg(not(feature = "no_stats"))]
+            alloc_assert_eq!(dyn.stats().large_bytes, before);
+        }
+    }
+
+    #[test]
+    fn large_alloc_huge_alignments() {
+        const MB: usize = 1 << 20;
+        let mut dyn = DynamicAllocator::new();
+        for &align in &[4 * MB, 16 * MB, 64 * MB] {
+            for &size in &[MB, align, 64 * MB + 1] {
+                unsafe {
+                    #[cfg(not(feature = "no_stats"))]
+                    let before = dyn.stats().large_bytes;
+                    let item = dyn.alloc_aligned(size, align);
+                    alloc_assert!(!item.is_null());
+                    alloc_assert_eq!(item as usize % align, 0, "size {} align {}", size, align);
+                    alloc_assert_eq!(get_type(item), AllocType::Large);
+                    let rounded = (size + mmap::page_size() - 1) & !(mmap::page_size() - 1);
+                    let (region_size, base) = large_alloc::get_commitment(item);
+                    if cfg!(windows) {
+                        // the region is the whole reservation, padding included
+                        alloc_assert!(base <= item);
+                        alloc_assert!(base as usize + region_size >= item as usize + rounded);
+                    } else {
+                        // only the allocation itself stays mapped and counted
+                        alloc_assert_eq!((region_size, base), (rounded, item));
+                    }
+                    #[cfg(not(feature = "no_stats"))]
+                    alloc_assert_eq!(dyn.stats().large_bytes - before, region_size);
+                    write_volatile(item, 1);
+                    write_volatile(item.offset(size as isize - 1), 1);
+                    dyn.free(item);
+                    alloc_assert!(!large_alloc::is_large(item));
+                }
+            }
+        }
+        unsafe {
+            let item = global::alloc_aligned(MB, 16 * MB);
+            alloc_assert_eq!(item as usize % (16 * MB), 0);
+            alloc_assert_eq!(global::get_layout(item).0, MB);
+            write_volatile(item.offset(MB as isize - 1), 1);
+            global::free(item);
+        }
+    }
+
+    #[test]
+    fn large_alloc_boundary_sizes() {
+        let page = mmap::page_size();
+        let round = |size: usize| (size + page - 1) & !(page - 1);
+        let mut sizes = Vec::new();
+        for k in 1..40 {
+            for &base in &[k * ELFMALLOC_SMALL_CUTOFF, k * page] {
+                sizes.extend_from_slice(&[base - 1, base, base + 1]);
+            }
+        }
+        for &align in &[ELFMALLOC_SMALL_CUTOFF, 4 * ELFMALLOC_SMALL_CUTOFF, 1 << 20] {
+            for &size in &sizes {
+                unsafe {
+                    let item = large_alloc::alloc_aligned(size, align);
+                    alloc_assert!(!item.is_null());
+                    alloc_assert_eq!(item as usize % align, 0);
+                    let usable = large_alloc::get_size(item);
+                    if cfg!(windows) {
+                        // the padding stays in the region, after the allocation if not before it
+                        alloc_assert!(usable >= round(size), "size {} align {}", size, align);
+                    } else {
+                        // the padding used to align the region is unmapped
+                        alloc_assert_eq!(large_alloc::get_commitment(item), (round(size), item));
+                        alloc_assert_eq!(usable, round(size), "size {} align {}", size, align);
+                    }
+                    write_volatile(item.offset(size as isize - 1), 1);
+                    write_volatile(item.offset(usable as isize - 1), 1);
+                    large_alloc::free(item);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_alloc_overflow() {
+        const MB: usize = 1 << 20;
+        let page = mmap::page_size();
+        let max = usize::max_value();
+        let sizes = [
+            max,
+            max - 1,
+            max - MB,
+            max - page + 1,
+            max - super::super::sources::HUGE_PAGE_SIZE + 1,
+            isize::max_value() as usize,
+            isize::max_value() as usize + 1,
+        ];
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            let small = dyn.alloc(16);
+            let large = dyn.alloc(4 * MB);
+            write_bytes(small, 0xAB, 16);
+            write_bytes(large, 0xCD, 4 * MB);
+            for &size in &sizes {
+                for &align in &[ELFMALLOC_SMALL_CUTOFF, 4 * MB, isize::max_value() as usize + 1] {
+                    alloc_assert!(large_alloc::alloc_aligned(size, align).is_null());
+                    alloc_assert!(
+                        large_alloc::alloc_limited(size, align, None, HugePages::HugeTlb).is_null()
+                    );
+                }
+                alloc_assert!(large_alloc::alloc_zeroed(size).is_null());
+                for &align in &[1, 16, ELFMALLOC_SMALL_CUTOFF, 2 * ELFMALLOC_SMALL_CUTOFF] {
+                    alloc_assert!(dyn.alloc_aligned(size, align).is_null(), "{} {}", size, align);
+                    alloc_assert!(dyn.class_for(size, align).map_or(true, |(_, s, _)| s >= size));
+                    alloc_assert!(global::alloc_aligned(size, align).is_null());
+                }
+                alloc_assert!(dyn.try_alloc(size).is_err());
+                alloc_assert!(dyn.alloc_zeroed(size).is_null());
+                alloc_assert!(global::alloc(size).is_null());
+                alloc_assert!(global::alloc_zeroed(size).is_null());
+                alloc_assert_eq!(global::alloc_excess(size), (ptr::null_mut(), 0));
+                let mut out = [ptr::null_mut(); 2];
+                alloc_assert!(global::alloc_vectored(&[16, size], &mut out).is_err());
+                alloc_assert_eq!(out, [ptr::null_mut(); 2]);
+
+                // as with C's realloc, the original object is left untouched
+                alloc_assert!(dyn.realloc(small, size).is_null());
+                alloc_assert!(dyn.realloc(large, size).is_null());
+                alloc_assert!(dyn.aligned_realloc(large, size, 4 * MB).is_null());
+            }
+            alloc_assert!(dyn.class_for(max, 2 * ELFMALLOC_SMALL_CUTOFF).is_none());
+            alloc_assert!(dyn.class_for(max - 1, 16).is_none());
+            alloc_assert!((0..16).all(|i| *small.offset(i) == 0xAB));
+            alloc_assert!((0..4 * MB as isize).all(|i| *large.offset(i) == 0xCD));
+            alloc_assert_eq!(dyn.layout_of(large).0, 4 * MB);
+            dyn.free(small);
+            dyn.free(large);
+
+            let item = global::alloc(4 * MB);
+            alloc_assert!(global::realloc(item, max - MB).is_null());
+            alloc_assert!(global::aligned_realloc(item, max, 16).is_null());
+            alloc_assert_eq!(global::get_layout(item).0, 4 * MB);
+            global::free(item);
+        }
+    }
+
+    // Freed regions are only cached on Linux.
+    #[cfg(all(target_os = "linux", not(feature = "no_stats")))]
+    #[test]
+    fn large_stats() {
+        use std::env;
+        use std::process::{self, Command};
+        // The counts are shared by every allocator in the process, so they are checked in a child
+        // process that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_LARGE_STATS_CHILD";
+        if env::var_os(CHILD_VAR).is_some() {
+            const MB: usize = 1 << 20;
+            let mut dyn = DynamicAllocator::new();
+            unsafe {
+                // build the global heap, whose initialization may make large allocations
+                global::free(global::alloc(64));
+                dyn.trim();
+                let before = large_alloc::stats();
+                let delta = || {
+                    let now = large_alloc::stats();
+                    (
+                        now.live_regions - before.live_regions,
+                        now.live_bytes - before.live_bytes,
+                        now.maps - before.maps,
+                        now.unmaps - before.unmaps,
+                        now.reused - before.reused,
+                    )
+                };
+                let a = dyn.alloc(4 * MB);
+                let b = dyn.alloc(8 * MB);
+                alloc_assert_eq!(delta(), (2, 12 * MB, 2, 0, 0));
+                alloc_assert_eq!(dyn.stats().large, large_alloc::stats());
+                alloc_assert_eq!(
+                    large_alloc::stats().peak_bytes,
+                    cmp::max(before.peak_bytes, before.live_bytes + 12 * MB)
+                );
+                // a is cached, and then reused
+                dyn.free(a);
+                alloc_assert_eq!(delta(), (1, 8 * MB, 2, 0, 0));
+                let c = dyn.alloc(4 * MB);
+                alloc_assert_eq!(delta(), (2, 12 * MB, 2, 0, 1));
+                dyn.free(b);
+                dyn.free(c);
+                alloc_assert_eq!(delta(), (0, 0, 2, 0, 1));
+                alloc_assert!(dyn.trim() >= 12 * MB);
+                alloc_assert_eq!(delta(), (0, 0, 2, 2, 1));
+
+                // a region too large for the cache is unmapped as soon as it is freed, including
+                // by a thread without a handle on the global heap
+                let g = global::alloc(100 * MB);
+                alloc_assert_eq!(delta(), (1, 100 * MB, 3, 2, 1));
+                alloc_assert_eq!(global::stats().large, large_alloc::stats());
+                global::free_without_handle(g);
+                alloc_assert_eq!(delta(), (0, 0, 3, 3, 1));
+                alloc_assert_eq!(
+                    large_alloc::stats().peak_bytes,
+                    cmp::max(before.peak_bytes, before.live_bytes + 100 * MB)
+                );
+            }
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::large_stats", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "no_stats")))]
+    #[test]
+    fn large_free_policy() {
+        use std::env;
+        use std::process::{self, Command};
+        // The policy applies to the whole process, so it is changed in a child process that runs
+        // only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_LARGE_FREE_POLICY_CHILD";
+        if env::var_os(CHILD_VAR).is_some() {
+            const MB: usize = 1 << 20;
+            let mut dyn = DynamicAllocator::new();
+            unsafe {
+                global::free(global::alloc(64));
+                dyn.trim();
+                let before = large_alloc::stats();
+                let delta = || {
+                    let now = large_alloc::stats();
+                    (now.maps - before.maps, now.unmaps - before.unmaps, now.reused - before.reused)
+                };
+
+                // nothing is cached
+                global::set_large_free_policy(LargeFreePolicy::Unmap);
+                let a = dyn.alloc(4 * MB);
+                dyn.free(a);
+                alloc_assert!(!large_alloc::is_cached(a));
+                alloc_assert_eq!(delta(), (1, 1, 0));
+
+                // the region is cached, and zeroed when it is reused by alloc_zeroed, since it
+                // may not have been reclaimed yet
+                global::set_large_free_policy(LargeFreePolicy::MadvFree);
+                let b = dyn.alloc(4 * MB);
+                write_bytes(b, 0xAB, 4 * MB);
+                dyn.free(b);
+                alloc_assert!(large_alloc::is_cached(b));
+                let c = dyn.alloc_zeroed(4 * MB);
+                alloc_assert_eq!(c, b);
+                alloc_assert!(slice::from_raw_parts(c, 4 * MB).iter().all(|&x| x == 0));
+                alloc_assert_eq!(delta(), (2, 1, 1));
+                dyn.free(c);
+                // trim unmaps the region rather than leave it to the kernel
+                alloc_assert!(dyn.trim() >= 4 * MB);
+                alloc_assert!(!large_alloc::is_cached(c));
+                alloc_assert_eq!(delta(), (2, 2, 1));
+
+                // the region is cached already uncommitted
+                global::set_large_free_policy(LargeFreePolicy::MadvDontneed);
+                let d = dyn.alloc(4 * MB);
+                write_bytes(d, 0xAB, 4 * MB);
+                dyn.free(d);
+                alloc_assert!(large_alloc::is_cached(d));
+                let e = dyn.alloc(4 * MB);
+                alloc_assert_eq!(e, d);
+                alloc_assert_eq!(*e.offset(MB as isize), 0);
+                alloc_assert_eq!(delta(), (3, 2, 2));
+                dyn.free(e);
+
+                // switching to Unmap empties the cache
+                alloc_assert!(large_alloc::is_cached(e));
+                global::set_large_free_policy(LargeFreePolicy::Unmap);
+                alloc_assert!(!large_alloc::is_cached(e));
+                alloc_assert_eq!(delta(), (3, 3, 2));
+
+                for &(val, policy) in &[
+                    ("unmap", Some(LargeFreePolicy::Unmap)),
+                    ("madv_free", Some(LargeFreePolicy::MadvFree)),
+                    ("madv_dontneed", Some(LargeFreePolicy::MadvDontneed)),
+                    ("MADV_FREE", None),
+                ] {
+                    env::set_var("ELFMALLOC_TEST_LARGE_FREE_POLICY", val);
+                    let var = b"ELFMALLOC_TEST_LARGE_FREE_POLICY\0";
+                    alloc_assert_eq!(LargeFreePolicy::from_env_var(var), policy);
+                }
+            }
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::large_free_policy", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn large_alloc_cache() {
+        // an unusual size, so that other tests are unlikely to take the cached region
+        const SIZE: usize = (16 << 20) + (3 << 16);
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            // the cache is shared with other tests, which may take or flush the region between
+            // the free and the next allocation
+            let mut reused = false;
+            for _ in 0..16 {
+                let item = dyn.alloc(SIZE);
+                write_bytes(item, 0xFF, SIZE);
+                dyn.free(item);
+                if !large_alloc::is_cached(item) {
+                    continue;
+                }
+                alloc_assert!(!resident(item, SIZE));
+                let again = dyn.alloc(SIZE);
+                if again != item {
+                    dyn.free(again);
+                    continue;
+                }
+                reused = true;
+                // the cached region was uncommitted, so it reads as zeros
+                alloc_assert!(slice::from_raw_parts(again, SIZE).iter().all(|&b| b == 0));
+                alloc_assert_eq!(large_alloc::get_size(again), SIZE);
+                #[cfg(not(feature = "no_stats"))]
+                alloc_assert!(dyn.stats().large_bytes >= SIZE);
+                dyn.free(again);
+                dyn.trim();
+                alloc_assert!(!large_alloc::is_cached(again));
+                break;
+            }
+            alloc_assert!(reused);
+        }
+    }
+
+    #[test]
+    fn large_double_free_aborts() {
+        use std::env;
+        use std::process::{self, Command};
+        // The abort takes down the whole process, so each double free is done by a child process
+        // that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_LARGE_DOUBLE_FREE";
+        const SIZE: usize = 4 << 20;
+        if let Some(mode) = env::var_os(CHILD_VAR) {
+            let mut dyn = DynamicAllocator::new();
+            unsafe {
+                // a region whose address is reused is freed as usual
+                let a = dyn.alloc(SIZE);
+                dyn.free(a);
+                alloc_assert!(large_alloc::was_freed(a));
+                let b = dyn.alloc(SIZE);
+                alloc_assert!(!large_alloc::was_freed(b));
+                dyn.free(b);
+                match mode.to_str().unwrap() {
+                    "handle" => {
+                        let item = dyn.alloc(SIZE);
+                        dyn.free(item);
+                        dyn.free(item);
+                    }
+                    "global" => {
+                        let item = global::alloc(SIZE);
+                        global::free(item);
+                        global::free(item);
+                    }
+                    "batch" => {
+                        let item = dyn.alloc(SIZE);
+                        let small = dyn.alloc(64);
+                        dyn.free_batch(&[item, small, item]);
+                    }
+                    "without_handle" => {
+                        let item = global::alloc(SIZE);
+                        global::free(item);
+                        global::free_without_handle(item);
+                    }
+                    _ => {
+                        let item = large_alloc::alloc(SIZE);
+                        large_alloc::free(item);
+                        large_alloc::free(item);
+                    }
+                }
+            }
+            process::exit(0);
+        }
+        for &mode in &["handle", "global", "batch", "without_handle", "large_alloc"] {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::large_double_free_aborts", "--test-threads=1"])
+                .env(CHILD_VAR, mode)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            alloc_assert!(!out.status.success(), "a double free through {} did not abort", mode);
+            alloc_assert!(
+                stderr.contains("double free of large allocation"),
+                "unexpected output for a double free through {}: {}",
+                mode,
+                stderr
+            );
+        }
+    }
+
+    #[test]
+    fn large_registry_cross_thread() {
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        // The registry is shared by every test in the process, so its totals are only checked in
+        // a child process that runs this test alone.
+        const CHILD_VAR: &str = "ELFMALLOC_LARGE_REGISTRY";
+        const SIZE: usize = 4 << 20;
+        if let Some(mode) = env::var_os(CHILD_VAR) {
+            alloc_assert_eq!(global::live_large_bytes(), 0);
+            let dyn = DynamicAllocator::new();
+            // allocated on one thread...
+            let mut alloc_dyn = dyn.clone();
+            let items = thread::spawn(move || unsafe {
+                let mut items = Vec::new();
+                for &size in &[SIZE, 3 * SIZE] {
+                    items.push(alloc_dyn.alloc(size) as usize);
+                    items.push(global::alloc(size) as usize);
+                }
+                items
+            }).join()
+                .unwrap();
+            let mut total = 0;
+            for &item in &items {
+                let (region_size, base) = unsafe { large_alloc::get_commitment(item as *mut u8) };
+                alloc_assert_eq!(large_alloc::registered_size(base), Some(region_size));
+                total += region_size;
+            }
+            alloc_assert_eq!(global::live_large_bytes(), total);
+            // ...and resized and freed on another
+            let mut free_dyn = dyn.clone();
+            let leaked = thread::spawn(move || unsafe {
+                let grown = free_dyn.realloc(items[0] as *mut u8, 16 * SIZE);
+                let shrunk = global::realloc(items[3] as *mut u8, SIZE);
+                alloc_assert_eq!(
+                    global::live_large_bytes(),
+                    [grown, items[1] as *mut u8, items[2] as *mut u8, shrunk]
+                        .iter()
+                        .map(|&item| large_alloc::get_commitment(item).0)
+                        .sum::<usize>()
+                );
+                free_dyn.free_batch(&[grown, items[2] as *mut u8]);
+                global::free(items[1] as *mut u8);
+                shrunk as usize
+            }).join()
+                .unwrap();
+            if mode.to_str() == Some("leak") {
+                global::assert_no_live_large_allocs();
+            }
+            unsafe { global::free(leaked as *mut u8) };
+            alloc_assert_eq!(global::live_large_bytes(), 0);
+            global::assert_no_live_large_allocs();
+            process::exit(0);
+        }
+        for &mode in &["free", "leak"] {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&[
+                    "--exact",
+                    "general::tests::large_registry_cross_thread",
+                    "--test-threads=1",
+                ])
+                .env(CHILD_VAR, mode)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            alloc_assert_eq!(out.status.success(), mode == "free", "{}", stderr);
+            if mode == "leak" {
+                alloc_assert!(stderr.contains("large allocations still live: 1"), "{}", stderr);
+            }
+        }
+    }
+
+    #[test]
+    fn class_handle_large_alloc_zeroed() {
+        // an unusual size, so that only this test caches regions of it
+        const SIZE: usize = (8 << 20) + (7 << 12);
+        let dyn = DynamicAllocator::new();
+        let (mut handle, class_size, _) = dyn.class_for(SIZE, 4096).unwrap();
+        alloc_assert_eq!(class_size, SIZE);
+        unsafe {
+            let mut recycled = false;
+            for _ in 0..16 {
+                let item = handle.alloc();
+                write_bytes(item, 0xAB, SIZE);
+                // cache the region with its contents, as if it were not uncommitted
+                large_alloc::free_dirty(item);
+                let again = handle.alloc_zeroed(SIZE);
+                if again != item {
+                    // another test flushed the cache in between
+                    handle.free(again);
+                    continue;
+                }
+                recycled = true;
+                alloc_assert!(slice::from_raw_parts(again, SIZE).iter().all(|&b| b == 0));
+                handle.free(again);
+                break;
+            }
+            alloc_assert!(recycled);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn large_alloc_zeroed() {
+        // an unusual size, so that only this test caches regions of it
+        const SIZE: usize = (8 << 20) + (5 << 12);
+        let zeroed = |item: *mut u8| unsafe {
+            slice::from_raw_parts(item, SIZE).iter().all(|&b| b == 0)
+        };
+        unsafe {
+            let mut recycled = false;
+            for _ in 0..16 {
+                // a fresh (or uncommitted) region is known to be zero, so none of it is written
+                let item = large_alloc::alloc_zeroed(SIZE);
+                alloc_assert!(!resident(item, SIZE));
+                alloc_assert!(zeroed(item));
+                write_bytes(item, 0xAB, SIZE);
+                // cache the region with its contents, as if it were not uncommitted
+                large_alloc::free_dirty(item);
+                let again = large_alloc::alloc_zeroed(SIZE);
+                if again != item {
+                    // another test flushed the cache in between
+                    large_alloc::free(again);
+                    continue;
+                }
+                recycled = true;
+                alloc_assert!(zeroed(again));
+                large_alloc::free(again);
+                break;
+            }
+            alloc_assert!(recycled);
+        }
+    }
+
+    #[test]
+    fn instance_layout_lookup() {
+        let mut dyn = DynamicAllocator::new();
+        let mut test_and_free = |inp: usize, tester: &Fn(usize, usize)| unsafe {
+            let obj = dyn.alloc(inp);
+            let (size, align) = dyn.layout_of(obj);
+            alloc_assert_eq!(dyn.usable_size(obj), size);
+            tester(size, align);
+            dyn.free(obj);
+        };
+
+        test_and_free(8, &|size, align| {
+            alloc_assert!(size >= 8);
+            alloc_assert!(align >= 8);
+        });
+        test_and_free(24, &|size, align| {
+            alloc_assert!(size >= 24);
+            alloc_assert!(align >= 8);
+        });
+        test_and_free(512, &|size, align| {
+            alloc_assert!(size >= 512);
+            alloc_assert!(align >= 512);
+        });
+        test_and_free(256 << 10, &|size, align| {
+            alloc_assert_eq!((size, align), (256 << 10, 256 << 10))
+        });
+        test_and_free(4 << 20, &|size, align| {
+            alloc_assert_eq!((size, align), (4 << 20, mmap::page_size()))
+        });
+    }
+
+    #[test]
+    fn instance_layout_slag_start() {
+        let _ = env_logger::init();
+        let word = mem::size_of::<usize>();
+        let custom = || {
+            DynamicAllocatorBuilder::default()
+                .small_page_size(64 << 10)
+                .large_page_size(512 << 10)
+                .build()
+                .unwrap()
+        };
+        let configs = vec![
+            (DynamicAllocator::new(), ELFMALLOC_SMALL_PAGE_SIZE, ELFMALLOC_PAGE_SIZE),
+            (custom(), 64 << 10, 512 << 10),
+        ];
+        for (mut dyn, small_page_size, large_page_size) in configs {
+            unsafe {
+                // sizes up to a word share the word class
+                for size in 1..(word + 1) {
+                    let obj = dyn.alloc(size);
+                    alloc_assert_eq!(dyn.layout_of(obj), (word, word));
+                    dyn.free(obj);
+                }
+                for &(size, class, align, page_size) in &[
+                    (word, word, word, small_page_size),
+                    (24, 24, word, small_page_size),
+                    (4 << 10, 4 << 10, 4 << 10, small_page_size),
+                    (100 << 10, 128 << 10, 128 << 10, large_page_size),
+                ]
+                {
+                    // Allocate until an object is the first in its slag, and the slag starts at a
+                    // 2MiB boundary, where get_type finds the stamp in the slag's own header.
+                    let mut live = Vec::new();
+                    loop {
+                        let obj = dyn.alloc(size);
+                        live.push(obj);
+                        let slag = Slag::find(obj, page_size);
+                        let first = (slag as *mut u8)
+                            .offset((*slag).get_metadata().objects_offset);
+                        if obj == first && slag as usize % ELFMALLOC_PAGE_SIZE == 0 {
+                            break;
+                        }
+                        alloc_assert!(live.len() < 1 << 22, "no slag start for size {}", size);
+                    }
+                    alloc_assert_eq!(dyn.layout_of(*live.last().unwrap()), (class, align));
+                    dyn.free_batch(&live);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_class_config() {
+        type Elf = ElfMalloc<PageAlloc<Source>, SizeClasses<ObjectAlloc<PageAlloc<Source>>>>;
+        const TIERED: ClassSpec<'static> = ClassSpec::Tiered {
+            start_from: 8,
+            n_classes: 25,
+            multiple: MULTIPLE,
+            small_cutoff: ELFMALLOC_SMALL_CUTOFF,
+            tiny_classes: false,
+        };
+        let pa_large = PageAlloc::new(ELFMALLOC_PAGE_SIZE, 1 << 20, 8, AllocType::BigSlag);
+        let pa_small = PageAlloc::new_aligned(
+            ELFMALLOC_SMALL_PAGE_SIZE,
+            1 << 20,
+            8,
+            ELFMALLOC_PAGE_SIZE,
+            AllocType::SmallSlag,
+        );
+        let (cutoff, never) = (ELFMALLOC_SMALL_CUTOFF, HugePages::Never);
+        let (small, large) = (pa_small.clone(), pa_large.clone());
+        let magazines = MagazineSpec::default();
+        match Elf::new_internal(1.5, magazines, cutoff, small, large, None, never, TIERED) {
+            Err(e) => alloc_assert_eq!(e, ConfigError::DegenerateCutoff { cutoff_factor: 1.5 }),
+            Ok(_) => alloc_panic!("constructed allocator with cutoff factor 1.5"),
+        }
+        let mut elf =
+            Elf::new_internal(1.0, magazines, cutoff, pa_small, pa_large, None, never, TIERED)
+                .unwrap();
+        unsafe {
+            let item = elf.alloc(16);
+            write_volatile(item, 1);
+            elf.free(item);
+        }
+    }
+
+    #[test]
+    fn builder_small_pages() {
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .small_page_size(64 << 10)
+            .build()
+            .unwrap();
+        alloc_assert_eq!(dyn.0.small_cutoff, 16 << 10);
+        let mut size = 1;
+        while size <= 4 << 20 {
+            unsafe {
+                let item = dyn.alloc(size);
+                write_bytes(item, 1, size);
+                let (obj_size, align) = dyn.layout_of(item);
+                alloc_assert!(obj_size >= size, "size {} got object of size {}", size, obj_size);
+                alloc_assert!(align >= mem::size_of::<usize>());
+                alloc_assert_eq!(item as usize % align, 0);
+                if size.is_power_of_two() {
+                    alloc_assert!(align >= size || size > dyn.0.max_size);
+                }
+                let item = dyn.realloc(item, size * 2);
+                alloc_assert!(dyn.layout_of(item).0 >= size * 2);
+                dyn.free(item);
+            }
+            size += size / 8 + 1;
+        }
+    }
+
+    #[test]
+    fn builder_small_cutoff() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .small_cutoff(256)
+            .n_classes(34)
+            .build()
+            .unwrap();
+        alloc_assert_eq!(dyn.0.small_cutoff, 256);
+        // the multiples of 16 stop at the cutoff, rather than taking half of the classes
+        let classes: Vec<usize> = dyn.0.allocs.iter().map(|(size, _)| size).collect();
+        alloc_assert!((1..17).all(|i| classes.contains(&(i * 16))), "{:?}", classes);
+        alloc_assert!(
+            classes.iter().all(|&size| size <= 256 || size.is_power_of_two()),
+            "{:?}",
+            classes
+        );
+        alloc_assert_eq!(classes.iter().find(|&&size| size > 256), Some(&512));
+        unsafe {
+            for &size in &[1, 200, 256, 300, 4 << 10, 100 << 10] {
+                let item = dyn.alloc(size);
+                write_bytes(item, 1, size);
+                alloc_assert!(dyn.layout_of(item).0 >= size, "size {}", size);
+                dyn.free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn builder_max_class_size() {
+        let _ = env_logger::init();
+        alloc_assert_eq!(DynamicAllocator::new().max_class_size(), 1 << 20);
+        // below the default, and large enough to need huge pages
+        for &max in &[256 << 10, 8 << 20] {
+            let mut dyn = DynamicAllocatorBuilder::default()
+                .max_class_size(max)
+                .build()
+                .unwrap();
+            alloc_assert_eq!(dyn.max_class_size(), max);
+            let ty = if max > ELFMALLOC_PAGE_SIZE / 2 {
+                AllocType::HugeSlag
+            } else {
+                AllocType::BigSlag
+            };
+            for &size in &[max - 1, max, max + 1, 3 << 20] {
+                unsafe {
+                    let item = dyn.alloc(size);
+                    // this would overwrite a type stamp placed inside the object
+                    write_bytes(item, 0xFF, size);
+                    let (obj_size, align) = dyn.layout_of(item);
+                    if size <= max {
+                        let class = size.next_power_of_two();
+                        alloc_assert_eq!((obj_size, align), (class, class));
+                        if class > ELFMALLOC_PAGE_SIZE / 2 {
+                            alloc_assert_eq!(get_type(item), AllocType::HugeSlag);
+                        }
+                    } else {
+                        alloc_assert!(obj_size >= size);
+                        alloc_assert_eq!(get_type(item), AllocType::Large);
+                    }
+                    dyn.free(item);
+                }
+            }
+            // recycled and trimmed pages are stamped again
+            for _ in 0..4 {
+                unsafe {
+                    let items: Vec<_> = (0..3).map(|_| dyn.alloc(max)).collect();
+                    for &item in &items {
+                        alloc_assert_eq!(get_type(item), ty);
+                        write_bytes(item, 1, max);
+                    }
+                    for item in items {
+                        dyn.free(item);
+                    }
+                }
+                dyn.trim();
+            }
+        }
+    }
+
+    #[test]
+    fn extend_classes() {
+        let mut dyn = DynamicAllocator::new();
+        let mut early_clone = dyn.clone();
+        let mut other_clone = dyn.clone();
+        unsafe {
+            let large = dyn.alloc(4 << 20);
+            alloc_assert_eq!(get_type(large), AllocType::Large);
+            let medium = dyn.alloc(1 << 20);
+            alloc_assert_eq!(dyn.extend_classes(5 << 20), Ok(()));
+            alloc_assert_eq!(dyn.max_class_size(), 8 << 20);
+            alloc_assert_eq!(dyn.size_class_for(3 << 20), Some(4 << 20));
+            let item = dyn.alloc(4 << 20);
+            alloc_assert_eq!(get_type(item), AllocType::HugeSlag);
+            alloc_assert_eq!(dyn.layout_of(item), (4 << 20, 4 << 20));
+            write_bytes(item, 1, 4 << 20);
+            // objects allocated before the extension are freed as usual
+            dyn.free(large);
+            dyn.free(medium);
+
+            // a clone made before the extension frees objects of the new classes, after which it
+            // has them too
+            alloc_assert_eq!(early_clone.max_class_size(), 1 << 20);
+            alloc_assert_eq!(early_clone.class_stats().len(), dyn.class_stats().len());
+            alloc_assert_eq!(early_clone.layout_of(item), (4 << 20, 4 << 20));
+            early_clone.free(item);
+            alloc_assert_eq!(early_clone.max_class_size(), 8 << 20);
+            // or picks them up when it allocates one of their sizes
+            let item = other_clone.alloc(8 << 20);
+            alloc_assert_eq!(get_type(item), AllocType::HugeSlag);
+            alloc_assert_eq!(other_clone.max_class_size(), 8 << 20);
+            dyn.free(item);
+            // clones made after the extension have the new classes from the start
+            let mut late_clone = dyn.clone();
+            alloc_assert_eq!(late_clone.max_class_size(), 8 << 20);
+            let item = late_clone.alloc_zeroed(2 << 20);
+            alloc_assert_eq!(get_type(item), AllocType::HugeSlag);
+            alloc_assert_eq!(*item, 0);
+            early_clone.free(item);
+        }
+        let stats = dyn.class_stats();
+        alloc_assert_eq!(stats[stats.len() - 1].object_size, 8 << 20);
+        alloc_assert!(stats.iter().all(|class| class.live_objects == 0));
+
+        // the largest class is already large enough
+        alloc_assert_eq!(dyn.extend_classes(2 << 20), Ok(()));
+        alloc_assert_eq!(dyn.max_class_size(), 8 << 20);
+        // the huge pages were made for classes up to 8MiB
+        alloc_assert_eq!(
+            dyn.extend_classes(16 << 20),
+            Err(ConfigError::ClassDoesNotFit {
+                size: 16 << 20,
+                page_size: 16 << 20,
+            })
+        );
+        alloc_assert_eq!(dyn.max_class_size(), 8 << 20);
+        alloc_assert_eq!(
+            dyn.extend_classes(2 << 30),
+            Err(ConfigError::ClassTooLarge {
+                size: 2 << 30,
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            })
+        );
+        let mut geometric = DynamicAllocatorBuilder::default().medium_ratio(1.5).build().unwrap();
+        alloc_assert_eq!(geometric.extend_classes(8 << 20), Err(ConfigError::FixedClasses));
+
+        // the huge pages of an allocator built with huge classes fit only those classes
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .max_class_size(2 << 20)
+            .build()
+            .unwrap();
+        alloc_assert_eq!(
+            dyn.extend_classes(4 << 20),
+            Err(ConfigError::ClassDoesNotFit {
+                size: 4 << 20,
+                page_size: 4 << 20,
+            })
+        );
+        alloc_assert_eq!(dyn.max_class_size(), 2 << 20);
+    }
+
+    /// Get the `VmFlags` of the mapping in `/proc/self/smaps` containing `item`.
+    #[cfg(target_os = "linux")]
+    fn vm_flags(item: *mut u8) -> String {
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+        let addr = item as usize;
+        let mut in_mapping = false;
+        for line in BufReader::new(File::open("/proc/self/smaps").unwrap()).lines() {
+            let line = line.unwrap();
+            let range = line.split_whitespace().next().unwrap().to_string();
+            if let Some(dash) = range.find('-') {
+                let lo = usize::from_str_radix(&range[..dash], 16);
+                let hi = usize::from_str_radix(&range[dash + 1..], 16);
+                if let (Ok(lo), Ok(hi)) = (lo, hi) {
+                    in_mapping = lo <= addr && addr < hi;
+                    continue;
+                }
+            }
+            if in_mapping && line.starts_with("VmFlags:") {
+                return line;
+            }
+        }
+        alloc_panic!("no mapping contains {:?}", item)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn huge_pages_transparent() {
+        use std::fs::File;
+        use std::io::Read;
+        let _ = env_logger::init();
+        let mut enabled = String::new();
+        let thp_available = File::open("/sys/kernel/mm/transparent_hugepage/enabled")
+            .and_then(|mut f| f.read_to_string(&mut enabled))
+            .is_ok() && !enabled.contains("[never]");
+        if !thp_available {
+            return;
+        }
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .huge_pages(HugePages::Transparent)
+            .build()
+            .unwrap();
+        let has_hg = |item| vm_flags(item).split_whitespace().any(|flag| flag == "hg");
+        unsafe {
+            let small = dyn.alloc(64);
+            let big = dyn.alloc(100 << 10);
+            write_bytes(big, 1, 100 << 10);
+            alloc_assert_eq!(get_type(big), AllocType::BigSlag);
+            alloc_assert!(has_hg(big));
+            alloc_assert_eq!(get_type(small), AllocType::SmallSlag);
+            alloc_assert!(!has_hg(small));
+            dyn.free(small);
+            dyn.free(big);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_huge_pages() {
+        use super::super::utils::mmap::HUGE_ADVICE;
+        let advised = |item: *mut u8| {
+            let (region_size, base) = unsafe { large_alloc::get_commitment(item) };
+            HUGE_ADVICE.with(|advice| advice.borrow().contains(&(base, region_size)))
+        };
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .large_huge_pages(HugePages::Transparent, 4 << 20)
+            .build()
+            .unwrap();
+        let small = dyn.max_class_size() + 1;
+        alloc_assert!(small < 4 << 20);
+        unsafe {
+            let below = dyn.alloc(small);
+            let above = dyn.alloc(4 << 20);
+            alloc_assert!(!advised(below));
+            alloc_assert!(advised(above));
+            dyn.free(below);
+            dyn.free(above);
+        }
+
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .large_huge_pages(HugePages::HugeTlb, 0)
+            .build()
+            .unwrap();
+        unsafe {
+            let item = dyn.alloc(3 << 20);
+            // whether or not hugetlbfs has pages, the region is a whole number of huge pages
+            alloc_assert_eq!(dyn.layout_of(item).0, 4 << 20);
+            let huge_tlb = vm_flags(item).split_whitespace().any(|flag| flag == "ht");
+            alloc_assert!(huge_tlb != advised(item));
+            write_bytes(item, 1, 4 << 20);
+            // free must unmap the whole region, which munmap rejects for a partial huge page
+            dyn.free(item);
+            alloc_assert!(!large_alloc::is_large(item));
+        }
+    }
+
+    #[test]
+    fn alloc_excess() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        let (cutoff, max) = (dyn.0.small_cutoff, dyn.max_class_size());
+        let sizes = [
+            1, 8, 9, 24, 600, cutoff - 1, cutoff, cutoff + 1, max - 1, max, max + 1, 5 << 20,
+        ];
+        unsafe {
+            for &size in &sizes {
+                let (item, usable) = dyn.alloc_excess(size);
+                alloc_assert!(usable >= size, "size={} usable={}", size, usable);
+                alloc_assert_eq!(dyn.usable_size(item), usable);
+                write_bytes(item, 1, usable);
+                dyn.free(item);
+
+                let (item, usable) = global::alloc_excess(size);
+                alloc_assert!(usable >= size, "size={} usable={}", size, usable);
+                alloc_assert_eq!(global::get_layout(item).0, usable);
+                write_bytes(item, 1, usable);
+                global::free(item);
+            }
+            // a 600-byte request gets a whole slot of its class
+            let (item, usable) = dyn.alloc_excess(600);
+            alloc_assert_eq!(usable, 1024);
+            dyn.free(item);
+
+            // objects of a frozen class come from a larger class
+            #[cfg(feature = "freeze")]
+            {
+                let guard = dyn.freeze_class(600, false).unwrap();
+                let (item, usable) = dyn.alloc_excess(600);
+                alloc_assert!(usable > guard.object_size());
+                alloc_assert_eq!(dyn.usable_size(item), usable);
+                mem::drop(guard);
+                dyn.free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed() {
+        let mut dyn = DynamicAllocator::new();
+        for &size in &[8, 24, 200, 4 << 10, 100 << 10, 4 << 20] {
+            unsafe {
+                let item = dyn.alloc_zeroed(size);
+                alloc_assert!((0..size).all(|i| *item.offset(i as isize) == 0));
+                write_bytes(item, 0xAB, size);
+                dyn.free(item);
+                // the object just freed is cached, and is handed out again
+                let again = dyn.alloc_zeroed(size);
+                if size <= dyn.0.max_size {
+                    alloc_assert_eq!(again, item);
+                }
+                alloc_assert!((0..size).all(|i| *again.offset(i as isize) == 0));
+                dyn.free(again);
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_aligned() {
+        let mut dyn = DynamicAllocator::new();
+        let mut align = 1;
+        while align <= ELFMALLOC_PAGE_SIZE {
+            for &size in &[1, 100, 3 << 20] {
+                unsafe {
+                    let item = dyn.alloc_aligned(size, align);
+                    alloc_assert_eq!(item as usize % align, 0, "size {} align {}", size, align);
+                    write_bytes(item, 1, size);
+                    alloc_assert!(dyn.layout_of(item).0 >= size);
+                    dyn.free(item);
+                }
+            }
+            align *= 2;
+        }
+        // sizes are not needlessly rounded up to a power of two
+        unsafe {
+            for &(size, align) in &[(3 << 20, 64), (100, 4096)] {
+                let item = dyn.alloc_aligned(size, align);
+                alloc_assert_eq!(item as usize % align, 0);
+                alloc_assert!(dyn.layout_of(item).0 <= 2 * cmp::max(size, align));
+                let new_item = dyn.aligned_realloc(item, size + 1, align);
+                alloc_assert_eq!(new_item as usize % align, 0);
+                alloc_assert!(dyn.layout_of(new_item).0 <= 2 * cmp::max(size + 1, align));
+                dyn.free(new_item);
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_aligned_classes() {
+        let mut dyn = DynamicAllocator::new();
+        // Aligned objects get the smallest class that is aligned enough: 48 and 112 are only
+        // aligned to 16 bytes. Objects too large for any class are aligned without rounding up.
+        let cases = [
+            (48, 16, 48),
+            (48, 64, 64),
+            (100, 16, 112),
+            (100, 256, 256),
+            (3 << 20, 64, 3 << 20),
+        ];
+        for &(size, align, usable) in &cases {
+            let msg = format!("size {} align {}", size, align);
+            alloc_assert_eq!(dyn.usable_size_aligned(size, align), usable, "{}", msg);
+            unsafe {
+                let item = dyn.alloc_aligned(size, align);
+                alloc_assert_eq!(item as usize % align, 0, "{}", msg);
+                write_bytes(item, 1, size);
+                let (layout_size, layout_align) = dyn.layout_of(item);
+                alloc_assert!(layout_size >= usable && layout_align >= align);
+                alloc_assert!(layout_size < usable + ELFMALLOC_SMALL_CUTOFF, "{}", msg);
+                let new_item = dyn.aligned_realloc(item, usable, align);
+                alloc_assert_eq!(new_item as usize % align, 0);
+                alloc_assert!(dyn.layout_of(new_item).0 >= usable);
+                dyn.free(new_item);
+            }
+        }
+        let (_, class_size, class_align) = dyn.class_for(100, 256).unwrap();
+        alloc_assert_eq!((class_size, class_align), (256, 256));
+        let (_, class_size, class_align) = dyn.class_for(48, 16).unwrap();
+        alloc_assert_eq!((class_size, class_align), (48, 16));
+    }
+
+    /// Whether every `FailingSource` fails to carve memory.
+    static CARVE_FAILS: AtomicBool = AtomicBool::new(false);
+
+    /// A `MemorySource` that runs out of memory whenever `CARVE_FAILS` is set.
+    #[derive(Clone)]
+    struct FailingSource(MmapSource);
+
+    impl MemorySource for FailingSource {
+        fn new(page_size: usize) -> FailingSource {
+            FailingSource(MmapSource::new(page_size))
+        }
+        fn page_size(&self) -> usize {
+            self.0.page_size()
+        }
+        fn carve(&self, npages: usize) -> Option<*mut u8> {
+            if CARVE_FAILS.load(Ordering::Relaxed) {
+                None
+            } else {
+                self.0.carve(npages)
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_exhausted() {
+        type Elf = ElfMalloc<
+            PageAlloc<FailingSource>,
+            SizeClasses<ObjectAlloc<PageAlloc<FailingSource>>>,
+        >;
+        const SIZE: usize = 32 << 10;
+        let mut elf = Elf::new();
+        unsafe {
+            // the first allocation of a class fails rather than aborting when the handle's cache
+            // for the class cannot get a slag
+            let source = FailingSource::new(ELFMALLOC_PAGE_SIZE);
+            let mut cold = DynamicAllocator::with_source(source.clone(), source);
+            CARVE_FAILS.store(true, Ordering::Relaxed);
+            alloc_assert_eq!(cold.try_alloc(SIZE), Err(Exhausted));
+            alloc_assert!(cold.0.alloc_zeroed(SIZE).is_null());
+            let mut out = [ptr::null_mut(); 4];
+            alloc_assert_eq!(cold.alloc_batch(64, &mut out), 0);
+            alloc_assert_eq!(cold.reserve_for_class(1000, 8), Err(Exhausted));
+            CARVE_FAILS.store(false, Ordering::Relaxed);
+            let item = cold.try_alloc(SIZE).unwrap();
+            cold.free(item);
+
+            let mut live = vec![elf.alloc(SIZE)];
+            CARVE_FAILS.store(true, Ordering::Relaxed);
+            loop {
+                let item = elf.alloc(SIZE);
+                if item.is_null() {
+                    break;
+                }
+                alloc_assert!(live.len() < 1 << 16, "allocations did not fail");
+                live.push(item);
+            }
+            CARVE_FAILS.store(false, Ordering::Relaxed);
+            // the class recovers once memory is available again
+            let item = elf.alloc(SIZE);
+            alloc_assert!(!item.is_null());
+            write_bytes(item, 1, SIZE);
+            live.push(item);
+            for item in live {
+                elf.free(item);
+            }
+
+            // alloc_batch keeps the objects it allocated before running out
+            let mut batch = Elf::new();
+            let first = batch.alloc(SIZE);
+            let mut out = vec![ptr::null_mut(); 1 << 16];
+            CARVE_FAILS.store(true, Ordering::Relaxed);
+            let n = batch.alloc_batch(SIZE, &mut out);
+            CARVE_FAILS.store(false, Ordering::Relaxed);
+            alloc_assert!(n > 0 && n < out.len(), "n={}", n);
+            alloc_assert!(out[..n].iter().all(|p| !p.is_null()));
+            alloc_assert!(out[n..].iter().all(|p| p.is_null()));
+            batch.free(first);
+            batch.free_batch(&out[..n]);
+
+            // mapping this much memory fails
+            let mut dyn = DynamicAllocator::new();
+            alloc_assert_eq!(dyn.try_alloc(1 << 60), Err(Exhausted));
+            let item = dyn.try_alloc(SIZE).unwrap();
+            dyn.free(item);
+        }
+    }
+
+    /// The number of bytes uncommitted through a `CountingSource`.
+    static UNCOMMITTED: AtomicUsize = AtomicUsize::new(0);
+
+    /// A `MemorySource` that counts the bytes it uncommits in `UNCOMMITTED`.
+    #[derive(Clone)]
+    struct CountingSource(MmapSource);
+
+    impl MemorySource for CountingSource {
+        fn new(page_size: usize) -> CountingSource {
+            CountingSource(MmapSource::new(page_size))
+        }
+        fn page_size(&self) -> usize {
+            self.0.page_size()
+        }
+        fn carve(&self, npages: usize) -> Option<*mut u8> {
+            self.0.carve(npages)
+        }
+        unsafe fn uncommit(&self, ptr: *mut u8, len: usize) {
+            UNCOMMITTED.fetch_add(len, Ordering::Relaxed);
+            self.0.uncommit(ptr, len)
+        }
+    }
+
+    #[test]
+    fn trim() {
+        type Elf = ElfMalloc<
+            PageAlloc<CountingSource>,
+            SizeClasses<ObjectAlloc<PageAlloc<CountingSource>>>,
+        >;
+        let _ = env_logger::init();
+        let mut elf = Elf::new();
+        unsafe {
+            // 64MiB each of objects from small and large pages
+            for &size in &[1 << 10, 128 << 10] {
+                let live: Vec<*mut u8> = (0..(64 << 20) / size)
+                    .map(|_| {
+                        let item = elf.alloc(size);
+                        write_bytes(item, 1, size);
+                        item
+                    })
+                    .collect();
+                for item in live {
+                    elf.free(item);
+                }
+            }
+            let before = UNCOMMITTED.load(Ordering::Relaxed);
+            let released = elf.trim();
+            alloc_assert_eq!(UNCOMMITTED.load(Ordering::Relaxed) - before, released);
+            // Most of the working set is released: only pages that still hold objects cached by
+            // the handle, and the retained pages, are kept.
+            alloc_assert!(released >= 64 << 20, "released {} bytes", released);
+            // everything beyond the retained pages is already released
+            alloc_assert_eq!(elf.trim(), 0);
+
+            // the released pages are reused, and read as zeros
+            let item = elf.alloc(128 << 10);
+            write_bytes(item, 1, 128 << 10);
+            elf.free(item);
+            let item = elf.alloc_zeroed(1 << 10);
+            alloc_assert!(slice::from_raw_parts(item, 1 << 10).iter().all(|&b| b == 0));
+            elf.free(item);
+        }
+    }
+
+    /// The number of bytes mapped through a `MappingSource` that have not been released.
+    static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+    /// A `MemorySource` that counts the bytes it has mapped and not released in `MAPPED`.
+    #[derive(Clone)]
+    struct MappingSource(MmapSource);
+
+    impl MemorySource for MappingSource {
+        fn new(page_size: usize) -> MappingSource {
+            MappingSource(MmapSource::new(page_size))
+        }
+        fn page_size(&self) -> usize {
+            self.0.page_size()
+        }
+        fn carve(&self, npages: usize) -> Option<*mut u8> {
+            let res = self.0.carve(npages);
+            if res.is_some() {
+                MAPPED.fetch_add(npages * self.page_size(), Ordering::Relaxed);
+            }
+            res
+        }
+        unsafe fn release(&self, ptr: *mut u8, len: usize) {
+            MAPPED.fetch_sub(len, Ordering::Relaxed);
+            self.0.release(ptr, len)
+        }
+    }
+
+    /// An allocator backed by a `MappingSource`, dropped the way a `DynamicAllocator` is.
+    struct MappingAllocator(
+        ElfMalloc<PageAlloc<MappingSource>, SizeClasses<ObjectAlloc<PageAlloc<MappingSource>>>>,
+    );
+
+    impl Drop for MappingAllocator {
+        fn drop(&mut self) {
+            unsafe { self.0.destroy_classes() }
+        }
+    }
+
+    #[test]
+    fn drop_releases_pages() {
+        let _ = env_logger::init();
+        for i in 0..1000 {
+            let mut elf = MappingAllocator(ElfMalloc::new());
+            let mut clone = MappingAllocator(elf.0.clone());
+            unsafe {
+                let items: Vec<*mut u8> = [8, 1 << 10, 128 << 10]
+                    .iter()
+                    .map(|&size| {
+                        let item = elf.0.alloc(size);
+                        write_bytes(item, 1, size);
+                        item
+                    })
+                    .collect();
+                // the pages stay mapped while a clone is alive
+                mem::drop(elf);
+                alloc_assert!(MAPPED.load(Ordering::Relaxed) > 0);
+                for item in items {
+                    clone.0.free(item);
+                }
+                let item = clone.0.alloc(1 << 10);
+                write_bytes(item, 2, 1 << 10);
+                clone.0.free(item);
+            }
+            mem::drop(clone);
+            alloc_assert_eq!(MAPPED.load(Ordering::Relaxed), 0, "iteration {}", i);
+        }
+    }
+
+    /// A region of memory mapped up front, from which a `RegionSource` carves pages.
+    struct Region {
+        start: usize,
+        len: usize,
+        /// The start of the part of the region that has not been carved yet.
+        next: AtomicUsize,
+    }
+
+    impl Drop for Region {
+        fn drop(&mut self) {
+            unsafe { mmap::unmap(self.start as *mut u8, self.len) }
+        }
+    }
+
+    /// A `MemorySource` that carves pages from a fixed `Region`, and fails once it is used up.
+    #[derive(Clone)]
+    struct RegionSource {
+        region: Arc<Region>,
+        page_size: usize,
+    }
+
+    impl RegionSource {
+        fn reserve(len: usize) -> RegionSource {
+            RegionSource {
+                region: Arc::new(Region {
+                    start: mmap::map(len) as usize,
+                    len: len,
+                    next: AtomicUsize::new(0),
+                }),
+                page_size: mmap::page_size(),
+            }
+        }
+
+        fn contains(&self, item: *mut u8) -> bool {
+            let start = self.region.start;
+            start <= item as usize && (item as usize) < start + self.region.len
+        }
+    }
+
+    impl MemorySource for RegionSource {
+        fn new(_page_size: usize) -> RegionSource {
+            alloc_panic!("a RegionSource must be created with reserve")
+        }
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+        fn carve(&self, npages: usize) -> Option<*mut u8> {
+            let region = &self.region;
+            let mut next = region.next.load(Ordering::Relaxed);
+            loop {
+                let start = (region.start + next + self.page_size - 1) & !(self.page_size - 1);
+                let end = start + npages * self.page_size;
+                if end > region.start + region.len {
+                    return None;
+                }
+                match region.next.compare_exchange(
+                    next,
+                    end - region.start,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(start as *mut u8),
+                    Err(cur) => next = cur,
+                }
+            }
+        }
+        fn with_page_size(&self, page_size: usize) -> RegionSource {
+            RegionSource {
+                region: self.region.clone(),
+                page_size: page_size,
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_region_source() {
+        let _ = env_logger::init();
+        let source = RegionSource::reserve(64 << 20);
+        let mut dyn = DynamicAllocator::with_source(source.clone(), source.clone());
+        let sizes = [8, 100, 1000, 16 << 10, 128 << 10, 1 << 20];
+        unsafe {
+            // Set up the handle's caches before the region runs out.
+            for &size in &sizes {
+                let item = dyn.alloc(size);
+                alloc_assert!(source.contains(item));
+                alloc_assert!(dyn.usable_size(item) >= size);
+                write_bytes(item, 1, size);
+                dyn.free(item);
+            }
+            // Objects come from the region until it is used up.
+            let mut live = Vec::new();
+            while let Ok(item) = dyn.try_alloc(128 << 10) {
+                alloc_assert!(source.contains(item));
+                write_bytes(item, 1, 128 << 10);
+                live.push(item);
+            }
+            alloc_assert!(!live.is_empty());
+            alloc_assert!(live.len() < (64 << 20) / (128 << 10));
+            for &item in &live {
+                alloc_assert_eq!(dyn.layout_of(item), (128 << 10, 128 << 10));
+                dyn.free(item);
+            }
+            // Large objects are mapped separately.
+            let large = dyn.alloc(4 << 20);
+            alloc_assert!(!source.contains(large));
+            dyn.free(large);
+        }
+        // The region stays mapped until the allocator is gone.
+        alloc_assert!(Arc::strong_count(&source.region) > 1);
+        mem::drop(dyn);
+        alloc_assert_eq!(Arc::strong_count(&source.region), 1);
+    }
+
+    #[test]
+    fn stats() {
+        let _ = env_logger::init();
+        const N_SMALL: usize = 1000;
+        const N_MEDIUM: usize = 10;
+        let mut dyn = DynamicAllocator::new();
+        let mut other = dyn.clone();
+        let large_size = dyn.max_class_size() + 1;
+        unsafe {
+            let small = (0..N_SMALL).map(|_| dyn.alloc(1024)).collect::<Vec<_>>();
+            // allocations from clones are included
+            let medium = (0..N_MEDIUM).map(|_| other.alloc(128 << 10)).collect::<Vec<_>>();
+            let large = dyn.alloc(large_size);
+            let stats = dyn.stats();
+            #[cfg(feature = "no_stats")]
+            alloc_assert_eq!(stats, ElfStats::default());
+            #[cfg(not(feature = "no_stats"))]
+            {
+                alloc_assert_eq!(stats.live_bytes, N_SMALL * 1024 + N_MEDIUM * (128 << 10));
+                // only the objects of the words each handle is allocating from are held back
+                alloc_assert!(
+                    stats.cached_bytes <= 64 * 1024 + 64 * (128 << 10),
+                    "{:?}",
+                    stats
+                );
+                alloc_assert!(stats.small_page_bytes >= N_SMALL * 1024, "{:?}", stats);
+                alloc_assert!(stats.large_page_bytes >= N_MEDIUM * (128 << 10), "{:?}", stats);
+                alloc_assert!(
+                    stats.large_bytes >= large_size &&
+                        stats.large_bytes <= large_size + 2 * ELFMALLOC_PAGE_SIZE,
+                    "{:?}",
+                    stats
+                );
+            }
+            for item in small {
+                dyn.free(item);
+            }
+            dyn.free(large);
+            let after = dyn.stats();
+            #[cfg(not(feature = "no_stats"))]
+            {
+                alloc_assert_eq!(after.live_bytes, N_MEDIUM * (128 << 10));
+                // the freed objects are cached by the handle
+                alloc_assert!(after.cached_bytes >= N_SMALL * 1024, "{:?}", after);
+                alloc_assert_eq!(after.small_page_bytes, stats.small_page_bytes);
+                alloc_assert_eq!(after.large_bytes, 0);
+            }
+            for item in medium {
+                other.free(item);
+            }
+            alloc_assert_eq!(dyn.stats().live_bytes, 0);
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn size_histogram() {
+        use std::thread;
+        fn class(histogram: &SizeHistogram, object_size: usize) -> ClassHistogram {
+            *histogram.classes.iter().find(|class| class.object_size == object_size).unwrap()
+        }
+        let mut dyn = DynamicAllocator::new();
+        let mut clone = dyn.clone();
+        unsafe {
+            for &(size, n) in &[(17, 10), (40, 3), (3 << 20, 2)] {
+                for _ in 0..n {
+                    let item = dyn.alloc(size);
+                    dyn.free(item);
+                }
+            }
+            for _ in 0..5 {
+                let item = clone.alloc(32);
+                clone.free(item);
+            }
+            let item = dyn.alloc_aligned(100, 256);
+            dyn.free(item);
+        }
+        // the clone's requests are only counted once it is dropped
+        let histogram = dyn.size_histogram();
+        alloc_assert_eq!(histogram.requests(), 16);
+        let class_32 = class(&histogram, 32);
+        alloc_assert_eq!((class_32.min_size, class_32.requests), (17, 10));
+        alloc_assert_eq!(class_32.buckets, [10, 0, 0, 0, 0, 0, 0, 0]);
+        // 40 is in the fourth eighth of the sizes from 33 to 48
+        let class_48 = class(&histogram, 48);
+        alloc_assert_eq!(class_48.buckets[3], 3);
+        alloc_assert_eq!(class_48.bucket_sizes(3), (39, 40));
+        alloc_assert_eq!(class(&histogram, 256).requests, 1);
+        alloc_assert_eq!(class(&histogram, 16).requests, 0);
+        alloc_assert_eq!((histogram.large_requests, histogram.large_bytes), (2, 6 << 20));
+        mem::drop(clone);
+        let histogram = dyn.size_histogram();
+        alloc_assert_eq!(histogram.requests(), 21);
+        alloc_assert_eq!(class(&histogram, 32).buckets, [10, 0, 0, 0, 0, 0, 0, 5]);
+        let report = histogram.to_string();
+        alloc_assert!(report.starts_with("21 requests\n"), "{}", report);
+        alloc_assert!(report.contains("  17-18: 10"), "{}", report);
+        alloc_assert!(report.contains("  31-32: 5"), "{}", report);
+        alloc_assert!(!report.contains("16 bytes"), "{}", report);
+
+        // the handles of exited threads are counted once the background thread drops them
+        let before = class(&global::size_histogram(), 2048).requests;
+        thread::spawn(|| unsafe {
+            for _ in 0..1000 {
+                global::free(global::alloc(1500));
+            }
+        }).join()
+            .unwrap();
+        let after = class(&global::size_histogram(), 2048);
+        alloc_assert!(after.requests >= before + 1000);
+        alloc_assert!(after.buckets[3] >= 1000);
+    }
+
+    #[test]
+    fn class_stats() {
+        let _ = env_logger::init();
+        const N_HOT: usize = 5000;
+        let mut dyn = DynamicAllocator::new();
+        let mut other = dyn.clone();
+        fn find(stats: &[ClassStats], size: usize) -> ClassStats {
+            *stats.iter().find(|class| class.object_size == size).unwrap()
+        }
+        unsafe {
+            let hot = (0..N_HOT).map(|_| dyn.alloc(48)).collect::<Vec<_>>();
+            let warm = (0..20).map(|_| dyn.alloc(1024)).collect::<Vec<_>>();
+            let medium = (0..3).map(|_| other.alloc(128 << 10)).collect::<Vec<_>>();
+            let stats = dyn.class_stats();
+            alloc_assert!(stats.windows(2).all(|pair| pair[0].object_size < pair[1].object_size));
+            alloc_assert_eq!(stats[stats.len() - 1].object_size, dyn.max_class_size());
+            let (hot_class, warm_class) = (find(&stats, 48), find(&stats, 1024));
+            alloc_assert!(hot_class.initialized && warm_class.initialized);
+            // the class was only used through the clone
+            let medium_class = find(&stats, 128 << 10);
+            alloc_assert!(!medium_class.initialized);
+            alloc_assert!(find(&other.class_stats(), 128 << 10).initialized);
+            // collecting the counts leaves unused classes alone
+            alloc_assert!(!find(&stats, 4096).initialized);
+            alloc_assert!(!find(&dyn.class_stats(), 4096).initialized);
+            #[cfg(not(feature = "no_stats"))]
+            {
+                alloc_assert_eq!(hot_class.live_objects, N_HOT);
+                alloc_assert_eq!(warm_class.live_objects, 20);
+                alloc_assert_eq!(medium_class.live_objects, 3);
+                alloc_assert!(
+                    hot_class.slags * ELFMALLOC_SMALL_PAGE_SIZE >= N_HOT * 48,
+                    "{:?}",
+                    hot_class
+                );
+                alloc_assert!(hot_class.slags >= warm_class.slags, "{:?}", stats);
+                let unused = ClassStats {
+                    object_size: 4096,
+                    ..ClassStats::default()
+                };
+                alloc_assert_eq!(find(&stats, 4096), unused);
+                let live = stats.iter().map(|class| class.live_objects * class.object_size);
+                alloc_assert_eq!(live.sum::<usize>(), dyn.stats().live_bytes);
+            }
+            for item in hot.into_iter().chain(warm) {
+                dyn.free(item);
+            }
+            for item in medium {
+                other.free(item);
+            }
+            let stats = dyn.class_stats();
+            alloc_assert!(stats.iter().all(|class| class.live_objects == 0), "{:?}", stats);
+        }
+    }
+
+    #[test]
+    fn reclaim_class() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 20_000;
+        let source = RegionSource::reserve(128 << 20);
+        let carved = || source.region.next.load(Ordering::Relaxed);
+        let mut dyn = DynamicAllocator::with_source(source.clone(), source.clone());
+        unsafe {
+            // Freeing the last live object of the class drops the handle's cache, which holds
+            // more than a slag's worth of objects.
+            let items = (0..N_ITEMS).map(|_| dyn.alloc(2048)).collect::<Vec<_>>();
+            for item in items {
+                dyn.free(item);
+            }
+            #[cfg(not(feature = "no_stats"))]
+            alloc_assert_eq!(dyn.stats().cached_bytes, 0);
+            #[cfg(feature = "no_stats")]
+            dyn.reclaim_class(2048);
+            // The class's pages can be used by another class.
+            let before = carved();
+            let items = (0..N_ITEMS).map(|_| dyn.alloc(1024)).collect::<Vec<_>>();
+            alloc_assert_eq!(carved(), before);
+
+            // Reclaiming by hand leaves live objects alone.
+            for &item in &items[1..] {
+                dyn.free(item);
+            }
+            dyn.reclaim_class(1024);
+            #[cfg(not(feature = "no_stats"))]
+            {
+                let stats = dyn.stats();
+                alloc_assert_eq!(stats.live_bytes, 1024);
+                alloc_assert_eq!(stats.cached_bytes, 0);
+            }
+            write_bytes(items[0], 1, 1024);
+            dyn.free(items[0]);
+            // reclaiming an unused or already reclaimed class does nothing
+            dyn.reclaim_class(1024);
+            dyn.reclaim_class(100);
+        }
+    }
+
+    #[test]
+    fn reclaim_cold() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 20_000;
+        let source = RegionSource::reserve(128 << 20);
+        let carved = || source.region.next.load(Ordering::Relaxed);
+        let mut dyn = DynamicAllocator::with_source(source.clone(), source.clone());
+        let clone = dyn.clone();
+        let initialized = |dyn: &DynamicAllocator, size: usize| {
+            dyn.class_stats().iter().find(|class| class.object_size == size).unwrap().initialized
+        };
+        unsafe {
+            // A class only used while starting up, with an object that stays live so that its
+            // cache is not reclaimed automatically.
+            let items = (0..N_ITEMS).map(|_| dyn.alloc(2048)).collect::<Vec<_>>();
+            for &item in &items[1..] {
+                dyn.free(item);
+            }
+            let hot = dyn.alloc(64);
+            dyn.free(hot);
+            // no class is cold before it has been seen
+            alloc_assert_eq!(dyn.reclaim_cold(1), 0);
+
+            alloc_assert_eq!(clone.advance_generation(), 1);
+            let hot = dyn.alloc(64);
+            dyn.free(hot);
+            alloc_assert_eq!(dyn.reclaim_cold(2), 0);
+            alloc_assert_eq!(dyn.reclaim_cold(1), 1);
+            alloc_assert!(!initialized(&dyn, 2048));
+            alloc_assert!(initialized(&dyn, 64));
+            // the class's pages can be used by another class
+            let before = carved();
+            let others = (0..N_ITEMS).map(|_| dyn.alloc(1024)).collect::<Vec<_>>();
+            alloc_assert_eq!(carved(), before);
+            for &item in &others[1..] {
+                dyn.free(item);
+            }
+
+            // the hot class goes cold once it is no longer used
+            alloc_assert_eq!(dyn.reclaim_cold(1), 0);
+            alloc_assert_eq!(dyn.advance_generation(), 2);
+            alloc_assert_eq!(dyn.reclaim_cold(1), 2);
+            alloc_assert!(!initialized(&dyn, 64));
+            alloc_assert!(!initialized(&dyn, 1024));
+            alloc_assert_eq!(dyn.reclaim_cold(0), 0);
+            write_bytes(items[0], 1, 2048);
+            dyn.free(items[0]);
+            dyn.free(others[0]);
+        }
+    }
+
+    #[test]
+    fn flush_thread_cache() {
+        use std::thread;
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 20_000;
+        let source = RegionSource::reserve(64 << 20);
+        let mut dyn = DynamicAllocator::with_source(source.clone(), source.clone());
+        let mut other = dyn.clone();
+        unsafe {
+            let items = (0..N_ITEMS).map(|_| dyn.alloc(256)).collect::<Vec<_>>();
+            for item in items {
+                dyn.free(item);
+            }
+            // without flushing, every object would stay in this handle's cache
+            dyn.flush_thread_cache();
+            alloc_assert_eq!(dyn.0.allocs.get_mut(256).stats().cached_objects, Some(0));
+            dyn.flush_thread_cache();
+        }
+        let carved = source.region.next.load(Ordering::Relaxed);
+        thread::spawn(move || unsafe {
+            let items = (0..N_ITEMS / 2).map(|_| other.alloc(256)).collect::<Vec<_>>();
+            for item in items {
+                other.free(item);
+            }
+        }).join()
+            .unwrap();
+        alloc_assert_eq!(source.region.next.load(Ordering::Relaxed), carved);
+    }
+
+    #[test]
+    fn contains() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        let mut other = DynamicAllocator::new();
+        let clone = dyn.clone();
+        let mut boxed = Box::new([0u8; 64]);
+        let mut local = 0u64;
+        unsafe {
+            let small = dyn.alloc(64);
+            let medium = dyn.alloc(64 << 10);
+            let large = dyn.alloc(8 << 20);
+            for &item in &[small, medium, large] {
+                alloc_assert!(dyn.contains(item));
+                alloc_assert!(clone.contains(item));
+                alloc_assert!(!other.contains(item));
+            }
+            alloc_assert!(dyn.contains(large.offset((8 << 20) - 1)));
+            let foreign = other.alloc(64);
+            alloc_assert!(!dyn.contains(foreign));
+            alloc_assert!(!dyn.contains(boxed.as_mut_ptr()));
+            alloc_assert!(!dyn.contains(&mut local as *mut u64 as *mut u8));
+            alloc_assert!(!dyn.contains(ptr::null_mut()));
+            dyn.free(large);
+            alloc_assert!(!dyn.contains(large));
+            // a large allocation moved by realloc is still found
+            let item = dyn.alloc(4 << 20);
+            let grown = dyn.realloc(item, 64 << 20);
+            alloc_assert!(dyn.contains(grown.offset((64 << 20) - 1)));
+            dyn.free(grown);
+            dyn.free(small);
+            dyn.free(medium);
+            other.free(foreign);
+        }
+    }
+
+    #[test]
+    fn warm_up() {
+        use super::super::utils::lazy_inits;
+        let mut dyn = DynamicAllocator::new();
+        let sizes = dyn.class_stats().iter().map(|class| class.object_size).collect::<Vec<_>>();
+        // 100 is served by the class of 112, and 300 by the class of 512
+        alloc_assert_eq!(dyn.warm_up_range(100, 300), 8);
+        for class in dyn.class_stats() {
+            let size = class.object_size;
+            alloc_assert_eq!(class.initialized, size >= 112 && size <= 512, "class {}", size);
+        }
+        alloc_assert_eq!(dyn.warm_up_range(300, 100), 0);
+        alloc_assert_eq!(dyn.warm_up(), sizes.len() - 8);
+        alloc_assert_eq!(dyn.warm_up(), 0);
+        alloc_assert!(dyn.class_stats().iter().all(|class| class.initialized));
+
+        // the first allocation of each class no longer sets up its cache
+        let before = lazy_inits();
+        unsafe {
+            for &size in &sizes {
+                let item = dyn.alloc(size);
+                alloc_assert!(!item.is_null());
+                dyn.free(item);
+            }
+        }
+        alloc_assert_eq!(lazy_inits(), before);
+
+        // but that of a clone does
+        let mut clone = dyn.clone();
+        unsafe {
+            let item = clone.alloc(sizes[0]);
+            clone.free(item);
+        }
+        alloc_assert_eq!(lazy_inits(), before + 1);
+        mem::drop(clone);
+
+        // reclaimed and frozen classes
+        dyn.reclaim_class(16);
+        dyn.reclaim_class(32);
+        alloc_assert_eq!(dyn.warm_up_range(16, 16), 1);
+        #[cfg(feature = "freeze")]
+        {
+            let guard = dyn.freeze_class(32, false).unwrap();
+            alloc_assert_eq!(dyn.warm_up_range(32, 32), 0);
+            mem::drop(guard);
+        }
+        alloc_assert_eq!(dyn.warm_up_range(32, 32), 1);
+    }
+
+    #[test]
+    fn reserve_for_class() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 50_000;
+        let source = RegionSource::reserve(256 << 20);
+        let carved = || source.region.next.load(Ordering::Relaxed);
+        let mut dyn = DynamicAllocator::with_source(source.clone(), source.clone());
+        unsafe {
+            alloc_assert_eq!(dyn.reserve_for_class(192, N_ITEMS), Ok(()));
+            // The whole burst is served without carving any more pages.
+            let before = carved();
+            let live = (0..N_ITEMS)
+                .map(|_| {
+                    let item = dyn.alloc(192);
+                    write_bytes(item, 1, 192);
+                    item
+                })
+                .collect::<Vec<_>>();
+            alloc_assert_eq!(carved(), before);
+            for item in live {
+                dyn.free(item);
+            }
+            // Large objects have no class to reserve.
+            alloc_assert_eq!(dyn.reserve_for_class(4 << 20, 16), Ok(()));
+            alloc_assert_eq!(carved(), before);
+            // Reserving more than the region holds fails, but the class can still be used.
+            alloc_assert_eq!(dyn.reserve_for_class(1000, (256 << 20) / 1000), Err(Exhausted));
+            let item = dyn.alloc(1000);
+            alloc_assert!(source.contains(item));
+            dyn.free(item);
+        }
+    }
+
+    #[test]
+    fn realloc_shrink() {
+        let _ = env_logger::init();
+        let page = mmap::page_size();
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            // large objects shrink in place, whether or not they fit in a size class afterwards
+            let item = dyn.alloc(8 << 20);
+            write_bytes(item, 1, 8 << 20);
+            for &new_size in &[3 << 20, 512 << 10] {
+                let used = dyn.memory_used();
+                alloc_assert_eq!(dyn.realloc(item, new_size), item);
+                let (size, _) = dyn.layout_of(item);
+                alloc_assert!(size >= new_size && size < new_size + page, "size={}", size);
+                alloc_assert!(dyn.memory_used() < used);
+                alloc_assert_eq!(*item.offset(new_size as isize - 1), 1);
+            }
+            dyn.free(item);
+
+            // medium objects move to a small class
+            let item = dyn.alloc(256 << 10);
+            write_bytes(item, 2, 256 << 10);
+            let small = dyn.realloc(item, 1000);
+            alloc_assert!(small != item);
+            let (size, _) = dyn.layout_of(small);
+            alloc_assert!(size >= 1000 && size < ELFMALLOC_SMALL_CUTOFF, "size={}", size);
+            alloc_assert!(slice::from_raw_parts(small, 1000).iter().all(|&b| b == 2));
+            dyn.free(small);
+
+            // but not if that saves less than half of the memory
+            let item = dyn.alloc(ELFMALLOC_SMALL_CUTOFF);
+            alloc_assert_eq!(dyn.realloc(item, 40 << 10), item);
+            alloc_assert_eq!(dyn.layout_of(item).0, ELFMALLOC_SMALL_CUTOFF);
+            dyn.free(item);
+        }
+    }
+
+    #[test]
+    fn realloc_shrink_releases_tail() {
+        let _ = env_logger::init();
+        let page = mmap::page_size();
+        const OLD_SIZE: usize = 256 << 20;
+        const NEW_SIZE: usize = 10 << 20;
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            for &global_heap in &[false, true] {
+                let item = if global_heap {
+                    global::alloc(OLD_SIZE)
+                } else {
+                    dyn.alloc(OLD_SIZE)
+                };
+                *item.offset(OLD_SIZE as isize - 1) = 1;
+                let old_size = large_alloc::get_size(item);
+                let (old_region, base) = large_alloc::get_commitment(item);
+                let used = dyn.memory_used();
+                let shrunk = if global_heap {
+                    global::realloc(item, NEW_SIZE)
+                } else {
+                    dyn.realloc(item, NEW_SIZE)
+                };
+                alloc_assert_eq!(shrunk, item);
+                let size = large_alloc::get_size(item);
+                alloc_assert!(size >= NEW_SIZE && size < NEW_SIZE + page, "size={}", size);
+                *item.offset(size as isize - 1) = 2;
+                // the region keeps its base, and the free pages are no longer accounted for
+                let (region_size, new_base) = large_alloc::get_commitment(item);
+                alloc_assert_eq!(new_base, base);
+                alloc_assert_eq!(old_region - region_size, old_size - size);
+                alloc_assert_eq!(large_alloc::registered_size(base), Some(region_size));
+                if !global_heap {
+                    alloc_assert_eq!(used - dyn.memory_used(), old_region - region_size);
+                }
+                if global_heap {
+                    global::free(item);
+                } else {
+                    dyn.free(item);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn realloc_large_grow() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        let page = mmap::page_size();
+        for &align in &[mem::size_of::<usize>(), 4 << 20] {
+            unsafe {
+                let item = dyn.alloc_aligned(64 << 20, align);
+                for i in 0..(64 << 20) / page {
+                    *item.offset((i * page) as isize) = i as u8;
+                }
+                let copies = REALLOC_COPIES.with(|c| c.get());
+                let grown = dyn.aligned_realloc(item, 256 << 20, align);
+                alloc_assert!(!grown.is_null());
+                alloc_assert_eq!(grown as usize % align, 0);
+                alloc_assert!(dyn.layout_of(grown).0 >= 256 << 20);
+                for i in 0..(64 << 20) / page {
+                    alloc_assert_eq!(*grown.offset((i * page) as isize), i as u8);
+                }
+                write_bytes(grown.offset(64 << 20), 1, 192 << 20);
+                if cfg!(target_os = "linux") {
+                    alloc_assert_eq!(REALLOC_COPIES.with(|c| c.get()), copies);
+                }
+                dyn.free(grown);
+            }
+        }
+    }
+
+    #[cfg(feature = "debug_ownership")]
+    #[test]
+    fn cross_free_aborts() {
+        use std::env;
+        use std::process::{self, Command};
+        // The abort takes down the whole process, so each cross-free is done by a child process
+        // that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_CROSS_FREE_SIZE";
+        if let Some(size) = env::var_os(CHILD_VAR) {
+            let size: usize = size.to_str().unwrap().parse().unwrap();
+            let mut a = DynamicAllocator::new();
+            let mut b = DynamicAllocator::new();
+            unsafe {
+                // clones share their allocator's id
+                a.clone().free(a.alloc(size));
+                b.free(a.alloc(size));
+            }
+            process::exit(0);
+        }
+        for size in &[16, 4 << 10, 128 << 10, 4 << 20] {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::cross_free_aborts", "--test-threads=1"])
+                .env(CHILD_VAR, size.to_string())
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            alloc_assert!(!out.status.success(), "freeing a {}-byte object did not abort", size);
+            alloc_assert!(
+                stderr.contains("ownership check failed"),
+                "unexpected output for a {}-byte object: {}",
+                size,
+                stderr
+            );
+        }
+    }
+
+    #[test]
+    fn memory_limit() {
+        use std::thread;
+        const N_THREADS: usize = 8;
+        let _ = env_logger::init();
+        let dyn = DynamicAllocator::new();
+        let sizes = [64, 1000, 128 << 10, 3 << 20];
+        // Set up the caches of each handle before the limit is set.
+        let handles: Vec<_> = (0..N_THREADS)
+            .map(|_| {
+                let mut dyn = dyn.clone();
+                for &size in &sizes {
+                    unsafe {
+                        let item = dyn.alloc(size);
+                        dyn.free(item);
+                    }
+                }
+                dyn
+            })
+            .collect();
+        let warm = dyn.memory_used();
+        let limit = warm + (256 << 20);
+        dyn.set_memory_limit(limit);
+        let threads: Vec<_> = handles
+            .into_iter()
+            .map(|mut dyn| {
+                thread::spawn(move || unsafe {
+                    let mut live = Vec::new();
+                    let mut failures = 0;
+                    let mut i = 0;
+                    while failures < 100 {
+                        match dyn.try_alloc(sizes[i % sizes.len()]) {
+                            Ok(item) => {
+                                write_bytes(item, 1, 64);
+                                live.push(item)
+                            }
+                            Err(Exhausted) => failures += 1,
+                        }
+                        alloc_assert!(dyn.memory_used() <= limit);
+                        i += 1;
+                    }
+                    for item in live {
+                        dyn.free(item);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        alloc_assert!(dyn.memory_used() <= limit);
+        // Freed large allocations are no longer counted, and neither are trimmed pages.
+        let mut dyn = dyn;
+        dyn.trim();
+        alloc_assert!(
+            dyn.memory_used() <= warm,
+            "{} bytes used, {} before the test",
+            dyn.memory_used(),
+            warm
+        );
+    }
+
+    #[test]
+    fn oom_handler() {
+        use std::cell::RefCell;
+        thread_local!{ static TRIM_HANDLE: RefCell<Option<DynamicAllocator>> = RefCell::new(None); }
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LAST_SIZE: AtomicUsize = AtomicUsize::new(0);
+        fn trim_and_retry(ctx: OomContext) -> OomAction {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            LAST_SIZE.store(ctx.size, Ordering::Relaxed);
+            alloc_assert!(ctx.mapped > 0);
+            TRIM_HANDLE.with(|h| h.borrow_mut().as_mut().unwrap().trim());
+            OomAction::Retry
+        }
+        fn fail(_ctx: OomContext) -> OomAction {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            OomAction::Fail
+        }
+
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            let item = dyn.alloc(128 << 10);
+            dyn.free(item);
+        }
+        dyn.set_memory_limit(dyn.memory_used() + (64 << 20));
+        // Fill the limit, then free everything: the freed pages stay cached until trimmed.
+        let mut live = Vec::new();
+        unsafe {
+            while let Ok(item) = dyn.try_alloc(128 << 10) {
+                live.push(item);
+            }
+            for item in live {
+                dyn.free(item);
+            }
+            alloc_assert_eq!(dyn.try_alloc(16 << 20), Err(Exhausted));
+        }
+
+        TRIM_HANDLE.with(|h| *h.borrow_mut() = Some(dyn.clone()));
+        dyn.set_oom_handler(trim_and_retry);
+        unsafe {
+            let item = dyn.try_alloc(16 << 20).unwrap();
+            alloc_assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            alloc_assert_eq!(LAST_SIZE.load(Ordering::Relaxed), 16 << 20);
+            // A retry that fails again is not passed back to the handler.
+            alloc_assert_eq!(dyn.try_alloc(1 << 40), Err(Exhausted));
+            alloc_assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+            dyn.free(item);
+        }
+
+        // The handler is used by clones.
+        dyn.set_oom_handler(fail);
+        let mut clone = dyn.clone();
+        unsafe {
+            alloc_assert_eq!(clone.try_alloc(1 << 40), Err(Exhausted));
+        }
+        alloc_assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+        TRIM_HANDLE.with(|h| h.borrow_mut().take());
+    }
+
+    #[test]
+    fn builder_invalid_config() {
+        fn build_err(builder: &mut DynamicAllocatorBuilder) -> ConfigError {
+            match builder.build() {
+                Err(e) => e,
+                Ok(_) => alloc_panic!("constructed allocator from {:?}", builder),
+            }
+        }
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().small_page_size(100 << 10)),
+            ConfigError::InvalidPageSize { page_size: 100 << 10 }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().large_page_size(4 << 20)),
+            ConfigError::PageTooLarge {
+                page_size: 4 << 20,
+                max: ELFMALLOC_PAGE_SIZE,
+            }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().large_page_size(128 << 10)),
+            ConfigError::PageSizeOrder {
+                small_page_size: ELFMALLOC_SMALL_PAGE_SIZE,
+                large_page_size: 128 << 10,
+            }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().small_cutoff(128 << 10)),
+            ConfigError::InvalidSmallCutoff {
+                small_cutoff: 128 << 10,
+                max: ELFMALLOC_SMALL_CUTOFF,
+            }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().n_classes(1)),
+            ConfigError::TooFewClasses { n_classes: 1 }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().max_class_size(3 << 20)),
+            ConfigError::InvalidMaxClassSize {
+                max_class_size: 3 << 20,
+                min: 32,
+            }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().max_class_size(2 << 30)),
+            ConfigError::ClassTooLarge {
+                size: 2 << 30,
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            }
+        );
+        for &min_align in &[0, 4, 12, 32] {
+            alloc_assert_eq!(
+                build_err(DynamicAllocatorBuilder::default().min_align(min_align)),
+                ConfigError::InvalidMinAlign {
+                    min_align: min_align,
+                    max: MULTIPLE,
+                }
+            );
+        }
+        let custom =
+            |sizes: &[usize]| build_err(DynamicAllocatorBuilder::default().size_classes(sizes));
+        alloc_assert_eq!(custom(&[64]), ConfigError::TooFewClasses { n_classes: 1 });
+        alloc_assert_eq!(custom(&[0, 64]), ConfigError::ZeroObjectSize);
+        alloc_assert_eq!(
+            custom(&[16, 64, 40]),
+            ConfigError::UnsortedClasses {
+                size: 40,
+                previous: 64,
+            }
+        );
+        alloc_assert_eq!(
+            custom(&[16, 64, 64]),
+            ConfigError::UnsortedClasses {
+                size: 64,
+                previous: 64,
+            }
+        );
+        alloc_assert_eq!(
+            custom(&[16, 44]),
+            ConfigError::MisalignedClass {
+                size: 44,
+                min_align: DEFAULT_MIN_ALIGN,
+            }
+        );
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default().min_align(16).size_classes(&[16, 40])),
+            ConfigError::MisalignedClass {
+                size: 40,
+                min_align: 16,
+            }
+        );
+        alloc_assert_eq!(
+            custom(&[16, ELFMALLOC_SMALL_CUTOFF + 4096]),
+            ConfigError::NonPowerOfTwoClass {
+                size: ELFMALLOC_SMALL_CUTOFF + 4096,
+                small_cutoff: ELFMALLOC_SMALL_CUTOFF,
+            }
+        );
+        alloc_assert_eq!(
+            custom(&[16, 2 << 30]),
+            ConfigError::ClassTooLarge {
+                size: 2 << 30,
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            }
+        );
+        for &ratio in &[0.5, 1.0, 2.5] {
+            alloc_assert_eq!(
+                build_err(DynamicAllocatorBuilder::default().medium_ratio(ratio)),
+                ConfigError::InvalidMediumRatio { ratio: ratio }
+            );
+        }
+        match build_err(DynamicAllocatorBuilder::default().medium_ratio(::std::f64::NAN)) {
+            ConfigError::InvalidMediumRatio { ratio } => alloc_assert!(ratio.is_nan()),
+            e => alloc_panic!("unexpected error {:?}", e),
+        }
+        alloc_assert_eq!(
+            build_err(DynamicAllocatorBuilder::default()
+                .medium_ratio(1.5)
+                .max_class_size(2 << 30)),
+            // 69 classes, of which the 35 medium classes start from 1KiB
+            ConfigError::ClassTooLarge {
+                size: GeometricTable::max_size_for(1 << 10, 35, 1.5),
+                max: ELFMALLOC_MAX_CLASS_SIZE,
+            }
+        );
+    }
+
+    #[test]
+    fn small_table() {
+        let configs = [(8, 8), (8, 16), (16, 16), (8, 32), (16, 32), (8, 64), (16, 64)];
+        for &(start, multiple) in &configs {
+            // enough classes for the multiples to reach the small cutoff
+            let n_classes = 2 * ELFMALLOC_SMALL_CUTOFF / multiple;
+            let (_, tiered) = TieredSizeClasses::init_with(
+                start,
+                n_classes,
+                multiple,
+                ELFMALLOC_SMALL_CUTOFF,
+                false,
+                |size| size,
+                |start, _, f| PowersOfTwo::init_conserve(start, 1, f),
+            );
+            let n_medium_classes = TieredSizeClasses::<()>::medium_classes(
+                start,
+                n_classes,
+                multiple,
+                ELFMALLOC_SMALL_CUTOFF,
+            ).1;
+            let n_small_classes = n_classes - n_medium_classes;
+            let (_, multiples) = Multiples::init_multiple(start, n_small_classes, multiple, |size| {
+                size
+            });
+            alloc_assert_eq!(tiered.small_max, multiples.max_key());
+            alloc_assert!(tiered.small_max >= ELFMALLOC_SMALL_CUTOFF - multiple);
+            // the rounding of Multiples, behind the class for 8-byte objects: every size gets the
+            // smallest class that holds it, with no gaps between the classes
+            let rounded = |n: usize| match n {
+                0...8 if start <= 8 => 8,
+                0...8 => multiples.starting_size,
+                _ => unsafe { *multiples.get(n) },
+            };
+            for n in 0..tiered.small_max + 1 {
+                let class = unsafe { *tiered.get(n) };
+                alloc_assert_eq!(
+                    class,
+                    rounded(n),
+                    "start {} multiple {} size {}",
+                    start,
+                    multiple,
+                    n
+                );
+                alloc_assert!(class >= n && (n <= 8 || class - n < multiple));
+            }
+            let medium = tiered.small_max + 1;
+            alloc_assert_eq!(unsafe { *tiered.get(medium) }, medium.next_power_of_two());
+            unsafe {
+                multiples.classes.destroy();
+                tiered.small_objs.destroy();
+                tiered.medium_objs.classes.destroy();
+            }
+        }
+    }
+
+    /// Check that `iter`, `foreach_mut` and `try_foreach` visit each class of `classes`, whose
+    /// contents are their sizes, exactly once and in ascending order, and that those sizes are
+    /// `sizes`.
+    fn check_visits<M: AllocMap<usize, Key = usize>>(classes: &mut M, sizes: &[usize]) {
+        let keys: Vec<usize> = classes
+            .iter()
+            .map(|(key, &class)| {
+                alloc_assert_eq!(key, class);
+                key
+            })
+            .collect();
+        alloc_assert_eq!(&keys[..], sizes);
+        classes.foreach_mut(|class| *class += 1);
+        for (key, &class) in classes.iter() {
+            alloc_assert_eq!(class, key + 1, "class {} visited {} times", key, class - key);
+        }
+        let mut visited = Vec::new();
+        let res = classes.try_foreach(|&class| {
+            visited.push(class - 1);
+            if visited.len() == 2 { Err(class) } else { Ok(()) }
+        });
+        alloc_assert_eq!(res, Err(sizes[1] + 1));
+        alloc_assert_eq!(&visited[..], &sizes[..2]);
+    }
+
+    #[test]
+    fn alloc_map_iteration() {
+        let mut multiples = Multiples::init(24, 4, |size| size);
+        check_visits(&mut multiples, &[32, 48, 64, 80]);
+        let mut powers = PowersOfTwo::init(24, 4, |size| size);
+        check_visits(&mut powers, &[32, 64, 128, 256]);
+        let (_, mut geometric) = Geometric::init_ratio(2 << 10, 4, 1.25, |size| size);
+        check_visits(&mut geometric, &[2 << 10, 4 << 10, 8 << 10, 12 << 10]);
+        let custom_sizes = [8, 24, 4 << 10, 1 << 20];
+        let mut custom = CustomSizeClasses::new(&custom_sizes, |size| size);
+        check_visits(&mut custom, &custom_sizes);
+        // clones copy each class, which check_visits has incremented
+        let clone = multiples.clone();
+        alloc_assert!(clone.iter().all(|(key, &class)| class == key + 1));
+        unsafe {
+            for classes in &[&multiples.classes, &powers.classes, &clone.classes] {
+                classes.destroy();
+            }
+            geometric.classes.destroy();
+            custom.classes.destroy();
+        }
+        // the optional class for 8-byte objects comes first
+        let small: Vec<usize> = (1..13).map(|i| i * MULTIPLE).collect();
+        let medium: Vec<usize> = (0..13).map(|i| 256 << i).collect();
+        for &(start, word_class) in &[(8, true), (16, false)] {
+            let mut sizes = if word_class { vec![8] } else { Vec::new() };
+            sizes.extend(small.iter().chain(medium.iter()));
+            let mut tiered = TieredSizeClasses::<usize>::init(start, 25, |size| size);
+            check_visits(&mut tiered, &sizes);
+            let mut like = SizeClasses::Tiered(tiered.init_like(|size| size));
+            check_visits(&mut like, &sizes);
+            unsafe {
+                tiered.medium_objs.classes.destroy();
+                tiered.destroy_small();
+                if let SizeClasses::Tiered(ref mut like) = like {
+                    like.medium_objs.classes.destroy();
+                    like.destroy_small();
+                }
+            }
+        }
+    }
+
+    /// Check that `try_get_raw` on `classes`, whose contents are their sizes, gets the first class
+    /// for 0 bytes and the last one for `max_key()`, and that it gets no class for larger sizes.
+    fn check_try_get<M: AllocMap<usize, Key = usize>>(classes: &M) {
+        let (first, _) = classes.iter().next().unwrap();
+        let max = classes.max_key();
+        unsafe {
+            alloc_assert_eq!(classes.try_get_raw(0).map(|class| *class), Some(first));
+            alloc_assert_eq!(classes.try_get_raw(max).map(|class| *class), Some(max));
+        }
+        alloc_assert!(classes.try_get_raw(max + 1).is_none());
+        alloc_assert!(classes.try_get_raw(usize::max_value()).is_none());
+    }
+
+    #[test]
+    fn alloc_map_try_get_raw() {
+        let multiples = Multiples::init(24, 4, |size| size);
+        check_try_get(&multiples);
+        let powers = PowersOfTwo::init(24, 4, |size| size);
+        check_try_get(&powers);
+        let (_, geometric) = Geometric::init_ratio(2 << 10, 4, 1.25, |size| size);
+        check_try_get(&geometric);
+        let custom = CustomSizeClasses::new(&[24, 4 << 10, 1 << 20], |size| size);
+        check_try_get(&custom);
+        unsafe {
+            multiples.classes.destroy();
+            powers.classes.destroy();
+            geometric.classes.destroy();
+            custom.classes.destroy();
+        }
+        for &(start, tiny_classes) in &[(8, false), (8, true), (16, false)] {
+            let (_, mut tiered) = TieredSizeClasses::init_with(
+                start,
+                25,
+                MULTIPLE,
+                ELFMALLOC_SMALL_CUTOFF,
+                tiny_classes,
+                |size| size,
+                PowersOfTwo::init_conserve,
+            );
+            check_try_get(&tiered);
+            let like = SizeClasses::Tiered(tiered.init_like(|size| size));
+            check_try_get(&like);
+            unsafe {
+                tiered.medium_objs.classes.destroy();
+                tiered.destroy_small();
+                if let SizeClasses::Tiered(mut like) = like {
+                    like.medium_objs.classes.destroy();
+                    like.destroy_small();
+                }
+            }
+        }
+        // lookups outside of the classes are not served from them
+        let mut dyn = DynamicAllocator::new();
+        let max = dyn.max_class_size();
+        alloc_assert_eq!(dyn.size_class_for(max + 1), None);
+        #[cfg(feature = "freeze")]
+        alloc_assert!(dyn.freeze_class(max + 1, false).is_none());
+        alloc_assert_eq!(dyn.reserve_for_class(max + 1, 1), Ok(()));
+        dyn.reclaim_class(max + 1);
+    }
+
+    #[test]
+    fn geometric_classes() {
+        let (_, classes) = Geometric::init_ratio(256, 24, 1.25, |size| size);
+        let clone = classes.init_like(|size| size);
+        let sizes: Vec<usize> = classes.classes.iter().map(|class| unsafe { *class }).collect();
+        alloc_assert_eq!(&sizes[..6], &[256, 512, 1 << 10, 2 << 10, 4 << 10, 8 << 10][..]);
+        for pair in sizes.windows(2) {
+            if pair[0] >= GEOMETRIC_GRANULE {
+                alloc_assert_eq!(pair[1] % GEOMETRIC_GRANULE, 0);
+                alloc_assert!(pair[1] * 4 >= pair[0] * 5, "{} follows {}", pair[1], pair[0]);
+                alloc_assert!(pair[1] < pair[0] * 5 / 4 + GEOMETRIC_GRANULE);
+            }
+        }
+        let max = sizes[sizes.len() - 1];
+        alloc_assert_eq!(max, 740 << 10);
+        alloc_assert_eq!(classes.max_key(), max);
+        alloc_assert_eq!(GeometricTable::max_size_for(256, 24, 1.25), max);
+        let mut probes: Vec<_> =
+            sizes.iter().flat_map(|&size| vec![size - 1, size, size + 1]).collect();
+        probes.extend((129..max).filter(|n| n % 1000 == 0));
+        probes.retain(|&n| n > 128 && n <= max);
+        for &n in &probes {
+            let class = *sizes.iter().find(|&&size| size >= n).unwrap();
+            unsafe {
+                alloc_assert_eq!(*classes.get(n), class, "size {}", n);
+                alloc_assert_eq!(*clone.get(n), class, "size {}", n);
+            }
+        }
+        unsafe {
+            classes.classes.destroy();
+            clone.classes.destroy();
+        }
+    }
+
+    #[test]
+    fn builder_medium_ratio() {
+        let _ = env_logger::init();
+        // the waste of rounding sizes uniformly distributed between 64KiB and 256KiB up to their
+        // classes, as a fraction of the memory of the classes
+        let waste = |dyn: &DynamicAllocator| {
+            let (mut sizes, mut classes) = (0, 0);
+            for size in (4 << 10..(16 << 10) + 1).map(|i| i * 16) {
+                sizes += size;
+                classes += dyn.usable_size_aligned(size, 1);
+            }
+            (classes - sizes) as f64 / classes as f64
+        };
+        let doubling = DynamicAllocator::new();
+        alloc_assert!(doubling.0.medium_aligned);
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .medium_ratio(1.25)
+            .max_class_size(1 << 20)
+            .build()
+            .unwrap();
+        let (doubling_waste, geometric_waste) = (waste(&doubling), waste(&dyn));
+        alloc_eprintln!(
+            "waste for 64KiB-256KiB objects: doubling {:.1}%, ratio 1.25 {:.1}%",
+            doubling_waste * 100.0,
+            geometric_waste * 100.0
+        );
+        alloc_assert!((doubling_waste - 0.25).abs() < 0.001, "{}", doubling_waste);
+        alloc_assert!(geometric_waste < 0.12, "{}", geometric_waste);
+        // the first class of at least 1MiB, which needs huge pages
+        alloc_assert_eq!(dyn.max_class_size(), 1160 << 10);
+        alloc_assert!(!dyn.0.medium_aligned);
+        let mut clone = dyn.clone();
+        unsafe {
+            // enough objects of each class to fill several pages, so that some are aligned to the
+            // small cutoff and most are not
+            for &(size, class) in &[(70 << 10, 76 << 10), (200 << 10, 240 << 10)] {
+                let items: Vec<_> = (0..64).map(|_| clone.alloc(size)).collect();
+                for &item in &items {
+                    write_bytes(item, 1, size);
+                    alloc_assert_eq!(get_type(item), AllocType::BigSlag);
+                    alloc_assert_eq!(dyn.layout_of(item), (class, MULTIPLE));
+                }
+                for item in items {
+                    dyn.free(item);
+                }
+            }
+            let item = dyn.alloc(1 << 20);
+            alloc_assert_eq!(get_type(item), AllocType::HugeSlag);
+            alloc_assert_eq!(dyn.usable_size(item), 1160 << 10);
+            clone.free(item);
+            // small objects are still found, although no longer by their alignment alone
+            let small = dyn.alloc(100);
+            alloc_assert_eq!(dyn.usable_size(small), 112);
+            // 76KiB objects are not aligned to 4KiB
+            let item = dyn.alloc_aligned(70 << 10, 4096);
+            alloc_assert_eq!(item as usize % 4096, 0);
+            alloc_assert_eq!(get_type(item), AllocType::Large);
+            dyn.free(item);
+            let item = dyn.realloc(small, 70 << 10);
+            alloc_assert_eq!(dyn.usable_size(item), 76 << 10);
+            dyn.free(item);
+        }
+        // a ratio of 2 gives the default classes
+        let dyn = DynamicAllocatorBuilder::default()
+            .medium_ratio(2.0)
+            .max_class_size(1 << 20)
+            .build()
+            .unwrap();
+        alloc_assert_eq!(dyn.max_class_size(), 1 << 20);
+        alloc_assert!(dyn.0.medium_aligned);
+        alloc_assert_eq!(waste(&dyn), doubling_waste);
+    }
+
+    #[test]
+    fn builder_size_classes() {
+        let _ = env_logger::init();
+        let classes = [8, 16, 40, 64, 712, 1024, 4424, 8192, 64 << 10, 128 << 10, 1 << 20];
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .size_classes(&classes)
+            .build()
+            .unwrap();
+        let mut clone = dyn.clone();
+        alloc_assert_eq!(dyn.max_class_size(), 1 << 20);
+        let mut sizes: Vec<usize> = (1..10_000).collect();
+        let mut size = 10_000;
+        while size <= (1 << 20) + 1 {
+            sizes.push(size);
+            size += size / 8 + 1;
+        }
+        sizes.extend(classes.iter().flat_map(|&class| vec![class - 1, class, class + 1]));
+        for &size in &sizes {
+            unsafe {
+                // objects of every size get the smallest class that holds them, from any clone
+                let item = if size % 2 == 0 {
+                    dyn.alloc(size)
+                } else {
+                    clone.alloc(size)
+                };
+                write_bytes(item, 1, cmp::min(size, 4096));
+                match classes.iter().find(|&&class| class >= size) {
+                    Some(&class) => {
+                        alloc_assert_eq!(dyn.usable_size(item), class, "size {}", size);
+                        alloc_assert!(get_type(item) != AllocType::Large, "size {}", size);
+                        alloc_assert_eq!(item as usize % class_alignment(class), 0);
+                    }
+                    None => alloc_assert_eq!(get_type(item), AllocType::Large, "size {}", size),
+                }
+                dyn.free(item);
+            }
+        }
+        unsafe {
+            // 64 is a class, but 128 is served by a class that is only aligned to 8 bytes, so the
+            // next aligned class is used
+            let item = dyn.alloc_aligned(48, 64);
+            alloc_assert_eq!(item as usize % 64, 0);
+            alloc_assert_eq!(dyn.usable_size(item), 64);
+            dyn.free(item);
+            let item = dyn.alloc_aligned(100, 128);
+            alloc_assert_eq!(item as usize % 128, 0);
+            alloc_assert_eq!(dyn.usable_size(item), 1024);
+            alloc_assert_eq!(dyn.usable_size_aligned(100, 128), 1024);
+            dyn.free(item);
+            // 40 is only aligned to 8 bytes
+            alloc_assert_eq!(dyn.usable_size_aligned(24, 16), 64);
+            // no class is aligned to more than 64KiB
+            let item = dyn.alloc_aligned(100, 128 << 10);
+            alloc_assert_eq!(item as usize % (128 << 10), 0);
+            alloc_assert_eq!(get_type(item), AllocType::Large);
+            alloc_assert_eq!(dyn.usable_size_aligned(100, 128 << 10), 100);
+            dyn.free(item);
+        }
+    }
+
+    #[test]
+    fn system_page_size() {
+        let page_size = mmap::page_size();
+        alloc_assert!(page_size.is_power_of_two());
+        // the cached value
+        alloc_assert_eq!(mmap::page_size(), page_size);
+        alloc_assert_eq!(check_system_page_size(page_size), Ok(()));
+        // 16K (Apple Silicon) and 64K (ppc64) pages are supported
+        for &page_size in &[4 << 10, 16 << 10, 64 << 10] {
+            alloc_assert_eq!(check_system_page_size(page_size), Ok(()));
+        }
+        for &page_size in &[3000, 128 << 10, 4 << 20] {
+            alloc_assert_eq!(
+                check_system_page_size(page_size),
+                Err(ConfigError::UnsupportedSystemPageSize { system_page_size: page_size })
+            );
+        }
+        if page_size > 1 {
+            let small = page_size / 2;
+            alloc_assert_eq!(
+                DynamicAllocatorBuilder::default().small_page_size(small).build().err(),
+                Some(ConfigError::PageBelowSystemPage {
+                    page_size: small,
+                    system_page_size: page_size,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn size_class_for() {
+        let _ = env_logger::init();
+        let allocators = vec![
+            DynamicAllocator::new(),
+            DynamicAllocatorBuilder::default()
+                .medium_ratio(1.25)
+                .max_class_size(1 << 20)
+                .build()
+                .unwrap(),
+            DynamicAllocatorBuilder::default()
+                .size_classes(&[8, 24, 40, 64, 712, 1024, 4424, 8192, 64 << 10, 256 << 10])
+                .build()
+                .unwrap(),
+        ];
+        for mut dyn in allocators {
+            let max = dyn.large_threshold();
+            alloc_assert_eq!(max, dyn.max_class_size());
+            // every small size, a sample of the rest, and the sizes around each class
+            let mut sizes: Vec<usize> = (0..(16 << 10) + 1).collect();
+            sizes.extend(((16 << 10)..max + (64 << 10)).filter(|size| size % 61 == 0));
+            let mut size = 0;
+            while let Some(class) = dyn.size_class_for(size) {
+                sizes.extend(&[class, class + 1]);
+                size = class + 1;
+            }
+            for size in sizes {
+                unsafe {
+                    let item = dyn.alloc(size);
+                    match dyn.size_class_for(size) {
+                        Some(class) => {
+                            alloc_assert!(class >= size && size <= max, "size {}", size);
+                            alloc_assert_eq!(dyn.usable_size(item), class, "size {}", size);
+                            alloc_assert!(get_type(item) != AllocType::Large, "size {}", size);
+                        }
+                        None => {
+                            alloc_assert!(size > max, "size {}", size);
+                            alloc_assert_eq!(get_type(item), AllocType::Large, "size {}", size);
+                        }
+                    }
+                    dyn.free(item);
+                }
+            }
+        }
+        // the global heap rounds sizes in the same way
+        let max = global::large_threshold();
+        alloc_assert_eq!(global::size_class_for(max + 1), None);
+        for &size in &[0, 1, 100, 1000, 4000, 100 << 10, max] {
+            let class = global::size_class_for(size).unwrap();
+            alloc_assert_eq!(class, global::usable_size_for(size));
+            unsafe {
+                let item = global::alloc(size);
+                alloc_assert_eq!(global::usable_size(item), class, "size {}", size);
+                global::free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn builder_small_class_multiple() {
+        let _ = env_logger::init();
+        for &multiple in &[8, 16, 32, 64] {
+            let mut dyn = DynamicAllocatorBuilder::default()
+                .small_class_multiple(multiple)
+                .min_align(mem::size_of::<usize>())
+                .build()
+                .unwrap();
+            let classes: Vec<usize> = dyn.0.allocs.iter().map(|(size, _)| size).collect();
+            // half of the classes are multiples, after the class for 8-byte objects
+            let first_multiple = if multiple > 8 { 1 } else { 0 };
+            alloc_assert_eq!(classes[0], 8);
+            for i in 0..12 {
+                alloc_assert_eq!(classes[first_multiple + i], (i + 1) * multiple);
+            }
+            alloc_assert!(classes[first_multiple + 12].is_power_of_two());
+            for size in 1..(16 << 10) {
+                let class = classes.iter().cloned().find(|&class| class >= size);
+                alloc_assert_eq!(
+                    dyn.size_class_for(size),
+                    class,
+                    "multiple {} size {}",
+                    multiple,
+                    size
+                );
+            }
+            unsafe {
+                for &size in &[1, multiple - 1, multiple + 1, 5 * multiple, 12 * multiple + 1] {
+                    let item = dyn.alloc(size);
+                    write_bytes(item, 1, size);
+                    let (usable, align) = dyn.layout_of(item);
+                    alloc_assert!(usable >= size, "multiple {} size {}", multiple, size);
+                    alloc_assert_eq!(item as usize % align, 0);
+                    dyn.free(item);
+                }
+            }
+        }
+        let multiple = |multiple: usize, min_align: usize| {
+            DynamicAllocatorBuilder::default()
+                .small_class_multiple(multiple)
+                .min_align(min_align)
+                .build()
+                .unwrap_err()
+        };
+        let word = mem::size_of::<usize>();
+        for &invalid in &[0, 4, 24, ELFMALLOC_SMALL_CUTOFF] {
+            alloc_assert_eq!(
+                multiple(invalid, word),
+                ConfigError::InvalidMultiple {
+                    multiple: invalid,
+                    min: 8,
+                    max: ELFMALLOC_SMALL_CUTOFF / 2,
+                }
+            );
+        }
+        // the classes would not all be aligned to the minimum alignment
+        alloc_assert_eq!(
+            multiple(8, 16),
+            ConfigError::InvalidMultiple {
+                multiple: 8,
+                min: 16,
+                max: ELFMALLOC_SMALL_CUTOFF / 2,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "magazine_layer", feature = "local_cache")))]
+    fn magazine_capacity_large_classes() {
+        let _ = env_logger::init();
+        const SIZE: usize = 256 << 10;
+        // the objects cached for SIZE after a burst of 64 of them
+        fn cached_after_burst(dyn: &mut DynamicAllocator) -> usize {
+            unsafe {
+                // keep an object live, so that the cache is not dropped when the burst drains
+                let keep = dyn.alloc(SIZE);
+                let items = (0..64).map(|_| dyn.alloc(SIZE)).collect::<Vec<_>>();
+                for item in items {
+                    dyn.free(item);
+                }
+                let cached = dyn.0.allocs.get_mut(SIZE).stats().cached_objects.unwrap();
+                dyn.free(keep);
+                cached
+            }
+        }
+        // a capacity for every class that suits small objects holds on to a lot of memory in the
+        // caches of large ones, which hold only a few objects by default
+        let per_class = cached_after_burst(&mut DynamicAllocator::new());
+        let mut fixed = DynamicAllocatorBuilder::default().magazine_capacity(64).build().unwrap();
+        let fixed = cached_after_burst(&mut fixed);
+        alloc_assert!(per_class <= frontends::magazine_capacity(SIZE), "{}", per_class);
+        alloc_assert!(per_class < fixed, "{} {}", per_class, fixed);
+    }
+
+    #[test]
+    fn builder_magazine_capacity() {
+        let _ = env_logger::init();
+        // the capacity each class's caches are created with
+        fn capacities(dyn: &DynamicAllocator) -> Vec<(usize, MagazineCapacity)> {
+            dyn.0
+                .allocs
+                .iter()
+                .map(|(size, class)| {
+                    #[cfg(not(feature = "magazine_layer"))]
+                    let params = class.params();
+                    #[cfg(feature = "magazine_layer")]
+                    let params = &class.params().0;
+                    (size, params.4)
+                })
+                .collect()
+        }
+        let dyn = DynamicAllocator::new();
+        for (size, capacity) in capacities(&dyn) {
+            let fixed = MagazineCapacity::fixed(frontends::magazine_capacity(size));
+            alloc_assert_eq!(capacity, fixed, "size {}", size);
+        }
+        alloc_assert!(frontends::magazine_capacity(64) >= frontends::magazine_capacity(16 << 10));
+        alloc_assert_eq!(frontends::magazine_capacity(16 << 10), 32);
+        alloc_assert_eq!(frontends::magazine_capacity(256 << 10), 4);
+        alloc_assert_eq!(frontends::magazine_capacity(1 << 20), 1);
+
+        let mut dyn = DynamicAllocatorBuilder::default().magazine_capacity(4).build().unwrap();
+        alloc_assert_eq!(dyn.extend_classes(4 << 20), Ok(()));
+        alloc_assert!(
+            capacities(&dyn)
+                .iter()
+                .all(|&(_, capacity)| capacity == MagazineCapacity::fixed(4))
+        );
+        unsafe {
+            let items = (0..100).map(|_| dyn.alloc(256)).collect::<Vec<_>>();
+            for item in items {
+                dyn.free(item);
+            }
+            // the magazines of the depot, and the caches of the local_cache feature, hold more
+            #[cfg(not(any(feature = "magazine_layer", feature = "local_cache")))]
+            alloc_assert!(dyn.0.allocs.get_mut(256).stats().cached_objects.unwrap() <= 4);
+        }
+        alloc_assert_eq!(
+            DynamicAllocatorBuilder::default().magazine_capacity(0).build().unwrap_err(),
+            ConfigError::ZeroMagazineCapacity
+        );
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "magazine_layer", feature = "local_cache")))]
+    fn adaptive_magazines() {
+        let _ = env_logger::init();
+        unsafe fn burst(dyn: &mut DynamicAllocator) {
+            let items = (0..512).map(|_| dyn.alloc(256)).collect::<Vec<_>>();
+            for item in items {
+                dyn.free(item);
+            }
+        }
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .magazine_capacity(16)
+            .adaptive_magazines(8, 1024)
+            .build()
+            .unwrap();
+        unsafe {
+            for _ in 0..8 {
+                burst(&mut dyn);
+            }
+            // the cache grew to hold a whole burst
+            let cache = dyn.0.allocs.get_mut(256);
+            alloc_assert!(cache.capacity() >= 512, "capacity {}", cache.capacity());
+            alloc_assert_eq!(cache.stats().cached_objects, Some(512));
+        }
+        for _ in 0..3 * 8 {
+            dyn.decay_caches();
+        }
+        unsafe {
+            let cache = dyn.0.allocs.get_mut(256);
+            alloc_assert_eq!(cache.capacity(), 8);
+            alloc_assert!(cache.stats().cached_objects.unwrap() <= 8);
+            for _ in 0..8 {
+                burst(&mut dyn);
+            }
+            alloc_assert!(dyn.0.allocs.get_mut(256).capacity() >= 512);
+        }
+        // trim lets the caches decay as well
+        for _ in 0..3 * 8 {
+            dyn.trim();
+        }
+        alloc_assert_eq!(unsafe { dyn.0.allocs.get_mut(256).capacity() }, 8);
+
+        let mut fixed = DynamicAllocatorBuilder::default().magazine_capacity(16).build().unwrap();
+        unsafe {
+            for _ in 0..8 {
+                burst(&mut fixed);
+            }
+            alloc_assert_eq!(fixed.0.allocs.get_mut(256).capacity(), 16);
+        }
+        for &(min, max) in &[(0, 8), (16, 8)] {
+            alloc_assert_eq!(
+                DynamicAllocatorBuilder::default()
+                    .adaptive_magazines(min, max)
+                    .build()
+                    .unwrap_err(),
+                ConfigError::InvalidMagazineBounds { min: min, max: max }
+            );
+        }
+    }
+
+    #[test]
+    fn builder_depot_shards() {
+        use std::thread;
+        let _ = env_logger::init();
+        let dyn = DynamicAllocatorBuilder::default().depot_shards(4, 2).build().unwrap();
+        #[cfg(feature = "magazine_layer")]
+        for (size, class) in dyn.0.allocs.iter() {
+            alloc_assert_eq!(class.params().1.n_shards(), 4, "size {}", size);
+        }
+        // handles on different shards free each other's objects
+        let threads = (0..8)
+            .map(|_| {
+                let mut dyn = dyn.clone();
+                thread::spawn(move || unsafe {
+                    (0..4096).map(|_| dyn.alloc(64) as usize).collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut dyn = dyn;
+        for t in threads {
+            for item in t.join().unwrap() {
+                unsafe { dyn.free(item as *mut u8) };
+            }
+        }
+        alloc_assert_eq!(
+            DynamicAllocatorBuilder::default().depot_shards(4, 0).build().unwrap_err(),
+            ConfigError::ZeroDepotBatch
+        );
+    }
+
+    #[test]
+    fn builder_tiny_classes() {
+        let _ = env_logger::init();
+        let mut tiny = DynamicAllocatorBuilder::default().tiny_classes(true).build().unwrap();
+        for &(size, class) in &[(1, 1), (2, 2), (3, 4), (4, 4), (5, 8), (8, 8), (9, 16)] {
+            alloc_assert_eq!(tiny.size_class_for(size), Some(class), "size {}", size);
+        }
+        // alignments up to the word size are still honoured
+        alloc_assert_eq!(tiny.usable_size_aligned(1, 4), 4);
+        alloc_assert_eq!(tiny.usable_size_aligned(2, 8), 8);
+        unsafe {
+            for size in 1..9 {
+                let item = tiny.alloc(size);
+                write_bytes(item, 1, size);
+                let (obj_size, align) = tiny.layout_of(item);
+                alloc_assert_eq!(obj_size, size.next_power_of_two(), "size {}", size);
+                alloc_assert_eq!(align, obj_size, "size {}", size);
+                alloc_assert_eq!(item as usize % align, 0, "size {}", size);
+                tiny.free(item);
+            }
+            let item = tiny.alloc_aligned(1, 8);
+            alloc_assert_eq!(item as usize % 8, 0);
+            alloc_assert_eq!(tiny.layout_of(item), (8, 8));
+            tiny.free(item);
+        }
+        let geometric = DynamicAllocatorBuilder::default()
+            .tiny_classes(true)
+            .medium_ratio(1.25)
+            .build()
+            .unwrap();
+        alloc_assert_eq!(geometric.size_class_for(2), Some(2));
+        let custom = DynamicAllocatorBuilder::default()
+            .tiny_classes(true)
+            .size_classes(&[16, 32])
+            .build()
+            .unwrap();
+        alloc_assert_eq!(custom.size_class_for(2), Some(16));
+
+        /// The bytes of small pages mapped to allocate `n` objects of 2 bytes.
+        fn small_bytes_for(dyn: &mut DynamicAllocator, n: usize) -> usize {
+            let before = dyn.stats().small_page_bytes;
+            let items: Vec<*mut u8> = (0..n).map(|_| unsafe { dyn.alloc(2) }).collect();
+            let bytes = dyn.stats().small_page_bytes - before;
+            for &item in &items {
+                unsafe { dyn.free(item) };
+            }
+            bytes
+        }
+        const N: usize = 1_000_000;
+        let tiny_bytes = small_bytes_for(&mut tiny, N);
+        let mut word = DynamicAllocatorBuilder::default().min_align(8).build().unwrap();
+        let word_bytes = small_bytes_for(&mut word, N);
+        alloc_assert!(word_bytes >= 8 * N, "{} bytes for the 8-byte class", word_bytes);
+        // pages are mapped 2MiB at a time, so this is short of the 4x of the objects themselves
+        alloc_assert!(
+            tiny_bytes * 2 <= word_bytes,
+            "{} bytes for the 2-byte class, {} for the 8-byte class",
+            tiny_bytes,
+            word_bytes
+        );
+    }
+
+    #[test]
+    fn builder_min_align() {
+        let _ = env_logger::init();
+        // The small cutoff (and so the alignment checked by get_page_size) differs between these.
+        for &small_page_size in &[ELFMALLOC_SMALL_PAGE_SIZE, 64 << 10, 16 << 10] {
+            if small_page_size < mmap::page_size() {
+                continue;
+            }
+            let mut dyn = DynamicAllocatorBuilder::default()
+                .small_page_size(small_page_size)
+                .min_align(16)
+                .build()
+                .unwrap();
+            let mut sizes: Vec<usize> = (1..2049).collect();
+            let mut size = 2049;
+            while size <= 4 << 20 {
+                sizes.push(size);
+                size += size / 8 + 1;
+            }
+            for &size in &sizes {
+                unsafe {
+                    let item = dyn.alloc(size);
+                    write_bytes(item, 1, size);
+                    let (obj_size, align) = dyn.layout_of(item);
+                    alloc_assert!(obj_size >= cmp::max(size, 16), "size {}: {}", size, obj_size);
+                    alloc_assert!(align >= 16, "size {} reported alignment {}", size, align);
+                    alloc_assert_eq!(item as usize % 16, 0, "size {}", size);
+                    alloc_assert_eq!(item as usize % align, 0, "size {}", size);
+                    dyn.free(item);
+                }
+            }
+        }
+        // With an alignment of 8, word-sized objects keep their class.
+        let mut dyn = DynamicAllocatorBuilder::default().min_align(8).build().unwrap();
+        unsafe {
+            let item = dyn.alloc(8);
+            alloc_assert_eq!(dyn.layout_of(item), (8, 8));
+            dyn.free(item);
+        }
+    }
+
+    #[test]
+    fn word_class() {
+        let _ = env_logger::init();
+        for &(min_align, word_class) in &[(8, true), (16, false)] {
+            let mut dyn = DynamicAllocatorBuilder::default().min_align(min_align).build().unwrap();
+            let smallest = if word_class { 8 } else { 16 };
+            alloc_assert_eq!(dyn.size_class_for(1), Some(smallest));
+            alloc_assert_eq!(dyn.size_class_for(8), Some(smallest));
+            alloc_assert_eq!(dyn.size_class_for(9), Some(16));
+            alloc_assert_eq!(
+                dyn.class_stats().iter().any(|class| class.object_size == 8),
+                word_class,
+                "min_align {}",
+                min_align
+            );
+            for size in 1..9 {
+                unsafe {
+                    let item = dyn.alloc(size);
+                    write_bytes(item, 1, size);
+                    alloc_assert_eq!(dyn.usable_size(item), smallest, "size {}", size);
+                    alloc_assert_eq!(item as usize % min_align, 0, "size {}", size);
+                    dyn.free(item);
+                }
+            }
+            // Clones have the same classes.
+            let mut clone = dyn.clone();
+            unsafe {
+                let item = clone.alloc(8);
+                alloc_assert_eq!(clone.usable_size(item), smallest);
+                clone.free(item);
             }
         }
-
-        test_and_free(8, |size, align| {
-            alloc_assert!(size >= 8);
-            alloc_assert!(align >= 8);
-        });
-        test_and_free(24, |size, align| {
-            alloc_assert!(size >= 24);
-            alloc_assert!(align >= 8);
-        });
-        test_and_free(512, |size, align| {
-            alloc_assert!(size >= 512);
-            alloc_assert!(align >= 512);
-        });
-        test_and_free(4 << 20, |size, align| {
-            alloc_assert_eq!((size, align), (4 << 20, mmap::page_size()))
-        });
+        alloc_assert_eq!(
+            DynamicAllocator::new().size_class_for(8),
+            Some(cmp::max(8, DEFAULT_MIN_ALIGN))
+        );
     }
 
     #[test]
@@ -1148,6 +10456,999 @@ mod tests {
         }
     }
 
+    #[test]
+    fn global_short_lived_threads() {
+        let _ = env_logger::init();
+        use std::thread;
+        struct AllocOnDrop;
+        impl Drop for AllocOnDrop {
+            fn drop(&mut self) {
+                unsafe {
+                    let item = global::alloc(64);
+                    // realloc falls back to a temporary handle, which is dropped again right away
+                    let item = global::realloc(item, 128);
+                    global::free(item);
+                }
+            }
+        }
+        thread_local!{ static LATE: AllocOnDrop = AllocOnDrop; }
+        const N_THREADS: usize = 4000;
+        for i in 0..N_THREADS {
+            thread::spawn(move || unsafe {
+                if i % 2 == 1 {
+                    // LATE is registered before the thread's handle, so on Linux its destructor
+                    // runs after the handle has been dropped, and takes the fallback paths.
+                    LATE.with(|_| {});
+                }
+                global::free(global::alloc(64));
+            }).join()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn global_free_while_exiting() {
+        let _ = env_logger::init();
+        use std::cell::RefCell;
+        use std::collections::HashSet;
+        use std::sync::Arc;
+        use std::thread;
+        // a size no other test uses, so that only this test's objects are in its class
+        const SIZE: usize = 1800;
+        const N_THREADS: usize = 64;
+        const N_ITEMS: usize = 1000;
+        struct FreeOnDrop(RefCell<Vec<usize>>);
+        impl Drop for FreeOnDrop {
+            fn drop(&mut self) {
+                for &item in self.0.borrow().iter() {
+                    unsafe { global::free(item as *mut u8) };
+                }
+            }
+        }
+        thread_local!{ static ITEMS: FreeOnDrop = FreeOnDrop(RefCell::new(Vec::new())); }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let drainer = {
+            let done = done.clone();
+            thread::spawn(move || unsafe {
+                while !done.load(Ordering::Relaxed) {
+                    global::free(global::alloc(SIZE));
+                }
+            })
+        };
+        let threads = (0..N_THREADS)
+            .map(|_| {
+                thread::spawn(|| {
+                    // ITEMS is registered before the thread's handle, so on Linux its destructor
+                    // runs after the handle has been dropped, and the objects are freed remotely.
+                    ITEMS.with(|items| for _ in 0..N_ITEMS {
+                        let item = unsafe { global::alloc(SIZE) };
+                        unsafe { write_bytes(item, 0xff, SIZE) };
+                        items.0.borrow_mut().push(item as usize);
+                    });
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        drainer.join().unwrap();
+        // Every object is freed exactly once, however it was freed, so none is handed out twice.
+        unsafe {
+            let items = (0..N_THREADS * N_ITEMS).map(|_| global::alloc(SIZE)).collect::<Vec<_>>();
+            alloc_assert_eq!(items.iter().collect::<HashSet<_>>().len(), items.len());
+            for item in items {
+                global::free(item);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn global_shutdown() {
+        use std::env;
+        use std::fs;
+        use std::process::{self, Command};
+        use std::thread;
+        // Other tests start threads of their own, so the threads are counted in a child process
+        // that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_SHUTDOWN_CHILD";
+        if env::var_os(CHILD_VAR).is_some() {
+            let n_threads = || fs::read_dir("/proc/self/task").unwrap().count();
+            let baseline = n_threads();
+            // an exiting thread's handle is torn down on the thread itself
+            thread::spawn(|| unsafe { global::free(global::alloc(64)) }).join().unwrap();
+            alloc_assert_eq!(n_threads(), baseline);
+            // the first allocator to be retired starts the background thread
+            global::retire(DynamicAllocator::new());
+            alloc_assert_eq!(n_threads(), baseline + 1);
+            global::shutdown();
+            alloc_assert_eq!(n_threads(), baseline);
+            global::shutdown();
+            let item = unsafe { global::alloc(64) } as usize;
+            thread::spawn(move || unsafe {
+                global::free(item as *mut u8);
+                global::free(global::alloc(4 << 10));
+            }).join()
+                .unwrap();
+            unsafe {
+                global::free_without_handle(global::alloc(64));
+                global::free(global::alloc(64));
+            }
+            alloc_assert_eq!(n_threads(), baseline);
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::global_shutdown", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    #[cfg(not(feature = "no_stats"))]
+    #[test]
+    fn global_stats() {
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        // Other tests share the global heap, so the counts are checked in a child process that
+        // runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_STATS_CHILD";
+        if env::var_os(CHILD_VAR).is_none() {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::global_stats", "--test-threads=1"])
+                .env(CHILD_VAR, "1")
+                .output()
+                .unwrap();
+            alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        const N_THREADS: usize = 8;
+        const N_ITEMS: usize = 1000;
+        let sizes = [16, 1000, 40 << 10];
+        let large_size = 4 << 20;
+        let before = global::stats_detailed();
+        alloc_assert_eq!(before.live_bytes, 0, "{:?}", before);
+        // Each thread frees everything it allocated, and its handle is left caching the objects
+        // when it exits.
+        let threads = (0..N_THREADS)
+            .map(|_| {
+                thread::spawn(move || unsafe {
+                    let items = (0..N_ITEMS)
+                        .map(|i| global::alloc(sizes[i % sizes.len()]) as usize)
+                        .collect::<Vec<_>>();
+                    let large = global::alloc(large_size);
+                    let during = global::stats();
+                    alloc_assert!(during.live_bytes >= N_ITEMS, "{:?}", during);
+                    alloc_assert!(during.large_bytes >= large_size, "{:?}", during);
+                    alloc_assert!(
+                        during.mapped_bytes >= during.live_bytes + during.large_bytes,
+                        "{:?}",
+                        during
+                    );
+                    for item in items {
+                        global::free(item as *mut u8);
+                    }
+                    global::free(large);
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let after = global::stats_detailed();
+        alloc_assert_eq!(after.live_bytes, 0, "{:?}", after);
+        alloc_assert_eq!(after.large_bytes, 0, "{:?}", after);
+        // The exited threads' caches have been returned. At most the objects of the words each
+        // handle was allocating from may be held back.
+        let held_back = N_THREADS * 64 *
+            sizes.iter().map(|&size| global::usable_size_for(size)).sum::<usize>();
+        alloc_assert!(after.cached_bytes <= held_back, "{:?}", after);
+        alloc_assert!(!after.classes.is_empty());
+        alloc_assert!(after.classes.windows(2).all(|w| w[0].object_size <= w[1].object_size));
+        alloc_assert!(after.classes.iter().all(|c| c.live_objects == 0));
+        alloc_assert_eq!(
+            after.classes.iter().map(|c| c.cached_objects * c.object_size).sum::<usize>(),
+            after.cached_bytes
+        );
+        unsafe {
+            let items = (0..N_ITEMS).map(|_| global::alloc(1000)).collect::<Vec<_>>();
+            let stats = global::stats_detailed();
+            let class = stats
+                .classes
+                .iter()
+                .find(|c| c.object_size == global::usable_size_for(1000))
+                .unwrap();
+            alloc_assert_eq!(class.live_objects, N_ITEMS, "{:?}", stats);
+            alloc_assert_eq!(stats.live_bytes, N_ITEMS * class.object_size, "{:?}", stats);
+            for item in items {
+                global::free(item);
+            }
+        }
+        process::exit(0);
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "no_stats")))]
+    #[test]
+    fn global_thread_waves() {
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        // Other tests share the global heap, so the counts are checked in a child process that
+        // runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_WAVES_CHILD";
+        if env::var_os(CHILD_VAR).is_none() {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::global_thread_waves", "--test-threads=1"])
+                .env(CHILD_VAR, "1")
+                .output()
+                .unwrap();
+            alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        const N_WAVES: usize = 20;
+        const N_THREADS: usize = 4;
+        const N_ITEMS: usize = 4000;
+        let sizes = [64, 1000, 8 << 10];
+        let held_back = N_THREADS * 64 *
+            sizes.iter().map(|&size| global::usable_size_for(size)).sum::<usize>();
+        // Without the background thread, handles that were not torn down on their own thread
+        // would be leaked along with their caches.
+        global::shutdown();
+        let mut first_wave = 0;
+        for wave in 0..N_WAVES {
+            let threads = (0..N_THREADS)
+                .map(|_| {
+                    thread::spawn(move || unsafe {
+                        let items = (0..N_ITEMS)
+                            .map(|i| global::alloc(sizes[i % sizes.len()]) as usize)
+                            .collect::<Vec<_>>();
+                        for item in items {
+                            global::free(item as *mut u8);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for t in threads {
+                t.join().unwrap();
+            }
+            // the exited threads' caches are returned before join returns
+            let stats = global::stats();
+            alloc_assert_eq!(stats.live_bytes, 0, "{:?}", stats);
+            alloc_assert!(stats.cached_bytes <= (wave + 1) * held_back, "{:?}", stats);
+            if wave == 0 {
+                first_wave = stats.mapped_bytes;
+            } else {
+                alloc_assert!(
+                    stats.mapped_bytes <= 2 * first_wave,
+                    "wave {}: {:?}, first wave mapped {}",
+                    wave,
+                    stats,
+                    first_wave
+                );
+            }
+        }
+        process::exit(0);
+    }
+
+    #[test]
+    fn global_sharded_mode() {
+        use std::env;
+        use std::process::Command;
+        // The mode is chosen once per process, so the multi-threaded workloads of the global
+        // interface are run again in child processes that select the sharded mode. Two shards
+        // are shared by all of their threads.
+        let tests = [
+            "layout_lookup",
+            "general_alloc_basic_global_single_threaded",
+            "general_alloc_basic_global_many_threads",
+            "general_alloc_large_ws_global_many_threads",
+            "global_short_lived_threads",
+            "global_free_while_exiting",
+            "global_usable_size",
+            "global_alloc_zeroed",
+        ];
+        for test in &tests {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", &format!("general::tests::{}", test), "--test-threads=1"])
+                .env("ELFMALLOC_NO_TLS", "1")
+                .env("ELFMALLOC_SHARDS", "2")
+                .output()
+                .unwrap();
+            alloc_assert!(
+                out.status.success(),
+                "{}: {}",
+                test,
+                String::from_utf8_lossy(&out.stderr)
+            );
+            // make sure that the test ran rather than being filtered out
+            alloc_assert!(
+                String::from_utf8_lossy(&out.stdout).contains("1 passed"),
+                "{}: {}",
+                test,
+                String::from_utf8_lossy(&out.stdout)
+            );
+        }
+    }
+
+    #[test]
+    fn global_husk_backpressure() {
+        use std::env;
+        use std::process::{self, Command};
+        use std::sync::Arc;
+        use std::thread;
+        // The background thread is slowed down for this test only, so it runs in a child process.
+        const CHILD_VAR: &str = "ELFMALLOC_HUSK_BACKPRESSURE_CHILD";
+        const N_THREADS: usize = 4 * global::HUSK_QUEUE_CAPACITY;
+        if env::var_os(CHILD_VAR).is_some() {
+            global::BACKGROUND_DELAY_MS.store(1, Ordering::Relaxed);
+            let done = Arc::new(AtomicBool::new(false));
+            let sampler = {
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut max = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        max = cmp::max(max, global::pending_remote_frees());
+                        thread::yield_now();
+                    }
+                    max
+                })
+            };
+            // Threads are spawned much faster than the background thread tears down handles.
+            for _ in 0..N_THREADS {
+                thread::spawn(|| unsafe {
+                    let mut handle = global::clone_heap();
+                    let items = (0..16).map(|_| handle.alloc(64)).collect::<Vec<_>>();
+                    for item in items {
+                        handle.free(item);
+                    }
+                    global::retire(handle);
+                }).join()
+                    .unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            let max = sampler.join().unwrap();
+            // one message may be counted before it is found not to fit
+            alloc_assert!(max <= global::HUSK_QUEUE_CAPACITY + 1, "{} messages pending", max);
+            alloc_assert!(max >= global::HUSK_QUEUE_CAPACITY / 2, "{} messages pending", max);
+            // every handle was torn down, returning the objects it cached
+            let stats = global::stats_detailed();
+            alloc_assert_eq!(global::pending_remote_frees(), 0);
+            alloc_assert_eq!(stats.live_bytes, 0);
+            alloc_assert_eq!(stats.cached_bytes, 0);
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::global_husk_backpressure", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn global_background_lazy() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process::{self, Command};
+        // Whether a thread of this process has the background thread's name, which Linux
+        // truncates to 15 bytes.
+        fn background_exists() -> bool {
+            let name = &global::BACKGROUND_THREAD_NAME.as_bytes()[..15];
+            fs::read_dir("/proc/self/task").unwrap().any(|task| {
+                let mut comm = Vec::new();
+                let path = task.unwrap().path().join("comm");
+                // a thread that has just exited has no comm file
+                let _ = fs::File::open(path).and_then(|mut f| f.read_to_end(&mut comm));
+                comm.starts_with(name)
+            })
+        }
+        // Other tests start the background thread, so this one runs in a child process.
+        const CHILD_VAR: &str = "ELFMALLOC_BACKGROUND_LAZY_CHILD";
+        if env::var_os(CHILD_VAR).is_some() {
+            unsafe {
+                let items = (0..1024).map(|i| global::alloc(1 + i * 64)).collect::<Vec<_>>();
+                for item in items {
+                    global::free(item);
+                }
+                global::free(global::alloc(1 << 20));
+            }
+            let _ = global::stats_detailed();
+            alloc_assert!(!background_exists());
+            // The first handle sent to the background thread starts it. The thread names itself
+            // once it is running, which it is by the time it has answered stats_detailed.
+            global::retire(global::clone_heap());
+            let _ = global::stats_detailed();
+            alloc_assert!(background_exists());
+            alloc_assert_eq!(global::pending_remote_frees(), 0);
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::global_background_lazy", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    /// Whether every system page of the `len` bytes at `start` is resident.
+    #[cfg(target_os = "linux")]
+    fn resident(start: *mut u8, len: usize) -> bool {
+        extern crate libc;
+        let page = mmap::page_size();
+        let mut vec = vec![0u8; len / page];
+        alloc_assert_eq!(
+            unsafe { libc::mincore(start as *mut libc::c_void, len, vec.as_mut_ptr()) },
+            0
+        );
+        vec.iter().all(|&v| v & 1 == 1)
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn eager_dirty_inline() {
+        // Background is only honoured by the global heap.
+        for &policy in &[EagerDirty::Inline, EagerDirty::Background] {
+            let mut dyn = DynamicAllocatorBuilder::default().eager_dirty(policy).build().unwrap();
+            unsafe {
+                let item = dyn.alloc_zeroed(100 << 10);
+                alloc_assert_eq!(get_type(item), AllocType::BigSlag);
+                // the slag's pages were touched before the object was handed out
+                alloc_assert!(resident(round_to_page(item), ELFMALLOC_PAGE_SIZE), "{:?}", policy);
+                // touching them left them reading as zeros
+                alloc_assert!(slice::from_raw_parts(item, 100 << 10).iter().all(|&b| b == 0));
+                dyn.free(item);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn global_eager_dirty_background() {
+        use std::env;
+        use std::process::{self, Command};
+        // The policy is read from the environment when the heap is built.
+        const CHILD_VAR: &str = "ELFMALLOC_EAGER_DIRTY_CHILD";
+        if env::var_os(CHILD_VAR).is_some() {
+            unsafe {
+                let item = global::alloc_zeroed(100 << 10);
+                alloc_assert_eq!(get_type(item), AllocType::BigSlag);
+                // every message sent before stats_detailed has been handled once it returns
+                let _ = global::stats_detailed();
+                alloc_assert!(resident(round_to_page(item), ELFMALLOC_PAGE_SIZE));
+                alloc_assert!(slice::from_raw_parts(item, 100 << 10).iter().all(|&b| b == 0));
+                global::free(item);
+            }
+            process::exit(0);
+        }
+        let out = Command::new(env::current_exe().unwrap())
+            .args(&["--exact", "general::tests::global_eager_dirty_background", "--test-threads=1"])
+            .env(CHILD_VAR, "1")
+            .env("ELFMALLOC_EAGER_DIRTY", "background")
+            .output()
+            .unwrap();
+        alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    #[cfg(not(feature = "no_stats"))]
+    #[test]
+    fn global_leak_report() {
+        use std::collections::HashMap;
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        // Every test allocates from the global heap, so this one runs in a child process.
+        const CHILD_VAR: &str = "ELFMALLOC_LEAK_REPORT_CHILD";
+        // The objects leaked by the child, by size.
+        const LEAKED: [(usize, usize); 3] = [(64, 3), (1000, 2), (3000, 1)];
+        fn expected() -> HashMap<usize, usize> {
+            let mut classes = HashMap::new();
+            for &(size, n) in LEAKED.iter() {
+                *classes.entry(global::usable_size_for(size)).or_insert(0) += n;
+            }
+            classes
+        }
+        // Check the classes and large allocations of a report.
+        fn check(report: &str) {
+            let mut classes = HashMap::new();
+            let mut large = 0;
+            for line in report.lines().skip_while(|line| !line.starts_with("elfmalloc leak")) {
+                let line = line.trim();
+                let mut words = line.split(|c| c == ' ' || c == ':').filter(|w| !w.is_empty());
+                match words.next() {
+                    Some("class") => {
+                        let size = words.next().unwrap().parse::<usize>().unwrap();
+                        let n = words.next().unwrap().parse::<usize>().unwrap();
+                        classes.insert(size, n);
+                    }
+                    Some("large") => large = words.nth(1).unwrap().parse::<usize>().unwrap(),
+                    _ => {}
+                }
+            }
+            alloc_assert_eq!(classes, expected(), "{}", report);
+            alloc_assert!(large >= 1 << 20, "{}", report);
+        }
+        if let Some(mode) = env::var_os(CHILD_VAR) {
+            unsafe {
+                // Objects that are freed are not reported, including those cached by a thread
+                // that has exited.
+                for _ in 0..100 {
+                    global::free(global::alloc(64));
+                }
+                let mut leaked = vec![global::alloc(1 << 20) as usize];
+                for &(size, n) in &LEAKED[..2] {
+                    leaked.extend((0..n).map(|_| global::alloc(size) as usize));
+                }
+                leaked.push(thread::spawn(|| {
+                    for _ in 0..100 {
+                        global::free(global::alloc(3000));
+                    }
+                    global::alloc(LEAKED[2].0) as usize
+                }).join()
+                    .unwrap());
+                alloc_assert!(leaked.iter().all(|&item| item != 0));
+            }
+            if mode == "direct" {
+                let mut report = String::new();
+                global::write_leak_report(&mut report).unwrap();
+                check(&report);
+            }
+            // the report is printed by the atexit handler
+            process::exit(0);
+        }
+        for &mode in &["direct", "atexit"] {
+            let mut cmd = Command::new(env::current_exe().unwrap());
+            cmd.args(&["--exact", "general::tests::global_leak_report", "--test-threads=1"])
+                .env(CHILD_VAR, mode);
+            if mode == "atexit" {
+                cmd.env("ELFMALLOC_LEAK_REPORT", "1");
+            }
+            let out = cmd.output().unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            alloc_assert!(out.status.success(), "{}", stderr);
+            if mode == "atexit" {
+                check(&stderr);
+            }
+        }
+    }
+
+    #[test]
+    fn global_handle_creation() {
+        use std::thread;
+        // make sure that the heap is built, which does allocate
+        unsafe { global::free(global::alloc(64)) };
+        thread::spawn(|| unsafe {
+            let item = global::alloc(64);
+            alloc_assert!(!item.is_null());
+            alloc_assert_eq!(global::HANDLES_CREATED, 1);
+            alloc_assert_eq!(global::CALLS_CREATING_HANDLE, 0);
+            global::free(item);
+        }).join()
+            .unwrap();
+    }
+
+    #[test]
+    fn global_layout_without_handle() {
+        use std::cell::Cell;
+        use std::thread;
+        struct LayoutOnDrop(Cell<usize>);
+        impl Drop for LayoutOnDrop {
+            fn drop(&mut self) {
+                unsafe {
+                    let item = self.0.get() as *mut u8;
+                    let created = global::HANDLES_CREATED;
+                    let (size, _) = global::get_layout(item);
+                    alloc_assert!(size >= 100);
+                    alloc_assert_eq!(global::usable_size(item), size);
+                    alloc_assert_eq!(global::HANDLES_CREATED, created);
+                    global::free(item);
+                }
+            }
+        }
+        thread_local!{ static LATE: LayoutOnDrop = LayoutOnDrop(Cell::new(0)); }
+        for &size in &[100, 10 << 10, 1 << 20] {
+            thread::spawn(move || {
+                // LATE is registered before the thread's handle, so on Linux its destructor runs
+                // after the handle has been dropped, when the fallback paths are taken.
+                LATE.with(|late| late.0.set(unsafe { global::alloc(size) } as usize));
+            }).join()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn global_validate_free() {
+        use std::cell::Cell;
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        const CHILD_VAR: &str = "ELFMALLOC_VALIDATE_FREE_CHILD";
+        static FOREIGN: AtomicUsize = AtomicUsize::new(0);
+        unsafe fn free_box(item: *mut u8) {
+            FOREIGN.store(item as usize, Ordering::SeqCst);
+            drop(Box::from_raw(item as *mut [u8; 64]));
+        }
+        struct FreeOnDrop(Cell<usize>);
+        impl Drop for FreeOnDrop {
+            fn drop(&mut self) {
+                unsafe { global::free(self.0.get() as *mut u8) };
+            }
+        }
+        thread_local!{ static LATE: FreeOnDrop = FreeOnDrop(Cell::new(0)); }
+        if let Some(mode) = env::var_os(CHILD_VAR) {
+            unsafe {
+                // objects of the heap, including one allocated by the fallback paths, are freed
+                for &size in &[64, 10 << 10, 1 << 20] {
+                    global::free(global::alloc(size));
+                    thread::spawn(move || {
+                        LATE.with(|late| late.0.set(global::alloc(size) as usize));
+                    }).join()
+                        .unwrap();
+                }
+                global::free(ptr::null_mut());
+                // Box is not backed by elfmalloc in this crate's tests
+                let foreign = Box::into_raw(Box::new([0u8; 64])) as *mut u8;
+                if mode == "callback" {
+                    global::set_foreign_free(free_box);
+                }
+                global::free(foreign);
+                alloc_assert_eq!(FOREIGN.load(Ordering::SeqCst), foreign as usize);
+            }
+            process::exit(0);
+        }
+        for &mode in &["abort", "callback"] {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::global_validate_free", "--test-threads=1"])
+                .env(CHILD_VAR, mode)
+                .env("ELFMALLOC_VALIDATE_FREE", "1")
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            if mode == "abort" {
+                alloc_assert!(!out.status.success());
+                alloc_assert!(stderr.contains("pointer not owned by elfmalloc"), "{}", stderr);
+            } else {
+                alloc_assert!(out.status.success(), "{}", stderr);
+            }
+        }
+    }
+
+    #[test]
+    fn global_alloc_aligned() {
+        unsafe {
+            for &align in &[0, 3, 24, 48, 3 << 20] {
+                alloc_assert!(global::alloc_aligned(64, align).is_null(), "align {}", align);
+            }
+            let mut align = 16;
+            while align <= 2 << 20 {
+                for &size in &[1, 100, 3000, (100 << 10) + 1, 3 << 20] {
+                    let item = global::alloc_aligned(size, align);
+                    alloc_assert!(!item.is_null());
+                    alloc_assert_eq!(item as usize % align, 0, "size {} align {}", size, align);
+                    let usable = global::usable_size(item);
+                    alloc_assert!(usable >= size, "size {} align {}", size, align);
+                    // sizes are not rounded up to a power of two past the alignment
+                    alloc_assert!(
+                        usable <= 2 * cmp::max(size, align),
+                        "size {} align {}: {} usable",
+                        size,
+                        align,
+                        usable
+                    );
+                    write_bytes(item, 0xff, usable);
+                    global::free(item);
+                }
+                align *= 2;
+            }
+        }
+    }
+
+    #[test]
+    fn global_usable_size() {
+        unsafe {
+            alloc_assert_eq!(global::usable_size(ptr::null_mut()), 0);
+            // one size from each class, then large allocations
+            let max = DynamicAllocator::new().max_class_size();
+            let mut sizes = Vec::new();
+            let mut size = 1;
+            while size <= max {
+                sizes.push(size);
+                size = global::usable_size_for(size) + 1;
+            }
+            sizes.extend_from_slice(&[max + 1, 1 << 20, (4 << 20) + 1]);
+            for &size in &sizes {
+                let item = global::alloc(size);
+                alloc_assert!(!item.is_null());
+                let usable = global::usable_size(item);
+                alloc_assert_eq!(usable, global::get_layout(item).0);
+                alloc_assert!(usable >= size, "{} < {}", usable, size);
+                ptr::write_bytes(item, 0xff, usable);
+                global::free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn global_alloc_zeroed() {
+        let sizes = [1, 8, 24, 100, 1000, 4000, 16 << 10, 40 << 10, 100 << 10, 1 << 20, 4 << 20];
+        for &size in &sizes {
+            for _ in 0..3 {
+                unsafe {
+                    // dirty objects of this size, so that some of the zeroed ones are recycled
+                    let items = (0..16).map(|_| global::alloc(size)).collect::<Vec<_>>();
+                    for &item in &items {
+                        alloc_assert!(!item.is_null());
+                        ptr::write_bytes(item, 0xff, size);
+                    }
+                    for &item in &items {
+                        global::free(item);
+                    }
+                    let items = (0..32).map(|_| global::alloc_zeroed(size)).collect::<Vec<_>>();
+                    for &item in &items {
+                        alloc_assert!(!item.is_null());
+                        alloc_assert!(
+                            slice::from_raw_parts(item, size).iter().all(|&b| b == 0),
+                            "object of size {} is not zeroed",
+                            size
+                        );
+                        ptr::write_bytes(item, 0xff, size);
+                    }
+                    for &item in &items {
+                        global::free(item);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn global_fork() {
+        extern crate libc;
+        use std::env;
+        use std::process::{self, Command};
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+        // The child of a fork may only use locks that no other thread of the parent could have
+        // held, which rules out the other tests' threads and the standard heap they use. This
+        // test runs in a child process on its own, and its allocating threads use only the
+        // global heap.
+        const CHILD_VAR: &str = "ELFMALLOC_FORK_CHILD";
+        if env::var_os(CHILD_VAR).is_none() {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::global_fork", "--test-threads=1"])
+                .env(CHILD_VAR, "1")
+                .output()
+                .unwrap();
+            alloc_assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        const SIZES: [usize; 4] = [24, 1000, 40 << 10, 1 << 20];
+        unsafe fn churn(rounds: usize) {
+            let mut items = [ptr::null_mut(); 32];
+            for round in 0..rounds {
+                for (i, item) in items.iter_mut().enumerate() {
+                    let size = SIZES[(round + i) % SIZES.len()];
+                    *item = global::alloc(size);
+                    alloc_assert!(!item.is_null());
+                    ptr::write_bytes(*item, 1, size);
+                }
+                for item in &items {
+                    global::free(*item);
+                }
+            }
+        }
+        // the first allocator to be retired starts the background thread
+        global::retire(DynamicAllocator::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+                thread::spawn(move || while !stop.load(Ordering::Relaxed) {
+                    unsafe { churn(1) }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..20 {
+            unsafe {
+                let pid = libc::fork();
+                alloc_assert!(pid >= 0);
+                if pid == 0 {
+                    // a deadlock kills the child
+                    libc::alarm(10);
+                    churn(16);
+                    thread::spawn(|| churn(4)).join().unwrap();
+                    // The retired allocator is torn down by a new background thread, which
+                    // shutdown then stops.
+                    global::retire(DynamicAllocator::new());
+                    global::shutdown();
+                    churn(1);
+                    libc::_exit(0);
+                }
+                let mut status = 0;
+                alloc_assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                alloc_assert!(status == 0, "child failed with status {}", status);
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+        process::exit(0);
+    }
+
+    #[test]
+    fn global_env_config() {
+        use std::env;
+        use std::process::{self, Command};
+        // The global heap reads the environment once, so each configuration is tested in a child
+        // process that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_ENV_CONFIG_CHILD";
+        match env::var(CHILD_VAR).as_ref().map(|s| s.as_str()) {
+            Ok("valid") => unsafe {
+                let expected = DynamicAllocatorBuilder::default()
+                    .small_page_size(64 << 10)
+                    .n_classes(12)
+                    .build()
+                    .unwrap();
+                let default = DynamicAllocator::new();
+                let sizes = [1, 24, 100, 1000, 10 << 10, 20 << 10, 100 << 10, 1 << 20];
+                for &size in &sizes {
+                    alloc_assert_eq!(
+                        global::usable_size_for(size),
+                        expected.usable_size_aligned(size, mem::size_of::<usize>())
+                    );
+                }
+                alloc_assert!(sizes.iter().any(|&size| {
+                    global::usable_size_for(size) !=
+                        default.usable_size_aligned(size, mem::size_of::<usize>())
+                }));
+                // objects freed without a handle are found in the configured pages
+                for &size in &sizes {
+                    let item = global::alloc(size);
+                    alloc_assert!(!item.is_null());
+                    global::free_without_handle(item);
+                    global::free(global::alloc(size));
+                }
+                alloc_assert!(global::alloc(128 << 20).is_null());
+                let item = global::alloc(16 << 20);
+                alloc_assert!(!item.is_null());
+                global::free(item);
+                process::exit(0);
+            },
+            Ok("invalid") => unsafe {
+                // both variables are ignored, so the heap has the default configuration
+                alloc_assert_eq!(
+                    global::usable_size_for(100 << 10),
+                    DynamicAllocator::new().usable_size_aligned(100 << 10, mem::size_of::<usize>())
+                );
+                let item = global::alloc(128 << 20);
+                alloc_assert!(!item.is_null());
+                global::free(item);
+                process::exit(0);
+            },
+            _ => {}
+        }
+        let run = |mode: &str, vars: &[(&str, &str)]| {
+            let mut cmd = Command::new(env::current_exe().unwrap());
+            cmd.args(&["--exact", "general::tests::global_env_config", "--test-threads=1"])
+                .env(CHILD_VAR, mode);
+            for &(var, val) in vars {
+                cmd.env(var, val);
+            }
+            let out = cmd.output().unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+            alloc_assert!(out.status.success(), "{}", stderr);
+            stderr
+        };
+        let stderr = run(
+            "valid",
+            &[
+                ("ELFMALLOC_SMALL_PAGE", "64K"),
+                ("ELFMALLOC_N_CLASSES", "12"),
+                ("ELFMALLOC_MAX_BYTES", "64M"),
+            ],
+        );
+        alloc_assert!(!stderr.contains("elfmalloc: ignoring"), "{}", stderr);
+        let stderr = run(
+            "invalid",
+            &[("ELFMALLOC_SMALL_PAGE", "3000"), ("ELFMALLOC_MAX_BYTES", "lots")],
+        );
+        alloc_assert!(
+            stderr.contains("elfmalloc: ignoring malformed value \"lots\" for ELFMALLOC_MAX_BYTES"),
+            "{}",
+            stderr
+        );
+        alloc_assert!(
+            stderr.contains("elfmalloc: ignoring configuration from the environment"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn global_init_reentrant() {
+        use std::env;
+        use std::process::{self, Command};
+        use std::thread;
+        use std::time::Duration;
+        // The global heap is only initialized once, so each way of first using it is tested in a
+        // child process that runs only this test.
+        const CHILD_VAR: &str = "ELFMALLOC_INIT_REENTRANT_CHILD";
+        static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+        // Stands in for libc calling calloc (through the global interface, as with LD_PRELOAD)
+        // while the heap is being initialized.
+        fn mock_calloc() {
+            HOOK_CALLS.fetch_add(1, Ordering::SeqCst);
+            unsafe {
+                let item = global::alloc_zeroed(4 * mem::size_of::<usize>());
+                alloc_assert!(!item.is_null());
+                alloc_assert!(
+                    slice::from_raw_parts(item, 4 * mem::size_of::<usize>())
+                        .iter()
+                        .all(|&b| b == 0)
+                );
+                global::free(item);
+            }
+            // give the other threads time to find the heap being initialized
+            thread::sleep(Duration::from_millis(20));
+        }
+        fn churn() {
+            for &size in &[8, 100, 1000, 20 << 10] {
+                unsafe {
+                    let item = global::alloc(size);
+                    alloc_assert!(!item.is_null());
+                    global::free(item);
+                }
+            }
+        }
+        let mode = env::var(CHILD_VAR);
+        if mode.is_ok() {
+            global::INIT_HOOK.store(mock_calloc as usize, Ordering::Release);
+        }
+        match mode.as_ref().map(|s| s.as_str()) {
+            Ok("handle") => {
+                churn();
+                alloc_assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 1);
+                process::exit(0);
+            }
+            Ok("direct") => {
+                // stats does not go through the thread's handle
+                alloc_assert_eq!(global::stats().live_bytes, 0);
+                alloc_assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 1);
+                churn();
+                process::exit(0);
+            }
+            Ok("threads") => {
+                let threads = (0..4).map(|_| thread::spawn(churn)).collect::<Vec<_>>();
+                for t in threads {
+                    t.join().unwrap();
+                }
+                alloc_assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 1);
+                process::exit(0);
+            }
+            _ => {}
+        }
+        for mode in &["handle", "direct", "threads"] {
+            let out = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", "general::tests::global_init_reentrant", "--test-threads=1"])
+                .env(CHILD_VAR, mode)
+                .output()
+                .unwrap();
+            alloc_assert!(
+                out.status.success(),
+                "{}: {}",
+                mode,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+    }
+
     #[test]
     fn realloc_basic() {
         let _ = env_logger::init();
@@ -1228,6 +11529,119 @@ mod tests {
         }
     }
 
+    #[test]
+    fn shared_many_threads() {
+        let _ = env_logger::init();
+        use std::sync::Arc;
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<SharedDynamicAllocator>();
+
+        const N_THREADS: usize = 32;
+        let alloc = Arc::new(SharedDynamicAllocator::with_shards(DynamicAllocator::new(), 4));
+        // objects allocated on one thread are freed on the next
+        let (txs, rxs): (Vec<_>, Vec<_>) = (0..N_THREADS).map(|_| channel::<usize>()).unzip();
+        let mut threads = Vec::with_capacity(N_THREADS);
+        for (t, rx) in rxs.into_iter().enumerate() {
+            let alloc = alloc.clone();
+            let tx = txs[(t + 1) % N_THREADS].clone();
+            threads.push(
+                thread::Builder::new()
+                    .name(t.to_string())
+                    .spawn(move || unsafe {
+                        for size in 1..(1 << 13) {
+                            let item = alloc.alloc(size * 8);
+                            write_bytes(item, 0xFF, size * 8);
+                            alloc.free(item);
+                            if size * 8 >= (1 << 20) {
+                                break;
+                            }
+                        }
+                        let ptrs: Vec<*mut u8> = (0..(1 << 16)).map(|_| alloc.alloc(8)).collect();
+                        for p in ptrs {
+                            write_volatile(p, 1);
+                            tx.send(p as usize).unwrap();
+                        }
+                        mem::drop(tx);
+                        for p in rx {
+                            alloc.free(p as *mut u8);
+                        }
+                    })
+                    .unwrap(),
+            );
+        }
+        mem::drop(txs);
+        for t in threads {
+            t.join().expect("threads should exit successfully")
+        }
+        alloc_assert_eq!(Arc::strong_count(&alloc), 1);
+    }
+
+    /// Check whether writing to `p` faults, by writing to it from a child process.
+    #[cfg(all(unix, feature = "freeze"))]
+    unsafe fn write_faults(p: *mut u8) -> bool {
+        extern crate libc;
+        match libc::fork() {
+            0 => {
+                write_volatile(p, 0);
+                libc::_exit(0)
+            }
+            pid => {
+                let mut status = 0;
+                alloc_assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+            }
+        }
+    }
+
+    #[cfg(feature = "freeze")]
+    #[test]
+    fn freeze_class() {
+        let _ = env_logger::init();
+        const SIZE: usize = 24;
+        let mut alloc = DynamicAllocator::new();
+        alloc_assert!(alloc.freeze_class(8 << 20, false).is_none());
+        unsafe {
+            let live = (0..1024).map(|_| alloc.alloc(SIZE)).collect::<Vec<_>>();
+            for &p in &live {
+                write_bytes(p, 1, SIZE);
+            }
+            let (class_size, _) = global::get_layout(live[0]);
+            let guard = alloc.freeze_class(SIZE, true).unwrap();
+            alloc_assert_eq!(guard.object_size(), class_size);
+            alloc_assert!(alloc.freeze_class(SIZE, false).is_none());
+            alloc_assert!(alloc.clone().freeze_class(SIZE, false).is_none());
+
+            // allocations of the same size overflow into a larger class
+            let other = alloc.alloc(SIZE);
+            alloc_assert!(global::get_layout(other).0 > class_size);
+            write_bytes(other, 2, SIZE);
+            alloc.free(other);
+
+            // frees are queued and leave the frozen pages untouched
+            for &p in &live[..512] {
+                alloc.free(p);
+            }
+            alloc_assert!(live.iter().all(|&p| (0..SIZE).all(|i| *p.offset(i as isize) == 1)));
+            #[cfg(unix)]
+            alloc_assert!(write_faults(live[600]));
+
+            alloc_assert_eq!(guard.thaw(), 512);
+            #[cfg(unix)]
+            alloc_assert!(!write_faults(live[600]));
+            write_bytes(live[600], 3, SIZE);
+            for &p in &live[512..] {
+                alloc.free(p);
+            }
+            // the class is used again once it is thawed
+            let p = alloc.alloc(SIZE);
+            alloc_assert_eq!(global::get_layout(p).0, class_size);
+            alloc.free(p);
+        }
+    }
+
     #[test]
     fn all_sizes_one_thread() {
         let _ = env_logger::init();
@@ -1242,4 +11656,687 @@ mod tests {
             }
         }
     }
+
+    /// A message with a header, a body, and a few small metadata blocks.
+    const MESSAGE: [usize; 5] = [64, 1500, 16, 16, 24];
+
+    #[test]
+    fn alloc_vectored() {
+        let _ = env_logger::init();
+        let sizes = [64, 1500, 16, 4 << 20, 16, 24, 1500, 8];
+        let mut out = [ptr::null_mut(); 9];
+        unsafe {
+            global::alloc_vectored(&sizes, &mut out).unwrap();
+            alloc_assert!(out[8].is_null());
+            for (i, (&p, &size)) in out.iter().zip(&sizes).enumerate() {
+                alloc_assert!(!p.is_null());
+                alloc_assert!(global::get_layout(p).0 >= size);
+                write_bytes(p, i as u8, size);
+            }
+            for (i, (&p, &size)) in out.iter().zip(&sizes).enumerate() {
+                alloc_assert!((0..size).all(|j| *p.offset(j as isize) == i as u8));
+            }
+            // each object can be freed individually
+            for &p in &out[..sizes.len()] {
+                global::free(p);
+            }
+            alloc_assert!(global::alloc_vectored(&sizes, &mut out[..4]).is_err());
+        }
+    }
+
+    #[test]
+    fn alloc_vectored_atomic() {
+        let _ = env_logger::init();
+        // the large request is served last, after the small objects have been allocated
+        let sizes = [64, 1500, 4 << 20, 16, 16, 24];
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            // set up the caches of the small classes, so that only the large request exceeds the
+            // limit
+            for &size in &[64, 1500, 16, 24] {
+                let item = dyn.alloc(size);
+                dyn.free(item);
+            }
+            let stats = dyn.stats();
+            dyn.set_memory_limit(dyn.memory_used() + (1 << 20));
+            let mut out = [1 as *mut u8; 6];
+            match dyn.0.alloc_vectored(&sizes, &mut out) {
+                Err(AllocErr::Exhausted { request }) => alloc_assert_eq!(request.size(), 4 << 20),
+                r => alloc_panic!("unexpected result {:?}", r),
+            }
+            alloc_assert!(out.iter().all(|p| p.is_null()));
+            // the small objects were returned
+            alloc_assert_eq!(dyn.stats().live_bytes, stats.live_bytes);
+
+            // recovers once the large request fits
+            dyn.set_memory_limit(usize::max_value());
+            dyn.0.alloc_vectored(&sizes, &mut out).unwrap();
+            for (&p, &size) in out.iter().zip(&sizes) {
+                alloc_assert!(dyn.usable_size(p) >= size);
+                dyn.free(p);
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_vectored_many() {
+        let _ = env_logger::init();
+        // more requests than are sorted at once, of every class
+        let sizes = (0..3 * VECTORED_CHUNK + 5).map(|i| (i * 97) % 20000 + 1).collect::<Vec<_>>();
+        let mut out = vec![ptr::null_mut(); sizes.len()];
+        let mut dyn = DynamicAllocator::new();
+        unsafe {
+            dyn.0.alloc_vectored(&sizes, &mut out).unwrap();
+            for (i, (&p, &size)) in out.iter().zip(&sizes).enumerate() {
+                alloc_assert!(dyn.usable_size(p) >= size, "request {}", i);
+                write_bytes(p, i as u8, size);
+            }
+            for (i, (&p, &size)) in out.iter().zip(&sizes).enumerate() {
+                alloc_assert!((0..size).all(|j| *p.offset(j as isize) == i as u8));
+                dyn.free(p);
+            }
+        }
+    }
+
+    #[test]
+    fn free_batch() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        // Mix objects of several classes, including some that share slags with each other and
+        // large objects, and free them in batches of several sizes.
+        let sizes = [8, 24, 100, 512, 4096, 40 << 10, 300 << 10, 3 << 20];
+        for &n in &[1, 255, 256, 257, 10_000] {
+            unsafe {
+                let items: Vec<*mut u8> = (0..n)
+                    .map(|i| {
+                        let size = sizes[(i * 7) % sizes.len()];
+                        let p = dyn.alloc(size);
+                        write_bytes(p, 0xFF, cmp::min(size, 4096));
+                        p
+                    })
+                    .collect();
+                dyn.free_batch(&items);
+            }
+        }
+        // the freed objects are reused
+        unsafe {
+            let items: Vec<*mut u8> = (0..512).map(|_| dyn.alloc(64)).collect();
+            dyn.free_batch(&items);
+            let again: Vec<*mut u8> = (0..512).map(|_| dyn.alloc(64)).collect();
+            alloc_assert!(again.iter().any(|p| items.contains(p)));
+            dyn.free_batch(&again);
+        }
+    }
+
+    #[test]
+    fn alloc_batch() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocator::new();
+        for &size in &[8, 64, 1000, 40 << 10, 300 << 10, 3 << 20] {
+            let large = size > dyn.max_class_size();
+            let mut out = vec![ptr::null_mut(); if size > (1 << 20) { 16 } else { 4096 }];
+            unsafe {
+                alloc_assert_eq!(dyn.alloc_batch(size, &mut out), out.len());
+                for &p in &out {
+                    alloc_assert!(dyn.usable_size(p) >= size);
+                    write_bytes(p, 0xFF, cmp::min(size, 4096));
+                }
+                let mut distinct = out.clone();
+                distinct.sort();
+                distinct.dedup();
+                alloc_assert_eq!(distinct.len(), out.len());
+                let regions: Vec<(usize, *mut u8)> = if large {
+                    out.iter().map(|&p| large_alloc::get_commitment(p)).collect()
+                } else {
+                    Vec::new()
+                };
+                for &(region_size, base) in &regions {
+                    alloc_assert_eq!(large_alloc::registered_size(base), Some(region_size));
+                }
+                dyn.free_batch(&out);
+                // every large allocation was released; a region registered again at the same
+                // address has been reused by another test
+                for (&p, &(_, base)) in out.iter().zip(&regions) {
+                    alloc_assert!(
+                        large_alloc::registered_size(base).is_none() || !large_alloc::was_freed(p)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_on_free() {
+        let _ = env_logger::init();
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .zero_on_free(true)
+            .build()
+            .unwrap();
+        let is_zeroed = |p: *mut u8, size: usize| unsafe {
+            slice::from_raw_parts(p, size).iter().all(|&b| b == 0)
+        };
+        for &size in &[8, 64, 1000, 4096, 40 << 10, 128 << 10] {
+            unsafe {
+                let p = dyn.alloc(size);
+                write_bytes(p, 0xAB, size);
+                dyn.free(p);
+                // the most recently freed object is reused first
+                let q = dyn.alloc(size);
+                alloc_assert_eq!(p, q);
+                alloc_assert!(is_zeroed(q, size));
+                dyn.free(q);
+            }
+        }
+        unsafe {
+            let items: Vec<*mut u8> = (0..256).map(|_| dyn.alloc(64)).collect();
+            for &p in &items {
+                write_bytes(p, 0xAB, 64);
+            }
+            dyn.free_batch(&items);
+            let again: Vec<*mut u8> = (0..256).map(|_| dyn.alloc(64)).collect();
+            alloc_assert!(again.iter().any(|p| items.contains(p)));
+            for &p in &again {
+                alloc_assert!(is_zeroed(p, 64));
+            }
+            dyn.free_batch(&again);
+        }
+    }
+
+    #[bench]
+    fn bench_alloc_batch(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = [ptr::null_mut(); 4096];
+        b.iter(|| unsafe {
+            dyn.alloc_batch(64, &mut items);
+            dyn.free_batch(&items);
+        });
+    }
+
+    #[bench]
+    fn bench_alloc_loop(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = [ptr::null_mut(); 4096];
+        b.iter(|| unsafe {
+            for p in items.iter_mut() {
+                *p = dyn.alloc(64);
+            }
+            dyn.free_batch(&items);
+        });
+    }
+
+    /// Allocate and free bursts of `items.len()` objects of each of `sizes`.
+    fn magazine_workload(dyn: &mut DynamicAllocator, sizes: &[usize], items: &mut [*mut u8]) {
+        for &size in sizes {
+            unsafe {
+                for p in items.iter_mut() {
+                    *p = dyn.alloc(size);
+                }
+                for &p in items.iter() {
+                    dyn.free(p);
+                }
+            }
+        }
+    }
+
+    /// Small, medium and large classes, allocated in bursts of 1024 objects.
+    const MAGAZINE_SIZES: [usize; 3] = [64, 1024, 16 << 10];
+    /// The largest classes, allocated in bursts of 64 objects, which at 64 objects per cache
+    /// holds 48MB in the caches of these classes.
+    const MAGAZINE_LARGE_SIZES: [usize; 2] = [256 << 10, 512 << 10];
+
+    #[bench]
+    fn bench_magazine_capacity_per_class(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = [ptr::null_mut(); 1024];
+        b.iter(|| magazine_workload(&mut dyn, &MAGAZINE_SIZES, &mut items));
+    }
+
+    // the same workload, with caches of the same capacity for every class
+    #[bench]
+    fn bench_magazine_capacity_fixed(b: &mut Bencher) {
+        let mut dyn = DynamicAllocatorBuilder::default().magazine_capacity(64).build().unwrap();
+        let mut items = [ptr::null_mut(); 1024];
+        b.iter(|| magazine_workload(&mut dyn, &MAGAZINE_SIZES, &mut items));
+    }
+
+    // Both workloads at once, from small classes to the largest ones. With per-class capacities,
+    // the caches of small classes hold whole bursts, while those of the large classes cache far
+    // less memory than with the fixed capacity (see magazine_capacity_large_classes).
+    #[bench]
+    fn bench_magazine_capacity_range_per_class(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let (mut items, mut large_items) = ([ptr::null_mut(); 1024], [ptr::null_mut(); 64]);
+        b.iter(|| {
+            magazine_workload(&mut dyn, &MAGAZINE_SIZES, &mut items);
+            magazine_workload(&mut dyn, &MAGAZINE_LARGE_SIZES, &mut large_items);
+        });
+    }
+
+    #[bench]
+    fn bench_magazine_capacity_range_fixed(b: &mut Bencher) {
+        let mut dyn = DynamicAllocatorBuilder::default().magazine_capacity(64).build().unwrap();
+        let (mut items, mut large_items) = ([ptr::null_mut(); 1024], [ptr::null_mut(); 64]);
+        b.iter(|| {
+            magazine_workload(&mut dyn, &MAGAZINE_SIZES, &mut items);
+            magazine_workload(&mut dyn, &MAGAZINE_LARGE_SIZES, &mut large_items);
+        });
+    }
+
+    /// Allocate and free bursts of objects from 32 clones of `dyn` at once, so that their caches
+    /// keep exchanging magazines with the depot of the class.
+    #[cfg(feature = "magazine_layer")]
+    fn depot_workload(dyn: &DynamicAllocator) {
+        use std::thread;
+        let threads = (0..32)
+            .map(|_| {
+                let mut dyn = dyn.clone();
+                thread::spawn(move || unsafe {
+                    let mut items = Vec::with_capacity(16 << 10);
+                    for _ in 0..4 {
+                        for _ in 0..items.capacity() {
+                            items.push(dyn.alloc(64));
+                        }
+                        for item in items.drain(..) {
+                            dyn.free(item);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "magazine_layer")]
+    #[bench]
+    fn bench_depot_contention_global(b: &mut Bencher) {
+        let dyn = DynamicAllocator::new();
+        b.iter(|| depot_workload(&dyn));
+    }
+
+    // the same workload, with handles spread over the shards of each depot
+    #[cfg(feature = "magazine_layer")]
+    #[bench]
+    fn bench_depot_contention_sharded(b: &mut Bencher) {
+        let dyn = DynamicAllocatorBuilder::default().depot_shards(8, 4).build().unwrap();
+        b.iter(|| depot_workload(&dyn));
+    }
+
+    #[bench]
+    fn bench_alloc_free_small(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        b.iter(|| unsafe {
+            for _ in 0..1024 {
+                let item = test::black_box(dyn.alloc(24));
+                dyn.free(item);
+            }
+        });
+    }
+
+    /// The default small classes of a `TieredSizeClasses`, the same classes as `Multiples`, and
+    /// a spread of sizes to look up in both.
+    fn small_lookup() -> (TieredSizeClasses<usize>, Multiples<usize>, Vec<usize>) {
+        let n_classes = 2 * ELFMALLOC_SMALL_CUTOFF / MULTIPLE;
+        let (_, tiered) = TieredSizeClasses::init_with(
+            8,
+            n_classes,
+            MULTIPLE,
+            ELFMALLOC_SMALL_CUTOFF,
+            false,
+            |size| size,
+            |start, _, f| PowersOfTwo::init_conserve(start, 1, f),
+        );
+        let n_medium_classes =
+            TieredSizeClasses::<()>::medium_classes(8, n_classes, MULTIPLE, ELFMALLOC_SMALL_CUTOFF)
+                .1;
+        let (_, multiples) =
+            Multiples::init_multiple(8, n_classes - n_medium_classes, MULTIPLE, |size| size);
+        let max = tiered.small_max;
+        let sizes = (0..1024).map(|i| MULTIPLE + (i * 40) % (max - MULTIPLE)).collect();
+        (tiered, multiples, sizes)
+    }
+
+    // Looking up small classes through the table, against the rounding it replaced below. Run both
+    // in one build to compare the lookups; bench_alloc_free_small shows the effect on each object.
+    #[bench]
+    fn bench_small_lookup_table(b: &mut Bencher) {
+        let (tiered, _, sizes) = small_lookup();
+        b.iter(|| for &n in &sizes {
+            test::black_box(unsafe { *tiered.get(test::black_box(n)) });
+        });
+    }
+
+    #[bench]
+    fn bench_small_lookup_multiples(b: &mut Bencher) {
+        let (_, multiples, sizes) = small_lookup();
+        b.iter(|| for &n in &sizes {
+            test::black_box(unsafe { *multiples.get(test::black_box(n)) });
+        });
+    }
+
+    #[bench]
+    fn bench_global_alloc_free(b: &mut Bencher) {
+        b.iter(|| unsafe {
+            for _ in 0..1024 {
+                global::free(global::alloc(64));
+            }
+        });
+    }
+
+    // the same workload as bench_global_alloc_free, through the handles of the sharded mode
+    #[bench]
+    fn bench_sharded_alloc_free(b: &mut Bencher) {
+        let dyn = DynamicAllocator::new();
+        let shards = global::Shards::new(&dyn.0, num_cpus::get());
+        b.iter(|| unsafe {
+            for _ in 0..1024 {
+                shards.with(|h| {
+                    let item = h.alloc(64);
+                    h.free(item)
+                });
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_free_without_handle(b: &mut Bencher) {
+        let mut items = [ptr::null_mut(); 4096];
+        b.iter(|| unsafe {
+            for p in items.iter_mut() {
+                *p = global::alloc(64);
+            }
+            // the objects are freed again by the next allocation
+            for &p in items.iter() {
+                global::free_without_handle(p);
+            }
+        });
+    }
+
+    /// Allocate and touch 64 medium objects, then free them.
+    fn bench_big_slags(b: &mut Bencher, huge_pages: HugePages) {
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .huge_pages(huge_pages)
+            .build()
+            .unwrap();
+        let mut items = [ptr::null_mut(); 64];
+        b.iter(|| unsafe {
+            for p in items.iter_mut() {
+                *p = dyn.alloc(256 << 10);
+                for i in 0..(256 << 10) / 4096 {
+                    write_volatile(p.offset(i * 4096), 1);
+                }
+            }
+            dyn.free_batch(&items);
+        });
+    }
+
+    #[bench]
+    fn bench_big_slags_normal_pages(b: &mut Bencher) {
+        bench_big_slags(b, HugePages::Never);
+    }
+
+    #[bench]
+    fn bench_big_slags_huge_pages(b: &mut Bencher) {
+        bench_big_slags(b, HugePages::Transparent);
+    }
+
+    /// The sizes of the objects allocated while handling a request.
+    const REQUEST: [usize; 8] = [16, 200, 48, 1 << 10, 24, 4 << 10, 64, 300];
+
+    #[bench]
+    fn bench_request_arena(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        b.iter(|| {
+            let arena = dyn.arena();
+            for _ in 0..32 {
+                for &size in &REQUEST {
+                    let mut obj = arena.alloc(size);
+                    unsafe { write_volatile(obj.as_mut_ptr(), 1) };
+                }
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_request_free(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = Vec::with_capacity(32 * REQUEST.len());
+        b.iter(|| unsafe {
+            for _ in 0..32 {
+                for &size in &REQUEST {
+                    let obj = dyn.alloc(size);
+                    write_volatile(obj, 1);
+                    items.push(obj);
+                }
+            }
+            for p in items.drain(..) {
+                dyn.free(p);
+            }
+        });
+    }
+
+    fn bench_free_touched(b: &mut Bencher, zero_on_free: bool) {
+        let mut dyn = DynamicAllocatorBuilder::default()
+            .zero_on_free(zero_on_free)
+            .build()
+            .unwrap();
+        let mut items = [ptr::null_mut(); 1024];
+        b.iter(|| unsafe {
+            for p in items.iter_mut() {
+                *p = dyn.alloc(256);
+                write_volatile(*p, 1);
+            }
+            for &p in &items[..] {
+                dyn.free(p);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_free_no_zeroing(b: &mut Bencher) {
+        bench_free_touched(b, false);
+    }
+
+    #[bench]
+    fn bench_free_zero_on_free(b: &mut Bencher) {
+        bench_free_touched(b, true);
+    }
+
+    /// Allocate and free a 16MiB buffer, which is reused from the cache of large regions.
+    #[bench]
+    fn bench_large_alloc_free(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        b.iter(|| unsafe {
+            let item = dyn.alloc(16 << 20);
+            write_volatile(item, 1);
+            dyn.free(item);
+        });
+    }
+
+    /// Allocate 10,000 small objects from two classes, interleaved.
+    unsafe fn alloc_interleaved(dyn: &mut DynamicAllocator, items: &mut [*mut u8]) {
+        for (i, p) in items.iter_mut().enumerate() {
+            *p = dyn.alloc(if i % 2 == 0 { 16 } else { 96 });
+        }
+    }
+
+    #[bench]
+    fn bench_free_batch(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = vec![ptr::null_mut(); 10_000];
+        b.iter(|| unsafe {
+            alloc_interleaved(&mut dyn, &mut items);
+            dyn.free_batch(&items);
+        });
+    }
+
+    #[bench]
+    fn bench_free_loop(b: &mut Bencher) {
+        let mut dyn = DynamicAllocator::new();
+        let mut items = vec![ptr::null_mut(); 10_000];
+        b.iter(|| unsafe {
+            alloc_interleaved(&mut dyn, &mut items);
+            for &p in &items {
+                dyn.free(p);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_message_vectored(b: &mut Bencher) {
+        let mut out = [ptr::null_mut(); 5];
+        b.iter(|| unsafe {
+            global::alloc_vectored(&MESSAGE, &mut out).unwrap();
+            for &p in &out {
+                global::free(p);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_message_individual(b: &mut Bencher) {
+        let mut out = [ptr::null_mut(); 5];
+        b.iter(|| unsafe {
+            for (p, &size) in out.iter_mut().zip(&MESSAGE) {
+                *p = global::alloc(size);
+            }
+            for &p in &out {
+                global::free(p);
+            }
+        });
+    }
+
+    #[cfg(feature = "owner_debug")]
+    #[test]
+    fn owner_attribution() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+        let _ = env_logger::init();
+        let (ptr_tx, ptr_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let t = thread::spawn(move || {
+            global::register_thread_name("owner-test");
+            // small, medium, and large objects
+            let ptrs: Vec<usize> = [16, 100 << 10, 4 << 20]
+                .iter()
+                .map(|size| unsafe { global::alloc(*size) as usize })
+                .collect();
+            ptr_tx.send(ptrs).unwrap();
+            // Names are only resolved while the thread is running.
+            done_rx.recv().unwrap();
+        });
+        let ptrs = ptr_rx.recv().unwrap();
+        for ptr in ptrs {
+            unsafe {
+                let label = global::owner_of(ptr as *mut u8).expect("owner should be known");
+                alloc_assert_eq!(label.name, Some(String::from("owner-test")));
+                global::free(ptr as *mut u8);
+            }
+        }
+        done_tx.send(()).unwrap();
+        t.join().unwrap();
+    }
+
+    /// Run `f` in a child process, returning what it wrote to standard error before exiting or
+    /// aborting.
+    #[cfg(all(unix, feature = "owner_debug"))]
+    unsafe fn child_stderr<F: FnOnce()>(f: F) -> String {
+        extern crate libc;
+        let mut fds = [0; 2];
+        alloc_assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+        let pid = libc::fork();
+        alloc_assert!(pid >= 0);
+        if pid == 0 {
+            libc::close(fds[0]);
+            libc::dup2(fds[1], 2);
+            // a deadlock kills the child
+            libc::alarm(10);
+            f();
+            libc::_exit(0);
+        }
+        libc::close(fds[1]);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            if n <= 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n as usize]);
+        }
+        libc::close(fds[0]);
+        let mut status = 0;
+        alloc_assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[cfg(all(unix, feature = "owner_debug"))]
+    #[test]
+    fn leak_report_owner() {
+        let _ = env_logger::init();
+        let report = unsafe {
+            child_stderr(|| {
+                global::register_thread_name("leaker");
+                let item = global::alloc(4 << 20);
+                write_volatile(item, 1);
+                global::assert_no_live_large_allocs();
+            })
+        };
+        alloc_assert!(report.contains("live large region of"), "report: {}", report);
+        alloc_assert!(report.contains("allocated by thread 'leaker'"), "report: {}", report);
+    }
+
+    #[cfg(all(unix, feature = "owner_debug", feature = "debug_ownership"))]
+    #[test]
+    fn violation_report_owner() {
+        let _ = env_logger::init();
+        let report = unsafe {
+            child_stderr(|| {
+                global::register_thread_name("violator");
+                let mut a = DynamicAllocator::new();
+                let mut b = DynamicAllocator::new();
+                let item = a.alloc(16);
+                b.free(item);
+            })
+        };
+        alloc_assert!(report.contains("ownership check failed"), "report: {}", report);
+        alloc_assert!(report.contains("on thread 'violator'"), "report: {}", report);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_keep_reservation() {
+        extern crate libc;
+        let _ = env_logger::init();
+        const SIZE: usize = 4 << 20;
+        unsafe {
+            let item = global::alloc(SIZE);
+            write_bytes(item, 1, SIZE);
+            let reserved = global::reserved_bytes();
+            let token = global::free_keep_reservation(item);
+            alloc_assert_eq!(token.addr(), item);
+            alloc_assert!(global::reserved_bytes() >= reserved + SIZE);
+
+            // Accessing the reserved range must fault; check that in a child process.
+            let pid = libc::fork();
+            if pid == 0 {
+                write_volatile(item, 2);
+                libc::_exit(0);
+            }
+            alloc_assert!(pid > 0);
+            let mut status = 0;
+            alloc_assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            alloc_assert_eq!(status & 0x7f, libc::SIGSEGV);
+
+            let token = global::realloc_from_reservation(token, SIZE + 1).unwrap_err();
+            let new_item = global::realloc_from_reservation(token, SIZE / 2).unwrap();
+            alloc_assert_eq!(new_item, item);
+            // The contents are gone, but the memory is usable again.
+            alloc_assert_eq!(*new_item, 0);
+            write_bytes(new_item, 3, SIZE / 2);
+            alloc_assert!(global::get_layout(new_item).0 >= SIZE / 2);
+            global::free(new_item);
+        }
+    }
 }
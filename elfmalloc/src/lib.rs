@@ -8,6 +8,7 @@
 #![feature(alloc)]
 #![feature(allocator_api)]
 #![cfg_attr(test, feature(test))]
+#![cfg_attr(test, feature(attr_literals, repr_align))]
 #![feature(thread_local_state)]
 #![feature(thread_local)]
 #![feature(const_fn)]
@@ -32,6 +33,7 @@ extern crate lazy_static;
 extern crate log;
 #[macro_use]
 extern crate alloc_tls;
+extern crate object_alloc;
 
 mod sources;
 mod alloc_type;
@@ -39,9 +41,17 @@ mod utils;
 #[macro_use]
 mod stats;
 mod slag;
+#[cfg(feature = "owner_debug")]
+mod owner;
+#[cfg(feature = "profiling")]
+mod profile;
 pub mod frontends;
 pub mod general;
+mod arena;
 
 pub mod alloc_impl;
+pub mod object_impl;
 pub mod rust_alloc;
 pub mod vec_alloc;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod program;
@@ -0,0 +1,771 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Typed object allocators implementing the traits from the `object-alloc` crate.
+//!
+//! An `ElfObjectAlloc<T>` allocates objects of type `T` from a single size class of a
+//! `DynamicAllocator`. The class is picked when the allocator is constructed: it is the smallest
+//! class whose objects are at least as large as `T`. If `T` must be aligned to more than a word,
+//! the smallest power-of-two class that is at least as large as both `T` and its alignment is used
+//! instead, as power-of-two classes are aligned to their size. Types too large for any size class
+//! are allocated with the same path as large allocations in the `DynamicAllocator`, as are types
+//! aligned to more than any size class; these are over-allocated and aligned within the
+//! allocated region. In all cases, `UntypedObjectAlloc::layout` reports the alignment that
+//! objects actually have, which is at least that of `T`.
+//!
+//! When objects are dropped is controlled by a `DropPolicy`. By default, objects are constructed
+//! when they are allocated and dropped when they are deallocated, so no constructed state is
+//! cached. With the other policies, deallocated objects are kept constructed in a cache local to
+//! the allocator handle, and are handed out again without being re-initialized. Slots in the
+//! underlying size class never hold constructed objects: an object is always dropped before its
+//! slot is returned to the class, so fresh slots from the class are always initialized with
+//! `init`.
+//!
+//! Zero-sized types do not use a size class. Allocating one returns a dangling pointer aligned to
+//! `T` without touching any memory, and deallocating one never frees anything. Objects are still
+//! initialized with `init` and dropped according to the `DropPolicy`, so a zero-sized type with a
+//! destructor has it run exactly once per constructed object.
+//!
+//! `ElfUntypedObjectAlloc` allocates uninitialized objects of a given `Layout` in the same way.
+//! Its `alloc_zeroed` only zeroes objects that have been allocated before: objects carved from
+//! newly-mapped pages already read as zeros.
+//!
+//! ```rust,ignore
+//! let mut parent = DynamicAllocator::new();
+//! let mut a = ElfObjectAlloc::with_parent(&parent, || [0u8; 24]);
+//! let x = unsafe { ObjectAlloc::alloc(&mut a) }.unwrap();
+//! // objects share pages with the parent, so they can be freed either way
+//! unsafe { parent.free(x as *mut u8) };
+//! ```
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+
+use alloc::allocator::Layout;
+use object_alloc::{Exhausted, ObjectAlloc, ObjectAllocStats, UntypedObjectAlloc};
+use super::general::{ClassHandle, DynamicAllocator};
+
+/// When a typed allocator drops the objects deallocated with it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop objects as soon as they are deallocated. Every allocation constructs a new object.
+    ///
+    /// This is the default, and is appropriate for types whose destructors release resources that
+    /// should not be held longer than necessary (e.g. file descriptors).
+    OnDealloc,
+    /// Keep up to `EVICT_CACHE_SIZE` deallocated objects constructed for reuse. Deallocating an
+    /// object while the cache is full drops it immediately; the rest are dropped along with the
+    /// allocator.
+    OnEvict,
+    /// Keep every deallocated object constructed for reuse until the allocator is dropped.
+    OnAllocatorDrop,
+}
+
+impl Default for DropPolicy {
+    fn default() -> DropPolicy {
+        DropPolicy::OnDealloc
+    }
+}
+
+/// The number of constructed objects cached by an allocator with the `DropPolicy::OnEvict`
+/// policy.
+pub const EVICT_CACHE_SIZE: usize = 64;
+
+/// The part of a typed allocator that does not depend on how objects are initialized.
+struct TypedClass<T> {
+    class: ClassHandle,
+    layout: Layout,
+    policy: DropPolicy,
+    /// Deallocated objects that are still constructed. These are only kept if `policy` is not
+    /// `OnDealloc`.
+    constructed: Vec<*mut T>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for TypedClass<T> {
+    /// Create a handle on the same class with an empty cache of constructed objects.
+    fn clone(&self) -> Self {
+        TypedClass {
+            class: self.class.clone(),
+            layout: self.layout.clone(),
+            policy: self.policy,
+            constructed: Self::new_cache(self.policy),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> TypedClass<T> {
+    fn new(parent: &DynamicAllocator, policy: DropPolicy) -> TypedClass<T> {
+        let (size, align) = (mem::size_of::<T>(), mem::align_of::<T>());
+        match parent.class_for(size, align) {
+            Some((class, class_size, class_align)) => TypedClass {
+                class: class,
+                layout: Layout::from_size_align(class_size, class_align).unwrap(),
+                policy: policy,
+                constructed: Self::new_cache(policy),
+                _marker: PhantomData,
+            },
+            None => alloc_panic!("no size class supports objects of size {} and alignment {}", size, align),
+        }
+    }
+
+    fn new_cache(policy: DropPolicy) -> Vec<*mut T> {
+        if policy == DropPolicy::OnEvict {
+            Vec::with_capacity(EVICT_CACHE_SIZE)
+        } else {
+            Vec::new()
+        }
+    }
+
+    unsafe fn alloc(&mut self, init: &Fn() -> T) -> Result<*mut T, Exhausted> {
+        if let Some(obj) = self.constructed.pop() {
+            return Ok(obj);
+        }
+        let obj = self.class.alloc() as *mut T;
+        if obj.is_null() {
+            return Err(Exhausted);
+        }
+        alloc_debug_assert_eq!(obj as usize % mem::align_of::<T>(), 0);
+        ptr::write(obj, init());
+        Ok(obj)
+    }
+
+    unsafe fn dealloc(&mut self, obj: *mut T) {
+        let keep = match self.policy {
+            DropPolicy::OnDealloc => false,
+            DropPolicy::OnEvict => self.constructed.len() < EVICT_CACHE_SIZE,
+            DropPolicy::OnAllocatorDrop => true,
+        };
+        if keep {
+            self.constructed.push(obj);
+        } else {
+            self.destroy(obj);
+        }
+    }
+
+    unsafe fn destroy(&mut self, obj: *mut T) {
+        ptr::drop_in_place(obj);
+        self.class.free(obj as *mut u8);
+    }
+
+    fn shrink_to(&mut self, target: usize) {
+        // Dropped objects' slots are cached by the class, so it is shrunk afterwards.
+        while self.constructed.len() > target {
+            let obj = self.constructed.pop().unwrap();
+            unsafe { self.destroy(obj) };
+        }
+        let remaining = target - self.constructed.len();
+        unsafe { self.class.shrink_to(remaining) };
+    }
+
+    fn stats(&self) -> ObjectAllocStats {
+        let mut stats = self.class.stats();
+        stats.cached_objects = stats.cached_objects.map(|n| n + self.constructed.len());
+        stats
+    }
+}
+
+impl<T> Drop for TypedClass<T> {
+    fn drop(&mut self) {
+        while let Some(obj) = self.constructed.pop() {
+            unsafe { self.destroy(obj) };
+        }
+    }
+}
+
+/// An `ObjectAlloc` for objects of type `T`, backed by a size class of a `DynamicAllocator`.
+///
+/// Objects are initialized by calling `init`, and are dropped according to a `DropPolicy`. See
+/// the module documentation for how the size class is chosen.
+pub struct ElfObjectAlloc<T, I: Fn() -> T = fn() -> T> {
+    class: TypedClass<T>,
+    init: I,
+}
+
+unsafe impl<T: Send, I: Fn() -> T + Send> Send for ElfObjectAlloc<T, I> {}
+
+impl<T, I: Fn() -> T> ElfObjectAlloc<T, I> {
+    /// Create a new allocator with its own pages.
+    pub fn new(init: I) -> ElfObjectAlloc<T, I> {
+        Self::with_parent(&DynamicAllocator::new(), init)
+    }
+
+    /// Create a new allocator sharing pages with `parent`.
+    ///
+    /// Objects allocated from the new allocator may be freed using `parent`, but doing so will
+    /// not drop them.
+    pub fn with_parent(parent: &DynamicAllocator, init: I) -> ElfObjectAlloc<T, I> {
+        Self::with_drop_policy(parent, init, DropPolicy::default())
+    }
+
+    /// Create a new allocator sharing pages with `parent` that drops objects according to
+    /// `policy`.
+    pub fn with_drop_policy(
+        parent: &DynamicAllocator,
+        init: I,
+        policy: DropPolicy,
+    ) -> ElfObjectAlloc<T, I> {
+        ElfObjectAlloc {
+            class: TypedClass::new(parent, policy),
+            init: init,
+        }
+    }
+
+    /// Release cached objects until at most `target` remain cached by this allocator.
+    ///
+    /// Constructed objects are dropped first, and the slots of the rest are returned to the size
+    /// class, where other handles may reuse them. Afterwards, `stats().cached_objects` is at most
+    /// `target`.
+    pub fn shrink_to(&mut self, target: usize) {
+        self.class.shrink_to(target)
+    }
+}
+
+impl<T: Default> ElfObjectAlloc<T> {
+    /// Create a new allocator with its own pages that initializes objects with `T::default`.
+    pub fn new_default() -> ElfObjectAlloc<T> {
+        ElfObjectAlloc::new(T::default as fn() -> T)
+    }
+}
+
+impl<T, I: Fn() -> T + Clone> Clone for ElfObjectAlloc<T, I> {
+    /// Create a new allocator with its own thread-local cache of the same size class. The new
+    /// allocator caches no constructed objects.
+    fn clone(&self) -> Self {
+        ElfObjectAlloc {
+            class: self.class.clone(),
+            init: self.init.clone(),
+        }
+    }
+}
+
+/// A `Clone`-able variant of `ElfObjectAlloc` with a shared initializer.
+///
+/// Each clone has its own thread-local cache of the same size class, along with its own cache of
+/// constructed objects. Objects may be freed using any clone, including on a different thread
+/// than the one that allocated them. To share a single allocator between threads instead, wrap it
+/// in an `object_alloc::per_thread::PerThreadAlloc` (available with `object-alloc`'s `std`
+/// feature), which implements `SharedObjectAlloc` by keeping a clone for each thread.
+pub struct SharedElfObjectAlloc<T> {
+    class: TypedClass<T>,
+    init: Arc<Fn() -> T + Send + Sync>,
+}
+
+unsafe impl<T: Send> Send for SharedElfObjectAlloc<T> {}
+
+impl<T> SharedElfObjectAlloc<T> {
+    /// Create a new allocator with its own pages.
+    pub fn new<I: Fn() -> T + Send + Sync + 'static>(init: I) -> SharedElfObjectAlloc<T> {
+        Self::with_parent(&DynamicAllocator::new(), init)
+    }
+
+    /// Create a new allocator sharing pages with `parent`.
+    pub fn with_parent<I: Fn() -> T + Send + Sync + 'static>(
+        parent: &DynamicAllocator,
+        init: I,
+    ) -> SharedElfObjectAlloc<T> {
+        Self::with_drop_policy(parent, init, DropPolicy::default())
+    }
+
+    /// Create a new allocator sharing pages with `parent` that drops objects according to
+    /// `policy`.
+    pub fn with_drop_policy<I: Fn() -> T + Send + Sync + 'static>(
+        parent: &DynamicAllocator,
+        init: I,
+        policy: DropPolicy,
+    ) -> SharedElfObjectAlloc<T> {
+        SharedElfObjectAlloc {
+            class: TypedClass::new(parent, policy),
+            init: Arc::new(init),
+        }
+    }
+
+    /// Release cached objects until at most `target` remain cached by this handle; see
+    /// `ElfObjectAlloc::shrink_to`.
+    pub fn shrink_to(&mut self, target: usize) {
+        self.class.shrink_to(target)
+    }
+}
+
+impl<T> Clone for SharedElfObjectAlloc<T> {
+    fn clone(&self) -> Self {
+        SharedElfObjectAlloc {
+            class: self.class.clone(),
+            init: self.init.clone(),
+        }
+    }
+}
+
+/// An `UntypedObjectAlloc` for uninitialized objects of a given `Layout`, backed by a size class
+/// of a `DynamicAllocator`.
+///
+/// The size class is chosen as for an `ElfObjectAlloc` of a type with the same size and
+/// alignment. `layout` reports the size and alignment of the class's objects, which may be larger
+/// than those requested.
+pub struct ElfUntypedObjectAlloc {
+    class: ClassHandle,
+    layout: Layout,
+}
+
+unsafe impl Send for ElfUntypedObjectAlloc {}
+
+impl ElfUntypedObjectAlloc {
+    /// Create a new allocator with its own pages.
+    pub fn new(layout: Layout) -> ElfUntypedObjectAlloc {
+        Self::with_parent(&DynamicAllocator::new(), layout)
+    }
+
+    /// Create a new allocator sharing pages with `parent`.
+    pub fn with_parent(parent: &DynamicAllocator, layout: Layout) -> ElfUntypedObjectAlloc {
+        // A Layout's alignment is always a power of two, so there is always a class.
+        let (class, size, align) = parent.class_for(layout.size(), layout.align()).unwrap();
+        ElfUntypedObjectAlloc {
+            class: class,
+            layout: Layout::from_size_align(size, align).unwrap(),
+        }
+    }
+
+    /// Release cached objects until at most `target` remain cached by this allocator.
+    pub fn shrink_to(&mut self, target: usize) {
+        unsafe { self.class.shrink_to(target) }
+    }
+}
+
+impl Clone for ElfUntypedObjectAlloc {
+    /// Create a new allocator with its own thread-local cache of the same size class.
+    fn clone(&self) -> Self {
+        ElfUntypedObjectAlloc {
+            class: self.class.clone(),
+            layout: self.layout.clone(),
+        }
+    }
+}
+
+unsafe impl UntypedObjectAlloc for ElfUntypedObjectAlloc {
+    fn layout(&self) -> Layout {
+        self.layout.clone()
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        let obj = self.class.alloc();
+        if obj.is_null() {
+            Err(Exhausted)
+        } else {
+            Ok(obj)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&mut self) -> Result<*mut u8, Exhausted> {
+        let obj = self.class.alloc_zeroed(self.layout.size());
+        if obj.is_null() {
+            Err(Exhausted)
+        } else {
+            Ok(obj)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, x: *mut u8) {
+        self.class.free(x)
+    }
+
+    fn stats(&self) -> ObjectAllocStats {
+        self.class.stats()
+    }
+}
+
+// `$($deref)*` is applied to the `init` field to get at the initializer.
+macro_rules! impl_object_alloc {
+    ([$($params:tt)*] $ty:ty, [$($deref:tt)*]) => {
+        unsafe impl<$($params)*> ObjectAlloc<T> for $ty {
+            unsafe fn alloc(&mut self) -> Result<*mut T, Exhausted> {
+                self.class.alloc(&$($deref)* self.init)
+            }
+
+            unsafe fn dealloc(&mut self, x: *mut T) {
+                self.class.dealloc(x)
+            }
+
+            fn stats(&self) -> ObjectAllocStats {
+                self.class.stats()
+            }
+        }
+
+        unsafe impl<$($params)*> UntypedObjectAlloc for $ty {
+            fn layout(&self) -> Layout {
+                self.class.layout.clone()
+            }
+
+            unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+                ObjectAlloc::alloc(self).map(|x| x as *mut u8)
+            }
+
+            unsafe fn dealloc(&mut self, x: *mut u8) {
+                ObjectAlloc::dealloc(self, x as *mut T)
+            }
+
+            fn stats(&self) -> ObjectAllocStats {
+                ObjectAlloc::stats(self)
+            }
+        }
+    };
+}
+
+impl_object_alloc!([T, I: Fn() -> T] ElfObjectAlloc<T, I>, []);
+impl_object_alloc!([T] SharedElfObjectAlloc<T>, [*]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_alloc::test_suite::{self, Counters, Tracked};
+    use std::slice;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[repr(align(64))]
+    #[derive(Default)]
+    struct CacheLine([u8; 24]);
+
+    #[test]
+    fn contract() {
+        test_suite::check_alignment::<[u8; 24], _>(ElfObjectAlloc::new(|| [0u8; 24]));
+        test_suite::check_alignment::<CacheLine, _>(ElfObjectAlloc::new_default());
+        test_suite::check_drop_on_allocator_drop(|counters| {
+            ElfObjectAlloc::new(move || Tracked::new(counters))
+        });
+        test_suite::check_recycle_state(|counters| {
+            ElfObjectAlloc::new(move || Tracked::new(counters))
+        });
+        test_suite::check_concurrent(
+            |counters| SharedElfObjectAlloc::new(move || Tracked::new(counters)),
+            4,
+        );
+    }
+
+    const POLICIES: [DropPolicy; 3] = [
+        DropPolicy::OnDealloc,
+        DropPolicy::OnEvict,
+        DropPolicy::OnAllocatorDrop,
+    ];
+
+    #[test]
+    fn drop_policy_contract() {
+        for &policy in &POLICIES {
+            test_suite::check_drop_on_allocator_drop(|counters| {
+                ElfObjectAlloc::with_drop_policy(
+                    &DynamicAllocator::new(),
+                    move || Tracked::new(counters),
+                    policy,
+                )
+            });
+            test_suite::check_recycle_state(|counters| {
+                ElfObjectAlloc::with_drop_policy(
+                    &DynamicAllocator::new(),
+                    move || Tracked::new(counters),
+                    policy,
+                )
+            });
+            test_suite::check_concurrent(
+                |counters| {
+                    SharedElfObjectAlloc::with_drop_policy(
+                        &DynamicAllocator::new(),
+                        move || Tracked::new(counters),
+                        policy,
+                    )
+                },
+                4,
+            );
+        }
+    }
+
+    #[test]
+    fn drop_policy_timing() {
+        const N_OBJECTS: usize = 4 * EVICT_CACHE_SIZE;
+        for &policy in &POLICIES {
+            let counters = Counters::new();
+            let mut a = ElfObjectAlloc::with_drop_policy(
+                &DynamicAllocator::new(),
+                move || Tracked::new(counters),
+                policy,
+            );
+            let objs = (0..N_OBJECTS)
+                .map(|_| unsafe { ObjectAlloc::alloc(&mut a) }.unwrap())
+                .collect::<Vec<_>>();
+            for &obj in &objs {
+                unsafe { ObjectAlloc::dealloc(&mut a, obj) };
+            }
+            let cached = match policy {
+                DropPolicy::OnDealloc => 0,
+                DropPolicy::OnEvict => EVICT_CACHE_SIZE,
+                DropPolicy::OnAllocatorDrop => N_OBJECTS,
+            };
+            alloc_assert_eq!(counters.dropped(), N_OBJECTS - cached, "{:?}", policy);
+            alloc_assert!(ObjectAlloc::stats(&a).cached_objects.unwrap() >= cached);
+            // cached objects are handed out again without being re-constructed
+            let objs = (0..N_OBJECTS)
+                .map(|_| unsafe { ObjectAlloc::alloc(&mut a) }.unwrap())
+                .collect::<Vec<_>>();
+            alloc_assert_eq!(counters.constructed(), 2 * N_OBJECTS - cached, "{:?}", policy);
+            for obj in objs {
+                unsafe { ObjectAlloc::dealloc(&mut a, obj) };
+            }
+            mem::drop(a);
+            alloc_assert_eq!(counters.constructed(), counters.dropped(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn zero_sized() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Counted {
+            fn new() -> Counted {
+                CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+                Counted
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        #[repr(align(128))]
+        #[derive(Default)]
+        struct AlignedUnit;
+
+        let mut a = ElfObjectAlloc::new(|| ());
+        alloc_assert_eq!(UntypedObjectAlloc::layout(&a), Layout::new::<()>());
+        unsafe {
+            let objs = (0..1024)
+                .map(|_| ObjectAlloc::alloc(&mut a).unwrap())
+                .collect::<Vec<_>>();
+            alloc_assert!(objs.iter().all(|&p| !p.is_null()));
+            for p in objs {
+                ObjectAlloc::dealloc(&mut a, p);
+            }
+        }
+        let mut a = ElfObjectAlloc::<AlignedUnit>::new_default();
+        alloc_assert_eq!(mem::size_of::<AlignedUnit>(), 0);
+        alloc_assert_eq!(UntypedObjectAlloc::layout(&a), Layout::from_size_align(0, 128).unwrap());
+        test_suite::check_alignment::<AlignedUnit, _>(ElfObjectAlloc::new_default());
+        unsafe {
+            let p = ObjectAlloc::alloc(&mut a).unwrap();
+            alloc_assert_eq!(p as usize % 128, 0);
+            ObjectAlloc::dealloc(&mut a, p);
+        }
+
+        for &policy in &POLICIES {
+            let mut a = ElfObjectAlloc::with_drop_policy(&DynamicAllocator::new(), Counted::new, policy);
+            unsafe {
+                let objs = (0..4 * EVICT_CACHE_SIZE)
+                    .map(|_| ObjectAlloc::alloc(&mut a).unwrap())
+                    .collect::<Vec<_>>();
+                for p in objs {
+                    ObjectAlloc::dealloc(&mut a, p);
+                }
+                let p = ObjectAlloc::alloc(&mut a).unwrap();
+                ObjectAlloc::dealloc(&mut a, p);
+            }
+            mem::drop(a);
+            alloc_assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), DROPPED.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn class_layout() {
+        // no class has exactly 24-byte objects
+        let a = ElfObjectAlloc::new(|| [0u8; 24]);
+        let layout = UntypedObjectAlloc::layout(&a);
+        alloc_assert!(layout.size() >= 24);
+        alloc_assert!(layout.align() >= mem::align_of::<[u8; 24]>());
+        let a = ElfObjectAlloc::<CacheLine>::new_default();
+        alloc_assert_eq!(UntypedObjectAlloc::layout(&a).size(), 64);
+        alloc_assert_eq!(UntypedObjectAlloc::layout(&a).align(), 64);
+        // too large for any size class
+        let a = ElfObjectAlloc::new(|| [0u8; 4 << 20]);
+        alloc_assert!(UntypedObjectAlloc::layout(&a).size() >= 4 << 20);
+    }
+
+    #[test]
+    fn over_aligned() {
+        #[repr(align(4096))]
+        struct Page([u8; 4096]);
+        #[repr(align(65536))]
+        struct Align64K(u8);
+        #[repr(align(2097152))]
+        struct Align2M(u8);
+
+        let a = ElfObjectAlloc::new(|| Page([1; 4096]));
+        alloc_assert_eq!(UntypedObjectAlloc::layout(&a).align(), 4096);
+        test_suite::check_alignment::<Page, _>(a);
+        let a = ElfObjectAlloc::new(|| Align64K(1));
+        alloc_assert!(UntypedObjectAlloc::layout(&a).align() >= 64 << 10);
+        test_suite::check_alignment::<Align64K, _>(a);
+
+        // Objects are constructed on the stack, which must be large enough to hold one.
+        thread::Builder::new()
+            .stack_size(32 << 20)
+            .spawn(|| {
+                let mut parent = DynamicAllocator::new();
+                let mut a = ElfObjectAlloc::with_parent(&parent, || Align2M(7));
+                alloc_assert_eq!(
+                    UntypedObjectAlloc::layout(&a),
+                    Layout::from_size_align(2 << 20, 2 << 20).unwrap()
+                );
+                match a.class.class {
+                    ClassHandle::Large(..) => {}
+                    _ => alloc_panic!("2MiB-aligned objects should use the large allocation path"),
+                }
+                unsafe {
+                    let objs = (0..8)
+                        .map(|_| ObjectAlloc::alloc(&mut a).unwrap())
+                        .collect::<Vec<_>>();
+                    for &obj in &objs {
+                        alloc_assert_eq!(obj as usize % (2 << 20), 0);
+                        alloc_assert_eq!((*obj).0, 7);
+                        // the whole object is usable
+                        ptr::write_bytes(obj as *mut u8, 0, 2 << 20);
+                    }
+                    // freeing recovers the start of the allocated region
+                    parent.free(objs[0] as *mut u8);
+                    for &obj in &objs[1..] {
+                        ObjectAlloc::dealloc(&mut a, obj);
+                    }
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn shrink_to() {
+        const N_OBJECTS: usize = 4096;
+        for &policy in &POLICIES {
+            let mut a = ElfObjectAlloc::with_drop_policy(&DynamicAllocator::new(), || 7usize, policy);
+            unsafe {
+                let objs = (0..N_OBJECTS)
+                    .map(|_| ObjectAlloc::alloc(&mut a).unwrap())
+                    .collect::<Vec<_>>();
+                for p in objs {
+                    ObjectAlloc::dealloc(&mut a, p);
+                }
+                a.shrink_to(256);
+                alloc_assert!(ObjectAlloc::stats(&a).cached_objects.unwrap() <= 256);
+                a.shrink_to(0);
+                alloc_assert_eq!(ObjectAlloc::stats(&a).cached_objects, Some(0));
+                let objs = (0..N_OBJECTS)
+                    .map(|_| ObjectAlloc::alloc(&mut a).unwrap())
+                    .collect::<Vec<_>>();
+                for &p in &objs {
+                    alloc_assert_eq!(*p, 7);
+                }
+                for p in objs {
+                    ObjectAlloc::dealloc(&mut a, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn untyped_alloc_zeroed() {
+        const N_OBJECTS: usize = 4096;
+        for &size in &[24, 512, 8 << 10, 4 << 20] {
+            let mut a = ElfUntypedObjectAlloc::new(Layout::from_size_align(size, 8).unwrap());
+            let size = UntypedObjectAlloc::layout(&a).size();
+            let n = if size > 1 << 20 { 4 } else { N_OBJECTS };
+            // objects are fresh at first, and recycled afterwards
+            for _ in 0..3 {
+                unsafe {
+                    let objs = (0..n)
+                        .map(|_| UntypedObjectAlloc::alloc_zeroed(&mut a).unwrap())
+                        .collect::<Vec<_>>();
+                    for &obj in &objs {
+                        alloc_assert!(
+                            slice::from_raw_parts(obj, size).iter().all(|b| *b == 0),
+                            "object of size {} is not zeroed",
+                            size
+                        );
+                        ptr::write_bytes(obj, 0xff, size);
+                    }
+                    for obj in objs {
+                        UntypedObjectAlloc::dealloc(&mut a, obj);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shared_parent() {
+        let mut parent = DynamicAllocator::new();
+        let mut a = ElfObjectAlloc::with_parent(&parent, || 7usize);
+        unsafe {
+            let x = ObjectAlloc::alloc(&mut a).unwrap();
+            alloc_assert_eq!(*x, 7);
+            parent.free(x as *mut u8);
+            let y = parent.alloc(mem::size_of::<usize>()) as *mut usize;
+            *y = 7;
+            ObjectAlloc::dealloc(&mut a, y);
+        }
+    }
+
+    #[test]
+    fn per_thread_shared() {
+        use object_alloc::per_thread::PerThreadAlloc;
+        for &policy in &POLICIES {
+            test_suite::check_shared_concurrent(
+                |counters| {
+                    PerThreadAlloc::new(SharedElfObjectAlloc::with_drop_policy(
+                        &DynamicAllocator::new(),
+                        move || Tracked::new(counters),
+                        policy,
+                    ))
+                },
+                4,
+            );
+        }
+    }
+
+    #[test]
+    fn cross_thread_free() {
+        const N_THREADS: usize = 4;
+        const N_ITEMS: usize = 1 << 12;
+        let a = SharedElfObjectAlloc::new(|| [1usize; 3]);
+        let (sender, receiver) = channel::<usize>();
+        let producers = (0..N_THREADS)
+            .map(|_| {
+                let mut a = a.clone();
+                let sender = sender.clone();
+                thread::spawn(move || for _ in 0..N_ITEMS {
+                    let obj = unsafe { ObjectAlloc::alloc(&mut a) }.unwrap();
+                    sender.send(obj as usize).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        mem::drop(sender);
+        let mut consumer = a.clone();
+        let consumer = thread::spawn(move || {
+            let mut n = 0;
+            for obj in receiver {
+                unsafe {
+                    alloc_assert_eq!(*(obj as *mut [usize; 3]), [1; 3]);
+                    ObjectAlloc::dealloc(&mut consumer, obj as *mut [usize; 3]);
+                }
+                n += 1;
+            }
+            n
+        });
+        for p in producers {
+            p.join().unwrap();
+        }
+        alloc_assert_eq!(consumer.join().unwrap(), N_THREADS * N_ITEMS);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Compact thread identifiers for attributing objects to the thread that allocated them.
+//!
+//! When the `owner_debug` feature is enabled, every allocation records a 16-bit id for the
+//! allocating thread. Ids are handed out from a global pool when a thread first allocates and are
+//! returned to the pool when the thread exits, so a long-running program with many short-lived
+//! threads will not exhaust them. Id 0 is reserved to mean "unknown": it is recorded if a thread
+//! allocates while its thread-local state is unavailable, or if all other ids are in use.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::sync::MutexGuard;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The id recorded when the allocating thread is not known.
+pub const UNKNOWN_OWNER: u16 = 0;
+
+lazy_static! {
+    static ref FREE_IDS: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+    // Names are kept separately from the pool of ids: registering a name allocates, and that
+    // allocation may itself need to acquire an id.
+    static ref NAMES: Mutex<HashMap<u16, String>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// A thread's id, which is returned to the pool when the thread exits.
+struct OwnerId(u16);
+
+impl OwnerId {
+    fn new() -> OwnerId {
+        if let Some(id) = FREE_IDS.lock().unwrap().pop() {
+            return OwnerId(id);
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        if id > u16::max_value() as usize {
+            OwnerId(UNKNOWN_OWNER)
+        } else {
+            OwnerId(id as u16)
+        }
+    }
+}
+
+impl Drop for OwnerId {
+    fn drop(&mut self) {
+        if self.0 == UNKNOWN_OWNER {
+            return;
+        }
+        // The name must be gone before the id can be handed out again.
+        NAMES.lock().unwrap().remove(&self.0);
+        FREE_IDS.lock().unwrap().push(self.0);
+    }
+}
+
+alloc_thread_local!{ static OWNER_ID: OwnerId = OwnerId::new(); }
+
+/// Get the id of the current thread.
+pub fn current() -> u16 {
+    unsafe { alloc_tls_fast_with!(OWNER_ID, id, { id.0 }) }.unwrap_or(UNKNOWN_OWNER)
+}
+
+/// Associate `name` with the current thread, replacing any previously registered name.
+pub fn register_thread_name(name: &str) {
+    let id = current();
+    if id != UNKNOWN_OWNER {
+        NAMES.lock().unwrap().insert(id, name.to_string());
+    }
+}
+
+/// The locks on `FREE_IDS` and `NAMES` held across a `fork`.
+#[cfg(unix)]
+static mut FORK_GUARDS: Option<
+    (MutexGuard<'static, Vec<u16>>, MutexGuard<'static, HashMap<u16, String>>),
+> = None;
+
+/// Acquire the locks of the id pool before a `fork`.
+#[cfg(unix)]
+pub unsafe fn prepare_fork() {
+    FORK_GUARDS = Some((FREE_IDS.lock().unwrap(), NAMES.lock().unwrap()));
+}
+
+/// Release the locks acquired by `prepare_fork`, in either the parent or the child. The ids of
+/// the parent's other threads are not returned to the pool in the child, since those threads do
+/// not exit there.
+#[cfg(unix)]
+pub unsafe fn after_fork() {
+    FORK_GUARDS = None;
+}
+
+/// A description of the thread that allocated an object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadLabel {
+    /// The thread's compact id. Ids are reused after a thread exits.
+    pub id: u16,
+    /// The name registered with `register_thread_name`, if the thread registered one and is
+    /// still running.
+    pub name: Option<String>,
+}
+
+impl fmt::Display for ThreadLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "thread '{}' (id {})", name, self.id),
+            None => write!(f, "thread {}", self.id),
+        }
+    }
+}
+
+/// The thread with a given id, as named in the reports of the allocator: `thread 'name' (id 3)`,
+/// `thread 3`, or `an unknown thread`.
+///
+/// Unlike `label`, this does not allocate, so it can be printed from inside the allocator. Only
+/// the id is printed if the names are locked, since the reporting thread may hold the lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Owner(pub u16);
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == UNKNOWN_OWNER {
+            return write!(f, "an unknown thread");
+        }
+        match NAMES.try_lock() {
+            Ok(names) => match names.get(&self.0) {
+                Some(name) => write!(f, "thread '{}' (id {})", name, self.0),
+                None => write!(f, "thread {}", self.0),
+            },
+            Err(_) => write!(f, "thread {}", self.0),
+        }
+    }
+}
+
+/// Resolve `id` to a `ThreadLabel`, or `None` if `id` is `UNKNOWN_OWNER`.
+pub fn label(id: u16) -> Option<ThreadLabel> {
+    if id == UNKNOWN_OWNER {
+        return None;
+    }
+    Some(ThreadLabel {
+        id: id,
+        name: NAMES.lock().unwrap().get(&id).cloned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn thread_names() {
+        let (id, name) = thread::spawn(|| {
+            register_thread_name("named");
+            let id = current();
+            (id, label(id).unwrap().name)
+        }).join()
+            .unwrap();
+        alloc_assert!(id != UNKNOWN_OWNER);
+        alloc_assert_eq!(name, Some(String::from("named")));
+    }
+
+    #[test]
+    fn owner_display() {
+        alloc_assert_eq!(Owner(UNKNOWN_OWNER).to_string(), "an unknown thread");
+        let (id, shown) = thread::spawn(|| {
+            register_thread_name("shown");
+            let id = current();
+            (id, Owner(id).to_string())
+        }).join()
+            .unwrap();
+        alloc_assert_eq!(shown, format!("thread 'shown' (id {})", id));
+        // the name is gone once the thread has exited
+        alloc_assert_eq!(Owner(id).to_string(), format!("thread {}", id));
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Histograms of the sizes requested from an allocator, kept when the `profiling` feature is
+//! enabled.
+//!
+//! Each handle counts the requests made through it in arrays of its own, without
+//! synchronization, and adds its counts to those shared with its clones when it is dropped. The
+//! handles of exited threads are dropped by the global heap's background thread, so their counts
+//! are shared once it has torn them down. The arrays are mapped when a handle is created, so
+//! counting a request never allocates.
+use std::cmp;
+use std::fmt;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::utils::OwnedArray;
+
+/// The number of ranges that the sizes served by each size class are divided into.
+pub const SIZE_BUCKETS: usize = 8;
+
+/// The requests counted for one size class, as reported in `SizeHistogram::classes`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClassHistogram {
+    /// The size of the class's objects.
+    pub object_size: usize,
+    /// The smallest size the class serves: one more than the size of the class below it, or 1
+    /// for the first class (which also serves requests of 0 bytes).
+    pub min_size: usize,
+    /// The number of requests for sizes served by the class.
+    pub requests: usize,
+    /// The requests by size. The sizes from `min_size` to `object_size` are split into
+    /// `SIZE_BUCKETS` ranges of nearly equal width, and `buckets[i]` counts the requests in the
+    /// `i`th range (see `bucket_sizes`).
+    pub buckets: [usize; SIZE_BUCKETS],
+}
+
+impl ClassHistogram {
+    /// The smallest and largest sizes counted in `buckets[i]`. The range is empty (its start is
+    /// past its end) for some buckets of a class that serves fewer than `SIZE_BUCKETS` sizes.
+    pub fn bucket_sizes(&self, i: usize) -> (usize, usize) {
+        let width = self.object_size + 1 - self.min_size;
+        let start = |i: usize| (i * width + SIZE_BUCKETS - 1) / SIZE_BUCKETS;
+        (self.min_size + start(i), self.min_size + start(i + 1) - 1)
+    }
+}
+
+/// The sizes requested from an allocator and its clones, returned by
+/// `DynamicAllocator::size_histogram` and `global::size_histogram`.
+///
+/// Each request is counted under the class that serves its size, even if the object comes from
+/// another class because that one is frozen. Requests with an alignment above the word size that
+/// are served from a class are counted as requests for the size of that class.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// The requests served by each size class, in increasing order of size. Classes appended by
+    /// `DynamicAllocator::extend_classes` are only listed once they have served a request.
+    pub classes: Vec<ClassHistogram>,
+    /// The number of requests too large for any class.
+    pub large_requests: usize,
+    /// The bytes asked for by those requests.
+    pub large_bytes: usize,
+}
+
+impl SizeHistogram {
+    /// The number of requests counted, including those too large for any class.
+    pub fn requests(&self) -> usize {
+        self.classes.iter().map(|class| class.requests).sum::<usize>() + self.large_requests
+    }
+}
+
+impl fmt::Display for SizeHistogram {
+    /// Write a line for each class that has served a request, with its non-empty buckets, and a
+    /// line for the requests too large for any class.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} requests", self.requests())?;
+        for class in self.classes.iter().filter(|class| class.requests > 0) {
+            write!(f, "{:>10} bytes: {:>10}", class.object_size, class.requests)?;
+            for (i, &count) in class.buckets.iter().enumerate() {
+                if count > 0 {
+                    let (start, end) = class.bucket_sizes(i);
+                    write!(f, "  {}-{}: {}", start, end, count)?;
+                }
+            }
+            writeln!(f, "")?;
+        }
+        writeln!(f, "     large: {:>10} ({} bytes)", self.large_requests, self.large_bytes)
+    }
+}
+
+/// The classes of an allocator and the counts flushed by the handles that have been dropped.
+struct Shared {
+    /// The size of each class the allocator was created with, in increasing order.
+    sizes: OwnedArray<usize>,
+    /// The counts of each class. There is a slot for each class the allocator was created with,
+    /// followed by one for each class that `extend_classes` may append.
+    buckets: OwnedArray<[AtomicUsize; SIZE_BUCKETS]>,
+    large_requests: AtomicUsize,
+    large_bytes: AtomicUsize,
+}
+
+impl Shared {
+    fn sizes(&self) -> &[usize] {
+        unsafe { slice::from_raw_parts(self.sizes.get(0), self.sizes.len()) }
+    }
+
+    /// The slot of the class of `object_size` bytes, or `None` if there is none.
+    fn index_of(&self, object_size: usize) -> Option<usize> {
+        let sizes = self.sizes();
+        let max = sizes[sizes.len() - 1];
+        if object_size <= max {
+            return sizes.binary_search(&object_size).ok();
+        }
+        if !object_size.is_power_of_two() || self.buckets.len() == sizes.len() {
+            return None;
+        }
+        // Only powers of two are appended, each twice the size of the one before it.
+        let i = sizes.len() - 1 + (object_size.trailing_zeros() - max.trailing_zeros()) as usize;
+        if i < self.buckets.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    /// The size of the class in slot `i`.
+    fn object_size(&self, i: usize) -> usize {
+        let sizes = self.sizes();
+        if i < sizes.len() {
+            sizes[i]
+        } else {
+            sizes[sizes.len() - 1] << (i + 1 - sizes.len())
+        }
+    }
+
+    fn min_size(&self, i: usize) -> usize {
+        if i == 0 {
+            1
+        } else {
+            self.object_size(i - 1) + 1
+        }
+    }
+}
+
+/// The counts of one handle, which are added to those shared with its clones when it is dropped.
+pub(crate) struct Profile {
+    buckets: OwnedArray<[usize; SIZE_BUCKETS]>,
+    large_requests: usize,
+    large_bytes: usize,
+    shared: Arc<Shared>,
+}
+
+impl Profile {
+    /// Create the counts of a new allocator whose classes are `sizes`, with room for
+    /// `n_appended` classes appended by `extend_classes`.
+    pub fn new(sizes: &[usize], n_appended: usize) -> Profile {
+        let n_classes = sizes.len();
+        let array = OwnedArray::new(n_classes);
+        for (i, &size) in sizes.iter().enumerate() {
+            unsafe { *array.get(i) = size };
+        }
+        Self::with_shared(Arc::new(Shared {
+            sizes: array,
+            // mapped memory is zeroed
+            buckets: OwnedArray::new(n_classes + n_appended),
+            large_requests: AtomicUsize::new(0),
+            large_bytes: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Create the counts of a clone of the handle counting into `self`.
+    pub fn like(&self) -> Profile {
+        Self::with_shared(self.shared.clone())
+    }
+
+    fn with_shared(shared: Arc<Shared>) -> Profile {
+        Profile {
+            buckets: OwnedArray::new(shared.buckets.len()),
+            large_requests: 0,
+            large_bytes: 0,
+            shared: shared,
+        }
+    }
+
+    /// Count `n` requests for `bytes` bytes, which are served by the class of `object_size`
+    /// bytes, or are too large for any class if `object_size` is `None`.
+    #[inline]
+    pub fn record(&mut self, bytes: usize, object_size: Option<usize>, n: usize) {
+        let object_size = match object_size {
+            Some(object_size) => object_size,
+            None => {
+                self.large_requests += n;
+                self.large_bytes = self.large_bytes.saturating_add(bytes.saturating_mul(n));
+                return;
+            }
+        };
+        let i = match self.shared.index_of(object_size) {
+            Some(i) => i,
+            None => return,
+        };
+        let min_size = self.shared.min_size(i);
+        let width = object_size + 1 - min_size;
+        let bucket = bytes.saturating_sub(min_size) * SIZE_BUCKETS / width;
+        unsafe { (*self.buckets.get(i))[cmp::min(bucket, SIZE_BUCKETS - 1)] += n };
+    }
+
+    /// The counts flushed by dropped handles, with those of `self` added.
+    pub fn histogram(&self) -> SizeHistogram {
+        let shared = &*self.shared;
+        let mut classes = Vec::new();
+        for i in 0..shared.buckets.len() {
+            let (local, flushed) = unsafe { (&*self.buckets.get(i), &*shared.buckets.get(i)) };
+            let mut buckets = [0; SIZE_BUCKETS];
+            for j in 0..SIZE_BUCKETS {
+                buckets[j] = local[j] + flushed[j].load(Ordering::Relaxed);
+            }
+            let requests: usize = buckets.iter().sum();
+            if i < shared.sizes.len() || requests > 0 {
+                classes.push(ClassHistogram {
+                    object_size: shared.object_size(i),
+                    min_size: shared.min_size(i),
+                    requests: requests,
+                    buckets: buckets,
+                });
+            }
+        }
+        SizeHistogram {
+            classes: classes,
+            large_requests: self.large_requests +
+                shared.large_requests.load(Ordering::Relaxed),
+            large_bytes: self.large_bytes + shared.large_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Profile {
+    fn drop(&mut self) {
+        let shared = &*self.shared;
+        for i in 0..shared.buckets.len() {
+            let (local, flushed) = unsafe { (&*self.buckets.get(i), &*shared.buckets.get(i)) };
+            for j in 0..SIZE_BUCKETS {
+                if local[j] > 0 {
+                    flushed[j].fetch_add(local[j], Ordering::Relaxed);
+                }
+            }
+        }
+        shared.large_requests.fetch_add(self.large_requests, Ordering::Relaxed);
+        shared.large_bytes.fetch_add(self.large_bytes, Ordering::Relaxed);
+    }
+}
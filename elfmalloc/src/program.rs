@@ -0,0 +1,440 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A small interpreter for allocation programs.
+//!
+//! A program is a compact sequence of operations over the public `DynamicAllocator` API. Programs
+//! are decoded from arbitrary bytes (e.g. fuzzer input) in such a way that every decoded program
+//! is valid: operations refer to slots rather than pointers, so a program can never free a
+//! pointer twice, free a pointer it did not allocate, or access memory outside of an object.
+//! Any failure while running a program is therefore a genuine allocator bug.
+//!
+//! After every operation the interpreter checks that the contents of all live objects are intact,
+//! and that each object's layout (as reported by the allocator) is consistent with the size and
+//! alignment it was requested with.
+//!
+//! This module is only compiled with the `fuzzing` feature; its main consumers are the targets in
+//! the `fuzz` directory.
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::thread;
+use std::panic;
+
+use super::general::DynamicAllocator;
+
+/// The maximum number of live objects in a program.
+const MAX_SLOTS: usize = 64;
+/// The maximum number of allocator handles created by `CloneHandle` operations.
+const MAX_HANDLES: usize = 8;
+/// The maximum number of threads a single run will hop through.
+const MAX_HOPS: usize = 8;
+/// The number of bytes at the start and end of every object that are stamped and verified.
+const CHECK_WINDOW: usize = 512;
+
+/// A single operation in an allocation program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Allocate `size` bytes into the first free slot.
+    Alloc { size: usize },
+    /// Free the object in `slot`, if there is one.
+    Free { slot: usize },
+    /// Reallocate the object in `slot` (or allocate a new one if the slot is empty).
+    Realloc { slot: usize, size: usize, align: usize },
+    /// Query the layout of the object in `slot` and grow the object to its usable size.
+    UsableSize { slot: usize },
+    /// Clone the current allocator handle and start using the clone.
+    CloneHandle,
+    /// Move the interpreter, with all of its handles and live objects, to a new thread.
+    ThreadHop,
+}
+
+/// The distribution of sizes used when decoding a program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SizeProfile {
+    /// Sizes between 1 byte and 64KiB; most of these are serviced by size classes.
+    Small,
+    /// Sizes at and around powers of two up to 8MiB. This exercises the boundaries between size
+    /// classes, between small and medium classes, and between size classes and large
+    /// allocations.
+    Boundary,
+}
+
+/// Decode `data` into a program.
+///
+/// Every byte string decodes to a valid program; trailing bytes that do not form a complete
+/// operation are ignored.
+pub fn decode(data: &[u8], profile: SizeProfile) -> Vec<Op> {
+    let mut res = Vec::new();
+    let mut bytes = data.iter().cloned();
+    macro_rules! next {
+        () => {
+            match bytes.next() {
+                Some(b) => b as usize,
+                None => return res,
+            }
+        };
+    }
+    loop {
+        let op = match next!() % 6 {
+            0 => Op::Alloc { size: decode_size(profile, next!(), next!()) },
+            1 => Op::Free { slot: next!() },
+            2 => Op::Realloc {
+                slot: next!(),
+                size: decode_size(profile, next!(), next!()),
+                align: decode_align(next!()),
+            },
+            3 => Op::UsableSize { slot: next!() },
+            4 => Op::CloneHandle,
+            _ => Op::ThreadHop,
+        };
+        res.push(op);
+    }
+}
+
+fn decode_size(profile: SizeProfile, b1: usize, b2: usize) -> usize {
+    match profile {
+        SizeProfile::Small => 1 + ((b1 << 8) | b2),
+        SizeProfile::Boundary => {
+            let base = 1 << (b1 % 24);
+            let size = match b2 % 3 {
+                0 => base - 1,
+                1 => base,
+                _ => base + 1,
+            };
+            cmp::max(size, 1)
+        }
+    }
+}
+
+fn decode_align(b: usize) -> usize {
+    // Alignments up to the (small) page size are supported for all object sizes.
+    cmp::max(1 << (b % 13), mem::size_of::<usize>())
+}
+
+/// A consistency violation found while running a program.
+#[derive(Debug)]
+pub struct Violation {
+    /// The index of the operation after which the violation was found.
+    pub op: usize,
+    pub description: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "after operation {}: {}", self.op, self.description)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    fill: u8,
+}
+
+/// An interpreter for allocation programs running against a `DynamicAllocator`.
+pub struct Interpreter {
+    handles: Vec<DynamicAllocator>,
+    cur: usize,
+    slots: Vec<Option<Slot>>,
+    executed: usize,
+    hops: usize,
+    next_fill: u8,
+    /// Simulate a realloc that fails to copy the final byte of the object, to check that the
+    /// checker catches it.
+    #[cfg(test)]
+    seed_realloc_off_by_one: bool,
+}
+
+// The raw pointers in `slots` are only ever accessed by the thread that currently owns the
+// interpreter.
+unsafe impl Send for Interpreter {}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            handles: vec![DynamicAllocator::new()],
+            cur: 0,
+            slots: (0..MAX_SLOTS).map(|_| None).collect(),
+            executed: 0,
+            hops: 0,
+            next_fill: 1,
+            #[cfg(test)]
+            seed_realloc_off_by_one: false,
+        }
+    }
+
+    /// Run `ops` to completion, freeing all remaining objects at the end.
+    ///
+    /// If a violation is found, the remaining objects are leaked.
+    pub fn run(mut self, ops: &[Op]) -> Result<(), Violation> {
+        for (i, op) in ops.iter().enumerate() {
+            if *op == Op::ThreadHop {
+                if self.hops == MAX_HOPS {
+                    continue;
+                }
+                self.hops += 1;
+                self.executed += 1;
+                let rest = ops[i + 1..].to_vec();
+                return match thread::spawn(move || self.run(&rest)).join() {
+                    Ok(res) => res,
+                    Err(e) => panic::resume_unwind(e),
+                };
+            }
+            unsafe { self.step(*op)? };
+            self.check()?;
+            self.executed += 1;
+        }
+        for i in 0..MAX_SLOTS {
+            unsafe { self.free_slot(i) };
+        }
+        Ok(())
+    }
+
+    fn violation<T>(&self, description: String) -> Result<T, Violation> {
+        Err(Violation {
+            op: self.executed,
+            description: description,
+        })
+    }
+
+    /// Map an arbitrary slot number to an index of a live object, if there is one.
+    fn live_slot(&self, slot: usize) -> Option<usize> {
+        let live = self.slots.iter().filter(|s| s.is_some()).count();
+        if live == 0 {
+            return None;
+        }
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|&(_, s)| s.is_some())
+            .map(|(i, _)| i)
+            .nth(slot % live)
+    }
+
+    fn fresh_fill(&mut self) -> u8 {
+        let res = self.next_fill;
+        // Never use 0, as it is the most likely value for memory to have by accident.
+        self.next_fill = self.next_fill.wrapping_add(1).wrapping_add((self.next_fill == 255) as u8);
+        res
+    }
+
+    unsafe fn step(&mut self, op: Op) -> Result<(), Violation> {
+        match op {
+            Op::Alloc { size } => {
+                if let Some(i) = self.slots.iter().position(|s| s.is_none()) {
+                    let ptr = self.handles[self.cur].alloc(size);
+                    self.install(i, ptr, size, mem::size_of::<usize>())?;
+                }
+            }
+            Op::Free { slot } => {
+                if let Some(i) = self.live_slot(slot) {
+                    self.free_slot(i);
+                }
+            }
+            Op::Realloc { slot, size, align } => {
+                // `aligned_realloc` rounds sizes up to a power of two to provide alignment, so
+                // it only guarantees alignments up to that power of two.
+                let align = cmp::min(align, size.next_power_of_two());
+                self.realloc(slot, size, align)?;
+            }
+            Op::UsableSize { slot } => {
+                if let Some(i) = self.live_slot(slot) {
+                    let mut s = self.slots[i].unwrap();
+                    // The object may be used up to its usable size; grow the slot to cover it.
+                    s.size = self.handles[self.cur].usable_size(s.ptr);
+                    stamp(&s);
+                    self.slots[i] = Some(s);
+                }
+            }
+            Op::CloneHandle => {
+                if self.handles.len() < MAX_HANDLES {
+                    let h = self.handles[self.cur].clone();
+                    self.handles.push(h);
+                }
+                self.cur = self.handles.len() - 1;
+            }
+            Op::ThreadHop => unreachable!(),
+        }
+        Ok(())
+    }
+
+    unsafe fn realloc(&mut self, slot: usize, size: usize, align: usize) -> Result<(), Violation> {
+        match self.live_slot(slot) {
+            Some(i) => {
+                let old = self.slots[i].take().unwrap();
+                let ptr = self.handles[self.cur].aligned_realloc(old.ptr, size, align);
+                if ptr.is_null() {
+                    return self.violation(format!("realloc to {} bytes returned null", size));
+                }
+                let preserved = cmp::min(old.size, size);
+                #[cfg(test)]
+                {
+                    if self.seed_realloc_off_by_one {
+                        *ptr.offset(preserved as isize - 1) = old.fill.wrapping_add(1);
+                    }
+                }
+                for off in window(preserved) {
+                    if *ptr.offset(off as isize) != old.fill {
+                        return self.violation(format!(
+                            "realloc from {} to {} bytes did not preserve byte {}",
+                            old.size,
+                            size,
+                            off
+                        ));
+                    }
+                }
+                self.install(i, ptr, size, align)?;
+            }
+            None => {
+                if let Some(i) = self.slots.iter().position(|s| s.is_none()) {
+                    let ptr = self.handles[self.cur].aligned_realloc(ptr::null_mut(), size, align);
+                    self.install(i, ptr, size, align)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn install(&mut self, i: usize, ptr: *mut u8, size: usize, align: usize) -> Result<(), Violation> {
+        if ptr.is_null() {
+            return self.violation(format!("allocation of {} bytes returned null", size));
+        }
+        let slot = Slot {
+            ptr: ptr,
+            size: size,
+            align: align,
+            fill: self.fresh_fill(),
+        };
+        stamp(&slot);
+        self.slots[i] = Some(slot);
+        Ok(())
+    }
+
+    unsafe fn free_slot(&mut self, i: usize) {
+        if let Some(s) = self.slots[i].take() {
+            // Free from an arbitrary handle: any handle may free any object.
+            let h = (i + self.executed) % self.handles.len();
+            self.handles[h].free(s.ptr);
+        }
+    }
+
+    /// Verify the contents and layout of every live object.
+    fn check(&self) -> Result<(), Violation> {
+        for s in self.slots.iter().filter_map(|s| s.as_ref()) {
+            if (s.ptr as usize) % s.align != 0 {
+                return self.violation(format!(
+                    "object {:?} of size {} not aligned to {}",
+                    s.ptr,
+                    s.size,
+                    s.align
+                ));
+            }
+            // any handle can look up any object
+            let (size, align) = unsafe { self.handles[0].layout_of(s.ptr) };
+            if size < s.size {
+                return self.violation(format!(
+                    "object {:?} has reported size {}, but {} bytes were requested",
+                    s.ptr,
+                    size,
+                    s.size
+                ));
+            }
+            if align < s.align {
+                return self.violation(format!(
+                    "object {:?} has reported alignment {}, but {} was requested",
+                    s.ptr,
+                    align,
+                    s.align
+                ));
+            }
+            for off in window(s.size) {
+                let b = unsafe { *s.ptr.offset(off as isize) };
+                if b != s.fill {
+                    return self.violation(format!(
+                        "object {:?} of size {} has byte {:#x} at offset {} (expected {:#x})",
+                        s.ptr,
+                        s.size,
+                        b,
+                        off,
+                        s.fill
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The offsets of an object of size `size` that are stamped and checked: the first and last
+/// `CHECK_WINDOW` bytes.
+fn window(size: usize) -> Box<Iterator<Item = usize>> {
+    if size <= 2 * CHECK_WINDOW {
+        Box::new(0..size)
+    } else {
+        Box::new((0..CHECK_WINDOW).chain(size - CHECK_WINDOW..size))
+    }
+}
+
+unsafe fn stamp(s: &Slot) {
+    for off in window(s.size) {
+        *s.ptr.offset(off as isize) = s.fill;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(profile: SizeProfile) -> Vec<Op> {
+        let bytes: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        decode(&bytes[..], profile)
+    }
+
+    #[test]
+    fn decode_is_total() {
+        alloc_assert_eq!(decode(&[], SizeProfile::Small), vec![]);
+        alloc_assert_eq!(decode(&[0, 1], SizeProfile::Small), vec![]);
+        alloc_assert_eq!(
+            decode(&[0, 0, 15, 4, 5], SizeProfile::Small),
+            vec![Op::Alloc { size: 16 }, Op::CloneHandle, Op::ThreadHop]
+        );
+    }
+
+    #[test]
+    fn run_small_program() {
+        Interpreter::new().run(&program(SizeProfile::Small)).unwrap();
+    }
+
+    #[test]
+    fn run_boundary_program() {
+        Interpreter::new().run(&program(SizeProfile::Boundary)).unwrap();
+    }
+
+    #[test]
+    fn catches_seeded_off_by_one() {
+        let ops = [
+            Op::Alloc { size: 24 },
+            Op::Realloc { slot: 0, size: 100, align: 8 },
+        ];
+        let mut interp = Interpreter::new();
+        interp.seed_realloc_off_by_one = true;
+        let v = interp.run(&ops).unwrap_err();
+        alloc_assert_eq!(v.op, 1);
+    }
+}
@@ -77,7 +77,7 @@ use super::alloc::allocator::{Alloc, AllocErr, Layout};
 use super::general::{Multiples, PowersOfTwo, ObjectAlloc, MULTIPLE, AllocMap};
 use super::slag::{PageAlloc, Metadata, RevocablePipe, compute_metadata, SlagPipe, PageCleanup};
 #[allow(unused_imports)]
-use super::frontends::{Depot, Frontend};
+use super::frontends::{magazine_capacity, Depot, Frontend, MagazineCapacity};
 use super::utils::{mmap, Lazy, LazyInitializable};
 use super::sources::MemorySource;
 use super::bagpipe::bag::WeakBag;
@@ -235,8 +235,8 @@ unsafe impl<M: MemorySource + Send> Send for OwnedElfMalloc<M> {}
 
 impl<M: MemorySource> ElfMalloc<M> {
     unsafe fn destroy(&mut self) {
-        self.small.foreach(|x| ptr::drop_in_place(x));
-        self.large.foreach(|x| ptr::drop_in_place(x));
+        self.small.foreach_mut(|x| ptr::drop_in_place(x));
+        self.large.foreach_mut(|x| ptr::drop_in_place(x));
         self.small.classes.destroy();
         self.large.classes.destroy();
     }
@@ -407,23 +407,22 @@ impl ElfMallocBuilder {
             let meta = meta_pointers;
             unsafe {
                 meta_pointers = meta_pointers.offset(1);
-                ptr::write(
-                    meta,
-                    compute_metadata(
-                        size,
-                        self.page_size,
-                        0,
-                        self.reuse_threshold,
-                        self.page_size,
-                        AllocType::SmallSlag,
-                    ),
-                );
+                let m = compute_metadata(
+                    size,
+                    self.page_size,
+                    0,
+                    self.reuse_threshold,
+                    self.page_size,
+                    AllocType::SmallSlag,
+                ).unwrap_or_else(|e| alloc_panic!("invalid size class: {}", e));
+                ptr::write(meta, m);
             }
             let params = (
                 meta,
                 usize::max_value(), /* no eager decommit */
                 pa.clone(),
-                RevocablePipe::new_size_cleanup(self.small_pipe_size, PageCleanup::new(self.page_size)),
+                RevocablePipe::new_size_cleanup(self.small_pipe_size, PageCleanup::retained()),
+                MagazineCapacity::fixed(magazine_capacity(size)),
             );
             #[cfg(not(feature = "magazine_layer"))]
             {
@@ -64,30 +64,44 @@ use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
 use super::bagpipe::bag::{Revocable, WeakBag};
 use super::bagpipe::{BagPipe, BagCleanup};
 use super::bagpipe::queue::{FAAQueueLowLevel, RevocableFAAQueue};
-use super::utils::{mmap, LazyInitializable, unlikely};
+use super::utils::{env_parse, mmap, LazyInitializable, MemoryLimit, OwnedArray, PointerLog,
+                   RangeSet, unlikely};
 use super::alloc_type::AllocType;
-use super::sources::MemorySource;
+use super::stats::{ClassCounters, Counter};
+use super::general::{ELFMALLOC_PAGE_SIZE, MULTIPLE};
+use super::sources::{HugePages, MemorySource};
 use std::marker::PhantomData;
 use std::ptr;
+use std::sync::Arc;
 use std::cmp;
+use std::error::Error;
+use std::fmt;
 
 pub type SlagPipe<T> = BagPipe<FAAQueueLowLevel<*mut T>, PageCleanup<T>>;
 pub type RevocablePipe<T> = BagPipe<RevocableFAAQueue<*mut T>, PageCleanup<T>>;
 
+/// Cleanup for pages left in a pipe when it is dropped.
 #[derive(Copy, Clone)]
-pub struct PageCleanup<T>(usize, PhantomData<T>);
+pub struct PageCleanup<T>(Option<usize>, PhantomData<T>);
 
 impl<T> PageCleanup<T> {
+    /// Unmap each page, of size `page_size`.
     pub fn new(page_size: usize) -> PageCleanup<T> {
-        PageCleanup(page_size, PhantomData)
+        PageCleanup(Some(page_size), PhantomData)
+    }
+
+    /// Leave pages mapped. This is used for pages handed out by a `PageAlloc`, which releases all
+    /// of its pages itself once it is dropped.
+    pub fn retained() -> PageCleanup<T> {
+        PageCleanup(None, PhantomData)
     }
 }
 
 impl<T> BagCleanup for PageCleanup<T> {
     type Item = *mut T;
     fn cleanup(&self, it: *mut T) {
-        unsafe {
-            mmap::unmap(it as *mut u8, self.0);
+        if let Some(page_size) = self.0 {
+            unsafe { mmap::unmap(it as *mut u8, page_size) };
         }
     }
 }
@@ -108,8 +122,17 @@ where
     /// `c.backing_memory().contains(c.alloc())`*.
     ///
     /// *That is, if that code actually compiled and didn't have a lifetime issue.
+    ///
+    /// A null pointer is returned if no more memory is available.
     unsafe fn alloc(&mut self) -> *mut u8;
 
+    /// Like `alloc`, but also report whether the page is known to read as zeros.
+    ///
+    /// The default implementation never reports a page as zeroed.
+    unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool) {
+        (self.alloc(), false)
+    }
+
     /// Free a page of memory back to the allocator.
     ///
     /// If `item` is not contained in `self.backing_memory()`, the behavior of `free` is undefined.
@@ -121,8 +144,12 @@ where
     fn backing_memory(&self) -> &Self::Block;
 }
 
+/// The allocator id of classes and large allocations that do not belong to a particular
+/// `DynamicAllocator`, and so are not checked on `free`.
+#[cfg(feature = "debug_ownership")]
+pub const UNOWNED_INSTANCE: usize = 0;
 
-pub use self::metadata::{Metadata, compute_metadata};
+pub use self::metadata::{ConfigError, Metadata, compute_metadata};
 
 mod metadata {
     use super::*;
@@ -142,6 +169,10 @@ mod metadata {
         pub bitset_offset: isize,
         /// Offset (in bytes) from the start of the slag to get to the first object.
         pub objects_offset: isize,
+        /// Offset (in bytes) from the start of the slag to get to the side array: an array of
+        /// `n_objects` `SideEntry` values holding per-object debugging information. The side
+        /// array is empty unless a debugging feature that uses it is enabled.
+        pub side_offset: isize,
         /// The mask used to initialize a `Slag` bit-set.
         pub object_mask: usize,
         /// The base-2 log of the amount of memory represented by a bit in a bit-set.
@@ -151,6 +182,19 @@ mod metadata {
         /// to each of these allocators in an array, this is the index into that array.
         local_index: usize,
 
+        /// The id of the allocator that this class belongs to, or `UNOWNED_INSTANCE` if it has
+        /// not been assigned one. Every slag of the class refers to this metadata, so the id
+        /// identifies the allocator that owns a slag's objects.
+        #[cfg(feature = "debug_ownership")]
+        pub instance: usize,
+
+        /// Whether objects of the class are zeroed when they are freed.
+        pub zero_on_free: bool,
+
+        /// The counts of objects of the class shared by all of its handles, or null if the class
+        /// is not counted.
+        pub counters: *const ClassCounters,
+
         /// A per-allocator tunable that indicates how many available objects a slag must have before
         /// it can be made available to allocating threads.
         pub cutoff_objects: usize,
@@ -165,12 +209,189 @@ mod metadata {
         pub ty: AllocType,
     }
 
+    /// An error describing why a size class, or an allocator built from several size classes,
+    /// could not be constructed.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum ConfigError {
+        /// Objects of size 0 were requested.
+        ZeroObjectSize,
+        /// The page size is zero or not a power of two.
+        InvalidPageSize { page_size: usize },
+        /// Not even a single object of size `size` (along with the `Slag` header) fits in a page
+        /// of size `page_size`.
+        ClassDoesNotFit { size: usize, page_size: usize },
+        /// The cutoff factor is not a number in the range `[0, 1]`.
+        DegenerateCutoff { cutoff_factor: f64 },
+        /// The page size is larger than the largest supported page size, `max`.
+        PageTooLarge { page_size: usize, max: usize },
+        /// The page size for small objects is larger than the page size for medium objects.
+        PageSizeOrder { small_page_size: usize, large_page_size: usize },
+        /// The size separating small and medium objects is not a power of two, or is larger than
+        /// `max`.
+        InvalidSmallCutoff { small_cutoff: usize, max: usize },
+        /// The smallest size class is not below the size separating small and medium objects.
+        StartAboveCutoff { start_from: usize, small_cutoff: usize },
+        /// A size class at or above the small cutoff is not a power of two. Such classes would
+        /// not be aligned to the cutoff.
+        NonPowerOfTwoClass { size: usize, small_cutoff: usize },
+        /// Fewer than two size classes were requested.
+        TooFewClasses { n_classes: usize },
+        /// The requested largest size class is not a power of two of at least `min`, the largest
+        /// class of the smallest valid set of classes.
+        InvalidMaxClassSize { max_class_size: usize, min: usize },
+        /// The largest size class is larger than the largest supported class, `max`.
+        ClassTooLarge { size: usize, max: usize },
+        /// The minimum alignment is not a power of two between the word size and `max`.
+        InvalidMinAlign { min_align: usize, max: usize },
+        /// The page size is smaller than the system page size.
+        PageBelowSystemPage { page_size: usize, system_page_size: usize },
+        /// The system page size does not divide the sizes that elfmalloc relies on being multiples
+        /// of it, such as the 2MiB alignment of `AllocType` stamps.
+        UnsupportedSystemPageSize { system_page_size: usize },
+        /// A size class in a list of classes is not larger than the class before it, `previous`.
+        UnsortedClasses { size: usize, previous: usize },
+        /// A size class in a list of classes is not a multiple of the minimum alignment.
+        MisalignedClass { size: usize, min_align: usize },
+        /// The ratio by which medium size classes grow is not a number in the range `(1, 2]`.
+        InvalidMediumRatio { ratio: f64 },
+        /// The multiple of the small size classes is not a power of two between `min` and `max`.
+        InvalidMultiple { multiple: usize, min: usize, max: usize },
+        /// The capacity of the caches of the size classes is zero.
+        ZeroMagazineCapacity,
+        /// The number of magazines the shards of a depot exchange at once is zero.
+        ZeroDepotBatch,
+        /// The bounds of adaptive magazine capacities do not satisfy `0 < min <= max`.
+        InvalidMagazineBounds { min: usize, max: usize },
+        /// The size classes cannot be extended, as they are not the default classes: they were
+        /// chosen with `medium_ratio` or `size_classes`.
+        FixedClasses,
+    }
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ConfigError::ZeroObjectSize => write!(f, "object size must be non-zero"),
+                ConfigError::InvalidPageSize { page_size } => {
+                    write!(f, "page size {} is not a power of two", page_size)
+                }
+                ConfigError::ClassDoesNotFit { size, page_size } => {
+                    write!(f, "objects of size {} do not fit in a page of size {}", size, page_size)
+                }
+                ConfigError::DegenerateCutoff { cutoff_factor } => {
+                    write!(f, "cutoff factor {} is not in the range [0, 1]", cutoff_factor)
+                }
+                ConfigError::PageTooLarge { page_size, max } => {
+                    write!(f, "page size {} is larger than the maximum of {}", page_size, max)
+                }
+                ConfigError::PageSizeOrder { small_page_size, large_page_size } => write!(
+                    f,
+                    "small page size {} is larger than large page size {}",
+                    small_page_size,
+                    large_page_size
+                ),
+                ConfigError::InvalidSmallCutoff { small_cutoff, max } => write!(
+                    f,
+                    "small cutoff {} is not a power of two no larger than {}",
+                    small_cutoff,
+                    max
+                ),
+                ConfigError::StartAboveCutoff { start_from, small_cutoff } => write!(
+                    f,
+                    "smallest size class {} is not below the small cutoff {}",
+                    start_from,
+                    small_cutoff
+                ),
+                ConfigError::NonPowerOfTwoClass { size, small_cutoff } => write!(
+                    f,
+                    "size class {} is not a power of two but is at least the small cutoff {}",
+                    size,
+                    small_cutoff
+                ),
+                ConfigError::TooFewClasses { n_classes } => {
+                    write!(f, "at least 2 size classes are required, got {}", n_classes)
+                }
+                ConfigError::InvalidMaxClassSize { max_class_size, min } => write!(
+                    f,
+                    "largest size class {} is not a power of two of at least {}",
+                    max_class_size,
+                    min
+                ),
+                ConfigError::ClassTooLarge { size, max } => {
+                    write!(f, "size class {} is larger than the maximum of {}", size, max)
+                }
+                ConfigError::InvalidMinAlign { min_align, max } => write!(
+                    f,
+                    "minimum alignment {} is not a power of two between the word size and {}",
+                    min_align,
+                    max
+                ),
+                ConfigError::PageBelowSystemPage { page_size, system_page_size } => write!(
+                    f,
+                    "page size {} is smaller than the system page size {}",
+                    page_size,
+                    system_page_size
+                ),
+                ConfigError::UnsupportedSystemPageSize { system_page_size } => write!(
+                    f,
+                    "system page size {} does not divide the page sizes elfmalloc requires",
+                    system_page_size
+                ),
+                ConfigError::UnsortedClasses { size, previous } => write!(
+                    f,
+                    "size class {} is not larger than the class before it, {}",
+                    size,
+                    previous
+                ),
+                ConfigError::MisalignedClass { size, min_align } => write!(
+                    f,
+                    "size class {} is not a multiple of the minimum alignment {}",
+                    size,
+                    min_align
+                ),
+                ConfigError::InvalidMediumRatio { ratio } => {
+                    write!(f, "medium size class ratio {} is not in the range (1, 2]", ratio)
+                }
+                ConfigError::InvalidMultiple { multiple, min, max } => write!(
+                    f,
+                    "small size class multiple {} is not a power of two between {} and {}",
+                    multiple,
+                    min,
+                    max
+                ),
+                ConfigError::ZeroMagazineCapacity => {
+                    write!(f, "magazine capacity must be non-zero")
+                }
+                ConfigError::ZeroDepotBatch => write!(f, "depot batch size must be non-zero"),
+                ConfigError::InvalidMagazineBounds { min, max } => write!(
+                    f,
+                    "magazine capacity bounds {}..{} are not non-zero and increasing",
+                    min,
+                    max
+                ),
+                ConfigError::FixedClasses => {
+                    write!(f, "only the default size classes can be extended")
+                }
+            }
+        }
+    }
+
+    impl Error for ConfigError {
+        fn description(&self) -> &str {
+            "invalid allocator configuration"
+        }
+    }
+
     /// Compute an optimal layout for objects of size `obj_size` for `Slag`s of size `page_size` with
     /// cutoff a `cutoff_factor` fraction of total objects, and a local index `local_index`.
     ///
     /// This function essentially performs an exhaustive search over the possible number of objects and
     /// the possible values of `bit_rep_shift` and picks the one with the lowest ratio of unused space,
     /// with a tie-braking preference for fewer words in the `Slag` bit-set.
+    ///
+    /// `usable_size` is a hint: if a single object does not fit in `usable_size` bytes but does fit
+    /// in `page_size` bytes, the resulting class has exactly one object per `Slag`. Such classes
+    /// always have a cutoff of one object. An error is returned if no object fits in a page at
+    /// all, or if the parameters are otherwise degenerate.
     pub fn compute_metadata(
         obj_size: usize,
         page_size: usize,
@@ -178,7 +399,24 @@ mod metadata {
         cutoff_factor: f64,
         usable_size: usize,
         ty: AllocType,
-    ) -> Metadata {
+    ) -> Result<Metadata, ConfigError> {
+        if obj_size == 0 {
+            return Err(ConfigError::ZeroObjectSize);
+        }
+        if !page_size.is_power_of_two() {
+            return Err(ConfigError::InvalidPageSize { page_size: page_size });
+        }
+        // NaN fails both comparisons.
+        if !(cutoff_factor >= 0.0 && cutoff_factor <= 1.0) {
+            return Err(ConfigError::DegenerateCutoff { cutoff_factor: cutoff_factor });
+        }
+        if obj_size > page_size {
+            // Rejecting these up front also keeps `next_power_of_two` below from overflowing.
+            return Err(ConfigError::ClassDoesNotFit {
+                size: obj_size,
+                page_size: page_size,
+            });
+        }
         // This is by far the ugliest function in this project. It is all plumbing, heuristics, and
         // other gross things.
         // We start with a bunch of useful helper functions:
@@ -208,17 +446,25 @@ mod metadata {
             rounded - h_bitset_size
         }
 
+        /// The alignment of objects of size `size`. Powers of two are aligned to their size, and
+        /// other sizes to the largest power of two dividing them, up to `MULTIPLE`. In particular,
+        /// every multiple of 16 bytes is aligned to 16 bytes.
+        fn object_align(size: usize) -> usize {
+            if size.is_power_of_two() {
+                size
+            } else {
+                cmp::min(size & size.wrapping_neg(), MULTIPLE)
+            }
+        }
+
         /// Compute the total bytes used for `n_objects` objects each of size `size` bytes represented
-        /// by `gran` bits in the bit-set. This function includes the heuristic that all power-of-two
-        /// sizes are aligned to their size, inserting padding accordingly.
+        /// by `gran` bits in the bit-set. This function includes the padding needed to align the
+        /// objects to `object_align(size)`.
         fn total_bytes(size: usize, gran: usize, n_objects: usize) -> usize {
             let header_size = slag_size();
-            let padding = if size.is_power_of_two() {
-                align_padding(size, n_objects, gran)
-            } else {
-                0
-            };
-            header_size + bitset_bytes(n_objects, gran) + padding + n_objects * size
+            let padding = align_padding(object_align(size), n_objects, gran);
+            header_size + bitset_bytes(n_objects, gran) + padding + n_objects * size +
+                n_objects * side_entry_size()
         }
 
         /// Perform an exhaustive search for the lowest-fragmentation layout of objects of a particular
@@ -236,9 +482,8 @@ mod metadata {
             local_index: usize,
             cutoff_factor: f64,
             usable_size: usize,
-        ) -> (f64, usize, Metadata) {
+        ) -> Option<(f64, usize, Metadata)> {
             use std::cmp;
-            let usable_size = cmp::min(usable_size, page_size);
             let mut mult = 1.0;
             // we round up to the nearest round_up_to_bytes. As a result, there may be a small amount
             // of padding per-object in the `Slag`.
@@ -251,7 +496,14 @@ mod metadata {
             // gran (read "granularity") is the number of bits used to represent a single object in the
             // slag bit-set.
             let gran = padded_size / round_up_to_bytes;
-            alloc_debug_assert!(usable_size > 0);
+            if total_bytes(padded_size, gran, 1) > page_size {
+                return None;
+            }
+            // At least one object always fits, even if that exceeds the requested usable size.
+            let usable_size = cmp::max(
+                cmp::min(usable_size, page_size),
+                total_bytes(padded_size, gran, 1),
+            );
             alloc_debug_assert!(round_up_to_bytes > 0);
             alloc_debug_assert!(round_up_to_bytes.is_power_of_two());
             alloc_debug_assert!(gran > 0);
@@ -298,22 +550,20 @@ mod metadata {
             }
             // Get the alignment padding we are using. Note that this is already computed in
             // `total_bytes`, we are just extracting it here.
-            let align_padding = if padded_size.is_power_of_two() {
-                align_padding(padded_size, n_objects, gran)
-            } else {
-                0
-            };
+            let align_padding = align_padding(object_align(padded_size), n_objects, gran);
 
             // This is takes all of the space we use in this configuration and subtracts all of
             // the "cruft" that isn't used to actually store an object.
             let bs = (total_bytes(padded_size, gran, n_objects) - n_objects * padding_per_object -
                           bitset_bytes(n_objects, gran) - slag_size() -
-                          align_padding) as f64;
+                          align_padding - n_objects * side_entry_size()) as f64;
             let score = if bs > usable_size as f64 { -1.0 } else { 1.0 } * bs /
                 (usable_size as f64);
             let header_offset = slag_size() as isize;
             let n_words = bitset_bytes(n_objects, gran) / mem::size_of::<Word>();
-            (
+            let objects_offset = header_offset +
+                (align_padding + bitset_bytes(n_objects, gran)) as isize;
+            Some((
                 score * mult,
                 n_words,
                 Metadata {
@@ -321,17 +571,21 @@ mod metadata {
                     n_bitset_words: n_words,
                     total_bytes: page_size,
                     bitset_offset: header_offset,
-                    objects_offset: header_offset +
-                        (align_padding + bitset_bytes(n_objects, gran)) as isize,
+                    objects_offset: objects_offset,
+                    side_offset: objects_offset + (n_objects * padded_size) as isize,
                     object_size: padded_size,
                     object_mask: 1,
                     bit_rep_shift: round_up_to_bytes.trailing_zeros() as usize,
                     local_index: local_index,
+                    #[cfg(feature = "debug_ownership")]
+                    instance: UNOWNED_INSTANCE,
+                    zero_on_free: false,
+                    counters: ptr::null(),
                     cutoff_objects: cmp::max(1, (n_objects as f64 * cutoff_factor) as usize),
                     usable_size: usable_size,
                     ty: AllocType::SmallSlag,
                 },
-            )
+            ))
         }
         let test_meta = Metadata {
             n_objects: 0,
@@ -339,21 +593,29 @@ mod metadata {
             total_bytes: 0,
             bitset_offset: 0,
             objects_offset: 0,
+            side_offset: 0,
             object_size: 0,
             object_mask: 0,
             bit_rep_shift: 0,
             local_index: 0,
+            #[cfg(feature = "debug_ownership")]
+            instance: UNOWNED_INSTANCE,
+            zero_on_free: false,
+            counters: ptr::null(),
             cutoff_objects: 0,
             usable_size: 0,
             ty: AllocType::SmallSlag,
         };
 
-        // now we perform an exhaustive search over these elements.
+        // now we perform an exhaustive search over these elements. A bit per byte is only
+        // considered for 1-byte objects, which cannot be represented any other way.
+        let min_shift = if obj_size == 1 { 0 } else { 1 };
+        let max_shift = obj_size.next_power_of_two().trailing_zeros() as usize;
 
         // unused in release builds
         #[allow(unused)]
-        let (frag, _, mut meta) = (1..(obj_size.next_power_of_two().trailing_zeros() as usize + 1))
-            .map(|shift| {
+        let (frag, _, mut meta) = (min_shift..(max_shift + 1))
+            .filter_map(|shift| {
                 meta_inner(
                     obj_size,
                     page_size,
@@ -371,6 +633,13 @@ mod metadata {
             } else {
                 o1
             });
+        if meta.n_objects == 0 {
+            // no value of `bit_rep_shift` admitted a single object
+            return Err(ConfigError::ClassDoesNotFit {
+                size: obj_size,
+                page_size: page_size,
+            });
+        }
         // Compute the mask used to represent the first bitset word
         let bits = Word::bits();
         let bits_per_object = meta.object_size >> meta.bit_rep_shift;
@@ -384,7 +653,7 @@ mod metadata {
         meta.ty = ty;
         trace!("created {:?} fragmentation: {:?}", meta, frag);
 
-        meta
+        Ok(meta)
     }
 
     #[cfg(test)]
@@ -395,16 +664,96 @@ mod metadata {
         fn metadata_basic() {
             let _ = env_logger::init();
 
-            compute_metadata(8, 4096, 0, 0.8, 4, AllocType::SmallSlag);
-            compute_metadata(16, 4096, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(24, 4096, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(127, 4096, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(800, 2 << 20, 0, 0.8, 32 << 10, AllocType::SmallSlag);
-            compute_metadata(514, 4096, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(513, 2 << 20, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(768, 4096, 0, 0.8, 1024, AllocType::SmallSlag);
-            compute_metadata(800, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag);
-            compute_metadata(1025, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag);
+            compute_metadata(8, 4096, 0, 0.8, 4, AllocType::SmallSlag).unwrap();
+            compute_metadata(16, 4096, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(24, 4096, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(127, 4096, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(800, 2 << 20, 0, 0.8, 32 << 10, AllocType::SmallSlag).unwrap();
+            compute_metadata(514, 4096, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(513, 2 << 20, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(768, 4096, 0, 0.8, 1024, AllocType::SmallSlag).unwrap();
+            compute_metadata(800, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag).unwrap();
+            compute_metadata(1025, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag).unwrap();
+        }
+
+        #[test]
+        fn metadata_tiny() {
+            let page_size = 256 << 10;
+            for &size in &[1, 2, 4] {
+                let m = compute_metadata(size, page_size, 0, 0.6, page_size, AllocType::SmallSlag)
+                    .unwrap();
+                alloc_assert_eq!(m.object_size, size);
+                alloc_assert_eq!(m.object_size >> m.bit_rep_shift, 1, "size {}", size);
+                alloc_assert_eq!(m.objects_offset as usize % size, 0);
+                // each object takes a bit of the bitset on top of its side entry
+                let per_object = size + side_entry_size();
+                alloc_assert!(m.n_objects * per_object * 10 >= page_size * 9, "size {}", size);
+            }
+        }
+
+        #[test]
+        fn metadata_single_object() {
+            // usable_size is too small for even one object, but the page is not
+            let m = compute_metadata(8, 4096, 0, 0.8, 4, AllocType::SmallSlag).unwrap();
+            alloc_assert_eq!(m.n_objects, 1);
+            alloc_assert!(m.usable_size >= m.objects_offset as usize + 8);
+            for &size in &[2048, 3000, 4000] {
+                let m = compute_metadata(size, 4096, 0, 0.8, 4096, AllocType::SmallSlag).unwrap();
+                alloc_assert_eq!(m.n_objects, 1);
+                alloc_assert_eq!(m.cutoff_objects, 1);
+                alloc_assert!(m.objects_offset as usize + m.object_size <= 4096);
+            }
+        }
+
+        #[test]
+        fn metadata_alignment() {
+            for &page_size in &[4096, 256 << 10, 2 << 20] {
+                for size in (1..257).map(|i| i * 8) {
+                    let ty = AllocType::SmallSlag;
+                    let m = compute_metadata(size, page_size, 0, 0.8, page_size, ty).unwrap();
+                    let align = if size % 16 == 0 { 16 } else { 8 };
+                    alloc_assert_eq!(m.objects_offset as usize % align, 0, "size {}", size);
+                    alloc_assert_eq!(m.object_size % align, 0, "size {}", size);
+                }
+            }
+        }
+
+        #[test]
+        fn metadata_errors() {
+            let ty = AllocType::SmallSlag;
+            alloc_assert_eq!(
+                compute_metadata(0, 4096, 0, 0.8, 4096, ty),
+                Err(ConfigError::ZeroObjectSize)
+            );
+            alloc_assert_eq!(
+                compute_metadata(8, 3000, 0, 0.8, 3000, ty),
+                Err(ConfigError::InvalidPageSize { page_size: 3000 })
+            );
+            alloc_assert_eq!(
+                compute_metadata(8, 0, 0, 0.8, 4096, ty),
+                Err(ConfigError::InvalidPageSize { page_size: 0 })
+            );
+            for &size in &[4096, 4097, usize::max_value()] {
+                alloc_assert_eq!(
+                    compute_metadata(size, 4096, 0, 0.8, 4096, ty),
+                    Err(ConfigError::ClassDoesNotFit {
+                        size: size,
+                        page_size: 4096,
+                    })
+                );
+            }
+            for &cutoff_factor in &[-0.1, 1.5] {
+                alloc_assert_eq!(
+                    compute_metadata(8, 4096, 0, cutoff_factor, 4096, ty),
+                    Err(ConfigError::DegenerateCutoff { cutoff_factor: cutoff_factor })
+                );
+            }
+            match compute_metadata(8, 4096, 0, ::std::f64::NAN, 4096, ty) {
+                Err(ConfigError::DegenerateCutoff { .. }) => {}
+                r => alloc_panic!("unexpected result {:?}", r),
+            }
+            compute_metadata(8, 4096, 0, 0.0, 4096, ty).unwrap();
+            compute_metadata(8, 4096, 0, 1.0, 4096, ty).unwrap();
         }
     }
 }
@@ -513,8 +862,6 @@ mod ref_count {
             (claimed, result)
         }
 
-        // keeping this around for debugging purposes
-        #[allow(dead_code)]
         pub fn load(&self) -> (bool, usize) {
             let was = self.0.load(Ordering::Acquire);
             let claimed = was & MASK == MASK;
@@ -553,6 +900,22 @@ fn slag_size() -> usize {
     cmp::max(mem::size_of::<Slag>(), 64)
 }
 
+/// Per-object debugging information, stored in a `Slag`'s side array.
+///
+/// Each debugging feature that needs per-object storage adds a field here, so that enabling
+/// several of them only requires a single side array.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SideEntry {
+    /// The compact id of the thread that allocated this object (see the `owner` module).
+    #[cfg(feature = "owner_debug")]
+    pub owner: u16,
+}
+
+#[inline]
+fn side_entry_size() -> usize {
+    mem::size_of::<SideEntry>()
+}
+
 impl Revocable for Slag {
     fn handle(&self) -> &AtomicUsize {
         &self.handle
@@ -588,6 +951,9 @@ pub struct AllocIter {
     /// A pointer to the corresponding `Slag`'s `RefCount`. This allows the iterator to decrement
     /// the `RefCount` when it consumes a new word.
     refcnt: *const RefCount,
+    /// The counts of the `Slag`'s class, which count the objects of each word as taken when the
+    /// iterator consumes it.
+    counters: *const ClassCounters,
     /// The pointer to the beginning of the corresponding `Slag`'s array of objects.
     object_base: *mut u8,
     /// The size of the objects being allocated.
@@ -596,6 +962,9 @@ pub struct AllocIter {
     remaining_words: usize,
     /// The index of the current word (starts at zero).
     cur_word_index: usize,
+    /// Whether the objects yielded by this iterator have never been allocated since their page
+    /// was mapped (or uncommitted), and therefore read as zeros.
+    pub fresh: bool,
 }
 
 impl AllocIter {
@@ -603,6 +972,7 @@ impl AllocIter {
         first_bitset_word: *mut Word,
         bitset_words: usize,
         refcnt: *const RefCount,
+        counters: *const ClassCounters,
         object_base: *mut u8,
         object_size: usize,
     ) -> AllocIter {
@@ -612,14 +982,19 @@ impl AllocIter {
                 .expect("bitset must point to valid memory")
                 .swap(0, Ordering::Acquire);
             (*refcnt).dec_n(cur_word.count_ones() as usize);
+            if let Some(counters) = counters.as_ref() {
+                counters.taken.add(cur_word.count_ones() as usize);
+            }
             AllocIter {
                 cur_word: cur_word,
                 next_word: first_bitset_word.offset(1),
                 refcnt: refcnt,
+                counters: counters,
                 object_base: object_base,
                 object_size: object_size,
                 remaining_words: (bitset_words - 1),
                 cur_word_index: 0,
+                fresh: false,
             }
         }
     }
@@ -637,6 +1012,9 @@ impl AllocIter {
         self.next_word = self.next_word.offset(1);
         self.cur_word = next.swap(0, Ordering::Acquire);
         (*self.refcnt).dec_n(self.cur_word.count_ones() as usize);
+        if let Some(counters) = self.counters.as_ref() {
+            counters.taken.add(self.cur_word.count_ones() as usize);
+        }
         self.remaining_words -= 1;
         self.cur_word_index += 1;
     }
@@ -717,6 +1095,7 @@ impl Slag {
         let slf = slag.as_mut().expect("null slag");
         slf.set_metadata(meta as *const _ as *mut Metadata);
         ptr::write(&mut slf.ty, meta.ty);
+        Self::stamp_objects(slag, meta);
         slf.rc.init(meta.n_objects);
         slf.handle.store(0, Ordering::Relaxed);
         // This is scaffolding, we perform a slush_size+bits_per_word-bit rotation to compute the
@@ -811,6 +1190,30 @@ impl Slag {
         split_index(item_ix)
     }
 
+    /// Write the type of the `Slag` at `slag` to the `ELFMALLOC_PAGE_SIZE` boundary preceding its
+    /// first object, if that boundary is past the start of the `Slag`.
+    ///
+    /// Type lookups read the stamp at the boundary preceding an object. For the single object of
+    /// a large `HugeSlag`, that boundary is in the padding after the header rather than at the
+    /// start of the page.
+    #[inline]
+    pub unsafe fn stamp_objects(slag: *mut Self, meta: &Metadata) {
+        let stamp = (slag as usize + meta.objects_offset as usize - 1) & !(ELFMALLOC_PAGE_SIZE - 1);
+        if stamp > slag as usize {
+            ptr::write(stamp as *mut AllocType, meta.ty);
+        }
+    }
+
+    /// Get a pointer to the `SideEntry` for `item`, an object in the `Slag` at `raw_self`.
+    #[inline]
+    #[cfg_attr(not(feature = "owner_debug"), allow(dead_code))]
+    pub fn side_entry(raw_self: *mut Slag, item: *mut u8, m: &Metadata) -> *mut SideEntry {
+        let item_ix = (item as usize - (m.objects_offset as usize + raw_self as usize)) /
+            m.object_size;
+        alloc_debug_assert!(item_ix < m.n_objects);
+        unsafe { ((raw_self as *mut u8).offset(m.side_offset) as *mut SideEntry).offset(item_ix as isize) }
+    }
+
     /// Free `item` back to this `Slag`.
     ///
     /// This method assumes `item` is a member of `self` (enforced in debug builds). It also
@@ -858,6 +1261,7 @@ impl Slag {
                 (self.as_raw() as *mut u8).offset(meta.bitset_offset) as *mut Word,
                 meta.n_bitset_words,
                 &self.rc,
+                meta.counters,
                 (self.as_raw() as *mut u8).offset(meta.objects_offset),
                 1 << meta.bit_rep_shift,
             )
@@ -879,6 +1283,51 @@ impl DirtyFn for () {
     fn dirty(_mem: *mut u8) {}
 }
 
+/// Whether a `PageAlloc` touches the pages it hands out before they are used, so that the cost of
+/// the page faults that commit them is not paid by the first accesses to each object.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EagerDirty {
+    /// Leave pages to be committed when they are first written to.
+    None,
+    /// Send pages to the global heap's background thread to be touched there. This only applies
+    /// to the global heap, which outlives the background thread's accesses to its pages; other
+    /// allocators touch their pages as with `Inline`. The allocating thread does not wait for the
+    /// pages to be touched, and if the background thread is busy or cannot be reached, they are
+    /// touched as with `Inline`.
+    Background,
+    /// Touch pages on the allocating thread as they are handed out.
+    Inline,
+}
+
+impl EagerDirty {
+    /// Read a policy from the `ELFMALLOC_EAGER_DIRTY` environment variable, which may be `none`,
+    /// `background` or `inline`. Returns `None` if the variable is unset or has any other value;
+    /// any other value is also reported on stderr.
+    pub fn from_env() -> Option<EagerDirty> {
+        env_parse(b"ELFMALLOC_EAGER_DIRTY\0", |val| match val {
+            val if val == b"none" => Some(EagerDirty::None),
+            val if val == b"background" => Some(EagerDirty::Background),
+            val if val == b"inline" => Some(EagerDirty::Inline),
+            _ => None,
+        })
+    }
+}
+
+/// Write to every system page of the `len` bytes at `mem` after the first, which holds the header
+/// of the slag on them and is written to when the slag is initialized.
+///
+/// Each page is written with an atomic `fetch_or` of 0, which commits it without changing its
+/// contents. This is safe to do from another thread while objects on the pages are in use, and
+/// keeps the pages reading as zeros if they are fresh.
+pub unsafe fn touch_pages(mem: *mut u8, len: usize) {
+    let base_page = mmap::page_size();
+    let mut offset = base_page;
+    while offset < len {
+        (*(mem.offset(offset as isize) as *mut AtomicUsize)).fetch_or(0, Ordering::Relaxed);
+        offset += base_page;
+    }
+}
+
 /// An allocator for large, fixed-sized objects.
 ///
 /// A `PageAlloc` is essentially a cache of pages sitting in front of a `Creek`. It keeps track of
@@ -908,9 +1357,41 @@ where
     aligned_source: C,
     pages_per: usize,
     ty: AllocType,
+    /// Every page ever returned by `refresh_pages`. Pages are never unmapped while the
+    /// `PageAlloc` is alive, so this lets us find all `Slag`s of a given size class. They are all
+    /// released when the last clone is dropped.
+    carved: Arc<CarvedPages<C>>,
+    /// Counts pages as in use from when they are first handed out until they are uncommitted.
+    limit: Arc<MemoryLimit>,
+    /// Memory used along with the pages, such as the metadata of the `Slag`s on them, which is
+    /// unmapped when the last clone is dropped.
+    retained: Option<Arc<OwnedArray<u8>>>,
+    /// Whether pages are touched as they are handed out.
+    eager_dirty: EagerDirty,
     _marker: PhantomData<D>,
 }
 
+/// The pages carved by a `PageAlloc` and its clones.
+struct CarvedPages<C: MemorySource> {
+    log: PointerLog,
+    /// The regions returned by each call to `carve`.
+    regions: RangeSet,
+    /// The source the pages were carved from.
+    source: C,
+    page_size: usize,
+    /// The number of bytes of pages carved.
+    mapped: Counter,
+}
+
+impl<C: MemorySource> Drop for CarvedPages<C> {
+    fn drop(&mut self) {
+        // Each page is either cached by the PageAlloc or holds a Slag. Either way, nothing can
+        // refer to it once the PageAlloc and all of the allocators using it are gone.
+        let (source, page_size) = (&self.source, self.page_size);
+        self.log.foreach(|page| unsafe { source.release(page, page_size) });
+    }
+}
+
 impl<C: MemorySource, D: DirtyFn> LazyInitializable for PageAlloc<C, D> {
     type Params = (usize, usize, usize, usize, AllocType);
     fn init(&(page_size, target_overhead, pipe_size, aligned_source, ty): &Self::Params) -> Self {
@@ -934,50 +1415,288 @@ impl<C: MemorySource, D: DirtyFn> PageAlloc<C, D> {
         alloc_debug_assert!(align >= page_size);
         alloc_debug_assert!(page_size.is_power_of_two());
         alloc_debug_assert!(align.is_power_of_two());
-        let pages_per = align / page_size;
-        let clean = PageCleanup::new(page_size);
         let creek = C::new(page_size);
-        let creek_2 = if pages_per > 1 {
+        let creek_2 = if align > page_size {
             C::new(align)
         } else {
             creek.clone()
         };
+        Self::from_parts(creek, creek_2, target_overhead, pipe_size, ty)
+    }
+
+    /// Create a new `PageAlloc` whose pages are carved from `source`.
+    ///
+    /// Sources for pages of size `page_size` and for groups of pages of size `align` are taken
+    /// from `source` with `MemorySource::with_page_size`, so the page size of `source` itself
+    /// does not matter.
+    pub fn from_source(
+        source: &C,
+        page_size: usize,
+        target_overhead: usize,
+        pipe_size: usize,
+        align: usize,
+        ty: AllocType,
+    ) -> Self {
+        alloc_debug_assert!(align >= page_size);
+        alloc_debug_assert!(page_size.is_power_of_two());
+        alloc_debug_assert!(align.is_power_of_two());
+        let creek = source.with_page_size(page_size);
+        let creek_2 = if align > page_size {
+            source.with_page_size(align)
+        } else {
+            creek.clone()
+        };
+        Self::from_parts(creek, creek_2, target_overhead, pipe_size, ty)
+    }
+
+    /// Create a `PageAlloc` for pages of `page_size` bytes of type `ty`, carved in groups aligned
+    /// to `align` from the source that `self` carves its pages from.
+    ///
+    /// The new `PageAlloc` counts its pages against the same limit as `self`, retains the same
+    /// memory, and touches its pages in the same way. Huge pages have to be asked for again with
+    /// `set_huge_pages`.
+    pub fn with_page_size(
+        &self,
+        page_size: usize,
+        pipe_size: usize,
+        align: usize,
+        ty: AllocType,
+    ) -> Self {
+        let mut res = Self::from_source(
+            &self.aligned_source,
+            page_size,
+            self.target_overhead,
+            pipe_size,
+            align,
+            ty,
+        );
+        res.limit = self.limit.clone();
+        res.retained = self.retained.clone();
+        res.eager_dirty = self.eager_dirty;
+        res
+    }
+
+    /// Create a new `PageAlloc` handing out pages of `creek`, which are carved in groups from
+    /// `aligned_source`.
+    fn from_parts(
+        creek: C,
+        aligned_source: C,
+        target_overhead: usize,
+        pipe_size: usize,
+        ty: AllocType,
+    ) -> Self {
+        let page_size = creek.page_size();
+        let pages_per = aligned_source.page_size() / page_size;
+        let clean = PageCleanup::retained();
+        let carved = CarvedPages {
+            log: PointerLog::new(),
+            regions: RangeSet::new(),
+            source: aligned_source.clone(),
+            page_size: page_size,
+            mapped: Counter::default(),
+        };
         PageAlloc {
             target_overhead: target_overhead,
             creek: creek,
             pages_per: pages_per,
-            aligned_source: creek_2,
+            aligned_source: aligned_source,
             clean: SlagPipe::new_size_cleanup(2, clean),
             dirty: SlagPipe::new_size_cleanup(pipe_size, clean),
             ty: ty,
+            carved: Arc::new(carved),
+            limit: Arc::new(MemoryLimit::new()),
+            retained: None,
+            eager_dirty: EagerDirty::None,
             _marker: PhantomData,
         }
     }
 
+    /// Count the pages handed out by `self` against `limit`.
+    ///
+    /// Once the limit is reached, no more pages are handed out, and `alloc` returns a null
+    /// pointer. This only affects clones of `self` made after it is called.
+    pub fn set_limit(&mut self, limit: Arc<MemoryLimit>) {
+        self.limit = limit;
+    }
+
+    /// Keep `region` mapped until `self` and all of its clones made after this call are dropped.
+    pub fn retain(&mut self, region: Arc<OwnedArray<u8>>) {
+        self.retained = Some(region);
+    }
+
+    /// Ask for the pages carved by `self` to be backed by huge pages.
+    ///
+    /// This only affects pages carved after it is called, and is a request rather than a
+    /// guarantee: sources fall back to normal pages if the kernel refuses.
+    pub fn set_huge_pages(&mut self, policy: HugePages) {
+        self.creek.set_huge_pages(policy);
+        self.aligned_source.set_huge_pages(policy);
+    }
+
+    /// Touch the pages handed out by `self` and the clones made after this call as described by
+    /// `policy`.
+    ///
+    /// `EagerDirty::Background` should only be set on the pages of the global heap; see its
+    /// documentation.
+    pub fn set_eager_dirty(&mut self, policy: EagerDirty) {
+        self.eager_dirty = policy;
+    }
+
+    /// Touch `page`, which is about to be handed out, as described by `eager_dirty`.
+    #[inline]
+    fn pre_dirty(&self, page: *mut u8) {
+        if page.is_null() {
+            return;
+        }
+        let len = self.creek.page_size();
+        match self.eager_dirty {
+            EagerDirty::None => {}
+            EagerDirty::Background => super::general::global::dirty_in_background(page, len),
+            EagerDirty::Inline => unsafe { touch_pages(page, len) },
+        }
+    }
+
     /// Get more clean pages from the backing memory.
     ///
     /// One of these pages is returned to the caller for allocation. The rest are added to the
-    /// clean `BagPipe`.
+    /// clean `BagPipe`. If the backing memory is exhausted, a null pointer is returned.
     fn refresh_pages(&mut self) -> *mut u8 {
         // If we are using a higher alignment, just allocate a single higher-aligned page. If not,
         // allocate two pages.
         let npages = cmp::max(self.pages_per, 2);
-        let creek = &self.aligned_source;
-        let pages = creek
-            .carve(if self.pages_per == 1 { 2 } else { 1 })
-            .expect("out of memory!");
         let page_size = self.creek.page_size();
+        // Only the returned page is counted as in use; the others are counted when they are
+        // taken from the clean pipe.
+        if !self.limit.try_acquire(page_size) {
+            return ptr::null_mut();
+        }
+        let creek = &self.aligned_source;
+        let pages = match creek.carve(if self.pages_per == 1 { 2 } else { 1 }) {
+            Some(pages) => pages,
+            None => {
+                self.limit.release(page_size);
+                return ptr::null_mut();
+            }
+        };
+        alloc_assert_eq!(
+            (pages as usize) % creek.page_size(),
+            0,
+            "memory source returned pages misaligned to {}",
+            creek.page_size()
+        );
         // Write the required AllocType to the aligned boundary. In some settings this is
         // unnecessary, but refresh_pages is not called in the hot path and the cost of writing
         // additional values is trivial compared with synchronization from the BagPipe. As such, it
         // makes sense to perform this write unconditionally.
         unsafe { ptr::write(pages as *mut AllocType, self.ty) };
+        self.carved.mapped.add(npages * page_size);
+        self.carved.regions.insert(pages, npages * page_size);
+        for i in 0..npages {
+            self.carved.log.push(unsafe { pages.offset(page_size as isize * (i as isize)) });
+        }
         let iter = (1..npages).map(|i| unsafe {
             pages.offset(page_size as isize * (i as isize))
         });
         self.clean.bulk_add(iter);
         pages
     }
+
+    /// Uncommit cached dirty pages until at most `retain` remain, returning the number of bytes
+    /// released.
+    ///
+    /// Uncommitted pages are moved to the clean pipe rather than unmapped, since pages are never
+    /// unmapped while the `PageAlloc` is alive. Only the concurrent operations of the page pipes
+    /// are used, so this is safe to call while clones are allocating and freeing pages.
+    pub fn trim(&mut self, retain: usize) -> usize {
+        let page_size = self.creek.page_size();
+        let group_size = page_size * self.pages_per;
+        let mut released = 0;
+        // Bound the number of iterations in case other threads keep freeing pages.
+        let mut budget = self.dirty.size_guess();
+        while budget > 0 && self.dirty.size_guess() > retain as isize {
+            budget -= 1;
+            let page = match self.dirty.try_pop_mut() {
+                Ok(page) => page,
+                Err(_) => break,
+            };
+            unsafe {
+                self.creek.uncommit(page, page_size);
+                if (page as usize) % group_size == 0 {
+                    // The first page of a group holds the AllocType of the whole group; see
+                    // refresh_pages.
+                    ptr::write(page as *mut AllocType, self.ty);
+                }
+            }
+            self.clean.push_mut(page);
+            self.limit.release(page_size);
+            released += page_size;
+        }
+        released
+    }
+
+    /// Get pages from the backing memory until at least `npages` clean pages are cached, returning
+    /// false if the backing memory or the memory limit is exhausted first.
+    ///
+    /// Clean pages are shared with all clones, and are only counted against the memory limit
+    /// once they are allocated.
+    pub fn reserve(&mut self, npages: usize) -> bool {
+        let page_size = self.creek.page_size();
+        // The pages are not counted until they are allocated, but there is no point in getting
+        // pages that could not be allocated within the limit.
+        let bytes = match npages.checked_mul(page_size) {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+        if !self.limit.try_acquire(bytes) {
+            return false;
+        }
+        self.limit.release(bytes);
+        while self.clean.size_guess() < npages as isize {
+            let page = self.refresh_pages();
+            if page.is_null() {
+                return false;
+            }
+            // refresh_pages counts the page it returns as in use
+            self.clean.push_mut(page);
+            self.limit.release(page_size);
+        }
+        true
+    }
+
+    /// The number of bytes of pages carved from the backing memory by `self` and its clones.
+    ///
+    /// Pages are never unmapped while the `PageAlloc` is alive, so this only grows, though some of
+    /// the pages may have been uncommitted.
+    pub fn mapped_bytes(&self) -> usize {
+        self.carved.mapped.get()
+    }
+
+    /// Is `it` a pointer into a page carved by `self` or one of its clones?
+    ///
+    /// Only the addresses of carved pages are compared, so `it` need not point to mapped memory.
+    pub fn contains(&self, it: *mut u8) -> bool {
+        self.carved.regions.contains(it)
+    }
+
+    /// Call `f` on every `Slag` with metadata `meta` that holds at least one allocated object,
+    /// among the pages handed out by this `PageAlloc` and its clones.
+    ///
+    /// `Slag`s that are currently claimed by a thread are always included. The result is only
+    /// accurate if no objects are concurrently allocated from or freed to `Slag`s of `meta`.
+    #[cfg(feature = "freeze")]
+    pub unsafe fn foreach_live_slag<F: FnMut(*mut Slag)>(&self, meta: *mut Metadata, mut f: F) {
+        self.carved.log.foreach(|page| {
+            // Pages that were never used, or that were uncommitted, read as zeros.
+            let slag = page as *mut Slag;
+            if (*slag).meta.load(Ordering::Acquire) != meta {
+                return;
+            }
+            let (claimed, available) = (*slag).rc.load();
+            if claimed || available < (*meta).n_objects {
+                f(slag)
+            }
+        })
+    }
 }
 
 impl<C: MemorySource, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
@@ -988,25 +1707,40 @@ impl<C: MemorySource, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
     }
 
     unsafe fn alloc(&mut self) -> *mut u8 {
+        self.alloc_fresh().0
+    }
+
+    unsafe fn alloc_fresh(&mut self) -> (*mut u8, bool) {
+        // Clean pages are either newly mapped or were uncommitted when they were freed. Only
+        // Linux guarantees that uncommitted memory reads as zeros (on Mac it may keep its old
+        // contents).
+        const CLEAN_IS_ZEROED: bool = cfg!(target_os = "linux");
         if let Ok(ptr) = self.dirty.try_pop_mut() {
             trace_event!(grabbed_dirty);
-            return ptr;
+            return (ptr, false);
         }
         if let Ok(ptr) = self.clean.try_pop_mut() {
+            if !self.limit.try_acquire(self.creek.page_size()) {
+                self.clean.push_mut(ptr);
+                return (ptr::null_mut(), false);
+            }
             trace_event!(grabbed_clean);
             D::dirty(ptr);
-            return ptr;
+            self.pre_dirty(ptr);
+            return (ptr, CLEAN_IS_ZEROED);
         }
-        self.refresh_pages()
+        let ptr = self.refresh_pages();
+        self.pre_dirty(ptr);
+        (ptr, true)
     }
 
     unsafe fn free(&mut self, ptr: *mut u8, decommit: bool) {
-        use self::mmap::uncommit;
         use std::cmp;
         let minor_page_size = mmap::page_size() as isize;
         if self.dirty.size_guess() >= self.target_overhead as isize {
-            uncommit(ptr, self.backing_memory().page_size());
+            self.creek.uncommit(ptr, self.backing_memory().page_size());
             self.clean.push_mut(ptr);
+            self.limit.release(self.backing_memory().page_size());
             return;
         }
         if decommit {
@@ -1017,7 +1751,7 @@ impl<C: MemorySource, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
             if uncommit_len == 0 {
                 self.dirty.push_mut(ptr);
             } else {
-                uncommit(ptr.offset(minor_page_size), uncommit_len);
+                self.creek.uncommit(ptr.offset(minor_page_size), uncommit_len);
                 self.dirty.push_mut(ptr);
             }
         } else {
@@ -1040,6 +1774,8 @@ pub struct SlagAllocator<CA: CoarseAllocator> {
     available: RevocablePipe<Slag>,
     /// Uncommit memory for full `Slag`s whose real memory footprint exceeds this threshold.
     eager_decommit_threshold: usize,
+    /// Whether `slag` came from a zeroed page and no objects have been allocated from it yet.
+    fresh: bool,
 }
 
 impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
@@ -1051,6 +1787,7 @@ impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
             if claimed {
                 // we used this slag at some point
                 if was == meta.n_objects {
+                    count_slag_freed(meta);
                     self.pages.free(slag as *mut u8, false);
                     trace_event!(transition_full);
                 // self.transition_full(slag, meta)
@@ -1059,6 +1796,7 @@ impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
                 }
             } else {
                 // we never allocated from this slag, so just free it back to the page allocator
+                count_slag_freed(meta);
                 self.pages.free(slag as *mut u8, false);
             }
         }
@@ -1067,24 +1805,47 @@ impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
 
 unsafe impl<C: CoarseAllocator + Send> Send for SlagAllocator<C> {}
 
+/// Count a `Slag` as taken from the page allocator by the class of `meta`.
+unsafe fn count_slag_taken(meta: &Metadata) {
+    if let Some(counters) = meta.counters.as_ref() {
+        counters.slags.add(1);
+    }
+}
+
+/// Count a `Slag` of the class of `meta` as returned to the page allocator.
+unsafe fn count_slag_freed(meta: &Metadata) {
+    if let Some(counters) = meta.counters.as_ref() {
+        counters.slags.sub(1);
+    }
+}
+
 impl<CA: CoarseAllocator> SlagAllocator<CA> {
+    /// Create an allocator for the class of `meta` that shares the available slags of `avail`,
+    /// taking its first slag from `pa`. Returns `None` if `pa` has no page for it.
     pub fn partial_new(
         meta: *mut Metadata,
         decommit: usize,
         mut pa: CA,
         avail: RevocablePipe<Slag>,
-    ) -> Self {
-        let first_slag = unsafe { pa.alloc() } as *mut Slag;
+    ) -> Option<Self> {
+        let (first_slag, fresh) = unsafe { pa.alloc_fresh() };
+        let first_slag = first_slag as *mut Slag;
+        if first_slag.is_null() {
+            return None;
+        }
         unsafe {
-            Slag::init(first_slag, meta.as_ref().expect("metadata null"));
+            let meta = meta.as_ref().expect("metadata null");
+            Slag::init(first_slag, meta);
+            count_slag_taken(meta);
         };
-        SlagAllocator {
+        Some(SlagAllocator {
             m: meta,
             slag: first_slag,
             pages: pa,
             available: avail,
             eager_decommit_threshold: decommit,
-        }
+            fresh: fresh,
+        })
     }
     pub fn new(
         max_objects: usize,
@@ -1096,32 +1857,63 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
     ) -> Self {
         // This is a bit wasteful as one metadata object consumes will wind up consuming a page. In
         // the dynamic allocator these are packed more tightly.
-        let meta = Box::into_raw(Box::new(compute_metadata(
+        let meta = match compute_metadata(
             object_size,
             pa.backing_memory().page_size(),
             index,
             cutoff_factor,
             max_objects,
             AllocType::SmallSlag,
-        )));
-        let first_slag = unsafe { pa.alloc() } as *mut Slag;
+        ) {
+            Ok(meta) => Box::into_raw(Box::new(meta)),
+            Err(e) => alloc_panic!("invalid size class: {}", e),
+        };
+        let (first_slag, fresh) = unsafe { pa.alloc_fresh() };
+        let first_slag = first_slag as *mut Slag;
+        if first_slag.is_null() {
+            alloc_panic!("out of memory!");
+        }
         unsafe {
             Slag::init(first_slag, meta.as_ref().expect("metadata null"));
         };
-        let cleanup = PageCleanup::new(pa.backing_memory().page_size());
+        let cleanup = PageCleanup::retained();
         SlagAllocator {
             m: meta,
             slag: first_slag,
             pages: pa,
             available: RevocablePipe::new_size_cleanup(8, cleanup),
             eager_decommit_threshold: eager_decommit,
+            fresh: fresh,
         }
     }
 
     /// Re-initialize a non-empty `AllocIter`; potentially getting a new `Slag` to do so.
+    ///
+    /// Panics if a new `Slag` is needed but no memory is available.
     pub unsafe fn refresh(&mut self) -> AllocIter {
-        let s_ref = &*self.slag;
+        match self.try_refresh() {
+            Some(iter) => iter,
+            None => alloc_panic!("out of memory!"),
+        }
+    }
+
+    /// Like `refresh`, but return `None` if a new `Slag` is needed and no memory is available. In
+    /// that case, the current `Slag` is kept.
+    pub unsafe fn try_refresh(&mut self) -> Option<AllocIter> {
         let meta = &*self.m;
+        // While we hold a claim on the current slag, its reference count only grows (through
+        // remote frees). If it is below the cutoff now, it may still be below the cutoff once it
+        // is unclaimed, at which point it can no longer be claimed again. We get the next slag
+        // before giving up the current one, so that we can keep the current one if that fails.
+        let next = if (*self.slag).rc.load().1 < meta.cutoff_objects {
+            match self.next_slag() {
+                Some(next) => Some(next),
+                None => return None,
+            }
+        } else {
+            None
+        };
+        let s_ref = &*self.slag;
         let (_claimed, was) = s_ref.rc.unclaim();
         // We used to have this debug_assert
         //
@@ -1156,30 +1948,53 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
                 _claimed,
                 "claiming slag either during initialization or due to being over cutoff"
             );
-            s_ref.refresh(meta)
+            if let Some((slag, _)) = next {
+                // enough objects were freed in the meantime; hand the next slag to whoever needs
+                // one first
+                self.transition_available(slag);
+            }
+            let mut iter = s_ref.refresh(meta);
+            // only the first iterator over a fresh slag yields fresh objects
+            iter.fresh = mem::replace(&mut self.fresh, false);
+            Some(iter)
         } else {
-            // we need a new slag!
-            // first we try and get a slag from the available slagpipe. If it is empty, then we get
-            // a fresh page from PageAlloc and initialize it with the current object class's
-            // metadata.
-            let next_slab = match self.available.try_pop_mut() {
-                Ok(slab) => {
-                    trace_event!(grabbed_available);
-                    slab
-                }
-                Err(_) => {
-                    let new_raw = self.pages.alloc() as *mut Slag;
-                    if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
-                        Slag::init(new_raw, meta);
-                    }
-                    new_raw
-                }
-            };
+            let (next_slab, fresh) = next.expect("claimed slag lost objects during refresh");
             self.slag = next_slab;
+            self.fresh = false;
             let s_ref = self.slag.as_mut().expect("s_ref_2"); // let s_ref = &*self.slag;
             let claimed = s_ref.rc.claim();
             alloc_debug_assert!(claimed, "claiming new slag after refresh");
-            s_ref.refresh(meta)
+            let mut iter = s_ref.refresh(meta);
+            iter.fresh = fresh;
+            Some(iter)
+        }
+    }
+
+    /// Get a new `Slag` to allocate from, along with whether its objects are fresh, or `None` if
+    /// no memory is available.
+    unsafe fn next_slag(&mut self) -> Option<(*mut Slag, bool)> {
+        // first we try and get a slag from the available slagpipe. If it is empty, then we get a
+        // fresh page from PageAlloc and initialize it with the current object class's metadata.
+        match self.available.try_pop_mut() {
+            Ok(slab) => {
+                trace_event!(grabbed_available);
+                Some((slab, false))
+            }
+            Err(_) => {
+                let (new_raw, fresh) = self.pages.alloc_fresh();
+                let new_raw = new_raw as *mut Slag;
+                if new_raw.is_null() {
+                    return None;
+                }
+                if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
+                    Slag::init(new_raw, &*self.m);
+                } else {
+                    // The page may have been uncommitted past its header when it was freed.
+                    Slag::stamp_objects(new_raw, &*self.m);
+                }
+                count_slag_taken(&*self.m);
+                Some((new_raw, fresh))
+            }
         }
     }
 
@@ -1195,6 +2010,7 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         if RevocablePipe::revoke(&slag) {
             (*slag).handle.store(0, Ordering::Release);
             trace_event!(transition_full);
+            count_slag_freed(meta);
             self.pages.free(
                 slag as *mut u8,
                 real_size >= self.eager_decommit_threshold,
@@ -1216,6 +2032,9 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
             return;
         }
         trace_event!(bulk_remote_free);
+        if let Some(counters) = meta.counters.as_ref() {
+            counters.taken.sub(n_ones);
+        }
         let s_ref = &*slag;
         let (claimed, was) = s_ref.rc.inc_n(n_ones);
         let before = (*word).fetch_or(mask, Ordering::Release);
@@ -1229,7 +2048,10 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         );
         let now = was + n_ones;
         if !claimed {
-            if now == meta.n_objects {
+            // Only slags that were already available can be revoked. A slag that crosses the
+            // cutoff and becomes full in the same step (e.g. a class with one object per slag) is
+            // made available instead, just as in `Slag::free`.
+            if now == meta.n_objects && was >= meta.cutoff_objects {
                 self.transition_full(slag, meta);
             } else if was < meta.cutoff_objects && now >= meta.cutoff_objects {
                 self.transition_available(slag);
@@ -1241,6 +2063,9 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
     pub unsafe fn free(&mut self, item: *mut u8) {
         trace_event!(remote_free);
         let meta = &*self.m;
+        if let Some(counters) = meta.counters.as_ref() {
+            counters.taken.sub(1);
+        }
         let it_slag = Slag::find(item, meta.total_bytes);
         match it_slag.as_ref().expect("found invalid slag").free(item) {
             Transition::Null => return,
@@ -1249,6 +2074,39 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         }
     }
 
+    /// Give up the current `Slag`, which must not be allocated from again, as when the cache using
+    /// `self` is dropped.
+    ///
+    /// Objects still held by the iterator over the current `Slag` must be freed first. If all of
+    /// the `Slag`'s objects are free, its page is returned to the page allocator; otherwise it is
+    /// made available to other handles as it would be by `refresh`.
+    pub unsafe fn release_slag(&mut self) {
+        let meta = &*self.m;
+        let slag = self.slag;
+        let (_claimed, was) = (*slag).rc.unclaim();
+        if was == meta.n_objects {
+            // Nothing else refers to the slag: it is not available, and no objects are allocated.
+            (*slag).handle.store(0, Ordering::Release);
+            count_slag_freed(meta);
+            self.pages.free(
+                slag as *mut u8,
+                meta.usable_size >= self.eager_decommit_threshold,
+            );
+        } else if was >= meta.cutoff_objects {
+            self.transition_available(slag);
+        }
+        // Otherwise the slag becomes available once enough of its objects are freed.
+    }
+
+    /// The number of `Slag`s held for this object class: the current `Slag` along with any
+    /// `Slag`s available to all handles for the class.
+    ///
+    /// `Slag`s currently owned by other handles are not counted, and the number of available
+    /// `Slag`s is approximate.
+    pub fn held_slags(&self) -> usize {
+        1 + self.available.size_guess() as usize
+    }
+
     /// Test if `it` is an element of the current `Slag`.
     pub fn contains(&self, it: *mut u8) -> bool {
         unsafe {
@@ -1262,7 +2120,8 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
 impl<CA: CoarseAllocator> Clone for SlagAllocator<CA> {
     fn clone(&self) -> Self {
         let mut new_page_handle = self.pages.clone();
-        let first_slag = unsafe { new_page_handle.alloc() as *mut Slag };
+        let (first_slag, fresh) = unsafe { new_page_handle.alloc_fresh() };
+        let first_slag = first_slag as *mut Slag;
         unsafe {
             Slag::init(
                 first_slag,
@@ -1277,6 +2136,7 @@ impl<CA: CoarseAllocator> Clone for SlagAllocator<CA> {
             pages: new_page_handle,
             available: self.available.clone(),
             eager_decommit_threshold: self.eager_decommit_threshold,
+            fresh: fresh,
         }
     }
 }
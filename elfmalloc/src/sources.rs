@@ -9,7 +9,45 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, AtomicPtr, Ordering};
 use std::mem;
-use super::utils::{likely, mmap};
+use super::utils::{env_parse, likely, mmap};
+
+/// The size of the huge pages requested with `HugePages::HugeTlb`.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Whether a `MemorySource` asks for its memory to be backed by huge pages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// Use the system's normal pages.
+    Never,
+    /// Advise the kernel with `madvise(MADV_HUGEPAGE)` to back memory with transparent huge
+    /// pages. The kernel may ignore the advice, for example if transparent huge pages are
+    /// disabled, in which case normal pages are used.
+    Transparent,
+    /// Map memory from the kernel's pool of 2MiB huge pages with `MAP_HUGETLB`. If the pool is
+    /// empty or not configured, or the page size is not a multiple of 2MiB, this falls back to
+    /// `Transparent`.
+    HugeTlb,
+}
+
+impl HugePages {
+    /// Read a policy from the `ELFMALLOC_HUGE_PAGES` environment variable, which may be `never`,
+    /// `thp` or `hugetlb`. Returns `None` if the variable is unset or has any other value; any
+    /// other value is also reported on stderr.
+    pub fn from_env() -> Option<HugePages> {
+        HugePages::from_env_var(b"ELFMALLOC_HUGE_PAGES\0")
+    }
+
+    /// Read a policy from the environment variable `name`, which must end with a nul byte, as
+    /// `from_env` does.
+    pub fn from_env_var(name: &[u8]) -> Option<HugePages> {
+        env_parse(name, |val| match val {
+            val if val == b"never" => Some(HugePages::Never),
+            val if val == b"thp" => Some(HugePages::Transparent),
+            val if val == b"hugetlb" => Some(HugePages::HugeTlb),
+            _ => None,
+        })
+    }
+}
 
 /// A generator of chunks of memory providing an `sbrk`-like interface.
 pub trait MemorySource
@@ -24,7 +62,36 @@ where
     ///
     /// Currently, there is code (see the `Coalescer` in the `slag` module) that relies on fresh
     /// pages returned from `carve` to be filled with zeros.
+    ///
+    /// The alignment is also what lets elfmalloc find the type of an allocation: the first page
+    /// of every group carved by a `PageAlloc` starts with an `AllocType`, which is found again
+    /// by rounding pointers into the group down to a multiple of 2MiB. `PageAlloc` aborts if a
+    /// source returns misaligned pages.
     fn carve(&self, npages: usize) -> Option<*mut u8>;
+
+    /// Return the physical memory backing `[ptr, ptr + len)`, which lies in pages returned by
+    /// `carve`, to the operating system. The memory stays mapped, and reads as zeros (on Linux)
+    /// the next time it is used.
+    unsafe fn uncommit(&self, ptr: *mut u8, len: usize) {
+        mmap::uncommit(ptr, len)
+    }
+
+    /// Unmap `[ptr, ptr + len)`, part of the memory returned by `carve` that is no longer used.
+    /// The default implementation leaves the memory mapped.
+    unsafe fn release(&self, _ptr: *mut u8, _len: usize) {}
+
+    /// Ask for the memory returned by later calls to `carve` to be backed by huge pages. The
+    /// default implementation ignores the request.
+    fn set_huge_pages(&mut self, _policy: HugePages) {}
+
+    /// A source of pages of size `page_size` from the same memory as `self`.
+    ///
+    /// This is used to get sources for each page size from the ones passed to
+    /// `DynamicAllocator::with_source`. The default implementation creates a new source with
+    /// `new`.
+    fn with_page_size(&self, page_size: usize) -> Self {
+        Self::new(page_size)
+    }
 }
 
 /// A `MemorySource` that can tell which pointers lie in a region returned by `carve`.
@@ -39,13 +106,39 @@ pub trait MemoryBlock: MemorySource {
 #[derive(Copy, Clone)]
 pub struct MmapSource {
     page_size: usize,
+    huge_pages: HugePages,
+}
+
+impl MmapSource {
+    /// Map `size` bytes, using huge pages if `self.huge_pages` asks for them.
+    fn map(&self, size: usize) -> Option<*mut u8> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.huge_pages == HugePages::HugeTlb && self.page_size % HUGE_PAGE_SIZE == 0 {
+                if let Some(mem) = mmap::fallible_map_huge_tlb(size) {
+                    return Some(mem);
+                }
+            }
+        }
+        let mem = mmap::fallible_map(size);
+        if let Some(mem) = mem {
+            if self.huge_pages != HugePages::Never {
+                // If the kernel refuses, the memory is simply backed by normal pages.
+                unsafe { mmap::advise_huge_pages(mem, size) };
+            }
+        }
+        mem
+    }
 }
 
 unsafe impl Send for MmapSource {}
 
 impl MemorySource for MmapSource {
     fn new(page_size: usize) -> MmapSource {
-        MmapSource { page_size: page_size.next_power_of_two() }
+        MmapSource {
+            page_size: page_size.next_power_of_two(),
+            huge_pages: HugePages::Never,
+        }
     }
     fn page_size(&self) -> usize {
         self.page_size
@@ -62,14 +155,20 @@ impl MemorySource for MmapSource {
         // system one.
         let system_page_size = mmap::page_size();
         if self.page_size <= system_page_size {
-            return mmap::fallible_map(npages * self.page_size);
+            return npages.checked_mul(self.page_size).and_then(|len| self.map(len));
         }
         // We want to return pages aligned to our page size, which is larger than the
         // system page size. As a result, we want to allocate an extra page to guarantee a slice of
         // the memory that is aligned to the larger page size.
-        let target_size = npages * self.page_size;
-        let req_size = target_size + self.page_size;
-        mmap::fallible_map(req_size).and_then(|mem| {
+        let target_size = match npages.checked_mul(self.page_size) {
+            Some(size) => size,
+            None => return None,
+        };
+        let req_size = match target_size.checked_add(self.page_size) {
+            Some(size) => size,
+            None => return None,
+        };
+        self.map(req_size).and_then(|mem| {
             let mem_num = mem as usize;
 
             alloc_debug_assert_eq!(mod_size(mem_num, system_page_size), 0);
@@ -91,6 +190,10 @@ impl MemorySource for MmapSource {
             }
         })
     }
+
+    unsafe fn release(&self, ptr: *mut u8, len: usize) {
+        mmap::unmap(ptr, len)
+    }
 }
 
 /// Base address and size of a memory map.
@@ -168,6 +271,15 @@ impl MemorySource for Creek {
         }
     }
 
+    /// A `Creek` is mapped up front, so `HugePages::HugeTlb` cannot be honored; both it and
+    /// `HugePages::Transparent` advise the kernel to use transparent huge pages for the rest of
+    /// the heap.
+    fn set_huge_pages(&mut self, policy: HugePages) {
+        if policy != HugePages::Never {
+            unsafe { mmap::advise_huge_pages(self.map_info.0, self.map_info.1) };
+        }
+    }
+
 
     /// Create a new `Creek` with pages of size `page_size` total heap size of `heap_size`,
     /// optionally backed by huge pages.
@@ -23,6 +23,9 @@
 //! encountered in the `general::global` in this crate. `RefCell`'s `try_borrow` gives us a guard
 //! against this recursion.
 
+#[cfg(not(feature = "no_stats"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 type Num = i64;
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -53,6 +56,98 @@ pub struct AllocStats {
     pub grabbed_clean: Num,
 }
 
+/// A count that is updated with relaxed atomic operations, and so can be shared by all clones of
+/// an allocator.
+///
+/// If the `no_stats` feature is enabled, updates do nothing and the count always reads as 0.
+#[derive(Default)]
+pub struct Counter {
+    #[cfg(not(feature = "no_stats"))]
+    n: AtomicUsize,
+}
+
+impl Counter {
+    /// A count of 0, usable in statics.
+    pub const fn new() -> Counter {
+        Counter {
+            #[cfg(not(feature = "no_stats"))]
+            n: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(not(feature = "no_stats"))]
+impl Counter {
+    #[inline(always)]
+    pub fn add(&self, n: usize) {
+        self.n.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn sub(&self, n: usize) {
+        self.n.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Like `sub`, but also return whether the count is now 0.
+    #[inline(always)]
+    pub fn sub_to_zero(&self, n: usize) -> bool {
+        self.n.fetch_sub(n, Ordering::Relaxed) == n
+    }
+
+    /// Raise the count to `n` if it is lower.
+    #[inline(always)]
+    pub fn raise_to(&self, n: usize) {
+        let mut cur = self.n.load(Ordering::Relaxed);
+        while cur < n {
+            match self.n.compare_exchange_weak(cur, n, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(now) => cur = now,
+            }
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.n.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "no_stats")]
+impl Counter {
+    #[inline(always)]
+    pub fn add(&self, _n: usize) {}
+
+    #[inline(always)]
+    pub fn sub(&self, _n: usize) {}
+
+    #[inline(always)]
+    pub fn sub_to_zero(&self, _n: usize) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn raise_to(&self, _n: usize) {}
+
+    pub fn get(&self) -> usize {
+        0
+    }
+}
+
+/// Counts of the objects of a size class, shared by all handles on the class.
+///
+/// The counts are read separately, so they need not be consistent with each other while other
+/// threads are allocating.
+#[derive(Default)]
+pub struct ClassCounters {
+    /// The number of objects allocated and not yet freed.
+    pub live: Counter,
+    /// The number of objects taken from `Slag`s by handles and not yet returned to a `Slag`. This
+    /// includes the live objects along with those cached by handles.
+    pub taken: Counter,
+    /// The number of `Slag`s taken from the page allocator for the class and not yet returned to
+    /// it.
+    pub slags: Counter,
+}
+
 #[cfg(feature = "print_stats")]
 pub mod state {
     use super::*;
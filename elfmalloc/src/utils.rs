@@ -9,15 +9,33 @@
 use std::cmp;
 use std::ops::{Deref, DerefMut};
 use std::cell::UnsafeCell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use super::stats::Counter;
 
 pub mod mmap {
+    extern crate libc;
     extern crate mmap_alloc;
     extern crate sysconf;
+    #[cfg(windows)]
+    extern crate kernel32;
+    #[cfg(windows)]
+    extern crate winapi;
     use self::mmap_alloc::MapAllocBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use super::super::alloc::allocator::{Alloc, Layout};
 
+    /// The system page size. It is looked up once and then cached.
     pub fn page_size() -> usize {
-        self::sysconf::page::pagesize()
+        static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+        let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+        if page_size != 0 {
+            return page_size;
+        }
+        let page_size = self::sysconf::page::pagesize();
+        PAGE_SIZE.store(page_size, Ordering::Relaxed);
+        page_size
     }
 
     pub fn map(size: usize) -> *mut u8 {
@@ -37,6 +55,32 @@ pub mod mmap {
         }
     }
 
+    /// Map `size` bytes that can be accessed at once. `fallible_map` only reserves memory on
+    /// Windows, and any access to it faults until it is committed.
+    #[cfg(windows)]
+    pub fn fallible_map_committed(size: usize) -> Option<*mut u8> {
+        unsafe {
+            MapAllocBuilder::default()
+                .exec(true)
+                .commit(true)
+                .build()
+                .alloc(Layout::from_size_align(size, 1).unwrap())
+                .ok()
+        }
+    }
+
+    /// Commit `[p, p + len)`, part of a reservation, so that it can be accessed again after
+    /// `uncommit`. It reads as zeros.
+    #[cfg(windows)]
+    pub unsafe fn commit(p: *mut u8, len: usize) {
+        MapAllocBuilder::default().exec(true).build().commit(
+            p,
+            Layout::from_size_align(len, 1).unwrap(),
+        )
+    }
+
+    /// Unmap the mapping at `p`. On Windows, `p` must be the start of a mapping returned by
+    /// `fallible_map`, all of which is released whatever `len` is.
     pub unsafe fn unmap(p: *mut u8, len: usize) {
         MapAllocBuilder::default().exec(true).build().dealloc(
             p,
@@ -49,6 +93,214 @@ pub mod mmap {
             Layout::from_size_align(len, 1).unwrap(),
         )
     }
+
+    /// Let the kernel reclaim the memory backing `[p, p + len)` lazily with `MADV_FREE`,
+    /// returning whether it accepted the advice (it refuses on kernels older than 4.5, and this
+    /// always fails on platforms other than Linux). Until the memory is reclaimed, it keeps its
+    /// contents and counts as resident; after, it reads as zeros. Writing to a page cancels the
+    /// advice for that page.
+    pub unsafe fn lazy_free(p: *mut u8, len: usize) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            libc::madvise(p as *mut libc::c_void, len, libc::MADV_FREE) == 0
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (p, len);
+            false
+        }
+    }
+
+    /// Resize the mapping `[p, p + old_len)` to `new_len` bytes, returning whether it succeeded.
+    ///
+    /// Without `dest`, the mapping is resized in place, which fails if the memory past its end is
+    /// already mapped. With `dest`, the mapping is moved to `dest`, replacing any mapping in
+    /// `[dest, dest + new_len)`.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn remap(p: *mut u8, old_len: usize, new_len: usize, dest: Option<*mut u8>) -> bool {
+        let res = match dest {
+            None => libc::mremap(p as *mut libc::c_void, old_len, new_len, 0),
+            Some(dest) => {
+                libc::mremap(
+                    p as *mut libc::c_void,
+                    old_len,
+                    new_len,
+                    libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+                    dest as *mut libc::c_void,
+                )
+            }
+        };
+        res != libc::MAP_FAILED
+    }
+
+    // The ranges passed to `advise_huge_pages` by this thread, so that tests can check that the
+    // advice was given even where the kernel ignores it.
+    #[cfg(test)]
+    thread_local! {
+        pub static HUGE_ADVICE: ::std::cell::RefCell<Vec<(*mut u8, usize)>> =
+            ::std::cell::RefCell::new(Vec::new());
+    }
+
+    /// Advise the kernel to back `[p, p + len)` with transparent huge pages, returning whether
+    /// the advice was accepted. This always fails on platforms other than Linux.
+    pub unsafe fn advise_huge_pages(p: *mut u8, len: usize) -> bool {
+        #[cfg(test)] HUGE_ADVICE.with(|advice| advice.borrow_mut().push((p, len)));
+        #[cfg(target_os = "linux")]
+        {
+            libc::madvise(p as *mut libc::c_void, len, libc::MADV_HUGEPAGE) == 0
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (p, len);
+            false
+        }
+    }
+
+    /// Map `size` bytes from the kernel's pool of huge pages with `MAP_HUGETLB`, or return `None`
+    /// if the pool cannot provide them. `size` must be a multiple of the huge page size.
+    #[cfg(target_os = "linux")]
+    pub fn fallible_map_huge_tlb(size: usize) -> Option<*mut u8> {
+        let mem = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                -1,
+                0,
+            )
+        };
+        if mem == libc::MAP_FAILED {
+            None
+        } else {
+            Some(mem as *mut u8)
+        }
+    }
+
+    /// Make `[p, p + len)` inaccessible; any access to it will fault until it is made accessible
+    /// again with `protect_read_write`.
+    pub unsafe fn protect_none(p: *mut u8, len: usize) {
+        #[cfg(unix)]
+        protect(p, len, libc::PROT_NONE);
+        #[cfg(windows)]
+        protect(p, len, winapi::winnt::PAGE_NOACCESS);
+    }
+
+    /// Make `[p, p + len)` read-only; any write to it will fault until it is made writable again
+    /// with `protect_read_write`.
+    #[cfg(feature = "freeze")]
+    pub unsafe fn protect_read(p: *mut u8, len: usize) {
+        #[cfg(unix)]
+        protect(p, len, libc::PROT_READ);
+        #[cfg(windows)]
+        protect(p, len, winapi::winnt::PAGE_READONLY);
+    }
+
+    /// Make `[p, p + len)` readable and writable.
+    pub unsafe fn protect_read_write(p: *mut u8, len: usize) {
+        #[cfg(unix)]
+        protect(p, len, libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC);
+        #[cfg(windows)]
+        protect(p, len, winapi::winnt::PAGE_EXECUTE_READWRITE);
+    }
+
+    #[cfg(unix)]
+    unsafe fn protect(p: *mut u8, len: usize, prot: libc::c_int) {
+        let ret = libc::mprotect(p as *mut libc::c_void, len, prot);
+        alloc_assert_eq!(ret, 0, "mprotect failed");
+    }
+
+    #[cfg(windows)]
+    unsafe fn protect(p: *mut u8, len: usize, prot: winapi::DWORD) {
+        let mut old_prot: winapi::DWORD = 0;
+        let ret = kernel32::VirtualProtect(p as *mut _, len as winapi::SIZE_T, prot, &mut old_prot);
+        alloc_assert!(
+            ret != 0,
+            "VirtualProtect failed with error code {}",
+            kernel32::GetLastError()
+        );
+    }
+}
+
+/// Call `f` with the value of the environment variable `name`, which must end with a nul byte.
+///
+/// Unlike `std::env::var_os`, this does not allocate, and so can be used while the allocator is
+/// being initialized.
+pub fn with_env_var<R, F: FnOnce(Option<&[u8]>) -> R>(name: &[u8], f: F) -> R {
+    extern crate libc;
+    use std::ffi::CStr;
+    alloc_debug_assert_eq!(name.last(), Some(&0));
+    unsafe {
+        let val = libc::getenv(name.as_ptr() as *const libc::c_char);
+        if val.is_null() {
+            f(None)
+        } else {
+            f(Some(CStr::from_ptr(val).to_bytes()))
+        }
+    }
+}
+
+/// Read the environment variable `name`, which must end with a nul byte, and parse it with
+/// `parse`. Returns `None` if the variable is unset. A value that `parse` rejects is ignored, and
+/// a one-line warning naming the variable is printed to stderr.
+///
+/// Like `with_env_var`, this does not allocate.
+pub fn env_parse<T, F: FnOnce(&[u8]) -> Option<T>>(name: &[u8], parse: F) -> Option<T> {
+    use std::str;
+    with_env_var(name, |val| {
+        let val = match val {
+            Some(val) => val,
+            None => return None,
+        };
+        let res = parse(val);
+        if res.is_none() {
+            alloc_eprintln!(
+                "elfmalloc: ignoring malformed value {:?} for {}",
+                str::from_utf8(val).unwrap_or("<non-UTF-8>"),
+                str::from_utf8(&name[..name.len() - 1]).unwrap_or("<non-UTF-8>")
+            );
+        }
+        res
+    })
+}
+
+/// Read a boolean from the environment variable `name`, which must end with a nul byte: `1` or
+/// `true` is `true`, and `0` or `false` is `false`. Returns `None` if the variable is unset or has
+/// any other value; see `env_parse`.
+pub fn env_flag(name: &[u8]) -> Option<bool> {
+    env_parse(name, |val| match val {
+        val if val == b"1" || val == b"true" => Some(true),
+        val if val == b"0" || val == b"false" => Some(false),
+        _ => None,
+    })
+}
+
+/// Read a size in bytes from the environment variable `name`, which must end with a nul byte.
+/// See `parse_size` for the accepted formats and `env_parse` for how malformed values are
+/// handled.
+pub fn env_size(name: &[u8]) -> Option<usize> {
+    env_parse(name, parse_size)
+}
+
+/// Parse a decimal number of bytes, optionally followed by a binary suffix `K`, `M` or `G` (in
+/// either case). Returns `None` if `val` has any other form or the size overflows a `usize`.
+pub fn parse_size(val: &[u8]) -> Option<usize> {
+    use std::str;
+    let (digits, shift) = match val.last() {
+        Some(&b'k') | Some(&b'K') => (&val[..val.len() - 1], 10),
+        Some(&b'm') | Some(&b'M') => (&val[..val.len() - 1], 20),
+        Some(&b'g') | Some(&b'G') => (&val[..val.len() - 1], 30),
+        _ => (val, 0),
+    };
+    // from_str accepts a leading '+', which we do not
+    if digits.first() == Some(&b'+') {
+        return None;
+    }
+    let n = match str::from_utf8(digits).ok().and_then(|s| s.parse::<usize>().ok()) {
+        Some(n) => n,
+        None => return None,
+    };
+    n.checked_mul(1 << shift)
 }
 
 // we use the unlikely intrinsic if it is available.
@@ -76,6 +328,14 @@ pub unsafe fn likely(b: bool) -> bool {
 pub trait LazyInitializable {
     type Params;
     fn init(p: &Self::Params) -> Self;
+    /// Like `init`, but return `None` if the value cannot be constructed, such as when there is
+    /// no memory for it. Types whose construction cannot fail only implement `init`.
+    fn try_init(p: &Self::Params) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        Some(Self::init(p))
+    }
 }
 
 /// A `Lazy` instance of a type `T` keeps `T::Params` strict but only initializes the value with
@@ -110,6 +370,58 @@ impl<T: LazyInitializable> Lazy<T> {
             val: UnsafeCell::new(None),
         }
     }
+
+    /// Get the parameters the underlying object is (or will be) constructed with.
+    pub fn params(&self) -> &T::Params {
+        &self.params
+    }
+
+    /// Get the underlying object if it has been initialized, without initializing it.
+    pub fn try_get(&self) -> Option<&T> {
+        unsafe { (*self.val.get()).as_ref() }
+    }
+
+    /// Mutable variant of `try_get`.
+    pub fn try_get_mut(&mut self) -> Option<&mut T> {
+        unsafe { (*self.val.get()).as_mut() }
+    }
+
+    /// Drop the underlying object, if it has been initialized. It is initialized again the next
+    /// time it is accessed.
+    pub fn reset(&mut self) {
+        unsafe { *self.val.get() = None }
+    }
+
+    /// Initialize the underlying object if it has not been initialized yet, without accessing
+    /// it. Returns whether it was initialized by this call, or `None` if `T::try_init` failed, in
+    /// which case initialization is tried again the next time.
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn ensure_initialized(&self) -> Option<bool> {
+        let state = unsafe { &mut *self.val.get() };
+        if unsafe { unlikely(state.is_none()) } {
+            *state = T::try_init(&self.params);
+            if state.is_none() {
+                return None;
+            }
+            #[cfg(test)] LAZY_INITS.with(|c| c.set(c.get() + 1));
+            Some(true)
+        } else {
+            Some(false)
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of `Lazy` objects initialized on this thread.
+    static LAZY_INITS: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
+
+/// The number of `Lazy` objects initialized on the current thread so far.
+#[cfg(test)]
+pub fn lazy_inits() -> usize {
+    LAZY_INITS.with(|c| c.get())
 }
 
 impl<T: LazyInitializable> Deref for Lazy<T> {
@@ -118,11 +430,10 @@ impl<T: LazyInitializable> Deref for Lazy<T> {
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
     fn deref(&self) -> &T {
-        let state = unsafe { &mut *self.val.get() };
-        if unsafe { unlikely(state.is_none()) } {
-            *state = Some(T::init(&self.params));
+        if self.ensure_initialized().is_none() {
+            alloc_panic!("out of memory!");
         }
-        state.as_ref().unwrap()
+        unsafe { (*self.val.get()).as_ref().unwrap() }
     }
 }
 
@@ -130,11 +441,10 @@ impl<T: LazyInitializable> DerefMut for Lazy<T> {
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
     fn deref_mut(&mut self) -> &mut T {
-        let state = unsafe { &mut *self.val.get() };
-        if unsafe { unlikely(state.is_none()) } {
-            *state = Some(T::init(&self.params));
+        if self.ensure_initialized().is_none() {
+            alloc_panic!("out of memory!");
         }
-        state.as_mut().unwrap()
+        unsafe { (*self.val.get()).as_mut().unwrap() }
     }
 }
 
@@ -209,6 +519,10 @@ impl<T> Deref for OwnedArray<T> {
     }
 }
 
+// An OwnedArray owns its elements, like a Box<[T]>.
+unsafe impl<T: Send> Send for OwnedArray<T> {}
+unsafe impl<T: Sync> Sync for OwnedArray<T> {}
+
 impl<T> Drop for OwnedArray<T> {
     fn drop(&mut self) {
         unsafe { self.destroy() }
@@ -235,6 +549,353 @@ impl<'a, T: 'a> Iterator for TypedArrayIter<'a, T> {
     }
 }
 
+const LOG_CHUNK_LEN: usize = 510;
+
+struct LogChunk {
+    next: *mut LogChunk,
+    /// The number of slots claimed so far. This can exceed `LOG_CHUNK_LEN` if several threads
+    /// race to push to a full chunk.
+    len: AtomicUsize,
+    slots: [AtomicPtr<u8>; LOG_CHUNK_LEN],
+}
+
+/// An append-only, lock-free log of pointers.
+///
+/// Like `TypedArray`, a `PointerLog` gets its memory directly from mmap, so it can be used from
+/// within the allocator without recursing into it. Pointers are stored in chunks of a few
+/// kilobytes; a chunk is mapped when the previous one fills up. The all-zeros `PointerLog` is
+/// empty, so a `PointerLog` can also live in memory that was freshly mapped.
+pub struct PointerLog {
+    head: AtomicPtr<LogChunk>,
+}
+
+impl PointerLog {
+    pub fn new() -> PointerLog {
+        PointerLog { head: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Append `p` to the log. `p` must not be null.
+    pub fn push(&self, p: *mut u8) {
+        alloc_debug_assert!(!p.is_null());
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if !head.is_null() {
+                let ix = unsafe { (*head).len.fetch_add(1, Ordering::Relaxed) };
+                if ix < LOG_CHUNK_LEN {
+                    unsafe { (*head).slots[ix].store(p, Ordering::Release) };
+                    return;
+                }
+            }
+            // The current chunk is full (or there isn't one yet): map a new one with `p` as its
+            // first entry. If another thread installs a chunk first, we try again with that one.
+            unsafe {
+                let chunk = mmap::map(mem::size_of::<LogChunk>()) as *mut LogChunk;
+                (*chunk).next = head;
+                (*chunk).len.store(1, Ordering::Relaxed);
+                (*chunk).slots[0].store(p, Ordering::Relaxed);
+                if self.head.compare_and_swap(head, chunk, Ordering::AcqRel) == head {
+                    return;
+                }
+                mmap::unmap(chunk as *mut u8, mem::size_of::<LogChunk>());
+            }
+        }
+    }
+
+    /// Call `f` on every pointer in the log, most recent chunks first.
+    ///
+    /// Pointers pushed concurrently with a call to `foreach` may or may not be visited.
+    pub fn foreach<F: FnMut(*mut u8)>(&self, mut f: F) {
+        let mut chunk = self.head.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let len = cmp::min((*chunk).len.load(Ordering::Relaxed), LOG_CHUNK_LEN);
+                for slot in &(*chunk).slots[..len] {
+                    let p = slot.load(Ordering::Acquire);
+                    if !p.is_null() {
+                        f(p);
+                    }
+                }
+                chunk = (*chunk).next;
+            }
+        }
+    }
+
+    /// Remove every pointer from the log, unmapping its memory.
+    ///
+    /// This must not be called concurrently with any other method.
+    pub unsafe fn clear(&self) {
+        let mut chunk = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        while !chunk.is_null() {
+            let next = (*chunk).next;
+            mmap::unmap(chunk as *mut u8, mem::size_of::<LogChunk>());
+            chunk = next;
+        }
+    }
+}
+
+impl Default for PointerLog {
+    fn default() -> PointerLog {
+        PointerLog::new()
+    }
+}
+
+impl Drop for PointerLog {
+    fn drop(&mut self) {
+        unsafe { self.clear() }
+    }
+}
+
+const RANGE_CHUNK_LEN: usize = 255;
+
+/// The start of a slot that is free to be reused by `RangeSet::insert`.
+const RANGE_VACANT: usize = 1;
+/// The start of a slot that `RangeSet::insert` is in the middle of reusing.
+const RANGE_CLAIMED: usize = 2;
+
+struct RangeChunk {
+    next: *mut RangeChunk,
+    /// The number of slots claimed so far, as in `LogChunk`.
+    len: AtomicUsize,
+    /// The start of each range, or 0, `RANGE_VACANT` or `RANGE_CLAIMED` if the slot does not hold
+    /// one.
+    starts: [AtomicUsize; RANGE_CHUNK_LEN],
+    ends: [AtomicUsize; RANGE_CHUNK_LEN],
+}
+
+/// A lock-free set of address ranges.
+///
+/// Like `PointerLog`, a `RangeSet` gets its memory directly from mmap and is stored in chunks
+/// that are mapped as they are needed. Removed ranges leave their slot to be reused by the next
+/// insertion, so the memory used is proportional to the largest number of ranges in the set at
+/// once. Every operation scans the whole set, so it is meant for sets of at most a few thousand
+/// ranges that change rarely relative to how often they are queried.
+pub struct RangeSet {
+    head: AtomicPtr<RangeChunk>,
+}
+
+impl RangeSet {
+    pub const fn new() -> RangeSet {
+        RangeSet { head: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Add `[start, start + len)` to the set. No range with the same start may already be
+    /// present, and `start` must not be one of the first few addresses.
+    pub fn insert(&self, start: *mut u8, len: usize) {
+        let (start, end) = (start as usize, start as usize + len);
+        alloc_debug_assert!(start > RANGE_CLAIMED);
+        // Reuse a slot emptied by `remove` if there is one. Slots claimed by a concurrent push
+        // have a start of 0, and so are never taken.
+        let mut chunk = self.head.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let len = cmp::min((*chunk).len.load(Ordering::Relaxed), RANGE_CHUNK_LEN);
+                for i in 0..len {
+                    let slot = &(*chunk).starts[i];
+                    if slot.compare_and_swap(RANGE_VACANT, RANGE_CLAIMED, Ordering::SeqCst) ==
+                        RANGE_VACANT
+                    {
+                        (*chunk).ends[i].store(end, Ordering::SeqCst);
+                        slot.store(start, Ordering::SeqCst);
+                        return;
+                    }
+                }
+                chunk = (*chunk).next;
+            }
+        }
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if !head.is_null() {
+                let ix = unsafe { (*head).len.fetch_add(1, Ordering::Relaxed) };
+                if ix < RANGE_CHUNK_LEN {
+                    unsafe {
+                        (*head).ends[ix].store(end, Ordering::SeqCst);
+                        (*head).starts[ix].store(start, Ordering::SeqCst);
+                    }
+                    return;
+                }
+            }
+            // As in `PointerLog::push`, the range is the first one of a new chunk.
+            unsafe {
+                let chunk = mmap::map(mem::size_of::<RangeChunk>()) as *mut RangeChunk;
+                (*chunk).next = head;
+                (*chunk).len.store(1, Ordering::Relaxed);
+                (*chunk).ends[0].store(end, Ordering::Relaxed);
+                (*chunk).starts[0].store(start, Ordering::Relaxed);
+                if self.head.compare_and_swap(head, chunk, Ordering::AcqRel) == head {
+                    return;
+                }
+                mmap::unmap(chunk as *mut u8, mem::size_of::<RangeChunk>());
+            }
+        }
+    }
+
+    /// Remove the range starting at `start`, returning whether there was one.
+    pub fn remove(&self, start: *mut u8) -> bool {
+        self.take(start).is_some()
+    }
+
+    /// Remove the range starting at `start`, returning its length if there was one.
+    pub fn take(&self, start: *mut u8) -> Option<usize> {
+        let start = start as usize;
+        let mut chunk = self.head.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let len = cmp::min((*chunk).len.load(Ordering::Relaxed), RANGE_CHUNK_LEN);
+                for i in 0..len {
+                    let slot = &(*chunk).starts[i];
+                    if slot.load(Ordering::SeqCst) != start {
+                        continue;
+                    }
+                    // The end is only written while the slot is claimed, so if the slot still
+                    // holds `start` when it is emptied, the end read here is this range's.
+                    let end = (*chunk).ends[i].load(Ordering::SeqCst);
+                    if slot.compare_and_swap(start, RANGE_VACANT, Ordering::SeqCst) == start {
+                        return Some(end - start);
+                    }
+                }
+                chunk = (*chunk).next;
+            }
+        }
+        None
+    }
+
+    /// Does a range in the set contain `p`?
+    ///
+    /// Ranges inserted or removed concurrently with a call to `contains` may or may not be seen.
+    pub fn contains(&self, p: *mut u8) -> bool {
+        let p = p as usize;
+        let mut chunk = self.head.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let len = cmp::min((*chunk).len.load(Ordering::Relaxed), RANGE_CHUNK_LEN);
+                for i in 0..len {
+                    let start = (*chunk).starts[i].load(Ordering::SeqCst);
+                    if start <= RANGE_CLAIMED || p < start {
+                        continue;
+                    }
+                    // If the slot was reused while we read its end, the end may belong to
+                    // another range.
+                    let end = (*chunk).ends[i].load(Ordering::SeqCst);
+                    if p < end && (*chunk).starts[i].load(Ordering::SeqCst) == start {
+                        return true;
+                    }
+                }
+                chunk = (*chunk).next;
+            }
+        }
+        false
+    }
+
+    /// Call `f` with the start and length of every range in the set.
+    ///
+    /// As with `contains`, ranges inserted or removed concurrently may or may not be seen.
+    pub fn foreach<F: FnMut(*mut u8, usize)>(&self, mut f: F) {
+        let mut chunk = self.head.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let len = cmp::min((*chunk).len.load(Ordering::Relaxed), RANGE_CHUNK_LEN);
+                for i in 0..len {
+                    let start = (*chunk).starts[i].load(Ordering::SeqCst);
+                    if start <= RANGE_CLAIMED {
+                        continue;
+                    }
+                    let end = (*chunk).ends[i].load(Ordering::SeqCst);
+                    if (*chunk).starts[i].load(Ordering::SeqCst) == start {
+                        f(start as *mut u8, end - start);
+                    }
+                }
+                chunk = (*chunk).next;
+            }
+        }
+    }
+}
+
+impl Default for RangeSet {
+    fn default() -> RangeSet {
+        RangeSet::new()
+    }
+}
+
+impl Drop for RangeSet {
+    fn drop(&mut self) {
+        let mut chunk = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                let next = (*chunk).next;
+                mmap::unmap(chunk as *mut u8, mem::size_of::<RangeChunk>());
+                chunk = next;
+            }
+        }
+    }
+}
+
+/// A count of the memory in use by an allocator, and a limit on it.
+///
+/// The count is in bytes and is updated with atomic operations, so a single `MemoryLimit` can be
+/// shared (e.g. in an `Arc`) by all clones of an allocator.
+pub struct MemoryLimit {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    /// The bytes counted by the large allocations in use, which are also included in `used`.
+    pub large: Counter,
+}
+
+impl MemoryLimit {
+    /// Create a `MemoryLimit` with no memory in use and no limit.
+    pub fn new() -> MemoryLimit {
+        MemoryLimit {
+            used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::max_value()),
+            large: Counter::default(),
+        }
+    }
+
+    /// Count `bytes` more bytes as in use, unless that would exceed the limit. Returns whether the
+    /// bytes were counted.
+    pub fn try_acquire(&self, bytes: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            match used.checked_add(bytes) {
+                Some(new) if new <= limit => {
+                    match self.used.compare_exchange_weak(
+                        used,
+                        new,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return true,
+                        Err(cur) => used = cur,
+                    }
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Stop counting `bytes` bytes, which were counted with `try_acquire`, as in use.
+    pub fn release(&self, bytes: usize) {
+        let prev = self.used.fetch_sub(bytes, Ordering::Relaxed);
+        alloc_debug_assert!(prev >= bytes, "released {} bytes, but only {} were in use", bytes, prev);
+    }
+
+    /// The number of bytes in use.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Set the limit. Memory already in use is not affected if it exceeds the new limit.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed)
+    }
+}
+
+impl Default for MemoryLimit {
+    fn default() -> MemoryLimit {
+        MemoryLimit::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[derive(Debug)]
@@ -246,7 +907,41 @@ mod tests {
         }
     }
 
+    /// Whether constructing a `FallibleInit` fails.
+    static INIT_FAILS: AtomicBool = AtomicBool::new(false);
+
+    struct FallibleInit;
+    impl LazyInitializable for FallibleInit {
+        type Params = ();
+        fn init(_p: &()) -> Self {
+            FallibleInit::try_init(&()).expect("initialization failed")
+        }
+        fn try_init(_p: &()) -> Option<Self> {
+            if INIT_FAILS.load(Ordering::Relaxed) {
+                None
+            } else {
+                Some(FallibleInit)
+            }
+        }
+    }
+
     use super::*;
+    use std::sync::atomic::AtomicBool;
+    #[test]
+    fn parse_sizes() {
+        alloc_assert_eq!(parse_size(b"0"), Some(0));
+        alloc_assert_eq!(parse_size(b"4096"), Some(4096));
+        alloc_assert_eq!(parse_size(b"64k"), Some(64 << 10));
+        alloc_assert_eq!(parse_size(b"256K"), Some(256 << 10));
+        alloc_assert_eq!(parse_size(b"2M"), Some(2 << 20));
+        alloc_assert_eq!(parse_size(b"1g"), Some(1 << 30));
+        for val in &[&b""[..], b"K", b"+1", b"-1", b" 1", b"1.5M", b"1KB", b"0x10", b"1T"] {
+            alloc_assert_eq!(parse_size(val), None);
+        }
+        alloc_assert_eq!(parse_size(b"18446744073709551616"), None);
+        alloc_assert_eq!(parse_size(b"17179869184G"), None);
+    }
+
     #[test]
     fn basic_functionality() {
         let mut l = Lazy::<DefaultInit<usize>>::new(());
@@ -257,4 +952,87 @@ mod tests {
         alloc_assert_eq!(l_u, 1);
     }
 
+    #[test]
+    fn ensure_initialized() {
+        let before = lazy_inits();
+        let l = Lazy::<DefaultInit<usize>>::new(());
+        alloc_assert!(l.try_get().is_none());
+        alloc_assert_eq!(l.ensure_initialized(), Some(true));
+        alloc_assert_eq!(l.ensure_initialized(), Some(false));
+        alloc_assert_eq!(l.0, 0);
+        alloc_assert_eq!(lazy_inits(), before + 1);
+        let clone = l.clone();
+        alloc_assert_eq!(clone.0, 0);
+        alloc_assert_eq!(lazy_inits(), before + 2);
+    }
+
+    #[test]
+    fn ensure_initialized_fails() {
+        let l = Lazy::<FallibleInit>::new(());
+        INIT_FAILS.store(true, Ordering::Relaxed);
+        alloc_assert_eq!(l.ensure_initialized(), None);
+        alloc_assert!(l.try_get().is_none());
+        // a failed initialization is retried
+        INIT_FAILS.store(false, Ordering::Relaxed);
+        alloc_assert_eq!(l.ensure_initialized(), Some(true));
+        alloc_assert!(l.try_get().is_some());
+    }
+
+    #[test]
+    fn pointer_log() {
+        use std::sync::Arc;
+        use std::thread;
+        const N_THREADS: usize = 4;
+        const N_ITEMS: usize = 3 * LOG_CHUNK_LEN;
+        let log = Arc::new(PointerLog::new());
+        let threads = (0..N_THREADS)
+            .map(|t| {
+                let log = log.clone();
+                thread::spawn(move || for i in 0..N_ITEMS {
+                    log.push((t * N_ITEMS + i + 1) as *mut u8);
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let mut seen = Vec::new();
+        log.foreach(|p| seen.push(p as usize));
+        seen.sort();
+        alloc_assert_eq!(seen, (1..N_THREADS * N_ITEMS + 1).collect::<Vec<_>>());
+        unsafe { log.clear() };
+        log.foreach(|_| alloc_panic!("log not empty after clear"));
+    }
+
+    #[test]
+    fn range_set() {
+        let set = RangeSet::new();
+        // enough ranges to need a second chunk
+        const N: usize = RANGE_CHUNK_LEN + 10;
+        for i in 1..N + 1 {
+            set.insert((i * 0x1000) as *mut u8, 0x800);
+        }
+        alloc_assert!(set.contains(0x1000 as *mut u8));
+        alloc_assert!(set.contains((N * 0x1000 + 0x7ff) as *mut u8));
+        alloc_assert!(!set.contains((N * 0x1000 + 0x800) as *mut u8));
+        alloc_assert!(!set.contains(0xfff as *mut u8));
+        alloc_assert!(set.remove(0x5000 as *mut u8));
+        alloc_assert!(!set.remove(0x5000 as *mut u8));
+        alloc_assert!(!set.contains(0x5400 as *mut u8));
+        // the emptied slot is reused rather than a new one being claimed
+        set.insert(0x5000 as *mut u8, 0x10);
+        alloc_assert!(set.contains(0x500f as *mut u8));
+        alloc_assert!(!set.contains(0x5010 as *mut u8));
+        alloc_assert_eq!(set.take(0x5000 as *mut u8), Some(0x10));
+        alloc_assert_eq!(set.take(0x5000 as *mut u8), None);
+        let (mut n, mut total) = (0, 0);
+        set.foreach(|_, len| {
+            n += 1;
+            total += len;
+        });
+        alloc_assert_eq!((n, total), (N - 1, (N - 1) * 0x800));
+        let head = set.head.load(Ordering::Relaxed);
+        alloc_assert_eq!(unsafe { (*head).len.load(Ordering::Relaxed) }, N - RANGE_CHUNK_LEN);
+    }
+
 }
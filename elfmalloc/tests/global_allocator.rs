@@ -0,0 +1,101 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Tests that use elfmalloc as the global allocator. Every allocation in this binary, including
+//! those made by the test harness and by elfmalloc itself, goes through `ElfMallocGlobal`.
+
+#![feature(global_allocator)]
+extern crate elfmalloc;
+
+use elfmalloc::alloc_impl::ElfMallocGlobal;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::channel;
+use std::thread;
+
+#[global_allocator]
+static GLOBAL: ElfMallocGlobal = ElfMallocGlobal;
+
+#[test]
+fn collections() {
+    let mut v = Vec::new();
+    for i in 0..100_000usize {
+        v.push(i);
+    }
+    assert_eq!(v.iter().sum::<usize>(), 99_999 * 100_000 / 2);
+
+    let mut m = HashMap::new();
+    for i in 0..10_000usize {
+        m.insert(i, format!("{}", i));
+    }
+    for i in 0..10_000usize {
+        assert_eq!(m[&i], format!("{}", i));
+    }
+
+    let mut b = BTreeMap::new();
+    for i in 0..10_000usize {
+        b.insert(i, vec![i as u8; i % 64]);
+    }
+    assert_eq!(b.len(), 10_000);
+
+    // Large allocations and reallocations across size classes.
+    let mut big = Vec::<u8>::with_capacity(1);
+    for i in 0..(4 << 20) {
+        big.push(i as u8);
+    }
+    assert_eq!(big[(3 << 20) + 5], 5);
+}
+
+#[test]
+fn cross_thread_free() {
+    let (send, recv) = channel();
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let send = send.clone();
+            thread::spawn(move || for j in 0..1000 {
+                send.send(vec![i as u8; j]).unwrap();
+            })
+        })
+        .collect();
+    drop(send);
+    let mut total = 0;
+    for v in recv {
+        total += v.len();
+    }
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(total, 8 * 999 * 1000 / 2);
+}
+
+#[test]
+fn thread_local_destructors() {
+    struct Noisy(Vec<String>);
+
+    impl Drop for Noisy {
+        fn drop(&mut self) {
+            // Allocate and free while thread-local state is being torn down.
+            self.0.push(String::from("dropped"));
+            self.0.clear();
+        }
+    }
+
+    thread_local! { static NOISY: RefCell<Noisy> = RefCell::new(Noisy(Vec::new())); }
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(|| {
+                NOISY.with(|n| for i in 0..100 {
+                    n.borrow_mut().0.push(format!("{}", i));
+                })
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
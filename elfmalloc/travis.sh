@@ -17,6 +17,6 @@ exit 0
 
 travis-cargo --only nightly build
 RUST_BACKTRACE=1 travis-cargo --only nightly test
-for feature in prime_schedules huge_segments no_lazy_region nightly; do
+for feature in prime_schedules huge_segments no_lazy_region nightly freeze global-allocator; do
   RUST_BACKTRACE=1 travis-cargo --only nightly test -- --features "$feature"
 done
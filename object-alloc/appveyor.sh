@@ -16,3 +16,5 @@ fi
 
 cargo build
 RUST_BACKTRACE=1 cargo test
+cargo build --no-default-features
+RUST_BACKTRACE=1 cargo test --features test-utils
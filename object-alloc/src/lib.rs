@@ -8,10 +8,25 @@
 #![no_std]
 #![feature(alloc, allocator_api)]
 #![feature(core_intrinsics)]
+#![cfg_attr(test, feature(attr_literals, repr_align))]
+#![cfg_attr(feature = "std", feature(thread_local_state))]
+
+#[cfg(feature = "std")]
+extern crate std;
 
 extern crate alloc;
-use alloc::allocator::Layout;
+use alloc::allocator::{Alloc, AllocErr, Layout};
+use core::cmp;
+use core::fmt;
+use core::mem;
+use core::ptr;
 use core::intrinsics::abort;
+use core::marker::PhantomData;
+
+#[cfg(feature = "std")]
+pub mod per_thread;
+#[cfg(feature = "test-utils")]
+pub mod test_suite;
 
 /// An error indicating that no memory is available.
 ///
@@ -21,6 +36,62 @@ use core::intrinsics::abort;
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Exhausted;
 
+impl Exhausted {
+    /// Converts this error into an `AllocErr` for a failed request for `request`.
+    ///
+    /// `AllocErr` records the `Layout` of the failed request, which `Exhausted` does not, so it
+    /// must be supplied by the caller. For an `UntypedObjectAlloc`, this is usually the value
+    /// returned by its `layout` method.
+    pub fn into_alloc_err(self, request: Layout) -> AllocErr {
+        AllocErr::Exhausted { request: request }
+    }
+
+    /// Converts an `AllocErr` into an `Exhausted`.
+    ///
+    /// `AllocErr::Exhausted` maps to `Ok(Exhausted)`. `AllocErr::Unsupported` does not indicate
+    /// that memory is exhausted - retrying the request after freeing memory will not help - so
+    /// it is returned unchanged as an `Err` for the caller to handle.
+    pub fn from_alloc_err(err: AllocErr) -> Result<Exhausted, AllocErr> {
+        match err {
+            AllocErr::Exhausted { .. } => Ok(Exhausted),
+            AllocErr::Unsupported { .. } => Err(err),
+        }
+    }
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("memory exhausted")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Exhausted {
+    fn description(&self) -> &str {
+        "memory exhausted"
+    }
+}
+
+/// A snapshot of the state of an object allocator.
+///
+/// Statistics are reported on a best-effort basis: any field may be `None` if the allocator does
+/// not track it, and concurrent allocators may report approximate values.
+///
+/// New fields may be added in the future. To construct an `ObjectAllocStats`, start from
+/// `ObjectAllocStats::default()`, which reports nothing, and set the fields that are known.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct ObjectAllocStats {
+    /// The number of objects cached by the allocator and available to serve future calls to
+    /// `alloc` without acquiring more memory.
+    pub cached_objects: Option<usize>,
+    /// The number of objects allocated over the allocator's lifetime.
+    pub total_allocated: Option<usize>,
+    /// The number of pages of backing memory held by the allocator.
+    pub backing_pages: Option<usize>,
+    // Prevents construction outside of this crate so that adding fields is not a breaking change.
+    _private: (),
+}
+
 /// Allocators which allocate objects of a particular type.
 ///
 /// `ObjectAlloc`s provide an interface which is slightly different than the interface provided by
@@ -43,6 +114,15 @@ pub struct Exhausted;
 /// When an `ObjectAlloc` that allocates initialized objects is dropped, all cached `T` objects
 /// that have not yet been dropped are dropped. The order in which they are dropped is undefined.
 ///
+/// # Zero-sized types
+///
+/// If `T` is zero-sized, `alloc` must not request any memory from a backing allocator (which may
+/// not support zero-sized requests). Instead, it returns a non-null dangling pointer aligned to
+/// `T`, such as the one returned by `dangling`; different objects may share the same address.
+/// Likewise, `dealloc` must not return any memory to a backing allocator. Zero-sized objects are
+/// otherwise treated like any other: they are initialized when allocated, and each constructed
+/// object is dropped exactly once, as described above.
+///
 /// # Use in unsafe code
 ///
 /// Unsafe code may rely on the fact that objects allocated by an `ObjectAlloc` are initialized.
@@ -74,6 +154,21 @@ pub unsafe trait ObjectAlloc<T> {
     /// `T` (that is, according to `core::mem::align_of::<T>()`).
     unsafe fn alloc(&mut self) -> Result<*mut T, Exhausted>;
 
+    /// Allocates an object of type `T`, reporting why allocation failed.
+    ///
+    /// `alloc_checked` behaves like `alloc`, except that on failure it returns an `AllocErr`,
+    /// which distinguishes between memory exhaustion (`AllocErr::Exhausted`) and requests that
+    /// this allocator cannot satisfy at all (`AllocErr::Unsupported`), such as an alignment that
+    /// its backing allocator does not support. Retrying an unsupported request will not help, so
+    /// clients that can fall back to a different allocator should use this method.
+    ///
+    /// The default implementation reports every failure of `alloc` as exhaustion. Allocators that
+    /// can fail for other reasons should override it.
+    unsafe fn alloc_checked(&mut self) -> Result<*mut T, AllocErr> {
+        self.alloc()
+            .map_err(|e| e.into_alloc_err(Layout::new::<T>()))
+    }
+
     /// Deallocates an object previously returned by `alloc`.
     ///
     /// If `x` was not obtained through a call to `alloc`, or if `x` has already been `dealloc`'d,
@@ -87,6 +182,36 @@ pub unsafe trait ObjectAlloc<T> {
     /// time in between.
     unsafe fn dealloc(&mut self, x: *mut T);
 
+    /// Reports statistics about this allocator.
+    ///
+    /// The default implementation reports nothing; see `ObjectAllocStats`.
+    fn stats(&self) -> ObjectAllocStats {
+        ObjectAllocStats::default()
+    }
+
+    /// Returns an iterator which allocates objects until this allocator is exhausted.
+    ///
+    /// See `AllocMany` for how to deallocate objects while the iterator is in use. Every object
+    /// yielded by the iterator must be deallocated as if it had been returned by `alloc`, which
+    /// is what the iterator calls.
+    unsafe fn alloc_many(&mut self) -> AllocMany<T, Self>
+    where
+        Self: Sized,
+    {
+        AllocMany::new(self, None)
+    }
+
+    /// Returns an iterator which allocates at most `n` objects, stopping early if this allocator
+    /// is exhausted.
+    ///
+    /// See `alloc_many`.
+    unsafe fn alloc_up_to(&mut self, n: usize) -> AllocMany<T, Self>
+    where
+        Self: Sized,
+    {
+        AllocMany::new(self, Some(n))
+    }
+
     /// Allocator-specific method for signalling an out-of-memory condition.
     ///
     /// `oom` aborts the thread or process, optionally performing cleanup or logging diagnostic
@@ -109,6 +234,117 @@ pub unsafe trait ObjectAlloc<T> {
     }
 }
 
+/// An iterator which allocates objects from an `ObjectAlloc`, returned by `alloc_many` and
+/// `alloc_up_to`.
+///
+/// Each call to `next` allocates a single object, so the iterator never holds objects that it has
+/// not yet yielded, and dropping it does not allocate or free anything. Iteration stops at the
+/// first allocation failure (or once the limit passed to `alloc_up_to` is reached), and the
+/// iterator keeps returning `None` from then on; use `exhausted` to tell the two cases apart.
+///
+/// The iterator borrows the allocator mutably for as long as it is alive. To deallocate objects
+/// while iterating - for example, to give back objects that turned out not to be needed - use
+/// the iterator's own `dealloc` method, which forwards to the allocator. Objects deallocated this
+/// way may be yielded again by later calls to `next`, unless iteration has already stopped.
+pub struct AllocMany<'a, T, A: 'a + ObjectAlloc<T>> {
+    alloc: &'a mut A,
+    remaining: Option<usize>,
+    exhausted: bool,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<'a, T, A: 'a + ObjectAlloc<T>> AllocMany<'a, T, A> {
+    fn new(alloc: &'a mut A, limit: Option<usize>) -> AllocMany<'a, T, A> {
+        AllocMany {
+            alloc: alloc,
+            remaining: limit,
+            exhausted: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Deallocates an object through the borrowed allocator.
+    ///
+    /// `x` may have been yielded by this iterator or allocated from the allocator beforehand. See
+    /// `ObjectAlloc::dealloc`.
+    pub unsafe fn dealloc(&mut self, x: *mut T) {
+        self.alloc.dealloc(x);
+    }
+
+    /// Whether iteration stopped because the allocator was exhausted.
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
+
+impl<'a, T, A: 'a + ObjectAlloc<T>> Iterator for AllocMany<'a, T, A> {
+    type Item = *mut T;
+
+    fn next(&mut self) -> Option<*mut T> {
+        if self.exhausted || self.remaining == Some(0) {
+            return None;
+        }
+        // The caller of alloc_many or alloc_up_to has taken on the obligations of alloc.
+        match unsafe { self.alloc.alloc() } {
+            Ok(x) => {
+                self.remaining = self.remaining.map(|n| n - 1);
+                Some(x)
+            }
+            Err(Exhausted) => {
+                self.exhausted = true;
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.exhausted {
+            (0, Some(0))
+        } else {
+            (0, self.remaining)
+        }
+    }
+}
+
+/// Returns a non-null pointer aligned to `T`, suitable for zero-sized objects of type `T`.
+///
+/// The pointer does not point to allocated memory, so it must not be dereferenced unless `T` is
+/// zero-sized.
+pub fn dangling<T>() -> *mut T {
+    mem::align_of::<T>() as *mut T
+}
+
+/// Object allocators which can be shared between threads.
+///
+/// `SharedObjectAlloc` is like `ObjectAlloc`, except that its methods take `&self`, so a single
+/// allocator can be used from many threads at once without external synchronization. Objects may
+/// be deallocated on a different thread than the one that allocated them. Otherwise, the contract
+/// is that of `ObjectAlloc`: allocated objects are initialized (unless the allocator was obtained
+/// using an `unsafe` constructor), and every cached object is dropped no later than when the
+/// allocator itself is dropped.
+///
+/// Many `ObjectAlloc`s are designed to be used through one handle per thread, with handles
+/// sharing state internally. The `PerThreadAlloc` adapter in the `per_thread` module (available
+/// with the `std` feature) turns such an allocator into a `SharedObjectAlloc`.
+pub unsafe trait SharedObjectAlloc<T>: Sync {
+    /// Allocates an object of type `T`.
+    ///
+    /// See `ObjectAlloc::alloc`.
+    unsafe fn alloc(&self) -> Result<*mut T, Exhausted>;
+
+    /// Deallocates an object previously returned by `alloc`, possibly on another thread.
+    ///
+    /// See `ObjectAlloc::dealloc`.
+    unsafe fn dealloc(&self, x: *mut T);
+
+    /// Reports statistics about this allocator.
+    ///
+    /// The default implementation reports nothing; see `ObjectAllocStats`.
+    fn stats(&self) -> ObjectAllocStats {
+        ObjectAllocStats::default()
+    }
+}
+
 /// An allocator for objects whose type or size is not known at compile time.
 ///
 /// `UntypedObjectAlloc` is like `ObjectAlloc`, except that the size that it allocates may be
@@ -130,12 +366,46 @@ pub unsafe trait UntypedObjectAlloc {
     /// `layout`.
     unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted>;
 
-    /// Deallocates an object previously returned by `alloc`.
+    /// Allocates an object, reporting why allocation failed.
     ///
-    /// If `x` was not obtained through a call to `alloc`, or if `x` has already been `dealloc`'d,
-    /// the behavior of `dealloc` is undefined.
+    /// See `ObjectAlloc::alloc_checked`. The default implementation reports every failure of
+    /// `alloc` as exhaustion of `layout()`.
+    unsafe fn alloc_checked(&mut self) -> Result<*mut u8, AllocErr> {
+        let layout = self.layout();
+        self.alloc().map_err(|e| e.into_alloc_err(layout))
+    }
+
+    /// Allocates an object whose memory is zeroed.
+    ///
+    /// All `layout().size()` bytes of the returned object are zero. The default implementation
+    /// zeroes an object returned by `alloc`. Implementations that know that an object has never
+    /// been handed out before (e.g. because it was carved from newly-mapped memory) can avoid
+    /// writing to it.
+    ///
+    /// Zeroing overwrites whatever the object held before. If this allocator otherwise allocates
+    /// initialized objects (as an `ObjectAlloc` used through this trait does), the caller must
+    /// restore a valid object before deallocating it.
+    unsafe fn alloc_zeroed(&mut self) -> Result<*mut u8, Exhausted> {
+        let size = self.layout().size();
+        self.alloc().map(|x| {
+            ptr::write_bytes(x, 0, size);
+            x
+        })
+    }
+
+    /// Deallocates an object previously returned by `alloc` or `alloc_zeroed`.
+    ///
+    /// If `x` was not obtained through a call to `alloc` or `alloc_zeroed`, or if `x` has already
+    /// been `dealloc`'d, the behavior of `dealloc` is undefined.
     unsafe fn dealloc(&mut self, x: *mut u8);
 
+    /// Reports statistics about this allocator.
+    ///
+    /// The default implementation reports nothing; see `ObjectAllocStats`.
+    fn stats(&self) -> ObjectAllocStats {
+        ObjectAllocStats::default()
+    }
+
     /// Allocator-specific method for signalling an out-of-memory condition.
     ///
     /// `oom` aborts the thread or process, optionally performing cleanup or logging diagnostic
@@ -159,6 +429,8 @@ pub unsafe trait UntypedObjectAlloc {
 }
 
 unsafe impl<T> UntypedObjectAlloc for ObjectAlloc<T> {
+    /// Returns `Layout::new::<T>()`. If `T` is zero-sized, so is the layout, and objects are
+    /// allocated as described under "Zero-sized types" in the `ObjectAlloc` documentation.
     fn layout(&self) -> Layout {
         // NOTE: This is safe because the layout method doesn't guarantee that it provides the most
         // specific layout, but rather simply that all objects returned from alloc are guaranteed
@@ -171,7 +443,356 @@ unsafe impl<T> UntypedObjectAlloc for ObjectAlloc<T> {
         ObjectAlloc::alloc(self).map(|x| x as *mut u8)
     }
 
+    unsafe fn alloc_checked(&mut self) -> Result<*mut u8, AllocErr> {
+        ObjectAlloc::alloc_checked(self).map(|x| x as *mut u8)
+    }
+
     unsafe fn dealloc(&mut self, x: *mut u8) {
         ObjectAlloc::dealloc(self, x as *mut T);
     }
+
+    fn stats(&self) -> ObjectAllocStats {
+        ObjectAlloc::stats(self)
+    }
+}
+
+/// An adapter which implements `Alloc` on top of an `UntypedObjectAlloc`.
+///
+/// `AsAlloc` allows a fixed-size object allocator to be used where a general-purpose `Alloc` is
+/// expected, such as in a container all of whose allocations have the same size. A request is
+/// served by the wrapped allocator if its `Layout` fits within the wrapped allocator's layout -
+/// that is, if neither its size nor its alignment is larger. All other requests fail with
+/// `AllocErr::Unsupported`.
+pub struct AsAlloc<A: UntypedObjectAlloc> {
+    alloc: A,
+}
+
+impl<A: UntypedObjectAlloc> AsAlloc<A> {
+    /// Constructs a new `AsAlloc` which allocates from `alloc`.
+    pub fn new(alloc: A) -> AsAlloc<A> {
+        AsAlloc { alloc: alloc }
+    }
+
+    /// Returns a reference to the wrapped allocator.
+    pub fn get_ref(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Consumes this `AsAlloc`, returning the wrapped allocator.
+    pub fn into_inner(self) -> A {
+        self.alloc
+    }
+
+    fn fits(&self, layout: &Layout) -> bool {
+        let inner = self.alloc.layout();
+        layout.size() <= inner.size() && layout.align() <= inner.align()
+    }
+}
+
+unsafe impl<A: UntypedObjectAlloc> Alloc for AsAlloc<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        if !self.fits(&layout) {
+            return Err(AllocErr::Unsupported {
+                details: "layout does not fit within the object allocator's layout",
+            });
+        }
+        // Report exhaustion of the requested layout rather than of the wrapped allocator's layout.
+        self.alloc.alloc_checked().map_err(|err| match err {
+            AllocErr::Exhausted { .. } => AllocErr::Exhausted { request: layout },
+            err => err,
+        })
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, _: Layout) {
+        self.alloc.dealloc(ptr);
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        if self.fits(layout) {
+            (layout.size(), self.alloc.layout().size())
+        } else {
+            (layout.size(), layout.size())
+        }
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> Result<*mut u8, AllocErr> {
+        // Check before allocating so that failure leaves the original object untouched.
+        if !self.fits(&new_layout) {
+            return Err(AllocErr::Unsupported {
+                details: "layout does not fit within the object allocator's layout",
+            });
+        }
+        let new = Alloc::alloc(self, new_layout.clone())?;
+        ptr::copy_nonoverlapping(ptr, new, cmp::min(layout.size(), new_layout.size()));
+        Alloc::dealloc(self, ptr, layout);
+        Ok(new)
+    }
+
+    fn oom(&mut self, _: AllocErr) -> ! {
+        self.alloc.oom()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    struct Buf {
+        bytes: [u8; 64],
+        len: usize,
+    }
+
+    impl Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.bytes.len() {
+                return Err(fmt::Error);
+            }
+            self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn display() {
+        let mut buf = Buf {
+            bytes: [0; 64],
+            len: 0,
+        };
+        write!(buf, "{}", Exhausted).unwrap();
+        assert_eq!(&buf.bytes[..buf.len], b"memory exhausted");
+    }
+
+    #[test]
+    fn alloc_err_conversion() {
+        let layout = Layout::new::<usize>();
+        let err = Exhausted.into_alloc_err(layout.clone());
+        assert_eq!(err, AllocErr::Exhausted { request: layout });
+        assert_eq!(Exhausted::from_alloc_err(err), Ok(Exhausted));
+        let unsupported = AllocErr::Unsupported { details: "" };
+        assert_eq!(
+            Exhausted::from_alloc_err(unsupported.clone()),
+            Err(unsupported)
+        );
+    }
+
+    #[test]
+    fn zero_sized() {
+        #[repr(align(64))]
+        struct Aligned;
+        struct Units(usize);
+        unsafe impl ObjectAlloc<Aligned> for Units {
+            unsafe fn alloc(&mut self) -> Result<*mut Aligned, Exhausted> {
+                self.0 += 1;
+                Ok(dangling())
+            }
+            unsafe fn dealloc(&mut self, _: *mut Aligned) {
+                self.0 -= 1;
+            }
+        }
+        assert_eq!(dangling::<()>() as usize, 1);
+        assert_eq!(dangling::<Aligned>() as usize % 64, 0);
+        let mut a = Units(0);
+        let untyped = &mut a as &mut ObjectAlloc<Aligned>;
+        assert_eq!(
+            UntypedObjectAlloc::layout(untyped),
+            Layout::from_size_align(0, 64).unwrap()
+        );
+        unsafe {
+            let x = UntypedObjectAlloc::alloc(untyped).unwrap();
+            assert_eq!(x as usize % 64, 0);
+            UntypedObjectAlloc::dealloc(untyped, x);
+        }
+        assert_eq!(a.0, 0);
+    }
+
+    #[test]
+    fn as_alloc() {
+        use alloc::heap::Heap;
+
+        // A fixed-layout allocator which checks that every object it hands out is returned.
+        struct Nodes {
+            layout: Layout,
+            live: usize,
+        }
+        unsafe impl UntypedObjectAlloc for Nodes {
+            fn layout(&self) -> Layout {
+                self.layout.clone()
+            }
+            unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+                self.live += 1;
+                Alloc::alloc(&mut Heap, self.layout.clone()).map_err(|_| Exhausted)
+            }
+            unsafe fn dealloc(&mut self, x: *mut u8) {
+                self.live -= 1;
+                Alloc::dealloc(&mut Heap, x, self.layout.clone());
+            }
+        }
+
+        struct Node {
+            val: usize,
+            next: *mut Node,
+        }
+
+        let node = Layout::new::<Node>();
+        let mut a = AsAlloc::new(Nodes {
+            layout: node.clone(),
+            live: 0,
+        });
+        unsafe {
+            // Build a linked list, then walk it and free it.
+            let mut head: *mut Node = ptr::null_mut();
+            for i in 0..100 {
+                let n = Alloc::alloc(&mut a, node.clone()).unwrap() as *mut Node;
+                ptr::write(n, Node { val: i, next: head });
+                head = n;
+            }
+            assert_eq!(a.get_ref().live, 100);
+            let mut expect = 100;
+            while !head.is_null() {
+                expect -= 1;
+                assert_eq!((*head).val, expect);
+                let next = (*head).next;
+                Alloc::dealloc(&mut a, head as *mut u8, node.clone());
+                head = next;
+            }
+            assert_eq!(expect, 0);
+
+            // Smaller layouts fit, but larger or more-aligned ones do not.
+            let small = Layout::new::<u8>();
+            let x = Alloc::alloc(&mut a, small.clone()).unwrap();
+            *x = 42;
+            assert_eq!(a.usable_size(&small), (1, node.size()));
+            let larger = Layout::from_size_align(node.size() + 1, node.align()).unwrap();
+            let aligned = Layout::from_size_align(node.size(), node.align() * 2).unwrap();
+            for l in &[larger.clone(), aligned] {
+                match Alloc::alloc(&mut a, l.clone()) {
+                    Err(AllocErr::Unsupported { .. }) => {}
+                    res => panic!("expected AllocErr::Unsupported, got {:?}", res),
+                }
+            }
+            match a.realloc(x, small.clone(), larger) {
+                Err(AllocErr::Unsupported { .. }) => {}
+                res => panic!("expected AllocErr::Unsupported, got {:?}", res),
+            }
+            assert_eq!(*x, 42);
+            let y = a.realloc(x, small, node.clone()).unwrap();
+            assert_eq!(*y, 42);
+            Alloc::dealloc(&mut a, y, node);
+        }
+        assert_eq!(a.into_inner().live, 0);
+    }
+
+    #[test]
+    fn alloc_many() {
+        // An allocator with room for only a few objects.
+        struct Capped {
+            slots: [usize; 4],
+            used: [bool; 4],
+        }
+        unsafe impl ObjectAlloc<usize> for Capped {
+            unsafe fn alloc(&mut self) -> Result<*mut usize, Exhausted> {
+                match self.used.iter().position(|used| !used) {
+                    Some(i) => {
+                        self.used[i] = true;
+                        Ok(&mut self.slots[i] as *mut usize)
+                    }
+                    None => Err(Exhausted),
+                }
+            }
+            unsafe fn dealloc(&mut self, x: *mut usize) {
+                let i = (x as usize - self.slots.as_ptr() as usize) / mem::size_of::<usize>();
+                assert!(self.used[i], "double free");
+                self.used[i] = false;
+            }
+        }
+
+        let mut a = Capped {
+            slots: [0; 4],
+            used: [false; 4],
+        };
+        unsafe {
+            {
+                let mut iter = a.alloc_many();
+                let first = iter.next().unwrap();
+                // deallocate through the iterator while it borrows the allocator
+                iter.dealloc(first);
+                assert_eq!(iter.by_ref().count(), 4);
+                assert!(iter.exhausted());
+                // iteration does not resume, even after a dealloc
+                iter.dealloc(first);
+                assert_eq!(iter.next(), None);
+            }
+            assert_eq!(a.used, [false, true, true, true]);
+            for i in 1..4 {
+                let x = &mut a.slots[i] as *mut usize;
+                a.dealloc(x);
+            }
+
+            {
+                let mut iter = a.alloc_up_to(3);
+                assert_eq!(iter.size_hint(), (0, Some(3)));
+                assert_eq!(iter.by_ref().count(), 3);
+                assert!(!iter.exhausted());
+            }
+            assert_eq!(a.used, [true, true, true, false]);
+            {
+                let mut iter = a.alloc_up_to(3);
+                assert_eq!(iter.by_ref().count(), 1);
+                assert!(iter.exhausted());
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed() {
+        use alloc::heap::Heap;
+
+        // An allocator whose objects are never zeroed.
+        struct Dirty(Layout);
+        unsafe impl UntypedObjectAlloc for Dirty {
+            fn layout(&self) -> Layout {
+                self.0.clone()
+            }
+            unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+                let x = Alloc::alloc(&mut Heap, self.0.clone()).map_err(|_| Exhausted)?;
+                ptr::write_bytes(x, 0xff, self.0.size());
+                Ok(x)
+            }
+            unsafe fn dealloc(&mut self, x: *mut u8) {
+                Alloc::dealloc(&mut Heap, x, self.0.clone());
+            }
+        }
+
+        let mut a = Dirty(Layout::from_size_align(96, 8).unwrap());
+        unsafe {
+            let x = a.alloc_zeroed().unwrap();
+            for i in 0..96 {
+                assert_eq!(*x.offset(i), 0);
+            }
+            a.dealloc(x);
+        }
+    }
+
+    #[test]
+    fn default_stats() {
+        struct Leaky;
+        unsafe impl ObjectAlloc<u8> for Leaky {
+            unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+                Err(Exhausted)
+            }
+            unsafe fn dealloc(&mut self, _: *mut u8) {}
+        }
+        let stats = ObjectAlloc::stats(&Leaky);
+        assert_eq!(stats, ObjectAllocStats::default());
+        assert_eq!(stats.cached_objects, None);
+        assert_eq!(UntypedObjectAlloc::stats(&Leaky as &ObjectAlloc<u8>), stats);
+    }
 }
@@ -0,0 +1,260 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An adapter for using per-thread allocator handles through a shared reference.
+//!
+//! Many allocators hand out one handle per thread: handles are cheap to `clone`, each has its own
+//! cache, and they share their backing memory, so that an object allocated with one handle can be
+//! freed with any other. `PerThreadAlloc` manages such handles automatically. Each thread that
+//! uses a `PerThreadAlloc` gets its own clone of the wrapped allocator the first time it allocates
+//! or deallocates, and uses that clone for every subsequent operation.
+//!
+//! This module is only available with the `std` feature.
+//!
+//! ```rust,ignore
+//! let a = Arc::new(PerThreadAlloc::new(MyAlloc::new()));
+//! let b = a.clone();
+//! let obj = thread::spawn(move || unsafe { b.alloc() }.unwrap() as usize).join().unwrap();
+//! unsafe { a.dealloc(obj as *mut _) };
+//! ```
+
+use std::prelude::v1::*;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::{Exhausted, ObjectAlloc, ObjectAllocStats, SharedObjectAlloc};
+
+/// The id of the next `PerThreadAlloc` to be created. Ids are never reused.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+thread_local! {
+    /// The handles used by the current thread, keyed by `PerThreadAlloc` id.
+    ///
+    /// Entries hold pointers to `Slot`s owned by the `PerThreadAlloc`. Entries for a
+    /// `PerThreadAlloc` that has been dropped are never looked up again, since ids are unique.
+    static HANDLES: RefCell<Vec<(usize, usize)>> = RefCell::new(Vec::new());
+}
+
+/// A thread's handle.
+///
+/// A `Slot` is only ever used by the thread that created it, through the pointer in that thread's
+/// `HANDLES`. `PerThreadAlloc` owns its `Slot`s so that they can be dropped along with it.
+struct Slot<A> {
+    handle: UnsafeCell<A>,
+    /// Set while the handle is being used, to detect reentrant calls (for example, from the
+    /// destructor of an object being deallocated).
+    in_use: Cell<bool>,
+}
+
+/// A `SharedObjectAlloc` that uses a separate handle of an `ObjectAlloc` on each thread.
+///
+/// `A` must be a shared-handle allocator: objects allocated with any clone of an `A` must be
+/// valid to deallocate with any other clone, on any thread, and must remain valid after the clone
+/// that allocated them is dropped.
+///
+/// Handles are created from the allocator passed to `new`, and are kept until the
+/// `PerThreadAlloc` is dropped, at which point all of them are dropped (dropping any objects they
+/// cache). If a handle cannot be used - because the current thread is being torn down, or because
+/// the call was made from within another call on the same thread - a temporary clone is used for
+/// that call instead.
+pub struct PerThreadAlloc<T, A: ObjectAlloc<T> + Clone + Send> {
+    id: usize,
+    /// The allocator new handles are cloned from.
+    parent: Mutex<A>,
+    /// Every handle created so far, one per thread.
+    slots: Mutex<Vec<Box<Slot<A>>>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, A: ObjectAlloc<T> + Clone + Send> PerThreadAlloc<T, A> {
+    /// Creates a new `PerThreadAlloc` whose handles are clones of `parent`.
+    pub fn new(parent: A) -> PerThreadAlloc<T, A> {
+        PerThreadAlloc {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            parent: Mutex::new(parent),
+            slots: Mutex::new(Vec::new()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// The number of handles created so far.
+    pub fn n_handles(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    fn new_slot(&self) -> *const Slot<A> {
+        let slot = Box::new(Slot {
+            handle: UnsafeCell::new(self.parent.lock().unwrap().clone()),
+            in_use: Cell::new(false),
+        });
+        let ptr = &*slot as *const Slot<A>;
+        self.slots.lock().unwrap().push(slot);
+        ptr
+    }
+
+    /// Calls `f` with the current thread's handle.
+    fn with_handle<R, F: FnOnce(&mut A) -> R>(&self, f: F) -> R {
+        let id = self.id;
+        let slot = HANDLES.try_with(|handles| {
+            let existing = handles
+                .borrow()
+                .iter()
+                .find(|&&(slot_id, _)| slot_id == id)
+                .map(|&(_, slot)| slot as *const Slot<A>);
+            existing.unwrap_or_else(|| {
+                let slot = self.new_slot();
+                handles.borrow_mut().push((id, slot as usize));
+                slot
+            })
+        });
+        match slot {
+            Ok(slot) => unsafe {
+                if (*slot).in_use.get() {
+                    return self.with_temporary(f);
+                }
+                (*slot).in_use.set(true);
+                let res = f(&mut *(*slot).handle.get());
+                (*slot).in_use.set(false);
+                res
+            },
+            Err(_) => self.with_temporary(f),
+        }
+    }
+
+    #[cold]
+    fn with_temporary<R, F: FnOnce(&mut A) -> R>(&self, f: F) -> R {
+        let mut handle = self.parent.lock().unwrap().clone();
+        f(&mut handle)
+    }
+}
+
+unsafe impl<T, A: ObjectAlloc<T> + Clone + Send> SharedObjectAlloc<T> for PerThreadAlloc<T, A> {
+    unsafe fn alloc(&self) -> Result<*mut T, Exhausted> {
+        self.with_handle(|h| h.alloc())
+    }
+
+    unsafe fn dealloc(&self, x: *mut T) {
+        self.with_handle(|h| h.dealloc(x))
+    }
+
+    /// Reports the statistics of the current thread's handle.
+    fn stats(&self) -> ObjectAllocStats {
+        self.with_handle(|h| h.stats())
+    }
+}
+
+impl<T, A: ObjectAlloc<T> + Clone + Send> Drop for PerThreadAlloc<T, A> {
+    fn drop(&mut self) {
+        let id = self.id;
+        let _ = HANDLES.try_with(|handles| handles.borrow_mut().retain(|&(slot_id, _)| slot_id != id));
+        // The slots (and the handles in them) are dropped along with `self.slots`.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A shared-handle allocator of leaked boxes that counts its live handles.
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Clone for Counting {
+        fn clone(&self) -> Counting {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Counting(self.0.clone())
+        }
+    }
+
+    impl Drop for Counting {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    unsafe impl ObjectAlloc<usize> for Counting {
+        unsafe fn alloc(&mut self) -> Result<*mut usize, Exhausted> {
+            Ok(Box::into_raw(Box::new(0)))
+        }
+
+        unsafe fn dealloc(&mut self, x: *mut usize) {
+            drop(Box::from_raw(x));
+        }
+    }
+
+    #[test]
+    fn one_handle_per_thread() {
+        let live = Arc::new(AtomicUsize::new(1));
+        let a = Arc::new(PerThreadAlloc::new(Counting(live.clone())));
+        let threads = (0..4)
+            .map(|_| {
+                let a = a.clone();
+                thread::spawn(move || for _ in 0..16 {
+                    unsafe { a.dealloc(a.alloc().unwrap()) };
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(a.n_handles(), 4);
+        // handles outlive their threads, and are dropped with the allocator
+        assert_eq!(live.load(Ordering::SeqCst), 5);
+        drop(Arc::try_unwrap(a).ok().unwrap());
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn cross_thread_frees() {
+        use std::sync::Barrier;
+        const N_THREADS: usize = 8;
+        const N_ITEMS: usize = 64;
+        let live = Arc::new(AtomicUsize::new(1));
+        let a = Arc::new(PerThreadAlloc::new(Counting(live.clone())));
+        let barrier = Arc::new(Barrier::new(N_THREADS));
+        let objs = Arc::new(Mutex::new(vec![Vec::new(); N_THREADS]));
+        let threads = (0..N_THREADS)
+            .map(|i| {
+                let (a, barrier, objs) = (a.clone(), barrier.clone(), objs.clone());
+                thread::spawn(move || {
+                    let mine = (0..N_ITEMS)
+                        .map(|_| unsafe {
+                            let obj = a.alloc().unwrap();
+                            *obj = i;
+                            obj as usize
+                        })
+                        .collect();
+                    objs.lock().unwrap()[i] = mine;
+                    barrier.wait();
+                    // free the objects of the next thread, which is still alive and holds the
+                    // handle that allocated them
+                    let from = (i + 1) % N_THREADS;
+                    let theirs = ::std::mem::replace(&mut objs.lock().unwrap()[from], Vec::new());
+                    assert_eq!(theirs.len(), N_ITEMS);
+                    for obj in theirs {
+                        unsafe {
+                            assert_eq!(*(obj as *mut usize), from);
+                            a.dealloc(obj as *mut usize);
+                        }
+                    }
+                    barrier.wait();
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(objs.lock().unwrap().iter().all(Vec::is_empty));
+        assert_eq!(a.n_handles(), N_THREADS);
+        assert_eq!(live.load(Ordering::SeqCst), N_THREADS + 1);
+        drop(Arc::try_unwrap(a).ok().unwrap());
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+}
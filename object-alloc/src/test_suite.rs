@@ -0,0 +1,450 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A suite of contract checks for `ObjectAlloc` implementations.
+//!
+//! Each check exercises an `ObjectAlloc` and panics if it violates the contract documented on the
+//! `ObjectAlloc` trait. Most checks are built around `Tracked`, an instrumented type that counts
+//! how many times it has been constructed and dropped, and which detects being dropped twice or
+//! being handed out after it was dropped. Since these checks need control over how objects are
+//! initialized, they take a constructor for the allocator under test rather than an allocator.
+//!
+//! ```rust,ignore
+//! check_drop_on_allocator_drop(|counters| {
+//!     MyAlloc::new(move || Tracked::new(counters))
+//! });
+//! ```
+//!
+//! This module is only available with the `test-utils` feature.
+
+use std::prelude::v1::*;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use core::mem;
+
+use std::sync::Arc;
+
+use super::{ObjectAlloc, SharedObjectAlloc};
+
+/// The number of objects allocated at once by each check.
+const N_OBJECTS: usize = 1 << 12;
+
+const MAGIC_LIVE: usize = 0x5ca1_ab1e;
+const MAGIC_DROPPED: usize = 0xdead_beef;
+
+/// The `state` of a `Tracked` that has just been constructed.
+pub const NEW_STATE: usize = 0;
+
+/// Construction and drop counters shared by a set of `Tracked` objects.
+#[derive(Debug, Default)]
+pub struct Counters {
+    constructed: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl Counters {
+    /// Creates a new set of counters.
+    ///
+    /// The counters are leaked so that `Tracked` objects can refer to them without any lifetime
+    /// constraints; this is fine for tests.
+    pub fn new() -> &'static Counters {
+        unsafe { &*Box::into_raw(Box::new(Counters::default())) }
+    }
+
+    /// The number of `Tracked` objects constructed with these counters.
+    pub fn constructed(&self) -> usize {
+        self.constructed.load(Ordering::SeqCst)
+    }
+
+    /// The number of `Tracked` objects constructed with these counters that have been dropped.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+/// An instrumented type for testing `ObjectAlloc`s.
+///
+/// Every construction and drop of a `Tracked` is recorded in its `Counters`. Dropping a `Tracked`
+/// twice, or calling `check` on one that has been dropped, panics.
+#[derive(Debug)]
+pub struct Tracked {
+    magic: usize,
+    /// A value for use by checks. A newly-constructed object has state `NEW_STATE`.
+    pub state: usize,
+    counters: &'static Counters,
+}
+
+impl Tracked {
+    pub fn new(counters: &'static Counters) -> Tracked {
+        counters.constructed.fetch_add(1, Ordering::SeqCst);
+        Tracked {
+            magic: MAGIC_LIVE,
+            state: NEW_STATE,
+            counters: counters,
+        }
+    }
+
+    /// Panics if `self` is not a live object: it has already been dropped, or it was never
+    /// constructed.
+    pub fn check(&self) {
+        assert!(self.magic != MAGIC_DROPPED, "use of a dropped Tracked object");
+        assert_eq!(self.magic, MAGIC_LIVE, "use of an unconstructed Tracked object");
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // A check has already failed; panicking again would abort.
+            return;
+        }
+        assert!(self.magic != MAGIC_DROPPED, "Tracked object dropped twice");
+        self.check();
+        self.magic = MAGIC_DROPPED;
+        self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Checks that allocated objects are distinct and aligned to `T`'s alignment.
+///
+/// Objects of a zero-sized type are only checked for alignment, as they may share an address.
+pub fn check_alignment<T, A: ObjectAlloc<T>>(mut a: A) {
+    let mut objs = Vec::with_capacity(N_OBJECTS);
+    for _ in 0..N_OBJECTS {
+        let obj = unsafe { a.alloc() }.expect("allocation failed");
+        assert_eq!(
+            obj as usize % mem::align_of::<T>(),
+            0,
+            "object {:?} is not aligned to {}",
+            obj,
+            mem::align_of::<T>()
+        );
+        objs.push(obj);
+    }
+    if mem::size_of::<T>() != 0 {
+        let mut addrs: Vec<usize> = objs.iter().map(|obj| *obj as usize).collect();
+        addrs.sort();
+        addrs.dedup();
+        assert_eq!(addrs.len(), objs.len(), "an object was allocated twice");
+    }
+    for obj in objs {
+        unsafe { a.dealloc(obj) };
+    }
+}
+
+/// Checks that every object that is constructed is eventually dropped exactly once, and that all
+/// objects have been dropped once the allocator itself is dropped.
+pub fn check_drop_on_allocator_drop<A, F>(new: F)
+where
+    A: ObjectAlloc<Tracked>,
+    F: FnOnce(&'static Counters) -> A,
+{
+    let counters = Counters::new();
+    let mut a = new(counters);
+    let mut objs = Vec::with_capacity(N_OBJECTS);
+    for _ in 0..N_OBJECTS {
+        let obj = unsafe { a.alloc() }.expect("allocation failed");
+        unsafe { (*obj).check() };
+        objs.push(obj);
+    }
+    // Free half of the objects, allocate them again, and then free everything, leaving a mix of
+    // objects that have been reused and objects that have not.
+    for obj in objs.drain(N_OBJECTS / 2..) {
+        unsafe { a.dealloc(obj) };
+    }
+    for _ in 0..N_OBJECTS / 4 {
+        objs.push(unsafe { a.alloc() }.expect("allocation failed"));
+    }
+    for obj in objs {
+        unsafe {
+            (*obj).check();
+            a.dealloc(obj);
+        }
+    }
+    mem::drop(a);
+    assert_eq!(
+        counters.constructed(),
+        counters.dropped(),
+        "objects were not dropped when the allocator was dropped"
+    );
+}
+
+/// Checks that every allocated object is either newly constructed or in the state it was in when
+/// it was last freed, and that no object is handed out while it is still allocated.
+pub fn check_recycle_state<A, F>(new: F)
+where
+    A: ObjectAlloc<Tracked>,
+    F: FnOnce(&'static Counters) -> A,
+{
+    let counters = Counters::new();
+    let mut a = new(counters);
+    // Every object handed out by the allocator is given a unique state, starting from 1. States
+    // of objects that have been freed may legally be seen again.
+    let mut next_state = NEW_STATE + 1;
+    let mut freed = HashSet::new();
+    let mut objs = Vec::with_capacity(N_OBJECTS);
+    for round in 0..4 {
+        while objs.len() < N_OBJECTS {
+            let obj = unsafe { a.alloc() }.expect("allocation failed");
+            let state = unsafe {
+                (*obj).check();
+                (*obj).state
+            };
+            assert!(
+                state == NEW_STATE || freed.remove(&state),
+                "allocated object has state {}, which is not the state of a freed object",
+                state
+            );
+            unsafe { (*obj).state = next_state };
+            next_state += 1;
+            objs.push(obj);
+        }
+        // Free a different subset of the objects in each round.
+        let mut i = 0;
+        objs.retain(|obj| {
+            i += 1;
+            if i % 4 == round {
+                return true;
+            }
+            unsafe {
+                freed.insert((**obj).state);
+                a.dealloc(*obj);
+            }
+            false
+        });
+    }
+    for obj in objs {
+        unsafe { a.dealloc(obj) };
+    }
+    mem::drop(a);
+    assert_eq!(counters.constructed(), counters.dropped());
+}
+
+/// Checks that a shared-handle allocator can be used concurrently from `n_threads` threads, with
+/// objects freed on a different thread than the one that allocated them.
+///
+/// Each thread uses its own clone of the allocator returned by `new`.
+pub fn check_concurrent<A, F>(new: F, n_threads: usize)
+where
+    A: ObjectAlloc<Tracked> + Clone + Send + 'static,
+    F: FnOnce(&'static Counters) -> A,
+{
+    assert!(n_threads > 0);
+    let counters = Counters::new();
+    let a = new(counters);
+    // Thread i sends objects to thread i + 1 (mod n_threads) to be freed.
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..n_threads).map(|_| channel::<usize>()).unzip();
+    let threads: Vec<_> = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(t, receiver)| {
+            let mut a = a.clone();
+            let sender = senders[(t + 1) % n_threads].clone();
+            thread::spawn(move || {
+                let tag = (t + 1) * N_OBJECTS;
+                let mut objs = Vec::with_capacity(N_OBJECTS);
+                for i in 0..N_OBJECTS {
+                    let obj = unsafe { a.alloc() }.expect("allocation failed");
+                    unsafe {
+                        (*obj).check();
+                        (*obj).state = tag + i;
+                    }
+                    objs.push(obj);
+                }
+                for (i, obj) in objs.into_iter().enumerate() {
+                    unsafe {
+                        (*obj).check();
+                        assert_eq!((*obj).state, tag + i, "object modified by another thread");
+                        if i % 2 == 0 {
+                            a.dealloc(obj);
+                        } else {
+                            sender.send(obj as usize).unwrap();
+                        }
+                    }
+                }
+                mem::drop(sender);
+                for obj in receiver {
+                    unsafe {
+                        (*(obj as *mut Tracked)).check();
+                        a.dealloc(obj as *mut Tracked);
+                    }
+                }
+            })
+        })
+        .collect();
+    // Each receiver is done once the thread sending to it drops its clone of the sender.
+    mem::drop(senders);
+    for t in threads {
+        t.join().expect("thread panicked");
+    }
+    mem::drop(a);
+    assert_eq!(counters.constructed(), counters.dropped());
+}
+
+/// Checks that a `SharedObjectAlloc` can be used concurrently from `n_threads` threads, with
+/// objects freed on a different thread than the one that allocated them.
+///
+/// Unlike `check_concurrent`, all threads use the single allocator returned by `new`.
+pub fn check_shared_concurrent<A, F>(new: F, n_threads: usize)
+where
+    A: SharedObjectAlloc<Tracked> + Send + 'static,
+    F: FnOnce(&'static Counters) -> A,
+{
+    assert!(n_threads > 0);
+    let counters = Counters::new();
+    let a = Arc::new(new(counters));
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..n_threads).map(|_| channel::<usize>()).unzip();
+    let threads: Vec<_> = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(t, receiver)| {
+            let a = a.clone();
+            let sender = senders[(t + 1) % n_threads].clone();
+            thread::spawn(move || {
+                let tag = (t + 1) * N_OBJECTS;
+                for i in 0..N_OBJECTS {
+                    let obj = unsafe { a.alloc() }.expect("allocation failed");
+                    unsafe {
+                        (*obj).check();
+                        (*obj).state = tag + i;
+                        if i % 2 == 0 {
+                            a.dealloc(obj);
+                        } else {
+                            sender.send(obj as usize).unwrap();
+                        }
+                    }
+                }
+                mem::drop(sender);
+                for obj in receiver {
+                    unsafe {
+                        (*(obj as *mut Tracked)).check();
+                        a.dealloc(obj as *mut Tracked);
+                    }
+                }
+            })
+        })
+        .collect();
+    mem::drop(senders);
+    for t in threads {
+        t.join().expect("thread panicked");
+    }
+    let a = Arc::try_unwrap(a).ok().expect("allocator still shared");
+    mem::drop(a);
+    assert_eq!(counters.constructed(), counters.dropped());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Exhausted;
+    use std::sync::Mutex;
+
+    /// A simple `ObjectAlloc` that allocates from the heap, caching freed objects.
+    struct HeapAlloc {
+        init: Box<Fn() -> Tracked + Send>,
+        cache: Vec<*mut Tracked>,
+        // If set, incorrectly drop objects in dealloc as well as caching them.
+        drop_on_dealloc: bool,
+    }
+
+    impl HeapAlloc {
+        fn new<F: Fn() -> Tracked + Send + 'static>(init: F) -> HeapAlloc {
+            HeapAlloc {
+                init: Box::new(init),
+                cache: Vec::new(),
+                drop_on_dealloc: false,
+            }
+        }
+    }
+
+    unsafe impl ObjectAlloc<Tracked> for HeapAlloc {
+        unsafe fn alloc(&mut self) -> Result<*mut Tracked, Exhausted> {
+            Ok(self.cache.pop().unwrap_or_else(
+                || Box::into_raw(Box::new((self.init)())),
+            ))
+        }
+
+        unsafe fn dealloc(&mut self, x: *mut Tracked) {
+            if self.drop_on_dealloc {
+                ::core::ptr::drop_in_place(x);
+            }
+            self.cache.push(x);
+        }
+    }
+
+    impl Drop for HeapAlloc {
+        fn drop(&mut self) {
+            for obj in self.cache.drain(..) {
+                mem::drop(unsafe { Box::from_raw(obj) });
+            }
+        }
+    }
+
+    /// A shared-handle wrapper around a `HeapAlloc`.
+    struct SharedHeapAlloc(Arc<Mutex<HeapAlloc>>);
+
+    unsafe impl Send for SharedHeapAlloc {}
+
+    impl Clone for SharedHeapAlloc {
+        fn clone(&self) -> SharedHeapAlloc {
+            SharedHeapAlloc(self.0.clone())
+        }
+    }
+
+    unsafe impl ObjectAlloc<Tracked> for SharedHeapAlloc {
+        unsafe fn alloc(&mut self) -> Result<*mut Tracked, Exhausted> {
+            self.0.lock().unwrap().alloc()
+        }
+
+        unsafe fn dealloc(&mut self, x: *mut Tracked) {
+            self.0.lock().unwrap().dealloc(x)
+        }
+    }
+
+    #[test]
+    fn heap_alloc() {
+        let counters = Counters::new();
+        check_alignment(HeapAlloc::new(move || Tracked::new(counters)));
+        check_drop_on_allocator_drop(|counters| HeapAlloc::new(move || Tracked::new(counters)));
+        check_recycle_state(|counters| HeapAlloc::new(move || Tracked::new(counters)));
+    }
+
+    #[test]
+    fn shared_heap_alloc() {
+        check_concurrent(
+            |counters| {
+                let a = HeapAlloc::new(move || Tracked::new(counters));
+                SharedHeapAlloc(Arc::new(Mutex::new(a)))
+            },
+            4,
+        );
+    }
+
+    #[test]
+    fn per_thread_heap_alloc() {
+        use super::super::per_thread::PerThreadAlloc;
+        check_shared_concurrent(
+            |counters| {
+                let a = HeapAlloc::new(move || Tracked::new(counters));
+                PerThreadAlloc::new(SharedHeapAlloc(Arc::new(Mutex::new(a))))
+            },
+            4,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped")]
+    fn detects_double_drop() {
+        check_drop_on_allocator_drop(|counters| {
+            let mut a = HeapAlloc::new(move || Tracked::new(counters));
+            a.drop_on_dealloc = true;
+            a
+        });
+    }
+}
@@ -12,3 +12,5 @@ set -e
 
 travis-cargo --only nightly build
 RUST_BACKTRACE=1 travis-cargo --only nightly test
+travis-cargo --only nightly build -- --no-default-features
+RUST_BACKTRACE=1 travis-cargo --only nightly test -- --features test-utils
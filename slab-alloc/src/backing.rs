@@ -43,6 +43,10 @@ pub mod alloc {
     use self::object_alloc::{Exhausted, UntypedObjectAlloc};
 
     /// An `UntypedObjectAlloc` that uses an arbitrary allocator.
+    ///
+    /// If the backing allocator reports `AllocErr::Unsupported`, `alloc` fails with `Exhausted`
+    /// and `alloc_checked` passes the error through unchanged. With the `strict` feature, `alloc`
+    /// panics instead.
     #[derive(Clone)]
     pub struct AllocObjectAlloc<A: Alloc> {
         alloc: A,
@@ -64,12 +68,19 @@ pub mod alloc {
             match self.alloc.alloc(self.layout.clone()) {
                 Ok(ptr) => Ok(ptr),
                 Err(AllocErr::Exhausted { .. }) => Err(Exhausted),
+                #[cfg(feature = "strict")]
                 Err(AllocErr::Unsupported { details }) => {
                     unreachable!("unexpected unsupported alloc: {}", details)
                 }
+                #[cfg(not(feature = "strict"))]
+                Err(AllocErr::Unsupported { .. }) => Err(Exhausted),
             }
         }
 
+        unsafe fn alloc_checked(&mut self) -> Result<*mut u8, AllocErr> {
+            self.alloc.alloc(self.layout.clone())
+        }
+
         unsafe fn dealloc(&mut self, ptr: *mut u8) {
             self.alloc.dealloc(ptr, self.layout.clone());
         }
@@ -13,7 +13,7 @@ extern crate sysconf;
 extern crate test;
 
 use SlabAllocBuilder;
-use self::alloc::heap::{Alloc, Heap, Layout};
+use self::alloc::heap::{Alloc, AllocErr, Heap, Layout};
 use self::object_alloc::{Exhausted, ObjectAlloc};
 use self::test::{Bencher, black_box};
 use self::object_alloc_test::leaky_alloc::LeakyAlloc;
@@ -46,6 +46,38 @@ fn leaky_get_large(layout: Layout) -> AllocObjectAlloc<LeakyAlloc> {
     AllocObjectAlloc::new(LeakyAlloc::new(), layout)
 }
 
+/// An allocator that supports no requests at all.
+struct UnsupportedAlloc;
+
+unsafe impl Alloc for UnsupportedAlloc {
+    unsafe fn alloc(&mut self, _: Layout) -> Result<*mut u8, AllocErr> {
+        Err(AllocErr::Unsupported { details: "UnsupportedAlloc" })
+    }
+
+    unsafe fn dealloc(&mut self, _: *mut u8, _: Layout) {
+        unreachable!()
+    }
+}
+
+#[cfg(not(feature = "strict"))]
+#[test]
+fn test_unsupported_backing_alloc() {
+    use self::object_alloc::UntypedObjectAlloc;
+    let layout = Layout::from_size_align(64, 64).unwrap();
+    let mut alloc = AllocObjectAlloc::new(UnsupportedAlloc, layout.clone());
+    unsafe {
+        assert_eq!(UntypedObjectAlloc::alloc(&mut alloc), Err(Exhausted));
+        match alloc.alloc_checked() {
+            Err(AllocErr::Unsupported { .. }) => {}
+            res => panic!("expected AllocErr::Unsupported, got {:?}", res),
+        }
+        // the caller can recover by falling back to another allocator
+        let mut fallback = AllocObjectAlloc::new(Heap, layout);
+        let ptr = fallback.alloc_checked().unwrap();
+        UntypedObjectAlloc::dealloc(&mut fallback, ptr);
+    }
+}
+
 fn test_memory_corruption<T: Copy + Send + 'static>() {
     use self::object_alloc_test::foreach_align;
     use self::object_alloc_test::corruption::{CorruptionTesterDefault, TestBuilder};